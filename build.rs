@@ -0,0 +1,12 @@
+fn main() {
+    // The gRPC code is generated with protox, so protoc isn't needed
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/multissh.proto");
+        let descriptors = protox::compile(["multissh.proto"], ["proto"])
+            .expect("Failed to parse proto/multissh.proto");
+        tonic_build::configure()
+            .compile_fds(descriptors)
+            .expect("Failed to generate the gRPC code");
+    }
+}
@@ -0,0 +1,284 @@
+//! HTTP API of `multissh serve`, on top of the job queue of the daemon.
+//!
+//! - `POST /jobs` with `{"group": "web", "command": "uptime"}` queues a job
+//!   and answers `{"id": 3}`. `"targets": ["host1", "host2"]` can be given
+//!   instead of, or in addition to, the inventory group, with the same
+//!   `@alias` and range syntax as `-t`.
+//! - `GET /jobs` lists the jobs, without their host results.
+//! - `GET /jobs/<id>` is a job with the result of every host once it is done.
+//! - `GET /jobs/<id>/hosts/<host>` is the result of a single host.
+//!
+//! Bodies are JSON, errors are `{"error": "..."}`. Every request needs an
+//! `Authorization: Bearer <token>` header, with the token given or the one
+//! made up and printed at startup, and `POST /jobs` a `Content-Type` of
+//! `application/json`. Served on loopback, the `Host` and any `Origin`
+//! must be loopback names too, and elsewhere an `Origin` must be the
+//! server itself: web pages the operator visits can't queue commands,
+//! not with simple requests nor through DNS rebinding.
+//!
+//! `GET /` is a web UI on top of these endpoints to browse the jobs, filter
+//! their hosts by name and status, and read their output.
+
+use crate::config::Aliases;
+use crate::daemon::{Daemon, Request, Response};
+use crate::inventory::Inventory;
+use crate::report::HTML_STYLE;
+use crate::runner::RunOptions;
+use crate::ssh::SshOptions;
+use crate::targets::{self, Target};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+use tiny_http::{Header, Method, Server};
+
+const UI_PAGE: &str = include_str!("ui.html");
+
+/// Settings of the API server
+pub struct ApiOptions {
+    /// Address to listen on, e.g. `127.0.0.1:8080`
+    pub listen: String,
+    /// Bearer token every request must carry
+    pub token: String,
+    /// Inventory the groups of submitted jobs are looked up in
+    pub inventory: Option<Inventory>,
+    /// Aliases the `@name` targets of submitted jobs are looked up in
+    pub aliases: Aliases,
+    /// How the hosts of every job are reached
+    pub ssh: SshOptions,
+    pub run: RunOptions,
+}
+
+/// Body of `POST /jobs`
+#[derive(Deserialize)]
+pub(crate) struct Submit {
+    pub command: String,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+/// Answer API requests until the process is killed
+pub fn serve(daemon: &Daemon, options: &ApiOptions) -> Result<()> {
+    let addrs = listen_addrs(&options.listen)?;
+    let loopback = addrs.iter().all(|addr| addr.ip().is_loopback());
+    let server = Server::http(addrs.as_slice())
+        .map_err(|e| anyhow!("Failed to listen on {}: {}", options.listen, e))?;
+    eprintln!("Serving the API and web UI on http://{}", options.listen);
+
+    for mut request in server.incoming_requests() {
+        if !same_origin(&request, loopback) {
+            let (status, body) = error(403, "Host or Origin not allowed".to_string());
+            let response = tiny_http::Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+            if let Err(e) = request.respond(response) {
+                eprintln!("Failed to answer request: {}", e);
+            }
+            continue;
+        }
+        // The page has no data of its own, it asks the API for everything
+        if *request.method() == Method::Get && request.url().split('?').next() == Some("/") {
+            let page = UI_PAGE.replace("/* report style */", HTML_STYLE);
+            let response = tiny_http::Response::from_string(page).with_header(
+                Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap(),
+            );
+            if let Err(e) = request.respond(response) {
+                eprintln!("Failed to answer request: {}", e);
+            }
+            continue;
+        }
+        let (status, body) = if !authorized(&request, options) {
+            error(401, "Missing or wrong bearer token".to_string())
+        } else if *request.method() == Method::Post && !json_body(&request) {
+            error(
+                415,
+                "Expected a Content-Type of application/json".to_string(),
+            )
+        } else {
+            let mut body = String::new();
+            match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => route(daemon, options, request.method(), request.url(), &body),
+                Err(e) => error(400, format!("Failed to read the request: {}", e)),
+            }
+        };
+        let response = tiny_http::Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        if let Err(e) = request.respond(response) {
+            eprintln!("Failed to answer request: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Addresses `listen` resolves to
+pub(crate) fn listen_addrs(listen: &str) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = listen.to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        bail!("{} resolves to no addresses", listen);
+    }
+    Ok(addrs)
+}
+
+/// A token for a server not given one: 32 random bytes, in hex
+pub fn generate_token() -> Result<String> {
+    let mut bytes = [0; 32];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .context("Failed to make up a token")?;
+    Ok(hex::encode(bytes))
+}
+
+/// Whether `given` is `token`, taking as long whatever the bytes they
+/// differ in, so the token can't be guessed from the time answers take
+pub(crate) fn token_matches(given: &str, token: &str) -> bool {
+    let (given, token) = (given.trim().as_bytes(), token.as_bytes());
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |differ, (a, b)| differ | (a ^ b))
+            == 0
+}
+
+fn header<'a>(request: &'a tiny_http::Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+fn authorized(request: &tiny_http::Request, options: &ApiOptions) -> bool {
+    header(request, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| token_matches(given, &options.token))
+}
+
+/// Whether the body is declared JSON, which browsers don't send without
+/// asking the server first
+fn json_body(request: &tiny_http::Request) -> bool {
+    header(request, "Content-Type")
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|kind| kind.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// Whether the `Host` and `Origin` of `request` are allowed: loopback names
+/// when served on loopback, and elsewhere an `Origin` of the `Host` itself
+fn same_origin(request: &tiny_http::Request, loopback: bool) -> bool {
+    let host = header(request, "Host").unwrap_or_default();
+    let origin = header(request, "Origin").map(|origin| {
+        origin
+            .strip_prefix("http://")
+            .or_else(|| origin.strip_prefix("https://"))
+            .unwrap_or("")
+    });
+    if loopback {
+        is_loopback_name(host) && origin.is_none_or(is_loopback_name)
+    } else {
+        origin.is_none_or(|origin| origin.eq_ignore_ascii_case(host))
+    }
+}
+
+/// Whether `host`, a `HOST[:PORT]` of a header, names this machine
+fn is_loopback_name(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+fn error(status: u16, message: String) -> (u16, Value) {
+    (status, json!({ "error": message }))
+}
+
+fn route(
+    daemon: &Daemon,
+    options: &ApiOptions,
+    method: &Method,
+    url: &str,
+    body: &str,
+) -> (u16, Value) {
+    let path = url.split('?').next().unwrap_or_default();
+    let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+    match (method, parts.as_slice()) {
+        (Method::Post, ["jobs"]) => match serde_json::from_str(body)
+            .map_err(anyhow::Error::from)
+            .and_then(|body| submit(daemon, options, body))
+        {
+            Ok(id) => (202, json!({ "id": id })),
+            Err(e) => error(400, format!("{:#}", e)),
+        },
+        (Method::Get, ["jobs"]) => match daemon.respond(Request::Jobs) {
+            Response::Jobs { jobs } => (200, json!(jobs)),
+            response => unexpected(response),
+        },
+        (Method::Get, ["jobs", id]) => match job(daemon, id) {
+            Ok(Response::Job { job }) => (200, json!(job)),
+            Ok(response) => unexpected(response),
+            Err(e) => e,
+        },
+        (Method::Get, ["jobs", id, "hosts", host]) => match job(daemon, id) {
+            Ok(Response::Job { job }) => match job.results.iter().find(|r| r.host == *host) {
+                Some(result) => (200, json!(result)),
+                None => error(404, format!("No result for host {} in job {}", host, id)),
+            },
+            Ok(response) => unexpected(response),
+            Err(e) => e,
+        },
+        (_, ["jobs", ..]) => error(405, format!("{} is not allowed on {}", method, path)),
+        _ => error(404, format!("No such endpoint: {}", path)),
+    }
+}
+
+fn unexpected(response: Response) -> (u16, Value) {
+    match response {
+        Response::Error { message } => error(404, message),
+        response => error(500, format!("Unexpected response: {:?}", response)),
+    }
+}
+
+fn job(daemon: &Daemon, id: &str) -> Result<Response, (u16, Value)> {
+    let id = id
+        .parse()
+        .map_err(|_| error(404, format!("Invalid job id: {}", id)))?;
+    Ok(daemon.respond(Request::Job { id }))
+}
+
+/// Queue the job described by the body of `POST /jobs`
+pub(crate) fn submit(daemon: &Daemon, options: &ApiOptions, submit: Submit) -> Result<u64> {
+    options.ssh.remote_command(&submit.command)?;
+    let mut targets = Vec::new();
+    for list in &submit.targets {
+        let hosts = targets::expand(list, &options.aliases)?;
+        targets.extend(hosts.into_iter().map(Target::new));
+    }
+    if let Some(group) = &submit.group {
+        let Some(inventory) = &options.inventory else {
+            bail!("No inventory was loaded, give the targets instead of a group");
+        };
+        targets.extend(inventory.targets(group)?);
+    }
+    targets.retain(|t| !t.host.is_empty());
+    targets::dedupe(&mut targets);
+    if targets.is_empty() {
+        bail!("No targets, give a group or targets");
+    }
+    let request = Request::Submit {
+        command: submit.command,
+        targets,
+        ssh: Box::new(options.ssh.clone()),
+        options: Box::new(options.run.clone()),
+    };
+    match daemon.respond(request) {
+        Response::Submitted { id } => Ok(id),
+        response => bail!("Unexpected response: {:?}", response),
+    }
+}
@@ -0,0 +1,173 @@
+//! Connection timings for `multissh bench`.
+//!
+//! Every iteration is one `ssh -v host true`, and the time of the debug
+//! lines ssh logs as it goes splits it into steps: the TCP connection
+//! (name resolution included), the SSH handshake up to the session keys,
+//! authentication, and running the command. Hosts are measured in
+//! parallel, the iterations of a host one after the other so they don't
+//! slow each other down.
+
+use crate::ssh::SshOptions;
+use crate::sys::{Cell, Table};
+use crate::targets::Target;
+use std::io::{BufRead, BufReader};
+use std::process::Stdio;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Steps of a connection, in order
+pub const STEPS: [&str; 4] = ["connect", "handshake", "auth", "command"];
+
+/// Debug lines of `ssh -v` ending every step but the last, which ends with
+/// ssh itself
+const STEP_ENDS: [&str; 3] = [
+    "debug1: Connection established",
+    "debug1: SSH2_MSG_NEWKEYS received",
+    "Authenticated to ",
+];
+
+/// Timings of one connection, one per step
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub steps: [Duration; 4],
+}
+
+impl Sample {
+    pub fn total(&self) -> Duration {
+        self.steps.iter().sum()
+    }
+}
+
+/// Every iteration of a host
+#[derive(Clone, Debug)]
+pub struct HostBench {
+    pub host: String,
+    pub samples: Vec<Sample>,
+    /// Why the other iterations failed
+    pub errors: Vec<String>,
+}
+
+/// Connect `iterations` times to every target, in target order
+pub fn bench_all(targets: &[Target], ssh: &SshOptions, iterations: u32) -> Vec<HostBench> {
+    let ssh = SshOptions {
+        debug: true,
+        ..ssh.clone()
+    };
+    let ssh = &ssh;
+    thread::scope(|scope| {
+        let handles: Vec<_> = targets
+            .iter()
+            .map(|target| {
+                scope.spawn(move || {
+                    let mut bench = HostBench {
+                        host: target.host.clone(),
+                        samples: Vec::new(),
+                        errors: Vec::new(),
+                    };
+                    let ssh = match ssh.with_vars(&target.vars) {
+                        Ok(ssh) => ssh,
+                        Err(e) => {
+                            bench.errors.push(format!("{:#}", e));
+                            return bench;
+                        }
+                    };
+                    for _ in 0..iterations {
+                        match sample(&target.host, &ssh) {
+                            Ok(sample) => bench.samples.push(sample),
+                            Err(e) => bench.errors.push(e),
+                        }
+                    }
+                    bench
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
+
+fn sample(host: &str, ssh: &SshOptions) -> Result<Sample, String> {
+    let mut cmd = ssh.command(host, "true").map_err(|e| format!("{:#}", e))?;
+    cmd.stdout(Stdio::null());
+    let start = Instant::now();
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("failed to run ssh: {}", e))?;
+    let mut ends = Vec::new();
+    let mut last_error = None;
+    if let Some(stderr) = child.stderr.take() {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if ends.len() < STEP_ENDS.len() && line.starts_with(STEP_ENDS[ends.len()]) {
+                ends.push(start.elapsed());
+            } else if !line.starts_with("debug") && !line.trim().is_empty() {
+                last_error = Some(line.trim().to_string());
+            }
+        }
+    }
+    let status = child.wait().map_err(|e| e.to_string())?;
+    ends.push(start.elapsed());
+    if !status.success() || ends.len() < STEPS.len() {
+        return Err(last_error.unwrap_or_else(|| format!("ssh exited with {:?}", status.code())));
+    }
+    let mut steps = [Duration::ZERO; 4];
+    let mut previous = Duration::ZERO;
+    for (step, end) in steps.iter_mut().zip(ends) {
+        *step = end - previous;
+        previous = end;
+    }
+    Ok(Sample { steps })
+}
+
+/// Median of every step and the total, then the 90th percentile and the
+/// slowest total, one row per host
+pub fn table(results: &[HostBench]) -> Table {
+    let mut columns = vec!["host"];
+    columns.extend(STEPS);
+    columns.extend(["total", "p90", "max", "failed"]);
+    let mut rows = Vec::new();
+    for result in results {
+        let mut row = vec![Cell::Text(result.host.clone())];
+        let mut totals: Vec<Duration> = result.samples.iter().map(Sample::total).collect();
+        totals.sort();
+        if totals.is_empty() {
+            row.extend((0..STEPS.len() + 3).map(|_| Cell::Text("-".to_string())));
+        } else {
+            for step in 0..STEPS.len() {
+                let mut times: Vec<Duration> =
+                    result.samples.iter().map(|s| s.steps[step]).collect();
+                times.sort();
+                row.push(millis(percentile(&times, 50)));
+            }
+            row.push(millis(percentile(&totals, 50)));
+            row.push(millis(percentile(&totals, 90)));
+            row.push(millis(percentile(&totals, 100)));
+        }
+        let failed = result.errors.len();
+        row.push(Cell::Number {
+            value: failed as f64,
+            text: failed.to_string(),
+        });
+        rows.push(row);
+    }
+    Table { columns, rows }
+}
+
+/// Nearest-rank percentile of sorted `times`
+fn percentile(times: &[Duration], percent: usize) -> Duration {
+    let rank = (times.len() * percent).div_ceil(100).max(1);
+    times[rank - 1]
+}
+
+fn millis(duration: Duration) -> Cell {
+    let ms = duration.as_secs_f64() * 1000.0;
+    Cell::Number {
+        value: ms,
+        text: format!("{:.1}ms", ms),
+    }
+}
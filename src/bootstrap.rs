@@ -0,0 +1,122 @@
+//! `multissh bootstrap-key`: turning hosts only reachable with a password
+//! into hosts reachable with a key. A fresh ed25519 keypair is made with
+//! `ssh-keygen`, its public key is appended to `~/.ssh/authorized_keys` of
+//! every target over password auth, and the command then runs with the key
+//! alone.
+//!
+//! The keypair is kept for later runs with `-k`, unless it's temporary: then
+//! it's taken off the hosts again once the command is done and deleted
+//! locally, so the key only lives as long as the session.
+
+use crate::ssh::quote;
+use anyhow::{bail, Context, Result};
+use std::ffi::OsString;
+use std::fs::Permissions;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+
+/// Comment of the keys, telling them apart in authorized_keys
+const COMMENT: &str = "multissh-bootstrap";
+
+/// A keypair for bootstrapping, deleted when dropped if temporary
+pub struct Keypair {
+    /// The private key, the public one is next to it with `.pub` added
+    pub path: PathBuf,
+    /// The public key, as its line in authorized_keys
+    pub public: String,
+    /// Directory of a temporary keypair, deleted along with it
+    _temporary: Option<TempDir>,
+}
+
+impl Keypair {
+    /// A new keypair in a directory of its own in the temp directory, made
+    /// with a random name and only for us, so no one else can have put a
+    /// key there
+    pub fn temporary() -> Result<Keypair> {
+        let dir = tempfile::Builder::new()
+            .prefix("multissh-key-")
+            .permissions(Permissions::from_mode(0o700))
+            .tempdir()
+            .context("Failed to create a directory for the temporary key")?;
+        let mut keypair = Keypair {
+            path: dir.path().join("id_ed25519"),
+            public: String::new(),
+            _temporary: Some(dir),
+        };
+        generate(&keypair.path)?;
+        keypair.public = read_public(&keypair.path)?;
+        Ok(keypair)
+    }
+
+    /// The keypair at `path`, made first if there is none yet
+    pub fn kept(path: &Path) -> Result<Keypair> {
+        if !path.exists() {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                create_private_dir(dir)?;
+            }
+            generate(path)?;
+        }
+        Ok(Keypair {
+            path: path.to_path_buf(),
+            public: read_public(path)?,
+            _temporary: None,
+        })
+    }
+
+    /// Remote command adding the public key to authorized_keys, unless it's
+    /// there already, after ending the last key's line if it isn't
+    pub fn install_command(&self) -> String {
+        let key = quote(&self.public);
+        format!(
+            "umask 077 && mkdir -p ~/.ssh && f=~/.ssh/authorized_keys && touch \"$f\" && \
+             {{ grep -qxF {key} \"$f\" || \
+             {{ [ ! -s \"$f\" ] || [ -z \"$(tail -c 1 \"$f\")\" ] || echo >> \"$f\"; \
+             printf '%s\\n' {key} >> \"$f\"; }}; }}"
+        )
+    }
+
+    /// Remote command taking the public key off authorized_keys again. The
+    /// file is rewritten in place, keeping its owner, mode and links.
+    pub fn remove_command(&self) -> String {
+        let key = quote(&self.public);
+        format!(
+            "f=~/.ssh/authorized_keys; [ -f \"$f\" ] || exit 0; \
+             grep -vxF {key} \"$f\" > \"$f.multissh-tmp\"; \
+             [ $? -le 1 ] && cat \"$f.multissh-tmp\" > \"$f\"; s=$?; \
+             rm -f \"$f.multissh-tmp\"; exit $s"
+        )
+    }
+}
+
+fn create_private_dir(dir: &Path) -> Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))
+}
+
+fn generate(path: &Path) -> Result<()> {
+    let output = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-C", COMMENT, "-f"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run ssh-keygen")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("ssh-keygen failed ({}): {}", output.status, stderr.trim());
+    }
+    Ok(())
+}
+
+fn read_public(path: &Path) -> Result<String> {
+    let mut public = OsString::from(path);
+    public.push(".pub");
+    let public = PathBuf::from(public);
+    let text = std::fs::read_to_string(&public)
+        .with_context(|| format!("Failed to read public key {}", public.display()))?;
+    Ok(text.trim().to_string())
+}
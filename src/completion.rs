@@ -0,0 +1,105 @@
+//! Shell completion, generated by clap_complete.
+//!
+//! `multissh completions SHELL` prints a script that hands the command line
+//! back to multissh whenever Tab is pressed, so completions always match
+//! the installed version. Inventory groups and `@aliases` are read at that
+//! moment from the default inventory and the config file. Encrypted
+//! inventories are skipped rather than asking for their key halfway
+//! through a command line.
+
+use crate::config::Config;
+use crate::inventory::Inventory;
+use crate::vault::Format;
+use clap_complete::CompletionCandidate;
+use std::ffi::OsStr;
+
+/// Shells `multissh completions` writes a script for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Environment variable the completion scripts set when calling multissh
+pub const COMPLETE_ENV: &str = "COMPLETE";
+
+/// Script registering the completions of `bin` with `shell`
+pub fn script(shell: Shell, bin: &str) -> std::io::Result<Vec<u8>> {
+    use clap_complete::env::EnvCompleter;
+    let completer: &dyn EnvCompleter = match shell {
+        Shell::Bash => &clap_complete::env::Bash,
+        Shell::Zsh => &clap_complete::env::Zsh,
+        Shell::Fish => &clap_complete::env::Fish,
+    };
+    let mut script = Vec::new();
+    completer.write_registration(COMPLETE_ENV, bin, bin, bin, &mut script)?;
+    Ok(script)
+}
+
+/// Groups of the default inventory, for `-g`
+pub fn groups(current: &OsStr) -> Vec<CompletionCandidate> {
+    complete_last(current, &group_names(), "")
+}
+
+/// Groups of the default inventory, for comma-separated lists of groups
+pub fn group_lists(current: &OsStr) -> Vec<CompletionCandidate> {
+    complete_last(current, &group_names(), ",")
+}
+
+/// `@aliases` of the config file, for `-t`
+pub fn aliases(current: &OsStr) -> Vec<CompletionCandidate> {
+    let aliases: Vec<String> = Config::load()
+        .map(|config| {
+            config
+                .aliases
+                .into_keys()
+                .map(|a| format!("@{}", a))
+                .collect()
+        })
+        .unwrap_or_default();
+    complete_last(current, &aliases, ",")
+}
+
+/// Candidates for the last item of `current`, the items before it being
+/// kept as typed when `separator` is given
+fn complete_last(current: &OsStr, names: &[String], separator: &str) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    let (done, last) = match separator {
+        "" => ("", current.as_ref()),
+        _ => match current.rfind(separator) {
+            Some(i) => current.split_at(i + separator.len()),
+            None => ("", current.as_ref()),
+        },
+    };
+    names
+        .iter()
+        .filter(|name| name.starts_with(last))
+        .map(|name| CompletionCandidate::new(format!("{}{}", done, name)))
+        .collect()
+}
+
+fn group_names() -> Vec<String> {
+    let config = Config::default();
+    let Some(path) = config.default_inventory_file.iter().find(|p| p.exists()) else {
+        return Vec::new();
+    };
+    let mut names = Vec::new();
+    for file in Inventory::files(std::slice::from_ref(path)).unwrap_or_default() {
+        let Ok(data) = std::fs::read(&file) else {
+            continue;
+        };
+        if Format::detect(&data) != Format::Plain {
+            continue;
+        }
+        let Ok(inventory) = Inventory::parse(&String::from_utf8_lossy(&data), &file) else {
+            continue;
+        };
+        for group in inventory.groups {
+            if !names.contains(&group.name) {
+                names.push(group.name);
+            }
+        }
+    }
+    names
+}
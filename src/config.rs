@@ -0,0 +1,207 @@
+//! Settings of multissh: built-in defaults, overridden by the config file
+//! (the first of `~/.config/multissh/config.toml`, `~/.multissh/config.toml`
+//! and `/etc/multissh/config.toml` that exists, see [`crate::paths`] for
+//! the XDG directories also looked in):
+//!
+//! ```toml
+//! # locale of remote commands, unless --lang is given
+//! lang = "C.UTF-8"
+//! # SSH agent to use instead of $SSH_AUTH_SOCK, unless --agent-socket is given
+//! agent_socket = "~/.1password/agent.sock"
+//! # algorithms to negotiate, unless --ciphers, --kex or --macs are given
+//! ciphers = "aes256-gcm@openssh.com,aes128-gcm@openssh.com"
+//! kex = "ecdh-sha2-nistp384,ecdh-sha2-nistp256"
+//! macs = "hmac-sha2-512,hmac-sha2-256"
+//! # host key algorithms, preferred in order, unless --host-key-algorithms is given
+//! host_key_algorithms = "ssh-ed25519,ecdsa-sha2-nistp256,rsa-sha2-512"
+//!
+//! [aliases]
+//! # use as -t @cache, or -t @cache,@queue
+//! cache = "redis-0[1-9].prod"
+//! queue = ["rabbit-01.prod", "rabbit-02.prod", "@cache"]
+//! ```
+
+use crate::{paths, suggest};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Named host sets, referenced as `@name` wherever targets are given. Every
+/// entry may itself be a range like `web[01-10]` or another `@alias`.
+pub type Aliases = BTreeMap<String, Vec<String>>;
+
+pub struct Config {
+    pub default_inventory_file: Vec<PathBuf>,
+    pub default_private_key: Vec<PathBuf>,
+    pub default_port: u16,
+    pub default_timeout: u64,
+    pub aliases: Aliases,
+    /// Locale remote commands run in when `--lang` isn't given
+    pub lang: Option<String>,
+    /// Agent socket used when `--agent-socket` isn't given
+    pub agent_socket: Option<PathBuf>,
+    /// Ciphers used when `--ciphers` isn't given
+    pub ciphers: Option<String>,
+    /// Key exchange algorithms used when `--kex` isn't given
+    pub kex: Option<String>,
+    /// MACs used when `--macs` isn't given
+    pub macs: Option<String>,
+    /// Host key algorithms used when `--host-key-algorithms` isn't given
+    pub host_key_algorithms: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            default_inventory_file: paths::config_files("inventory"),
+            default_private_key: vec![paths::expand(Path::new("~/.ssh/id_rsa"))],
+            default_port: 22,
+            default_timeout: 10,
+            aliases: Aliases::new(),
+            lang: None,
+            agent_socket: None,
+            ciphers: None,
+            kex: None,
+            macs: None,
+            host_key_algorithms: None,
+        }
+    }
+}
+
+/// Contents of the config file
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    lang: Option<String>,
+    #[serde(default)]
+    agent_socket: Option<String>,
+    #[serde(default)]
+    ciphers: Option<String>,
+    #[serde(default)]
+    kex: Option<String>,
+    #[serde(default)]
+    macs: Option<String>,
+    #[serde(default)]
+    host_key_algorithms: Option<String>,
+    #[serde(default)]
+    aliases: BTreeMap<String, HostSet>,
+}
+
+/// Hosts of an alias: a comma-separated string or a list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HostSet {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Config {
+    /// The defaults, overridden by the first config file that exists
+    pub fn load() -> Result<Config> {
+        let mut config = Config::default();
+        let Some(path) = config_paths().into_iter().find(|p| p.exists()) else {
+            return Ok(config);
+        };
+        let file = read_config_file(&path)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        config.lang = file.lang;
+        config.agent_socket = file
+            .agent_socket
+            .map(|socket| paths::expand(Path::new(&socket)));
+        config.ciphers = file.ciphers;
+        config.kex = file.kex;
+        config.macs = file.macs;
+        config.host_key_algorithms = file.host_key_algorithms;
+        for (name, hosts) in file.aliases {
+            let hosts = match hosts {
+                HostSet::One(hosts) => crate::targets::split_list(&hosts),
+                HostSet::Many(hosts) => hosts,
+            };
+            config.aliases.insert(name, hosts);
+        }
+        Ok(config)
+    }
+}
+
+/// Places the config file is looked for, in order
+fn config_paths() -> Vec<PathBuf> {
+    paths::config_files("config.toml")
+}
+
+/// Keys of the config file
+const KEYS: &[&str] = &[
+    "lang",
+    "agent_socket",
+    "ciphers",
+    "kex",
+    "macs",
+    "host_key_algorithms",
+    "aliases",
+];
+
+fn read_config_file(path: &Path) -> Result<ConfigFile> {
+    let text = std::fs::read_to_string(path)?;
+    check_keys(&text)?;
+    Ok(toml::from_str(&text)?)
+}
+
+/// Fail on unknown keys and aliases that aren't hosts, with the line and a
+/// likely correction; `toml` reports syntax errors and other mismatches
+fn check_keys(text: &str) -> Result<()> {
+    let Ok(table) = text.parse::<toml::Table>() else {
+        return Ok(());
+    };
+    if let Some(key) = table.keys().find(|key| !KEYS.contains(&key.as_str())) {
+        bail!(
+            "{}unknown key \"{}\"{} (expected one of: {})",
+            line_of(text, key),
+            key,
+            suggest::hint(key, KEYS),
+            KEYS.join(", ")
+        );
+    }
+    if let Some(toml::Value::Table(aliases)) = table.get("aliases") {
+        for (name, hosts) in aliases {
+            let valid = match hosts {
+                toml::Value::String(_) => true,
+                toml::Value::Array(hosts) => hosts.iter().all(toml::Value::is_str),
+                _ => false,
+            };
+            if !valid {
+                bail!(
+                    "{}alias \"{}\" must be a string or a list of hosts",
+                    line_of(text, name),
+                    name
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `line N: ` for the first line defining `key`, as a key or a table
+fn line_of(text: &str, key: &str) -> String {
+    let defines = |line: &str| {
+        let line = line.trim_start().trim_start_matches('[').trim_start();
+        let rest = line.strip_prefix(key).or_else(|| {
+            let quoted = format!("\"{}\"", key);
+            line.strip_prefix(quoted.as_str())
+        });
+        rest.is_some_and(|rest| {
+            let rest = rest.trim_start();
+            rest.starts_with('=') || rest.starts_with(']')
+        })
+    };
+    match text.lines().position(defines) {
+        Some(n) => format!("line {}: ", n + 1),
+        None => String::new(),
+    }
+}
+
+/// Directory multissh keeps its own state in, `~/.multissh`
+pub fn state_dir() -> Result<PathBuf> {
+    let home = paths::home().context("HOME is not set")?;
+    Ok(home.join(".multissh"))
+}
@@ -0,0 +1,382 @@
+//! Job queue daemon.
+//!
+//! `multissh daemon` listens on a Unix socket in `~/.multissh/` for jobs
+//! submitted with `multissh submit`. Jobs are queued and run by a fixed
+//! number of workers, which all share one pool of connections, so the
+//! number of hosts being worked on at once is limited across every job.
+//! Finished jobs are kept in `~/.multissh/jobs/` and survive a restart of
+//! the daemon.
+//!
+//! Requests and responses are single lines of JSON.
+
+use crate::config;
+use crate::output::Event;
+use crate::runner::{self, HostResult, RunOptions};
+use crate::ssh::SshOptions;
+use crate::targets::Target;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+}
+
+impl std::fmt::Display for JobState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JobState::Queued => write!(f, "queued"),
+            JobState::Running => write!(f, "running"),
+            JobState::Done => write!(f, "done"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub command: String,
+    pub state: JobState,
+    pub submitted: DateTime<Local>,
+    /// Number of target hosts
+    pub hosts: usize,
+    /// Hosts that failed, once the job is done
+    pub failed: usize,
+    /// Results of the hosts that finished, in target order once the job is
+    /// done
+    pub results: Vec<HostResult>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Submit {
+        command: String,
+        targets: Vec<Target>,
+        ssh: Box<SshOptions>,
+        options: Box<RunOptions>,
+    },
+    /// Every job, without the host results
+    Jobs,
+    Job {
+        id: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Submitted { id: u64 },
+    Jobs { jobs: Vec<Job> },
+    Job { job: Job },
+    Error { message: String },
+}
+
+/// Settings of the daemon
+#[derive(Clone, Copy, Debug)]
+pub struct DaemonOptions {
+    /// Hosts worked on at once, across all jobs
+    pub max_parallel: usize,
+    /// Jobs running at once, the others wait in the queue
+    pub max_jobs: usize,
+}
+
+/// A submitted job waiting for a worker
+struct Queued {
+    id: u64,
+    command: String,
+    targets: Vec<Target>,
+    ssh: SshOptions,
+    options: RunOptions,
+}
+
+/// Job store and queue of a running daemon
+#[derive(Default)]
+pub struct Daemon {
+    jobs: Mutex<Vec<Job>>,
+    /// Channels getting the result of every host of a job as it finishes.
+    /// Always locked after `jobs`.
+    watchers: Mutex<HashMap<u64, Vec<Sender<HostResult>>>>,
+    queue: Mutex<VecDeque<Queued>>,
+    queued: Condvar,
+}
+
+/// Socket the daemon listens on
+pub fn socket_path() -> Result<PathBuf> {
+    Ok(config::state_dir()?.join("daemon.sock"))
+}
+
+fn jobs_dir() -> Result<PathBuf> {
+    Ok(config::state_dir()?.join("jobs"))
+}
+
+/// Send a request to the running daemon
+pub fn request(request: &Request) -> Result<Response> {
+    let path = socket_path()?;
+    let stream = UnixStream::connect(&path).with_context(|| {
+        format!(
+            "Failed to connect to the daemon at {} (is `multissh daemon` running?)",
+            path.display()
+        )
+    })?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    (&stream).write_all(line.as_bytes())?;
+    line.clear();
+    BufReader::new(&stream).read_line(&mut line)?;
+    match serde_json::from_str(&line).context("Invalid response from the daemon")? {
+        Response::Error { message } => bail!(message),
+        response => Ok(response),
+    }
+}
+
+/// Run the daemon until it is killed
+pub fn serve(options: DaemonOptions) -> Result<()> {
+    start(options)?;
+    loop {
+        thread::park();
+    }
+}
+
+/// Start the workers and answer the requests on the socket in the
+/// background. The returned daemon can also be given requests directly.
+pub fn start(options: DaemonOptions) -> Result<Arc<Daemon>> {
+    let path = socket_path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            bail!("A daemon is already listening on {}", path.display());
+        }
+        // Left behind by a daemon that didn't shut down cleanly
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    // Jobs carry passwords, only the user may talk to the daemon
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+    let daemon = Arc::new(Daemon {
+        jobs: Mutex::new(load_jobs()?),
+        ..Default::default()
+    });
+    let pool = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(options.max_parallel)
+            .build()?,
+    );
+    for _ in 0..options.max_jobs.max(1) {
+        let (daemon, pool) = (daemon.clone(), pool.clone());
+        thread::spawn(move || work(&daemon, &pool));
+    }
+
+    eprintln!(
+        "Listening on {} ({} jobs, {} hosts at once)",
+        path.display(),
+        options.max_jobs.max(1),
+        options.max_parallel
+    );
+    let listening = daemon.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let daemon = listening.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle(&daemon, stream) {
+                    eprintln!("Client error: {:#}", e);
+                }
+            });
+        }
+    });
+    Ok(daemon)
+}
+
+/// Jobs saved by an earlier run of the daemon
+fn load_jobs() -> Result<Vec<Job>> {
+    let dir = jobs_dir()?;
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Ok(Vec::new());
+    };
+    let mut jobs = Vec::new();
+    for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+        let job = fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice::<Job>(&data)?));
+        match job {
+            Ok(job) => jobs.push(job),
+            Err(e) => eprintln!("Skipping job file {}: {:#}", path.display(), e),
+        }
+    }
+    jobs.sort_by_key(|job| job.id);
+    Ok(jobs)
+}
+
+/// Answer the requests of one client
+fn handle(daemon: &Daemon, stream: UnixStream) -> Result<()> {
+    let mut writer = &stream;
+    for line in BufReader::new(&stream).lines() {
+        let response = match serde_json::from_str(&line?) {
+            Ok(request) => daemon.respond(request),
+            Err(e) => Response::Error {
+                message: format!("invalid request: {}", e),
+            },
+        };
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Run queued jobs one after the other
+fn work(daemon: &Daemon, pool: &rayon::ThreadPool) {
+    loop {
+        let job = {
+            let mut queue = daemon.queue.lock().unwrap();
+            loop {
+                match queue.pop_front() {
+                    Some(job) => break job,
+                    None => queue = daemon.queued.wait(queue).unwrap(),
+                }
+            }
+        };
+        daemon.update(job.id, |j| j.state = JobState::Running);
+        let (tx, rx) = mpsc::channel();
+        let results = thread::scope(|scope| {
+            // Record every host as it finishes, the output lines are dropped
+            scope.spawn(move || {
+                for event in rx {
+                    if let Event::Done(result) = event {
+                        daemon.add_result(job.id, *result);
+                    }
+                }
+            });
+            let results = pool
+                .install(|| runner::run(&job.targets, &job.command, &job.ssh, &job.options, &tx));
+            drop(tx);
+            results
+        });
+        daemon.update(job.id, |j| {
+            j.state = JobState::Done;
+            j.failed = results.iter().filter(|r| r.failed()).count();
+            // In target order, rather than the order the hosts finished in
+            j.results = results;
+            if let Err(e) = save_job(j) {
+                eprintln!("Failed to save job {}: {:#}", j.id, e);
+            }
+        });
+        daemon.watchers.lock().unwrap().remove(&job.id);
+    }
+}
+
+fn save_job(job: &Job) -> Result<()> {
+    let dir = jobs_dir()?;
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.json", job.id));
+    fs::write(&path, serde_json::to_vec(job)?)?;
+    // Results may contain anything the command printed
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+impl Daemon {
+    pub fn respond(&self, request: Request) -> Response {
+        match request {
+            Request::Submit {
+                command,
+                targets,
+                ssh,
+                options,
+            } => {
+                let id = {
+                    let mut jobs = self.jobs.lock().unwrap();
+                    let id = jobs.last().map_or(1, |job| job.id + 1);
+                    jobs.push(Job {
+                        id,
+                        command: command.clone(),
+                        state: JobState::Queued,
+                        submitted: Local::now(),
+                        hosts: targets.len(),
+                        failed: 0,
+                        results: Vec::new(),
+                    });
+                    id
+                };
+                self.queue.lock().unwrap().push_back(Queued {
+                    id,
+                    command,
+                    targets,
+                    ssh: *ssh,
+                    options: *options,
+                });
+                self.queued.notify_one();
+                Response::Submitted { id }
+            }
+            Request::Jobs => {
+                let jobs = self.jobs.lock().unwrap();
+                let jobs = jobs
+                    .iter()
+                    .map(|job| Job {
+                        results: Vec::new(),
+                        ..job.clone()
+                    })
+                    .collect();
+                Response::Jobs { jobs }
+            }
+            Request::Job { id } => match self.jobs.lock().unwrap().iter().find(|j| j.id == id) {
+                Some(job) => Response::Job { job: job.clone() },
+                None => Response::Error {
+                    message: format!("No job {}", id),
+                },
+            },
+        }
+    }
+
+    /// Results of the hosts of job `id` that already finished, and a channel
+    /// receiving the results of the others until the job is done
+    pub fn watch(&self, id: u64) -> Option<(Vec<HostResult>, Receiver<HostResult>)> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter().find(|j| j.id == id)?;
+        let (tx, rx) = mpsc::channel();
+        if job.state != JobState::Done {
+            self.watchers
+                .lock()
+                .unwrap()
+                .entry(id)
+                .or_default()
+                .push(tx);
+        }
+        Some((job.results.clone(), rx))
+    }
+
+    fn add_result(&self, id: u64, result: HostResult) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter_mut().find(|j| j.id == id) {
+            if let Some(watchers) = self.watchers.lock().unwrap().get_mut(&id) {
+                watchers.retain(|tx| tx.send(result.clone()).is_ok());
+            }
+            job.results.push(result);
+        }
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter_mut().find(|j| j.id == id) {
+            f(job);
+        }
+    }
+}
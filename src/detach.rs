@@ -0,0 +1,121 @@
+//! Detached runs: the command is started in the background on every host and
+//! multissh returns right away.
+//!
+//! Every host runs the command under `nohup`/`setsid` with its stdout,
+//! stderr and exit code written to `~/.multissh/runs/<run id>/` on the host.
+//! The run id, remote PIDs and targets are recorded locally in
+//! `~/.multissh/runs/<run id>.json` so `multissh status` and
+//! `multissh collect` can find the hosts again later.
+
+use crate::config;
+use crate::inventory::Vars;
+use crate::ssh::quote;
+use crate::targets::Target;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A detached run as recorded locally
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DetachedRun {
+    pub id: String,
+    pub command: String,
+    pub started: DateTime<Local>,
+    pub hosts: Vec<DetachedHost>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DetachedHost {
+    pub host: String,
+    /// Inventory variables of the host, so it is reached the same way later
+    #[serde(default)]
+    pub vars: Vars,
+    /// PID of the command on the host, `None` if it couldn't be started
+    pub pid: Option<u32>,
+    pub error: Option<String>,
+}
+
+impl DetachedHost {
+    pub fn target(&self) -> Target {
+        Target {
+            host: self.host.clone(),
+            vars: self.vars.clone(),
+        }
+    }
+}
+
+impl DetachedRun {
+    /// Start recording a new run of `command`, with an id made of the
+    /// current time and process id
+    pub fn new(command: &str) -> DetachedRun {
+        let started = Local::now();
+        DetachedRun {
+            id: format!("{}-{}", started.format("%Y%m%d-%H%M%S"), std::process::id()),
+            command: command.to_string(),
+            started,
+            hosts: Vec::new(),
+        }
+    }
+
+    pub fn load(id: &str) -> Result<DetachedRun> {
+        let path = runs_dir()?.join(format!("{}.json", id));
+        let data = std::fs::read(&path)
+            .with_context(|| format!("Unknown run {} (no {})", id, path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to read {}", path.display()))
+    }
+
+    pub fn save(&self) -> Result<PathBuf> {
+        let dir = runs_dir()?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = dir.join(format!("{}.json", self.id));
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Remote command that starts `command` in the background and prints
+    /// its PID. `command` is a complete remote command line, see
+    /// [`crate::ssh::SshOptions::remote_command`].
+    pub fn launch_command(&self, command: &str) -> String {
+        // The command runs in the user's shell like it would without
+        // --detach, and the exit code is written once it is complete
+        let script =
+            r#""${SHELL:-/bin/sh}" -c "$1"; echo $? > "$2/exit.tmp"; mv "$2/exit.tmp" "$2/exit""#;
+        format!(
+            "{}\nmkdir -p \"$d\" || exit\nnohup $(command -v setsid) sh -c {} sh {} \"$d\" > \"$d/stdout\" 2> \"$d/stderr\" < /dev/null &\necho $! > \"$d/pid\"\necho $!",
+            self.remote_dir(),
+            quote(script),
+            quote(command)
+        )
+    }
+
+    /// Remote command printing whether the command is still running on the
+    /// host, or how it exited
+    pub fn status_command(&self) -> String {
+        format!(
+            "{}\nif [ -f \"$d/exit\" ]; then echo \"exit $(cat \"$d/exit\")\"\nelif kill -0 \"$(cat \"$d/pid\")\" 2>/dev/null; then echo running\nelse echo \"not running, no exit code recorded\"; fi",
+            self.remote_dir()
+        )
+    }
+
+    /// Remote command replaying the output of the command on the host and
+    /// exiting with its exit code
+    pub fn collect_command(&self) -> String {
+        format!(
+            "{}\ncat \"$d/stdout\" && cat \"$d/stderr\" >&2 || exit\nif [ ! -f \"$d/exit\" ]; then echo \"multissh: still running\" >&2; exit 1; fi\nexit \"$(cat \"$d/exit\")\"",
+            self.remote_dir()
+        )
+    }
+
+    /// Shell assignment of `$d` to the directory of the run on the host
+    fn remote_dir(&self) -> String {
+        format!("d=\"$HOME/.multissh/runs/{}\"", self.id)
+    }
+}
+
+/// Local directory the detached runs are recorded in
+fn runs_dir() -> Result<PathBuf> {
+    Ok(config::state_dir()?.join("runs"))
+}
@@ -0,0 +1,239 @@
+//! `--device-mode`, for switches and routers whose SSH servers have no exec
+//! channel: an interactive session is opened instead, and commands are
+//! typed at the prompt one after the other, as a person would.
+//!
+//! The command given is split into lines, one device command each. Once
+//! logged in, the paging-disable commands (e.g. `terminal length 0`) run
+//! first so long output doesn't stop at `--More--`. The output of every
+//! command is then captured on its own, up to the next prompt, and the host
+//! output reads like the session: each command after the prompt, followed
+//! by what it printed.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::io::{self, ErrorKind, Read, Write};
+use std::process::{Child, ChildStdin, Command};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Usual prompts of network devices: `switch#`, `router>`, `user@host$ `,
+/// `[edit] user@srx%`, ...
+pub const DEFAULT_PROMPT: &str = r"[\w.()\[\]/:@~-]+ ?[>#$%] ?$";
+
+/// Time without new output after which a line matching the prompt is taken
+/// for it, rather than for output that happens to look the same
+const QUIET: Duration = Duration::from_millis(300);
+
+/// Settings of `--device-mode`
+#[derive(Clone, Debug)]
+pub struct DeviceOptions {
+    /// Matched against the line being received
+    pub prompt: Regex,
+    /// Commands run before the others, with their output left out
+    pub setup: Vec<String>,
+    /// Longest wait for the prompt, after logging in and after every
+    /// command
+    pub timeout: Duration,
+}
+
+/// Output of one device command
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceCommand {
+    pub command: String,
+    pub output: String,
+}
+
+/// Device commands of the command line given
+pub fn commands(command: &str) -> Vec<&str> {
+    command
+        .lines()
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+/// A process on a terminal too, over `ssh -tt`, typed into through stdin
+/// and read from in chunks as they arrive
+pub struct Interactive {
+    child: Child,
+    stdin: ChildStdin,
+    chunks: Receiver<Vec<u8>>,
+    stderr: thread::JoinHandle<Vec<u8>>,
+}
+
+impl Interactive {
+    /// Start `cmd`, see [`crate::ssh::SshOptions::interactive`]
+    pub fn spawn(mut cmd: Command) -> io::Result<Interactive> {
+        let mut child = cmd.spawn()?;
+        let (Some(stdin), Some(mut stdout), Some(mut stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            return Err(io::Error::other("ssh has no pipes"));
+        };
+        let (tx, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 8192];
+            while let Ok(n @ 1..) = stdout.read(&mut buf) {
+                if tx.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        let stderr = thread::spawn(move || {
+            let mut data = Vec::new();
+            let _ = stderr.read_to_end(&mut data);
+            data
+        });
+        Ok(Interactive {
+            child,
+            stdin,
+            chunks,
+            stderr,
+        })
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stdin.write_all(data)?;
+        self.stdin.flush()
+    }
+
+    /// Next output, at most `timeout` from now, without carriage returns
+    pub fn recv(&self, timeout: Duration) -> Result<Vec<u8>, RecvTimeoutError> {
+        let mut chunk = self.chunks.recv_timeout(timeout)?;
+        chunk.retain(|&b| b != b'\r');
+        Ok(chunk)
+    }
+
+    /// Type `last` if given, then close stdin and give the process `wait`
+    /// to exit. Returns how ssh exited (`None` when it had to be killed)
+    /// with what it wrote to stderr, less the note that the connection was
+    /// closed.
+    pub fn close(mut self, last: Option<&[u8]>, wait: Duration) -> (Option<i32>, Vec<u8>) {
+        if let Some(last) = last {
+            let _ = self.stdin.write_all(last);
+        }
+        drop(self.stdin);
+        let deadline = Instant::now() + wait;
+        let mut status = self.child.try_wait().ok().flatten();
+        while status.is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+            status = self.child.try_wait().ok().flatten();
+        }
+        let status = match status {
+            Some(status) => status.code(),
+            None => {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                None
+            }
+        };
+        let stderr = self.stderr.join().unwrap_or_default();
+        // ssh tells when a session on a terminal ends, nothing went wrong
+        let stderr = String::from_utf8_lossy(&stderr)
+            .lines()
+            .map(|l| l.trim_end_matches('\r'))
+            .filter(|l| !(l.starts_with("Connection to ") && l.ends_with(" closed.")))
+            .flat_map(|l| [l, "\n"])
+            .collect::<String>();
+        (status, stderr.into_bytes())
+    }
+}
+
+/// An interactive session on a device
+pub struct Session {
+    io: Interactive,
+    /// Output received and not handed out yet
+    pending: Vec<u8>,
+    /// The prompt the last command ended with
+    pub prompt: String,
+    options: DeviceOptions,
+}
+
+impl Session {
+    pub fn new(io: Interactive, options: &DeviceOptions) -> Session {
+        Session {
+            io,
+            pending: Vec::new(),
+            prompt: String::new(),
+            options: options.clone(),
+        }
+    }
+
+    /// Wait for the first prompt, returning what came before it: the banner
+    pub fn login(&mut self) -> io::Result<Vec<u8>> {
+        let mut banner = Vec::new();
+        self.wait_prompt(&mut |line| banner.extend_from_slice(line))?;
+        Ok(banner)
+    }
+
+    /// Run the paging-disable commands, ignoring their output
+    pub fn setup(&mut self) -> io::Result<()> {
+        for command in self.options.setup.clone() {
+            self.run(&command, &mut |_| {})?;
+        }
+        Ok(())
+    }
+
+    /// Type `command` and pass every line it prints, with its newline, to
+    /// `line` as it arrives, until the prompt is back
+    pub fn run(&mut self, command: &str, line: &mut dyn FnMut(&[u8])) -> io::Result<()> {
+        self.io.send(format!("{}\n", command).as_bytes())?;
+        // The device echoes the command back first
+        let mut echoed = false;
+        self.wait_prompt(&mut |data| match echoed {
+            true => line(data),
+            false => echoed = true,
+        })
+    }
+
+    /// End the session, logging out first when `logout`, see
+    /// [`Interactive::close`]
+    pub fn close(self, logout: bool) -> (Option<i32>, Vec<u8>) {
+        let exit = logout.then_some(&b"exit\n"[..]);
+        self.io.close(exit, QUIET * 10)
+    }
+
+    /// Hand out complete lines until the line being received matches the
+    /// prompt and nothing more arrives
+    fn wait_prompt(&mut self, line: &mut dyn FnMut(&[u8])) -> io::Result<()> {
+        let deadline = Instant::now() + self.options.timeout;
+        loop {
+            while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+                let rest = self.pending.split_off(end + 1);
+                line(&self.pending);
+                self.pending = rest;
+            }
+            let wait = deadline
+                .saturating_duration_since(Instant::now())
+                .min(QUIET);
+            match self.io.recv(wait) {
+                Ok(chunk) => self.pending.extend(chunk),
+                Err(RecvTimeoutError::Timeout) => {
+                    let last = String::from_utf8_lossy(&self.pending).to_string();
+                    if self.options.prompt.is_match(&last) {
+                        self.pending.clear();
+                        self.prompt = last;
+                        return Ok(());
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(io::Error::new(
+                            ErrorKind::TimedOut,
+                            format!(
+                                "no prompt after {}s, last line: {:?}",
+                                self.options.timeout.as_secs(),
+                                last.trim()
+                            ),
+                        ));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "session closed before the prompt came back",
+                    ))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::ssh::{AddressFamily, SshOptions};
+use crate::targets::Target;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::net::{IpAddr, ToSocketAddrs};
+
+/// Addresses a target resolved to, or why it couldn't be resolved. Targets
+/// reached through a ProxyCommand or ProxyJump aren't resolved and have no
+/// addresses: the proxy resolves them, and may be the only one able to.
+pub struct Resolution {
+    pub host: String,
+    pub addrs: Result<Vec<IpAddr>, String>,
+    /// Port and user ssh connects with, which tell targets on the same
+    /// address apart
+    pub port: u16,
+    pub user: Option<String>,
+}
+
+/// Resolve every target in parallel, in target order
+pub fn resolve_all(targets: &[Target], ssh: &SshOptions) -> Vec<Resolution> {
+    targets
+        .par_iter()
+        .map(|target| {
+            // The run fails the host, telling why
+            let Ok(ssh) = ssh.with_vars(&target.vars) else {
+                return Resolution {
+                    host: target.host.clone(),
+                    addrs: Ok(Vec::new()),
+                    port: ssh.port,
+                    user: None,
+                };
+            };
+            let destination = ssh.destination(&target.host);
+            let (port, user) = (destination.port, destination.user.clone());
+            if destination.proxy.is_some() {
+                return Resolution {
+                    host: target.host.clone(),
+                    addrs: Ok(Vec::new()),
+                    port,
+                    user,
+                };
+            }
+            let addrs = match (destination.hostname.as_str(), port).to_socket_addrs() {
+                Ok(addrs) => {
+                    let family = ssh.address_family;
+                    let mut addrs: Vec<_> = addrs
+                        .map(|a| a.ip())
+                        .filter(|ip| family.allows(ip))
+                        .collect();
+                    addrs.sort();
+                    addrs.dedup();
+                    if addrs.is_empty() {
+                        Err(match family {
+                            AddressFamily::Any => "no addresses found",
+                            AddressFamily::Inet => "no IPv4 addresses found",
+                            AddressFamily::Inet6 => "no IPv6 addresses found",
+                        }
+                        .to_string())
+                    } else {
+                        Ok(addrs)
+                    }
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            Resolution {
+                host: target.host.clone(),
+                addrs,
+                port,
+                user,
+            }
+        })
+        .collect()
+}
+
+/// Targets that could not be resolved, along with the reason
+pub fn unresolvable(resolved: &[Resolution]) -> Vec<(String, String)> {
+    resolved
+        .iter()
+        .filter_map(|r| match &r.addrs {
+            Ok(_) => None,
+            Err(e) => Some((r.host.clone(), e.clone())),
+        })
+        .collect()
+}
+
+/// Targets that resolved to exactly the same addresses as an earlier target
+/// and are connected to on the same port as the same user, as `(duplicate,
+/// earlier target)` pairs
+pub fn same_address(resolved: &[Resolution]) -> Vec<(String, String)> {
+    type Key<'a> = (&'a [IpAddr], u16, Option<&'a str>);
+    let mut seen: HashMap<Key, &str> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for r in resolved {
+        // Proxied targets have no addresses to compare
+        if let Some(addrs) = r.addrs.as_ref().ok().filter(|addrs| !addrs.is_empty()) {
+            let key = (addrs.as_slice(), r.port, r.user.as_deref());
+            match seen.get(&key) {
+                Some(first) => duplicates.push((r.host.clone(), first.to_string())),
+                None => {
+                    seen.insert(key, &r.host);
+                }
+            }
+        }
+    }
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolution(host: &str, ip: &str, port: u16, user: Option<&str>) -> Resolution {
+        Resolution {
+            host: host.to_string(),
+            addrs: Ok(vec![ip.parse().unwrap()]),
+            port,
+            user: user.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn same_address_needs_the_same_port_and_user() {
+        let resolved = [
+            resolution("nat-a", "192.0.2.1", 2201, None),
+            resolution("nat-b", "192.0.2.1", 2202, None),
+            resolution("box-deploy", "192.0.2.2", 22, Some("deploy")),
+            resolution("box-admin", "192.0.2.2", 22, Some("admin")),
+            resolution("web1", "192.0.2.3", 22, Some("deploy")),
+            resolution("web1.example.com", "192.0.2.3", 22, Some("deploy")),
+        ];
+        assert_eq!(
+            same_address(&resolved),
+            vec![("web1.example.com".to_string(), "web1".to_string())]
+        );
+    }
+
+    #[test]
+    fn proxied_targets_are_never_duplicates() {
+        let mut first = resolution("db1", "192.0.2.1", 22, None);
+        let mut second = resolution("db2", "192.0.2.1", 22, None);
+        (first.addrs, second.addrs) = (Ok(Vec::new()), Ok(Vec::new()));
+        assert!(same_address(&[first, second]).is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+//! Why a host failed, for callers that handle failures differently, e.g.
+//! retrying unreachable hosts later but reporting rejected keys at once.
+//! Failures are told apart by what ssh prints on its own errors, which it
+//! exits with 255 on.
+
+use crate::runner::HostResult;
+use thiserror::Error;
+
+/// How running the command on a host failed, see [`HostResult::check`]
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum MultiSshError {
+    /// The name of the host couldn't be resolved
+    #[error("{host}: could not resolve host: {message}")]
+    Dns { host: String, message: String },
+    /// Nothing answered within the connect timeout
+    #[error("{host}: connection timed out: {message}")]
+    ConnectTimeout { host: String, message: String },
+    /// The connection was refused, reset or couldn't be routed
+    #[error("{host}: could not connect: {message}")]
+    Connect { host: String, message: String },
+    /// The host rejected every way of logging in
+    #[error("{host}: authentication rejected: {message}")]
+    Auth { host: String, message: String },
+    /// The key of the host is unknown or changed
+    #[error("{host}: host key verification failed: {message}")]
+    HostKey { host: String, message: String },
+    /// ssh connected but failed on its own, e.g. opening the session
+    #[error("{host}: ssh failed: {message}")]
+    Channel { host: String, message: String },
+    /// The command ran and exited with `code`, or was killed by a signal
+    /// when `code` is `None`
+    #[error("{host}: command failed with {}", match .code {
+        Some(code) => format!("exit code {}", code),
+        None => "a signal".to_string(),
+    })]
+    Command { host: String, code: Option<i32> },
+    /// The command was stopped by the exec timeout of the host
+    #[error("{host}: {message}")]
+    Timeout { host: String, message: String },
+    /// The deadline of the run passed before the host was done with
+    #[error("{host}: {message}")]
+    Deadline { host: String, message: String },
+    /// Anything else keeping the command from running or being checked,
+    /// like a local file that can't be read
+    #[error("{host}: {message}")]
+    Other { host: String, message: String },
+}
+
+const DNS_ERRORS: &[&str] = &[
+    "Could not resolve hostname",
+    "Temporary failure in name resolution",
+    "Name or service not known",
+    "nodename nor servname provided",
+];
+
+const TIMEOUT_ERRORS: &[&str] = &["Connection timed out", "Operation timed out"];
+
+const CONNECT_ERRORS: &[&str] = &[
+    "Connection refused",
+    "No route to host",
+    "Network is unreachable",
+    "Connection reset by peer",
+    "Connection closed by remote host",
+    "kex_exchange_identification",
+];
+
+const AUTH_ERRORS: &[&str] = &[
+    "Permission denied",
+    "Too many authentication failures",
+    "No more authentication methods",
+    "Authentication failed",
+];
+
+const HOST_KEY_ERRORS: &[&str] = &[
+    "Host key verification failed",
+    "REMOTE HOST IDENTIFICATION HAS CHANGED",
+    "No matching host key type found",
+];
+
+type Kind = fn(String, String) -> MultiSshError;
+
+/// The ssh errors told apart, in the order they are looked for
+const KINDS: &[(&[&str], Kind)] = &[
+    (DNS_ERRORS, |host, message| MultiSshError::Dns {
+        host,
+        message,
+    }),
+    (TIMEOUT_ERRORS, |host, message| {
+        MultiSshError::ConnectTimeout { host, message }
+    }),
+    (CONNECT_ERRORS, |host, message| MultiSshError::Connect {
+        host,
+        message,
+    }),
+    (AUTH_ERRORS, |host, message| MultiSshError::Auth {
+        host,
+        message,
+    }),
+    (HOST_KEY_ERRORS, |host, message| MultiSshError::HostKey {
+        host,
+        message,
+    }),
+];
+
+impl MultiSshError {
+    /// Why `result` failed, `None` if it succeeded or was skipped
+    pub fn of(result: &HostResult) -> Option<MultiSshError> {
+        if !result.failed() {
+            return None;
+        }
+        let host = result.host.clone();
+        if result.cut_by_deadline() {
+            let message = result.error.clone().unwrap_or_default();
+            return Some(MultiSshError::Deadline { host, message });
+        }
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        let from_ssh = result.exit_code == Some(255) || result.unreachable;
+        // What the command printed itself is left alone
+        let ssh_lines = stderr.lines().filter(|_| from_ssh);
+        for line in ssh_lines.chain(result.error.as_deref()) {
+            for (errors, kind) in KINDS {
+                if errors.iter().any(|e| line.contains(e)) {
+                    return Some(kind(host, line.trim().to_string()));
+                }
+            }
+        }
+        Some(match &result.error {
+            Some(e) if e.starts_with("timed out after") => MultiSshError::Timeout {
+                host,
+                message: e.clone(),
+            },
+            Some(e) => MultiSshError::Other {
+                host,
+                message: e.clone(),
+            },
+            // ssh prefixes its own messages, remote commands can exit with
+            // 255 too
+            None if from_ssh && stderr.lines().any(is_ssh_message) => {
+                let message = stderr.lines().rfind(|l| is_ssh_message(l)).unwrap_or("");
+                MultiSshError::Channel {
+                    host,
+                    message: message.trim().to_string(),
+                }
+            }
+            None => MultiSshError::Command {
+                host,
+                code: result.exit_code,
+            },
+        })
+    }
+
+    /// The host, every failure has one
+    pub fn host(&self) -> &str {
+        match self {
+            MultiSshError::Dns { host, .. }
+            | MultiSshError::ConnectTimeout { host, .. }
+            | MultiSshError::Connect { host, .. }
+            | MultiSshError::Auth { host, .. }
+            | MultiSshError::HostKey { host, .. }
+            | MultiSshError::Channel { host, .. }
+            | MultiSshError::Command { host, .. }
+            | MultiSshError::Timeout { host, .. }
+            | MultiSshError::Deadline { host, .. }
+            | MultiSshError::Other { host, .. } => host,
+        }
+    }
+
+    /// Name of the kind of failure, as in the `error_kind` of JSON output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MultiSshError::Dns { .. } => "dns",
+            MultiSshError::ConnectTimeout { .. } => "connect_timeout",
+            MultiSshError::Connect { .. } => "connect",
+            MultiSshError::Auth { .. } => "auth",
+            MultiSshError::HostKey { .. } => "host_key",
+            MultiSshError::Channel { .. } => "channel",
+            MultiSshError::Command { .. } => "command",
+            MultiSshError::Timeout { .. } => "timeout",
+            MultiSshError::Deadline { .. } => "deadline",
+            MultiSshError::Other { .. } => "other",
+        }
+    }
+
+    /// Whether the host couldn't be reached at all, so trying again later
+    /// may help
+    pub fn is_connection(&self) -> bool {
+        matches!(
+            self,
+            MultiSshError::Dns { .. }
+                | MultiSshError::ConnectTimeout { .. }
+                | MultiSshError::Connect { .. }
+        )
+    }
+}
+
+fn is_ssh_message(line: &str) -> bool {
+    line.starts_with("ssh:") || line.contains("channel")
+}
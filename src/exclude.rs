@@ -0,0 +1,82 @@
+//! Gitignore-style patterns leaving files out of a directory push
+//! (`--exclude`):
+//!
+//! - `*.log` matches files and directories named so anywhere in the tree
+//! - `build/` matches directories only, with everything in them
+//! - `/dist` or `docs/*.tmp`, containing a slash, match from the top of the
+//!   pushed directory only
+//! - `**` matches any number of directories, as in `**/cache`
+//! - `!keep.log` brings back what an earlier pattern left out, unless a
+//!   directory above it is left out
+//!
+//! The last pattern matching a path decides.
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+
+const OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    /// Matched against the whole path, or else against the file name
+    anchored: bool,
+}
+
+/// Parsed `--exclude` patterns
+#[derive(Debug, Default)]
+pub struct Excludes {
+    rules: Vec<Rule>,
+}
+
+impl Excludes {
+    pub fn new(patterns: &[String]) -> Result<Excludes> {
+        let mut rules = Vec::new();
+        for text in patterns {
+            let (negated, rest) = match text.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, text.as_str()),
+            };
+            let dir_only = rest.ends_with('/');
+            let rest = rest.trim_end_matches('/');
+            let anchored = rest.contains('/');
+            let rest = rest.trim_start_matches('/');
+            let pattern = Pattern::new(rest)
+                .with_context(|| format!("Invalid --exclude pattern {:?}", text))?;
+            rules.push(Rule {
+                pattern,
+                negated,
+                dir_only,
+                anchored,
+            });
+        }
+        Ok(Excludes { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether `path`, relative to the pushed directory and with `/`
+    /// between its parts, is left out
+    pub fn excluded(&self, path: &str, is_dir: bool) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let mut excluded = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let subject = if rule.anchored { path } else { name };
+            if rule.pattern.matches_with(subject, OPTIONS) {
+                excluded = !rule.negated;
+            }
+        }
+        excluded
+    }
+}
@@ -0,0 +1,307 @@
+//! Send/expect scripts for interactive remote flows (`--expect-script`):
+//! password changes, installers asking questions, device wizards. The
+//! command runs on a terminal and the script answers it:
+//!
+//! ```text
+//! # Change the password of the deploy user
+//! timeout 20
+//! expect [Cc]urrent password:
+//! sendline {{old_password}}
+//! expect New password:
+//! sendline {{new_password}}
+//! expect Retype new password:
+//! sendline {{new_password}}
+//! expect eof
+//! ```
+//!
+//! One step per line, blank lines and `#` comments are skipped:
+//!
+//! - `expect REGEX` waits for output matching REGEX, `expect eof` for the
+//!   command to exit
+//! - `send TEXT` types TEXT, `sendline TEXT` followed by Enter
+//! - `timeout SECONDS` sets how long the following expects wait (default 30)
+//! - `sleep SECONDS` pauses
+//!
+//! TEXT and REGEX may be put in double quotes, to keep spaces at the ends
+//! or to use `\n`, `\r`, `\t`, `\"` and `\\`. `{{name}}` is replaced with
+//! the `name` variable of the host from the inventory, or with the host
+//! name for `{{host}}`. Values are matched literally in patterns. A host
+//! missing a variable fails without being connected to.
+//!
+//! Everything the command prints is the host output, and it exits with the
+//! status of the command.
+
+use crate::device::Interactive;
+use crate::inventory::Vars;
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde_yaml::Value;
+use std::path::Path;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long expects wait unless the script says otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+enum Step {
+    Expect(String),
+    Eof,
+    Send(String),
+    Timeout(Duration),
+    Sleep(Duration),
+}
+
+/// A parsed script, its variables not filled in yet
+#[derive(Clone, Debug)]
+pub struct Script {
+    /// Steps with the line they are on
+    steps: Vec<(usize, Step)>,
+}
+
+/// A script for one host, variables filled in and patterns compiled
+#[derive(Debug)]
+pub struct HostScript {
+    steps: Vec<(usize, HostStep)>,
+}
+
+#[derive(Debug)]
+enum HostStep {
+    Expect(Regex),
+    Eof,
+    Send(String),
+    Timeout(Duration),
+    Sleep(Duration),
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Script::parse(&text).with_context(|| format!("Invalid expect script {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Script> {
+        let mut steps = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let n = i + 1;
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let argument = unquote(rest.trim()).with_context(|| format!("line {}", n))?;
+            let seconds = || -> Result<Duration> {
+                argument
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|s| *s >= 0.0)
+                    .map(Duration::from_secs_f64)
+                    .with_context(|| {
+                        format!("line {}: invalid number of seconds {:?}", n, argument)
+                    })
+            };
+            let step = match keyword {
+                "expect" if argument.is_empty() => bail!("line {}: expect needs a pattern", n),
+                "expect" if argument == "eof" && !rest.trim().starts_with('"') => Step::Eof,
+                "expect" => {
+                    // Placeholders stand for literal text, check the rest
+                    Regex::new(&substitute(&argument, &|_| Some("x".to_string()), true)?)
+                        .with_context(|| format!("line {}", n))?;
+                    Step::Expect(argument)
+                }
+                "send" => Step::Send(argument),
+                "sendline" => Step::Send(format!("{}\n", argument)),
+                "timeout" => Step::Timeout(seconds()?),
+                "sleep" => Step::Sleep(seconds()?),
+                _ => bail!("line {}: unknown step {:?}", n, keyword),
+            };
+            steps.push((n, step));
+        }
+        if steps.is_empty() {
+            bail!("no steps");
+        }
+        Ok(Script { steps })
+    }
+
+    /// The script with the variables of `host` filled in
+    pub fn for_host(&self, host: &str, vars: &Vars) -> Result<HostScript> {
+        let lookup = |name: &str| -> Option<String> {
+            match (name, vars.get(name)) {
+                ("host", _) => Some(host.to_string()),
+                (_, Some(Value::String(s))) => Some(s.clone()),
+                (_, Some(Value::Number(n))) => Some(n.to_string()),
+                (_, Some(Value::Bool(b))) => Some(b.to_string()),
+                _ => None,
+            }
+        };
+        let mut steps = Vec::new();
+        for (n, step) in &self.steps {
+            let step = match step {
+                Step::Expect(pattern) => {
+                    let pattern = substitute(pattern, &lookup, true)
+                        .with_context(|| format!("expect script line {}", n))?;
+                    HostStep::Expect(Regex::new(&pattern)?)
+                }
+                Step::Send(text) => HostStep::Send(
+                    substitute(text, &lookup, false)
+                        .with_context(|| format!("expect script line {}", n))?,
+                ),
+                Step::Eof => HostStep::Eof,
+                Step::Timeout(timeout) => HostStep::Timeout(*timeout),
+                Step::Sleep(duration) => HostStep::Sleep(*duration),
+            };
+            steps.push((*n, step));
+        }
+        Ok(HostScript { steps })
+    }
+}
+
+impl HostScript {
+    /// Run the script against `io`, passing everything the command prints
+    /// to `line`, one line at a time with its newline. Returns the timeout
+    /// in effect at the end, to wait for the command to exit.
+    pub fn run(&self, io: &mut Interactive, line: &mut dyn FnMut(&[u8])) -> Result<Duration> {
+        let mut output = Output::default();
+        let mut timeout = DEFAULT_TIMEOUT;
+        for (n, step) in &self.steps {
+            match step {
+                HostStep::Expect(pattern) => {
+                    let deadline = Instant::now() + timeout;
+                    loop {
+                        if let Some(m) = pattern.find(&String::from_utf8_lossy(&output.unmatched)) {
+                            let end = m.end().min(output.unmatched.len());
+                            output.unmatched.drain(..end);
+                            break;
+                        }
+                        let wait = deadline.saturating_duration_since(Instant::now());
+                        match io.recv(wait) {
+                            Ok(chunk) => output.push(&chunk, line),
+                            Err(RecvTimeoutError::Timeout) => bail!(
+                                "line {}: no output matching /{}/ after {}s",
+                                n,
+                                pattern,
+                                timeout.as_secs_f64()
+                            ),
+                            Err(RecvTimeoutError::Disconnected) => {
+                                output.flush(line);
+                                bail!("line {}: the command exited before /{}/", n, pattern)
+                            }
+                        }
+                    }
+                }
+                HostStep::Eof => {
+                    let deadline = Instant::now() + timeout;
+                    loop {
+                        let wait = deadline.saturating_duration_since(Instant::now());
+                        match io.recv(wait) {
+                            Ok(chunk) => output.push(&chunk, line),
+                            Err(RecvTimeoutError::Timeout) => {
+                                bail!("line {}: still running after {}s", n, timeout.as_secs_f64())
+                            }
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                }
+                HostStep::Send(text) => io
+                    .send(text.as_bytes())
+                    .with_context(|| format!("line {}", n))?,
+                HostStep::Timeout(duration) => timeout = *duration,
+                HostStep::Sleep(duration) => thread::sleep(*duration),
+            }
+        }
+        // What is left after the last step
+        while let Ok(chunk) = io.recv(Duration::ZERO) {
+            output.push(&chunk, line);
+        }
+        output.flush(line);
+        Ok(timeout)
+    }
+}
+
+/// Output of the command: what expects haven't matched yet, and the line
+/// being received
+#[derive(Default)]
+struct Output {
+    unmatched: Vec<u8>,
+    line: Vec<u8>,
+}
+
+impl Output {
+    fn push(&mut self, chunk: &[u8], line: &mut dyn FnMut(&[u8])) {
+        self.unmatched.extend_from_slice(chunk);
+        for &b in chunk {
+            self.line.push(b);
+            if b == b'\n' {
+                line(&self.line);
+                self.line.clear();
+            }
+        }
+    }
+
+    fn flush(&mut self, line: &mut dyn FnMut(&[u8])) {
+        if !self.line.is_empty() {
+            self.line.push(b'\n');
+            line(&self.line);
+            self.line.clear();
+        }
+    }
+}
+
+/// `text` without its double quotes and escapes, or as is when unquoted
+fn unquote(text: &str) -> Result<String> {
+    let Some(inner) = text.strip_prefix('"') else {
+        return Ok(text.to_string());
+    };
+    let inner = inner.strip_suffix('"').context("missing closing quote")?;
+    let mut unquoted = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unquoted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unquoted.push('\n'),
+            Some('r') => unquoted.push('\r'),
+            Some('t') => unquoted.push('\t'),
+            Some('"') => unquoted.push('"'),
+            Some('\\') => unquoted.push('\\'),
+            // Kept for patterns, e.g. \d or \.
+            Some(c) => {
+                unquoted.push('\\');
+                unquoted.push(c);
+            }
+            None => bail!("escape at the end of the text"),
+        }
+    }
+    Ok(unquoted)
+}
+
+/// Replace every `{{name}}` of `text`, escaping the values for a regex when
+/// `literal`
+fn substitute(
+    text: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    literal: bool,
+) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end)
+            .context("unclosed {{")?;
+        let name = rest[start + 2..end].trim();
+        let value = lookup(name).with_context(|| format!("no variable {:?}", name))?;
+        out.push_str(&rest[..start]);
+        match literal {
+            true => out.push_str(&regex::escape(&value)),
+            false => out.push_str(&value),
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
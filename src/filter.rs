@@ -0,0 +1,208 @@
+//! Rhai scripts post-processing host results before they are shown
+//! (`--filter-script`), for one-off parsing without new options. The script
+//! runs once per host, with these variables:
+//!
+//! - `host`, `stdout`, `stderr`: strings
+//! - `exit_code`: the exit code, `()` when there is none
+//! - `error`: why the command couldn't be run, `()` when it could
+//! - `duration`: seconds, as a float
+//! - `success`: whether the host succeeded
+//!
+//! Assigning to `stdout` or `stderr` replaces the output shown, assigning a
+//! string to `note` shows it along with the host, and a script evaluating
+//! to `false` hides the host:
+//!
+//! ```text
+//! if success && stdout.contains("active") { return false; }
+//! let load = stdout.split(" ")[0];
+//! note = `load ${load}`;
+//! stdout = stdout.to_upper();
+//! ```
+//!
+//! A `.wasm` or `.wat` file is taken for a WASM module exporting
+//! `transform` instead, see [`crate::wasm`].
+//!
+//! Only what is shown, and saved with `--output-dir`, changes: reports,
+//! history and the exit code keep the results as they were. A script
+//! failing on a host is reported, and the host is shown unchanged.
+
+use crate::output::Stream;
+use crate::runner::HostResult;
+use anyhow::{anyhow, Context, Result};
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::fmt;
+use std::path::Path;
+
+/// A compiled `--filter-script`
+pub struct FilterScript {
+    kind: Kind,
+}
+
+enum Kind {
+    Rhai {
+        engine: Box<Engine>,
+        ast: AST,
+    },
+    #[cfg(feature = "wasm")]
+    Wasm(crate::wasm::WasmPlugin),
+}
+
+/// What a WASM module answers to change
+#[cfg(feature = "wasm")]
+#[derive(Default, serde::Deserialize)]
+struct Changes {
+    stdout: Option<String>,
+    stderr: Option<String>,
+    note: Option<String>,
+    #[serde(default)]
+    hide: bool,
+}
+
+impl fmt::Debug for FilterScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FilterScript").finish_non_exhaustive()
+    }
+}
+
+impl FilterScript {
+    /// Read and compile the script at `path`, reporting syntax errors
+    /// before anything runs
+    pub fn load(path: &Path) -> Result<FilterScript> {
+        let extension = path.extension().and_then(|e| e.to_str());
+        if matches!(extension, Some("wasm" | "wat")) {
+            return Self::load_wasm(path);
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read filter script {}", path.display()))?;
+        let engine = Engine::new();
+        let ast = engine
+            .compile(&text)
+            .map_err(|e| anyhow!("Invalid filter script {}: {}", path.display(), e))?;
+        Ok(FilterScript {
+            kind: Kind::Rhai {
+                engine: Box::new(engine),
+                ast,
+            },
+        })
+    }
+
+    #[cfg(feature = "wasm")]
+    fn load_wasm(path: &Path) -> Result<FilterScript> {
+        let plugin = crate::wasm::WasmPlugin::load(path)?;
+        if !plugin.exports("transform") {
+            anyhow::bail!("WASM plugin {} exports no transform", path.display());
+        }
+        Ok(FilterScript {
+            kind: Kind::Wasm(plugin),
+        })
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    fn load_wasm(path: &Path) -> Result<FilterScript> {
+        anyhow::bail!(
+            "Can't load WASM plugin {}, multissh was built without the wasm feature",
+            path.display()
+        )
+    }
+
+    /// `result` as the script leaves it, `None` when it hides it
+    pub fn apply(&self, result: HostResult) -> Option<HostResult> {
+        match self.run(&result) {
+            Ok(filtered) => filtered,
+            Err(e) => {
+                eprintln!("Filter script failed on {}: {:#}", result.host, e);
+                Some(result)
+            }
+        }
+    }
+
+    fn run(&self, result: &HostResult) -> Result<Option<HostResult>> {
+        let stdout = String::from_utf8_lossy(&result.full_output(Stream::Stdout)?).into_owned();
+        let stderr = String::from_utf8_lossy(&result.full_output(Stream::Stderr)?).into_owned();
+        match &self.kind {
+            Kind::Rhai { engine, ast } => run_rhai(engine, ast, result, stdout, stderr),
+            #[cfg(feature = "wasm")]
+            Kind::Wasm(plugin) => run_wasm(plugin, result, stdout, stderr),
+        }
+    }
+}
+
+fn run_rhai(
+    engine: &Engine,
+    ast: &AST,
+    result: &HostResult,
+    stdout: String,
+    stderr: String,
+) -> Result<Option<HostResult>> {
+    let mut scope = Scope::new();
+    scope.push_constant("host", result.host.clone());
+    scope.push("stdout", stdout.clone());
+    scope.push("stderr", stderr.clone());
+    scope.push_constant("exit_code", optional(result.exit_code.map(i64::from)));
+    scope.push_constant("error", optional(result.error.clone()));
+    scope.push_constant("duration", result.duration.as_secs_f64());
+    scope.push_constant("success", result.success());
+    scope.push("note", Dynamic::UNIT);
+    let value: Dynamic = engine
+        .eval_ast_with_scope(&mut scope, ast)
+        .map_err(|e| anyhow!("{}", e))?;
+    if value.as_bool() == Ok(false) {
+        return Ok(None);
+    }
+
+    let mut filtered = result.clone();
+    let changed = |name: &str, before: &str| {
+        let after = scope.get_value::<String>(name);
+        after.filter(|after| after != before)
+    };
+    if let Some(stdout) = changed("stdout", &stdout) {
+        (filtered.stdout, filtered.stdout_spilled) = (stdout.into_bytes(), None);
+    }
+    if let Some(stderr) = changed("stderr", &stderr) {
+        (filtered.stderr, filtered.stderr_spilled) = (stderr.into_bytes(), None);
+    }
+    filtered.note = scope
+        .get_value::<Dynamic>("note")
+        .filter(|note| !note.is_unit())
+        .map(|note| note.to_string());
+    Ok(Some(filtered))
+}
+
+#[cfg(feature = "wasm")]
+fn run_wasm(
+    plugin: &crate::wasm::WasmPlugin,
+    result: &HostResult,
+    stdout: String,
+    stderr: String,
+) -> Result<Option<HostResult>> {
+    let request = serde_json::json!({
+        "host": result.host,
+        "stdout": stdout,
+        "stderr": stderr,
+        "exit_code": result.exit_code,
+        "error": result.error,
+        "duration": result.duration.as_secs_f64(),
+        "success": result.success(),
+    });
+    let answer = plugin.call("transform", request.to_string().as_bytes())?;
+    let changes: Changes = match answer.is_empty() {
+        true => Changes::default(),
+        false => serde_json::from_slice(&answer).context("Invalid answer")?,
+    };
+    if changes.hide {
+        return Ok(None);
+    }
+    let mut filtered = result.clone();
+    if let Some(stdout) = changes.stdout {
+        (filtered.stdout, filtered.stdout_spilled) = (stdout.into_bytes(), None);
+    }
+    if let Some(stderr) = changes.stderr {
+        (filtered.stderr, filtered.stderr_spilled) = (stderr.into_bytes(), None);
+    }
+    filtered.note = changes.note;
+    Ok(Some(filtered))
+}
+
+fn optional<T: Into<Dynamic>>(value: Option<T>) -> Dynamic {
+    value.map_or(Dynamic::UNIT, Into::into)
+}
@@ -0,0 +1,164 @@
+//! gRPC API of `multissh serve --grpc-listen`, see `proto/multissh.proto`.
+//!
+//! Built with the `grpc` feature. Jobs go through the same daemon job queue
+//! as the HTTP API, and `Run` and `Watch` stream the result of every host as
+//! soon as it finishes. Every call needs an `authorization: Bearer <token>`
+//! metadata entry, with the token of the HTTP API.
+
+// tonic::Status is large, but it is the error type tonic expects
+#![allow(clippy::result_large_err)]
+
+use crate::api::{self, ApiOptions, Submit};
+use crate::daemon::{self, Daemon};
+use crate::runner;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("multissh");
+}
+
+use proto::multi_ssh_server::{MultiSsh, MultiSshServer};
+
+type ResultStream = ReceiverStream<Result<proto::HostResult, Status>>;
+
+struct Service {
+    daemon: Arc<Daemon>,
+    options: Arc<ApiOptions>,
+}
+
+/// Listen on `listen` and answer gRPC calls in the background
+pub fn start(listen: &str, daemon: Arc<Daemon>, options: Arc<ApiOptions>) -> Result<()> {
+    let addrs = api::listen_addrs(listen)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind(addrs.as_slice()))
+        .with_context(|| format!("Failed to listen on {}", listen))?;
+    let token = options.token.clone();
+    let service = MultiSshServer::with_interceptor(Service { daemon, options }, move |request| {
+        authorize(request, &token)
+    });
+    eprintln!("Serving gRPC on {}", listen);
+    std::thread::spawn(move || {
+        let server = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        if let Err(e) = runtime.block_on(server) {
+            eprintln!("gRPC server failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn authorize(request: Request<()>, token: &str) -> Result<Request<()>, Status> {
+    let given = request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        Some(given) if api::token_matches(given, token) => Ok(request),
+        _ => Err(Status::unauthenticated("Missing or wrong bearer token")),
+    }
+}
+
+impl Service {
+    fn submit(&self, request: proto::RunRequest) -> Result<u64, Status> {
+        let submit = Submit {
+            command: request.command,
+            group: Some(request.group).filter(|g| !g.is_empty()),
+            targets: request.targets,
+        };
+        api::submit(&self.daemon, &self.options, submit)
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))
+    }
+
+    /// Stream the results of job `id` as its hosts finish
+    fn watch(&self, id: u64) -> Result<ResultStream, Status> {
+        let (done, rx) = self
+            .daemon
+            .watch(id)
+            .ok_or_else(|| Status::not_found(format!("No job {}", id)))?;
+        let (tx, stream) = tokio::sync::mpsc::channel(16);
+        std::thread::spawn(move || {
+            for result in done.into_iter().chain(rx) {
+                if tx.blocking_send(Ok(result.into())).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(ReceiverStream::new(stream))
+    }
+}
+
+#[tonic::async_trait]
+impl MultiSsh for Service {
+    type RunStream = ResultStream;
+    type WatchStream = ResultStream;
+
+    async fn run(
+        &self,
+        request: Request<proto::RunRequest>,
+    ) -> Result<Response<Self::RunStream>, Status> {
+        let id = self.submit(request.into_inner())?;
+        Ok(Response::new(self.watch(id)?))
+    }
+
+    async fn submit(
+        &self,
+        request: Request<proto::RunRequest>,
+    ) -> Result<Response<proto::JobId>, Status> {
+        let id = Service::submit(self, request.into_inner())?;
+        Ok(Response::new(proto::JobId { id }))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<proto::JobId>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        Ok(Response::new(Service::watch(
+            self,
+            request.into_inner().id,
+        )?))
+    }
+
+    async fn get_job(
+        &self,
+        request: Request<proto::JobId>,
+    ) -> Result<Response<proto::Job>, Status> {
+        let id = request.into_inner().id;
+        match self.daemon.respond(daemon::Request::Job { id }) {
+            daemon::Response::Job { job } => Ok(Response::new(proto::Job {
+                id: job.id,
+                command: job.command,
+                state: job.state.to_string(),
+                hosts: job.hosts as u64,
+                failed: job.failed as u64,
+                results: job.results.into_iter().map(Into::into).collect(),
+            })),
+            daemon::Response::Error { message } => Err(Status::not_found(message)),
+            response => Err(Status::internal(format!(
+                "Unexpected response: {:?}",
+                response
+            ))),
+        }
+    }
+}
+
+impl From<runner::HostResult> for proto::HostResult {
+    fn from(result: runner::HostResult) -> Self {
+        proto::HostResult {
+            host: result.host,
+            stdout: result.stdout,
+            stderr: result.stderr,
+            exit_code: result.exit_code,
+            error: result.error,
+            duration: result.duration.as_secs_f64(),
+            attempts: result.attempts,
+            unreachable: result.unreachable,
+            skipped: result.skipped,
+        }
+    }
+}
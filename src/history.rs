@@ -0,0 +1,332 @@
+//! History of past runs, kept in an SQLite database in
+//! `~/.multissh/history.db`.
+//!
+//! Every run stores its command with the remote shell and directory it ran
+//! in, its targets with their inventory variables, and the result of every
+//! host. Each line of output is also indexed with FTS5 so
+//! `multissh history search` can find which run and host printed it, and
+//! `multissh replay` can run the command again on the same hosts.
+
+use crate::config;
+use crate::inventory::Vars;
+use crate::output::Stream;
+use crate::runner::HostResult;
+use crate::ssh::{RemoteShell, SshOptions};
+use crate::targets::Target;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use glob::Pattern;
+use rusqlite::{params, Connection};
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    command TEXT NOT NULL,
+    shell TEXT NOT NULL,
+    chdir TEXT,
+    started TEXT NOT NULL,
+    duration REAL NOT NULL
+);
+CREATE TABLE IF NOT EXISTS hosts (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    host TEXT NOT NULL,
+    vars TEXT NOT NULL,
+    exit_code INTEGER,
+    error TEXT,
+    success INTEGER NOT NULL,
+    duration REAL NOT NULL,
+    stdout BLOB NOT NULL,
+    stderr BLOB NOT NULL
+);
+CREATE VIRTUAL TABLE IF NOT EXISTS lines USING fts5(
+    line,
+    run_id UNINDEXED,
+    host UNINDEXED,
+    stream UNINDEXED,
+    number UNINDEXED
+);
+";
+
+/// A run as listed by `multissh history list`
+#[derive(Clone, Debug)]
+pub struct Run {
+    pub id: i64,
+    pub command: String,
+    pub started: DateTime<Local>,
+    pub duration: Duration,
+    pub hosts: usize,
+    pub failed: usize,
+}
+
+/// A stored run, with what is needed to run it again
+#[derive(Clone, Debug)]
+pub struct StoredRun {
+    pub id: i64,
+    pub command: String,
+    pub shell: RemoteShell,
+    pub chdir: Option<String>,
+    pub hosts: Vec<RunHost>,
+}
+
+/// A host of a stored run
+#[derive(Clone, Debug)]
+pub struct RunHost {
+    pub target: Target,
+    pub success: bool,
+}
+
+/// A line of output matching a search
+#[derive(Clone, Debug)]
+pub struct Match {
+    pub run_id: i64,
+    pub started: DateTime<Local>,
+    pub host: String,
+    pub stream: String,
+    /// Line number in the stream, 1-based
+    pub number: i64,
+    pub line: String,
+}
+
+pub struct History {
+    db: Connection,
+}
+
+impl History {
+    pub fn open() -> Result<History> {
+        let dir = config::state_dir()?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = dir.join("history.db");
+        let db = Connection::open(&path)
+            .with_context(|| format!("Failed to open run history {}", path.display()))?;
+        // Outputs may contain anything the commands printed
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        db.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to set up run history {}", path.display()))?;
+        Ok(History { db })
+    }
+
+    /// Store a finished run, returns its id
+    pub fn record(
+        &mut self,
+        command: &str,
+        ssh: &SshOptions,
+        started: DateTime<Local>,
+        duration: Duration,
+        targets: &[Target],
+        results: &[HostResult],
+    ) -> Result<i64> {
+        let tx = self.db.transaction()?;
+        tx.execute(
+            "INSERT INTO runs (command, shell, chdir, started, duration) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                command,
+                serde_json::to_string(&ssh.shell)?,
+                ssh.chdir,
+                started.to_rfc3339(),
+                duration.as_secs_f64()
+            ],
+        )?;
+        let run_id = tx.last_insert_rowid();
+        {
+            let mut host = tx.prepare(
+                "INSERT INTO hosts (run_id, host, vars, exit_code, error, success, duration, stdout, stderr)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            let mut line = tx.prepare(
+                "INSERT INTO lines (line, run_id, host, stream, number) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for result in results {
+                // Spilled output is stored as far as it was kept in memory
+                let stdout = result.kept_output(Stream::Stdout);
+                let stderr = result.kept_output(Stream::Stderr);
+                let vars = targets
+                    .iter()
+                    .find(|t| t.host == result.host)
+                    .map(|t| &t.vars);
+                host.execute(params![
+                    run_id,
+                    result.host,
+                    serde_json::to_string(&vars.cloned().unwrap_or_default())?,
+                    result.exit_code,
+                    result.error,
+                    !result.failed(),
+                    result.duration.as_secs_f64(),
+                    stdout.as_ref(),
+                    stderr.as_ref(),
+                ])?;
+                for (stream, output) in [("stdout", &stdout), ("stderr", &stderr)] {
+                    let output = String::from_utf8_lossy(output);
+                    for (number, text) in output.lines().enumerate() {
+                        if !text.trim().is_empty() {
+                            line.execute(params![
+                                text,
+                                run_id,
+                                result.host,
+                                stream,
+                                number as i64 + 1
+                            ])?;
+                        }
+                    }
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(run_id)
+    }
+
+    /// The most recent runs, newest first
+    pub fn runs(&self, limit: usize) -> Result<Vec<Run>> {
+        let mut query = self.db.prepare(
+            "SELECT runs.id, runs.command, runs.started, runs.duration,
+                    COUNT(hosts.host), COALESCE(SUM(hosts.success = 0), 0)
+             FROM runs LEFT JOIN hosts ON hosts.run_id = runs.id
+             GROUP BY runs.id ORDER BY runs.id DESC LIMIT ?1",
+        )?;
+        let runs = query.query_map(params![limit as i64], |row| {
+            Ok(Run {
+                id: row.get(0)?,
+                command: row.get(1)?,
+                started: parse_time(row.get(2)?),
+                duration: Duration::from_secs_f64(row.get(3)?),
+                hosts: row.get::<_, i64>(4)? as usize,
+                failed: row.get::<_, i64>(5)? as usize,
+            })
+        })?;
+        Ok(runs.collect::<Result<_, _>>()?)
+    }
+
+    /// Id of the most recent run
+    pub fn last_run(&self) -> Result<i64> {
+        self.db
+            .query_row("SELECT MAX(id) FROM runs", [], |row| {
+                row.get::<_, Option<i64>>(0)
+            })?
+            .context("The history is empty")
+    }
+
+    /// Run `id` with its hosts, in target order
+    pub fn run(&self, id: i64) -> Result<StoredRun> {
+        let (command, shell, chdir): (String, String, Option<String>) = self
+            .db
+            .query_row(
+                "SELECT command, shell, chdir FROM runs WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .with_context(|| format!("No run {} in the history", id))?;
+        let mut query = self
+            .db
+            .prepare("SELECT host, vars, success FROM hosts WHERE run_id = ?1 ORDER BY rowid")?;
+        let hosts = query.query_map(params![id], |row| {
+            let vars: String = row.get(1)?;
+            Ok(RunHost {
+                target: Target {
+                    host: row.get(0)?,
+                    vars: serde_json::from_str::<Vars>(&vars).unwrap_or_default(),
+                },
+                success: row.get(2)?,
+            })
+        })?;
+        Ok(StoredRun {
+            id,
+            command,
+            shell: serde_json::from_str(&shell)?,
+            chdir,
+            hosts: hosts.collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Lines of output matching `query`, newest runs first. The query is
+    /// searched as a phrase unless `raw`, in which case it is an FTS5 query
+    /// (e.g. `disk AND (full OR quota)`).
+    pub fn search(&self, query: &str, raw: bool, limit: usize) -> Result<Vec<Match>> {
+        let query = if raw {
+            query.to_string()
+        } else {
+            format!("\"{}\"", query.replace('"', "\"\""))
+        };
+        let mut statement = self.db.prepare(
+            "SELECT lines.run_id, runs.started, lines.host, lines.stream, lines.number, lines.line
+             FROM lines JOIN runs ON runs.id = lines.run_id
+             WHERE lines MATCH ?1
+             ORDER BY lines.run_id DESC, lines.host, lines.stream DESC, lines.number
+             LIMIT ?2",
+        )?;
+        let matches = statement
+            .query_map(params![query, limit as i64], |row| {
+                Ok(Match {
+                    run_id: row.get(0)?,
+                    started: parse_time(row.get(1)?),
+                    host: row.get(2)?,
+                    stream: row.get(3)?,
+                    number: row.get(4)?,
+                    line: row.get(5)?,
+                })
+            })
+            .context("Invalid search")?;
+        matches
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid search")
+    }
+}
+
+impl StoredRun {
+    /// Targets of the run matching `limit`, comma-separated patterns: host
+    /// names or globs, `failed` or `ok` for the hosts that failed or
+    /// succeeded in the run, and `!PATTERN` to leave hosts out. Without
+    /// any pattern to include, every host not left out is kept.
+    pub fn targets(&self, limit: Option<&str>) -> Result<Vec<Target>> {
+        let mut include = Vec::new();
+        let mut exclude = Vec::new();
+        for pattern in limit.unwrap_or_default().split(',').map(str::trim) {
+            match pattern.strip_prefix('!') {
+                Some(pattern) => exclude.push(pattern),
+                None if !pattern.is_empty() => include.push(pattern),
+                None => {}
+            }
+        }
+        let mut targets = Vec::new();
+        for host in &self.hosts {
+            let matches = |pattern: &&str| -> Result<bool> {
+                Ok(match *pattern {
+                    "failed" => !host.success,
+                    "ok" => host.success,
+                    pattern => Pattern::new(pattern)
+                        .with_context(|| format!("Invalid --limit pattern {}", pattern))?
+                        .matches(&host.target.host),
+                })
+            };
+            let included = include.is_empty() || any(&include, matches)?;
+            if included && !any(&exclude, matches)? {
+                targets.push(host.target.clone());
+            }
+        }
+        if targets.is_empty() {
+            bail!(
+                "None of the {} hosts of run {} match --limit {}",
+                self.hosts.len(),
+                self.id,
+                limit.unwrap_or_default()
+            );
+        }
+        Ok(targets)
+    }
+}
+
+fn any(patterns: &[&str], matches: impl Fn(&&str) -> Result<bool>) -> Result<bool> {
+    for pattern in patterns {
+        if matches(pattern)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn parse_time(text: String) -> DateTime<Local> {
+    DateTime::parse_from_rfc3339(&text)
+        .map(|t| t.with_timezone(&Local))
+        .unwrap_or_default()
+}
@@ -0,0 +1,155 @@
+//! Closures called as a run goes, for library users with progress UIs,
+//! metrics or their own reasons to stop a run early:
+//!
+//! ```no_run
+//! use multissh_rs::hooks::{Flow, Hooks};
+//! use multissh_rs::runner::RunOptions;
+//! use multissh_rs::ssh::SshOptions;
+//! use multissh_rs::targets::Target;
+//!
+//! let targets = ["web1", "web2", "web3"].map(Target::new);
+//! let mut failed = 0;
+//! let results = Hooks::new()
+//!     .on_host_start(|host| {
+//!         println!("{} started", host);
+//!         Flow::Continue
+//!     })
+//!     .on_host_complete(|result| {
+//!         failed += result.failed() as usize;
+//!         if failed > 1 { Flow::Abort } else { Flow::Continue }
+//!     })
+//!     .on_run_complete(|results| println!("{} hosts done", results.len()))
+//!     .run(&targets, "uptime", &SshOptions::default(), &RunOptions::default());
+//! ```
+//!
+//! The hooks are called one at a time, from a thread of their own, in the
+//! order things happen. Aborting a run keeps hosts from being started, the
+//! others are skipped. Hosts already running are done with, as are hosts
+//! started while the hook was still on its way.
+
+use crate::output::{Event, Stream};
+use crate::runner::{self, HostResult, RunOptions};
+use crate::ssh::SshOptions;
+use crate::targets::Target;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
+/// Whether a run goes on after a hook
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /// Don't start any more hosts
+    Abort,
+}
+
+type HostStart<'a> = Box<dyn FnMut(&str) -> Flow + Send + 'a>;
+type HostOutput<'a> = Box<dyn FnMut(&str, Stream, &[u8]) -> Flow + Send + 'a>;
+type HostComplete<'a> = Box<dyn FnMut(&HostResult) -> Flow + Send + 'a>;
+type RunComplete<'a> = Box<dyn FnOnce(&[HostResult]) + Send + 'a>;
+
+/// A run with the hooks it calls, see the [module](self) docs
+#[derive(Default)]
+pub struct Hooks<'a> {
+    host_start: Option<HostStart<'a>>,
+    host_output: Option<HostOutput<'a>>,
+    host_complete: Option<HostComplete<'a>>,
+    run_complete: Option<RunComplete<'a>>,
+    forward: Option<Sender<Event>>,
+}
+
+impl<'a> Hooks<'a> {
+    pub fn new() -> Hooks<'a> {
+        Hooks::default()
+    }
+
+    /// Call `hook` when the command is started on a host, with the host
+    pub fn on_host_start(mut self, hook: impl FnMut(&str) -> Flow + Send + 'a) -> Self {
+        self.host_start = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` on every line a host writes, with the host, the stream
+    /// and the line without its newline
+    pub fn on_host_output(
+        mut self,
+        hook: impl FnMut(&str, Stream, &[u8]) -> Flow + Send + 'a,
+    ) -> Self {
+        self.host_output = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with the result of every host once it's done, skipped
+    /// hosts included
+    pub fn on_host_complete(mut self, hook: impl FnMut(&HostResult) -> Flow + Send + 'a) -> Self {
+        self.host_complete = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with the results of all hosts, in target order, once
+    /// the run is over
+    pub fn on_run_complete(mut self, hook: impl FnOnce(&[HostResult]) + Send + 'a) -> Self {
+        self.run_complete = Some(Box::new(hook));
+        self
+    }
+
+    /// Send the events on to `tx` after the hooks, e.g. to an
+    /// [`OutputWriter`](crate::output::OutputWriter)
+    pub fn forward(mut self, tx: Sender<Event>) -> Self {
+        self.forward = Some(tx);
+        self
+    }
+
+    /// Run `command` on every target like [`runner::run`], calling the
+    /// hooks as it goes
+    pub fn run(
+        self,
+        targets: &[Target],
+        command: &str,
+        ssh: &SshOptions,
+        options: &RunOptions,
+    ) -> Vec<HostResult> {
+        let Hooks {
+            mut host_start,
+            mut host_output,
+            mut host_complete,
+            run_complete,
+            forward,
+        } = self;
+        let abort = Arc::new(AtomicBool::new(false));
+        let options = RunOptions {
+            abort: Some(abort.clone()),
+            ..options.clone()
+        };
+        let (tx, rx) = mpsc::channel();
+        let results = thread::scope(|scope| {
+            scope.spawn(move || {
+                for event in rx {
+                    let flow = match &event {
+                        Event::Started { host } => host_start.as_mut().map(|hook| hook(host)),
+                        Event::Line { host, stream, line } => {
+                            host_output.as_mut().map(|hook| hook(host, *stream, line))
+                        }
+                        Event::Done(result) => host_complete.as_mut().map(|hook| hook(result)),
+                        Event::Notice { .. } => None,
+                    };
+                    if flow == Some(Flow::Abort) {
+                        abort.store(true, Ordering::Relaxed);
+                    }
+                    if let Some(tx) = &forward {
+                        let _ = tx.send(event);
+                    }
+                }
+            });
+            let results = runner::run(targets, command, ssh, &options, &tx);
+            // Ends the thread of the hooks, once it had every event
+            drop(tx);
+            results
+        });
+        if let Some(hook) = run_complete {
+            hook(&results);
+        }
+        results
+    }
+}
@@ -0,0 +1,1005 @@
+//! YAML inventory of hosts organized in groups.
+//!
+//! ```yaml
+//! web:
+//!   hosts:
+//!     web1.example.com:
+//!     web2.example.com:
+//!       port: 2222
+//!   vars:
+//!     user: deploy
+//! db:
+//!   hosts: [db1.example.com, db2.example.com]
+//! prod:
+//!   children: [web, db]
+//! ```
+//!
+//! Every top-level key is a group. `hosts` is either a list of host names or
+//! a mapping of host names to host variables, `children` lists other groups
+//! whose hosts are included, and `vars` applies to every host of the group.
+//!
+//! `connect_timeout` and `exec_timeout`, in seconds, override `--timeout`
+//! and `--exec-timeout` for hosts behind slower links.
+//!
+//! `jump_host` routes hosts through their own bastion, e.g. the one of
+//! their datacenter, like `-J`: a `[USER@]HOST[:PORT]`, a comma-separated
+//! chain or a list of them, or `none` to connect directly. `jump_user`,
+//! `jump_port` and `jump_private_key` log in to it.
+//!
+//! `legacy_crypto: true` lets ssh also offer the SHA-1 and CBC algorithms
+//! of old switches and iLO/iDRAC interfaces to the hosts that need them,
+//! like `--legacy-crypto` does for all of them.
+//!
+//! Unknown keys and variables are warned about, with the known name they
+//! are likely a misspelling of, and variables of the wrong type (a `port`
+//! that isn't a number) are errors.
+//!
+//! Hosts can carry `tags`, a list of attributes that don't fit the group
+//! hierarchy (e.g. `web1.example.com: {tags: [gpu, rhel9]}`), selected with
+//! `--tags`, see [`TagSelector`]. Tags given in the `vars` of a group apply
+//! to all of its hosts, on top of the hosts' own tags.
+//!
+//! Several inventories can be combined, e.g. one file per datacenter or an
+//! inventory directory of `*.yml`/`*.yaml` files read in name order. When the
+//! same group appears in more than one file its hosts and children are
+//! combined, and variables (of groups and of hosts) from later files override
+//! the ones from earlier files, which `inventory lint` reports as a warning.
+//!
+//! Inventories can also be encrypted with age or ansible-vault, see
+//! [`crate::vault`], or come from external programs, see [`crate::plugin`].
+
+use crate::plugin;
+use crate::suggest;
+use crate::targets::Target;
+use crate::vault::Keys;
+use anyhow::{bail, Context, Result};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Variables that can be set on hosts and groups
+pub const KNOWN_VARS: &[&str] = &[
+    "user",
+    "port",
+    "private_key",
+    "forward_agent",
+    "legacy_crypto",
+    "agent_socket",
+    "proxy_command",
+    "jump_host",
+    "jump_user",
+    "jump_port",
+    "jump_private_key",
+    "transport",
+    "connect_timeout",
+    "exec_timeout",
+    "tags",
+];
+
+/// Keys allowed in a group definition
+const GROUP_KEYS: &[&str] = &["hosts", "children", "vars"];
+
+pub type Vars = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug)]
+pub struct Group {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub children: Vec<String>,
+    pub vars: Vars,
+    /// File the group is (first) defined in
+    pub path: PathBuf,
+    /// Line the group is defined on, 1-based
+    pub line: Option<usize>,
+}
+
+/// How `inventory tree` renders the hierarchy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TreeFormat {
+    /// Indented tree
+    #[default]
+    Text,
+    /// Graphviz DOT graph
+    Dot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in the inventory
+#[derive(Clone, Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    pub path: PathBuf,
+    /// Line the problem is on, 1-based, when it could be located
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.line {
+            Some(line) => write!(
+                f,
+                "{}:{}: {}: {}",
+                self.path.display(),
+                line,
+                severity,
+                self.message
+            ),
+            None => write!(f, "{}: {}: {}", self.path.display(), severity, self.message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Inventory {
+    /// Files the inventory was read from
+    pub paths: Vec<PathBuf>,
+    /// Groups in the order they are defined
+    pub groups: Vec<Group>,
+    /// Variables set on individual hosts
+    pub host_vars: BTreeMap<String, Vars>,
+    /// Structural problems found while reading the inventory
+    pub issues: Vec<Issue>,
+    /// Groups of every host, worked out on first use after loading
+    pub(crate) index: OnceLock<Index>,
+}
+
+/// Which groups every host is in, so looking up a host doesn't walk the
+/// whole inventory
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Index {
+    /// Positions in `groups` of the groups containing each host, directly
+    /// or through children, in definition order
+    groups_of: HashMap<String, Vec<usize>>,
+    /// [`Inventory::depth`] of every group
+    depths: Vec<usize>,
+}
+
+impl Inventory {
+    /// Inventory files to read for the given paths: files are used as is,
+    /// directories contribute their `*.yml` and `*.yaml` files, and their
+    /// encrypted `*.yml.age`/`*.yaml.age` files, in name order
+    pub fn files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for path in paths {
+            if !path.is_dir() {
+                files.push(path.clone());
+                continue;
+            }
+            let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+                .with_context(|| format!("Failed to read inventory directory {}", path.display()))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file())
+                .filter(|p| is_inventory_file(p))
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        }
+        Ok(files)
+    }
+
+    /// Read and merge every inventory found in `paths`, see [`Inventory::files`]
+    pub fn load_all(paths: &[PathBuf], keys: &Keys) -> Result<Inventory> {
+        let mut inventory = Inventory::default();
+        for file in Self::files(paths)? {
+            inventory.merge(Self::load(&file, keys)?);
+        }
+        Ok(inventory)
+    }
+
+    /// Add the groups and hosts of `other`, see the module documentation for
+    /// the conflict rules
+    pub fn merge(&mut self, other: Inventory) {
+        self.index.take();
+        self.paths.extend(other.paths);
+        self.issues.extend(other.issues);
+        for group in other.groups {
+            let Some(existing) = self.groups.iter_mut().find(|g| g.name == group.name) else {
+                self.groups.push(group);
+                continue;
+            };
+            for host in group.hosts {
+                if !existing.hosts.contains(&host) {
+                    existing.hosts.push(host);
+                }
+            }
+            for child in group.children {
+                if !existing.children.contains(&child) {
+                    existing.children.push(child);
+                }
+            }
+            for (key, value) in group.vars {
+                if existing.vars.get(&key).is_some_and(|v| *v != value) {
+                    self.issues.push(Issue {
+                        severity: Severity::Warning,
+                        path: group.path.clone(),
+                        line: group.line,
+                        message: format!(
+                            "variable \"{}\" of group \"{}\" overrides the value from {}",
+                            key,
+                            group.name,
+                            existing.path.display()
+                        ),
+                    });
+                }
+                existing.vars.insert(key, value);
+            }
+        }
+        for (host, vars) in other.host_vars {
+            let existing = self.host_vars.entry(host.clone()).or_default();
+            for (key, value) in vars {
+                if existing.get(&key).is_some_and(|v| *v != value) {
+                    self.issues.push(Issue {
+                        severity: Severity::Warning,
+                        path: self.paths.last().cloned().unwrap_or_default(),
+                        line: None,
+                        message: format!(
+                            "variable \"{}\" of host \"{}\" overrides the value from an earlier inventory",
+                            key, host
+                        ),
+                    });
+                }
+                existing.insert(key, value);
+            }
+        }
+    }
+
+    /// Read, decrypt if needed, and parse an inventory file. Fails on YAML
+    /// syntax errors only, other problems are collected in `issues`.
+    pub fn load(path: &Path, keys: &Keys) -> Result<Inventory> {
+        if let Some((source, arg)) = plugin::parse_source(path) {
+            return plugin::load(source, arg);
+        }
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read inventory {}", path.display()))?;
+        let data = keys.decrypt(path, data)?;
+        let text = String::from_utf8(data)
+            .with_context(|| format!("Failed to read inventory {}", path.display()))?;
+        Self::parse(&text, path)
+    }
+
+    pub fn parse(text: &str, path: &Path) -> Result<Inventory> {
+        let mut inventory = Inventory {
+            paths: vec![path.to_path_buf()],
+            ..Default::default()
+        };
+        let root: Value = match serde_yaml::from_str(text) {
+            Ok(root) => root,
+            Err(e) => match e.location() {
+                Some(location) => bail!(
+                    "{}:{}: syntax error: {}",
+                    path.display(),
+                    location.line(),
+                    e
+                ),
+                None => bail!("{}: syntax error: {}", path.display(), e),
+            },
+        };
+        let root = match root {
+            Value::Null => return Ok(inventory),
+            Value::Mapping(root) => root,
+            _ => bail!(
+                "{}: the inventory must be a mapping of group names",
+                path.display()
+            ),
+        };
+
+        for (name, definition) in &root {
+            let Some(name) = scalar(name) else {
+                inventory.error(None, "group names must be strings".to_string());
+                continue;
+            };
+            let line = find_line(text, 0..usize::MAX, &name, true);
+            let group = inventory.parse_group(text, name, line, definition);
+            inventory.groups.push(group);
+        }
+        Ok(inventory)
+    }
+
+    fn parse_group(
+        &mut self,
+        text: &str,
+        name: String,
+        line: Option<usize>,
+        definition: &Value,
+    ) -> Group {
+        let mut group = Group {
+            name,
+            hosts: Vec::new(),
+            children: Vec::new(),
+            vars: Vars::new(),
+            path: self.paths.last().cloned().unwrap_or_default(),
+            line,
+        };
+        let section = section(text, line);
+        let definition = match definition {
+            Value::Null => return group,
+            Value::Mapping(definition) => definition,
+            _ => {
+                self.error(line, format!("group \"{}\" must be a mapping", group.name));
+                return group;
+            }
+        };
+
+        for (key, value) in definition {
+            let key = scalar(key).unwrap_or_default();
+            let key_line = find_line(text, section.clone(), &key, false);
+            match key.as_str() {
+                "hosts" => self.parse_hosts(text, &mut group, section.clone(), value),
+                "children" => match string_list(value) {
+                    Some(children) => group.children = children,
+                    None => self.error(
+                        key_line,
+                        format!(
+                            "children of group \"{}\" must be a list of group names",
+                            group.name
+                        ),
+                    ),
+                },
+                "vars" => match value {
+                    Value::Null => {}
+                    Value::Mapping(vars) => {
+                        group.vars = to_vars(vars);
+                        let owner = format!("group \"{}\"", group.name);
+                        self.check_vars(&group.vars, &owner, key_line);
+                    }
+                    _ => self.error(
+                        key_line,
+                        format!("vars of group \"{}\" must be a mapping", group.name),
+                    ),
+                },
+                _ => self.issue(
+                    Severity::Warning,
+                    key_line,
+                    format!(
+                        "unknown key \"{}\" in group \"{}\"{} (expected one of: {})",
+                        key,
+                        group.name,
+                        suggest::hint(&key, GROUP_KEYS),
+                        GROUP_KEYS.join(", ")
+                    ),
+                ),
+            }
+        }
+        group
+    }
+
+    fn parse_hosts(&mut self, text: &str, group: &mut Group, section: Range<usize>, value: &Value) {
+        let line = find_line(text, section.clone(), "hosts", false);
+        let hosts: Vec<(String, Option<&Mapping>)> = match value {
+            Value::Null => Vec::new(),
+            Value::Sequence(hosts) => hosts
+                .iter()
+                .filter_map(|h| scalar(h).map(|h| (h, None)))
+                .collect(),
+            Value::Mapping(hosts) => {
+                let mut list = Vec::new();
+                for (host, vars) in hosts {
+                    let Some(host) = scalar(host) else { continue };
+                    match vars {
+                        Value::Null => list.push((host, None)),
+                        Value::Mapping(vars) => list.push((host, Some(vars))),
+                        _ => {
+                            let host_line = find_line(text, section.clone(), &host, false);
+                            self.error(
+                                host_line,
+                                format!("variables of host \"{}\" must be a mapping", host),
+                            );
+                            list.push((host, None));
+                        }
+                    }
+                }
+                list
+            }
+            _ => {
+                self.error(
+                    line,
+                    format!(
+                        "hosts of group \"{}\" must be a list or a mapping",
+                        group.name
+                    ),
+                );
+                Vec::new()
+            }
+        };
+
+        for (host, vars) in hosts {
+            let host_line = find_line(text, section.clone(), &host, false);
+            if group.hosts.contains(&host) {
+                self.issue(
+                    Severity::Warning,
+                    host_line,
+                    format!(
+                        "host \"{}\" is listed more than once in group \"{}\"",
+                        host, group.name
+                    ),
+                );
+                continue;
+            }
+            if let Some(vars) = vars {
+                let vars = to_vars(vars);
+                self.check_vars(&vars, &format!("host \"{}\"", host), host_line);
+                for (key, value) in vars {
+                    let existing = self.host_vars.entry(host.clone()).or_default();
+                    match existing.get(&key) {
+                        Some(previous) if *previous != value => self.issue(
+                            Severity::Warning,
+                            host_line,
+                            format!("host \"{}\" has conflicting values for \"{}\"", host, key),
+                        ),
+                        _ => {
+                            existing.insert(key, value);
+                        }
+                    }
+                }
+            }
+            group.hosts.push(host);
+        }
+    }
+
+    /// Report unknown variables of `owner`, and known ones of the wrong type
+    pub(crate) fn check_vars(&mut self, vars: &Vars, owner: &str, line: Option<usize>) {
+        for (key, value) in vars {
+            if !KNOWN_VARS.contains(&key.as_str()) {
+                let message = format!(
+                    "unknown variable \"{}\" of {}{}",
+                    key,
+                    owner,
+                    suggest::hint(key, KNOWN_VARS)
+                );
+                self.issue(Severity::Warning, line, message);
+                continue;
+            }
+            let expected = match key.as_str() {
+                "user" | "private_key" | "agent_socket" | "proxy_command" | "jump_user"
+                | "jump_private_key"
+                    if value.as_str().is_none() =>
+                {
+                    "a string"
+                }
+                "jump_host" if crate::ssh::jump_chain(value).is_none() => {
+                    "[USER@]HOST[:PORT], a comma-separated chain or a list of them, or \"none\""
+                }
+                "port" | "jump_port"
+                    if !value.as_u64().is_some_and(|p| (1..=65535).contains(&p)) =>
+                {
+                    "a port number (1-65535)"
+                }
+                "forward_agent" | "legacy_crypto" if value.as_bool().is_none() => "true or false",
+                "transport" if !matches!(value.as_str(), Some("ssh" | "telnet")) => {
+                    "\"ssh\" or \"telnet\""
+                }
+                "connect_timeout" | "exec_timeout" if value.as_u64().is_none() => {
+                    "a number of seconds"
+                }
+                "tags" if tag_list(Some(value)).is_none() => "a list of names",
+                _ => continue,
+            };
+            let message = format!("{} of {} must be {}", key, owner, expected);
+            self.error(line, message);
+        }
+    }
+
+    fn issue(&mut self, severity: Severity, line: Option<usize>, message: String) {
+        self.issues.push(Issue {
+            severity,
+            path: self.paths.last().cloned().unwrap_or_default(),
+            line,
+            message,
+        });
+    }
+
+    fn error(&mut self, line: Option<usize>, message: String) {
+        self.issue(Severity::Error, line, message)
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|g| g.name == name)
+    }
+
+    /// All hosts of a group, including the hosts of its children, in
+    /// definition order and without duplicates
+    pub fn group_hosts(&self, name: &str) -> Result<Vec<String>> {
+        if self.group(name).is_none() {
+            let paths: Vec<_> = self.paths.iter().map(|p| p.display().to_string()).collect();
+            bail!("Group not found in {}: {}", paths.join(", "), name);
+        }
+        Ok(self
+            .collect_hosts(name)
+            .into_iter()
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Hosts of the group `name` and its children, without duplicates
+    fn collect_hosts<'a>(&'a self, name: &'a str) -> Vec<&'a str> {
+        let mut hosts = Vec::new();
+        let (mut added, mut visited) = (HashSet::new(), HashSet::new());
+        let mut stack = vec![name];
+        while let Some(name) = stack.pop() {
+            // Cycles are reported by lint, here they just stop the walk
+            if !visited.insert(name) {
+                continue;
+            }
+            let Some(group) = self.group(name) else {
+                continue;
+            };
+            hosts.extend(
+                group
+                    .hosts
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|host| added.insert(*host)),
+            );
+            // Depth first, children in the order they are listed
+            stack.extend(group.children.iter().rev().map(String::as_str));
+        }
+        hosts
+    }
+
+    fn index(&self) -> &Index {
+        self.index.get_or_init(|| {
+            let mut groups_of: HashMap<String, Vec<usize>> = HashMap::new();
+            for (i, group) in self.groups.iter().enumerate() {
+                for host in self.collect_hosts(&group.name) {
+                    groups_of.entry(host.to_string()).or_default().push(i);
+                }
+            }
+            let depths = self.groups.iter().map(|g| self.depth(&g.name)).collect();
+            Index { groups_of, depths }
+        })
+    }
+
+    /// Targets for the hosts of a group, carrying their variables
+    pub fn targets(&self, name: &str) -> Result<Vec<Target>> {
+        Ok(self
+            .group_hosts(name)?
+            .into_iter()
+            .map(|host| Target {
+                vars: self.vars_for(&host),
+                host,
+            })
+            .collect())
+    }
+
+    /// Every host of the inventory, in definition order
+    pub fn hosts(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        self.groups
+            .iter()
+            .flat_map(|group| &group.hosts)
+            .filter(|host| seen.insert(host.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// Targets for the hosts whose tags match `selector`, out of the hosts
+    /// of `group` or of the whole inventory
+    pub fn tagged_targets(
+        &self,
+        group: Option<&str>,
+        selector: &TagSelector,
+    ) -> Result<Vec<Target>> {
+        let hosts = match group {
+            Some(group) => self.targets(group)?,
+            None => self
+                .hosts()
+                .into_iter()
+                .map(|host| Target {
+                    vars: self.vars_for(&host),
+                    host,
+                })
+                .collect(),
+        };
+        Ok(hosts
+            .into_iter()
+            .filter(|target| selector.matches(&self.tags_for(&target.host)))
+            .collect())
+    }
+
+    /// Tags of a host: its own and the ones of every group containing it
+    pub fn tags_for(&self, host: &str) -> BTreeSet<String> {
+        let mut tags = BTreeSet::new();
+        for &i in self.index().groups_of.get(host).into_iter().flatten() {
+            tags.extend(tag_list(self.groups[i].vars.get("tags")).unwrap_or_default());
+        }
+        if let Some(vars) = self.host_vars.get(host) {
+            tags.extend(tag_list(vars.get("tags")).unwrap_or_default());
+        }
+        tags
+    }
+
+    /// Variables of a host: the variables of every group containing it, with
+    /// more deeply nested groups taking precedence over their parents, and
+    /// the host's own variables taking precedence over all groups
+    pub fn vars_for(&self, host: &str) -> Vars {
+        let index = self.index();
+        let mut groups: Vec<(usize, &Group)> = index
+            .groups_of
+            .get(host)
+            .into_iter()
+            .flatten()
+            .map(|&i| (index.depths[i], &self.groups[i]))
+            .collect();
+        groups.sort_by_key(|(depth, _)| *depth);
+
+        let mut vars = Vars::new();
+        for (_, group) in groups {
+            vars.extend(group.vars.clone());
+        }
+        if let Some(host_vars) = self.host_vars.get(host) {
+            vars.extend(host_vars.clone());
+        }
+        vars
+    }
+
+    /// Number of ancestors on the longest path from a top-level group
+    fn depth(&self, name: &str) -> usize {
+        let mut depth = 0;
+        let mut current = vec![name];
+        let mut visited = HashSet::new();
+        loop {
+            let parents: Vec<&str> = self
+                .groups
+                .iter()
+                .filter(|g| g.children.iter().any(|c| current.contains(&c.as_str())))
+                .map(|g| g.name.as_str())
+                .filter(|g| visited.insert(*g))
+                .collect();
+            if parents.is_empty() {
+                return depth;
+            }
+            depth += 1;
+            current = parents;
+        }
+    }
+
+    /// Check the inventory for mistakes: everything found while parsing
+    /// (unknown keys and variables, variables of the wrong type), plus empty
+    /// groups and undefined and cyclic children
+    pub fn lint(&self) -> Vec<Issue> {
+        let mut issues = self.issues.clone();
+        let mut push = |severity, path: &Path, line, message| {
+            issues.push(Issue {
+                severity,
+                path: path.to_path_buf(),
+                line,
+                message,
+            })
+        };
+
+        for group in &self.groups {
+            if group.hosts.is_empty() && group.children.is_empty() {
+                push(
+                    Severity::Warning,
+                    &group.path,
+                    group.line,
+                    format!("group \"{}\" has no hosts or children", group.name),
+                );
+            }
+            for child in &group.children {
+                if self.group(child).is_none() {
+                    push(
+                        Severity::Error,
+                        &group.path,
+                        group.line,
+                        format!(
+                            "group \"{}\" has undefined child group \"{}\"",
+                            group.name, child
+                        ),
+                    );
+                }
+            }
+            if self.in_cycle(&group.name) {
+                push(
+                    Severity::Error,
+                    &group.path,
+                    group.line,
+                    format!(
+                        "group \"{}\" is its own descendant through children",
+                        group.name
+                    ),
+                );
+            }
+        }
+
+        issues.sort_by_key(|i| (i.path.clone(), i.line.unwrap_or(usize::MAX)));
+        issues
+    }
+
+    /// Whether `name` can be reached again by following its children
+    fn in_cycle(&self, name: &str) -> bool {
+        let mut stack: Vec<&str> = match self.group(name) {
+            Some(group) => group.children.iter().map(String::as_str).collect(),
+            None => return false,
+        };
+        let mut visited = HashSet::new();
+        while let Some(current) = stack.pop() {
+            if current == name {
+                return true;
+            }
+            if !visited.insert(current) {
+                continue;
+            }
+            if let Some(group) = self.group(current) {
+                stack.extend(group.children.iter().map(String::as_str));
+            }
+        }
+        false
+    }
+}
+
+impl Inventory {
+    /// Groups that aren't a child of any other group, followed by groups
+    /// that are only reachable through a cycle
+    fn roots(&self) -> Vec<&Group> {
+        let is_child = |name: &str| {
+            self.groups
+                .iter()
+                .any(|g| g.children.iter().any(|c| c == name))
+        };
+        let mut roots: Vec<&Group> = self.groups.iter().filter(|g| !is_child(&g.name)).collect();
+        let mut reached = HashSet::new();
+        for root in &roots {
+            self.reachable(&root.name, &mut reached);
+        }
+        for group in &self.groups {
+            if !reached.contains(group.name.as_str()) {
+                roots.push(group);
+                self.reachable(&group.name, &mut reached);
+            }
+        }
+        roots
+    }
+
+    fn reachable<'a>(&'a self, name: &'a str, reached: &mut HashSet<&'a str>) {
+        if !reached.insert(name) {
+            return;
+        }
+        if let Some(group) = self.group(name) {
+            for child in &group.children {
+                self.reachable(child, reached);
+            }
+        }
+    }
+
+    /// Render the group, children and host hierarchy
+    pub fn tree(&self, format: TreeFormat) -> String {
+        match format {
+            TreeFormat::Text => {
+                let mut out = String::new();
+                for root in self.roots() {
+                    out.push_str(&root.name);
+                    out.push('\n');
+                    self.tree_children(root, "", &mut vec![root.name.as_str()], &mut out);
+                }
+                out
+            }
+            TreeFormat::Dot => self.dot(),
+        }
+    }
+
+    fn tree_children<'a>(
+        &'a self,
+        group: &'a Group,
+        prefix: &str,
+        path: &mut Vec<&'a str>,
+        out: &mut String,
+    ) {
+        let entries = group.children.len() + group.hosts.len();
+        for (n, child) in group.children.iter().enumerate() {
+            let last = n + 1 == entries;
+            let (branch, indent) = if last {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            out.push_str(prefix);
+            out.push_str(branch);
+            out.push_str(child);
+            match self.group(child) {
+                None => out.push_str(" (undefined)\n"),
+                Some(_) if path.contains(&child.as_str()) => out.push_str(" (cycle)\n"),
+                Some(child_group) => {
+                    out.push('\n');
+                    path.push(child);
+                    self.tree_children(child_group, &format!("{}{}", prefix, indent), path, out);
+                    path.pop();
+                }
+            }
+        }
+        for (n, host) in group.hosts.iter().enumerate() {
+            let last = group.children.len() + n + 1 == entries;
+            out.push_str(prefix);
+            out.push_str(if last { "└── " } else { "├── " });
+            out.push_str(host);
+            out.push('\n');
+        }
+    }
+
+    fn dot(&self) -> String {
+        let quote = |id: &str| format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut out = String::from("digraph inventory {\n  rankdir=LR;\n");
+        let mut hosts = HashSet::new();
+        for group in &self.groups {
+            out.push_str(&format!(
+                "  {} [label={}, shape=box];\n",
+                quote(&format!("group:{}", group.name)),
+                quote(&group.name)
+            ));
+        }
+        for group in &self.groups {
+            let from = quote(&format!("group:{}", group.name));
+            for child in &group.children {
+                if self.group(child).is_none() {
+                    out.push_str(&format!(
+                        "  {} [label={}, shape=box, style=dashed];\n",
+                        quote(&format!("group:{}", child)),
+                        quote(child)
+                    ));
+                }
+                out.push_str(&format!(
+                    "  {} -> {};\n",
+                    from,
+                    quote(&format!("group:{}", child))
+                ));
+            }
+            for host in &group.hosts {
+                if hosts.insert(host.as_str()) {
+                    out.push_str(&format!(
+                        "  {} [label={}];\n",
+                        quote(&format!("host:{}", host)),
+                        quote(host)
+                    ));
+                }
+                out.push_str(&format!(
+                    "  {} -> {};\n",
+                    from,
+                    quote(&format!("host:{}", host))
+                ));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Which tags hosts must have to be selected by `--tags`. Tags separated by
+/// `,` are alternatives and tags joined with `+` must all be present, `+`
+/// binding tighter: `gpu+rhel9,arm` selects the hosts tagged both gpu and
+/// rhel9, and the hosts tagged arm. A tag prefixed with `!` must be absent.
+#[derive(Clone, Debug)]
+pub struct TagSelector {
+    /// Alternatives, each a list of `(wanted, tag)` that must all hold
+    any: Vec<Vec<(bool, String)>>,
+}
+
+impl TagSelector {
+    pub fn parse(text: &str) -> Result<TagSelector> {
+        let mut any = Vec::new();
+        for alternative in text.split(',') {
+            let mut all = Vec::new();
+            for tag in alternative.split('+').map(str::trim) {
+                let (wanted, name) = match tag.strip_prefix('!') {
+                    Some(name) => (false, name.trim()),
+                    None => (true, tag),
+                };
+                if name.is_empty() {
+                    bail!("Invalid tag selector {:?}: empty tag name", text);
+                }
+                all.push((wanted, name.to_string()));
+            }
+            any.push(all);
+        }
+        Ok(TagSelector { any })
+    }
+
+    pub fn matches(&self, tags: &BTreeSet<String>) -> bool {
+        self.any.iter().any(|all| {
+            all.iter()
+                .all(|(wanted, tag)| tags.contains(tag) == *wanted)
+        })
+    }
+}
+
+/// String form of a scalar YAML value, so that e.g. `10.0.0.1` and `42` can
+/// be used as host and group names
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn string_list(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::Null => Some(Vec::new()),
+        Value::Sequence(items) => items.iter().map(scalar).collect(),
+        _ => None,
+    }
+}
+
+/// Tags given in a `tags` variable: a list, or a single name. None when it
+/// is neither, unset tags are an empty list.
+fn tag_list(value: Option<&Value>) -> Option<Vec<String>> {
+    match value {
+        None => Some(Vec::new()),
+        Some(value @ (Value::Sequence(_) | Value::Null)) => string_list(value),
+        Some(value) => scalar(value).map(|tag| vec![tag]),
+    }
+}
+
+fn to_vars(mapping: &Mapping) -> Vars {
+    mapping
+        .iter()
+        .filter_map(|(k, v)| scalar(k).map(|k| (k, v.clone())))
+        .collect()
+}
+
+/// Lines (0-based) of the group defined on `line` (1-based): everything up
+/// to the next top-level key
+fn section(text: &str, line: Option<usize>) -> Range<usize> {
+    let Some(line) = line else {
+        return 0..usize::MAX;
+    };
+    let end = text
+        .lines()
+        .enumerate()
+        .skip(line)
+        .find(|(_, l)| !l.is_empty() && !l.starts_with(char::is_whitespace) && !l.starts_with('#'))
+        .map(|(n, _)| n)
+        .unwrap_or(usize::MAX);
+    line - 1..end
+}
+
+/// Best-effort line number (1-based) of `key` within `lines` (0-based).
+/// serde_yaml doesn't keep positions for values, so this looks for a line
+/// starting with the key, or with a `- key` list item.
+fn find_line(text: &str, lines: Range<usize>, key: &str, top_level: bool) -> Option<usize> {
+    text.lines()
+        .enumerate()
+        .skip(lines.start)
+        .take(lines.end.saturating_sub(lines.start))
+        .find(|(_, line)| {
+            if top_level && line.starts_with(char::is_whitespace) {
+                return false;
+            }
+            let line = line.trim_start();
+            let line = line.strip_prefix("- ").unwrap_or(line);
+            let line = line.trim_start_matches(['"', '\'']);
+            match line.strip_prefix(key) {
+                Some(rest) => {
+                    let rest = rest.trim_start_matches(['"', '\'']);
+                    rest.is_empty()
+                        || rest.starts_with(':')
+                        || rest.starts_with(char::is_whitespace)
+                }
+                None => false,
+            }
+        })
+        .map(|(n, _)| n + 1)
+}
+
+/// Whether a file found in an inventory directory is an inventory
+fn is_inventory_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let name = name.strip_suffix(".age").unwrap_or(name);
+    name.ends_with(".yml") || name.ends_with(".yaml")
+}
@@ -0,0 +1,46 @@
+//! Blazingly Fast Parallel SSH
+
+pub mod api;
+pub mod bench;
+pub mod bootstrap;
+pub mod completion;
+pub mod config;
+pub mod daemon;
+pub mod detach;
+pub mod device;
+pub mod dns;
+pub mod error;
+pub mod exclude;
+pub mod expect;
+pub mod filter;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod history;
+pub mod hooks;
+pub mod inventory;
+pub mod limits;
+pub mod logs;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod output;
+pub mod paths;
+pub mod plugin;
+pub mod ports;
+pub mod progress;
+pub mod proxy;
+pub mod record;
+pub mod report;
+pub mod runner;
+pub mod scp;
+pub mod ssh;
+pub mod suggest;
+pub mod sys;
+pub mod targets;
+pub mod tasks;
+pub mod telnet;
+pub mod transfer;
+pub mod update;
+pub mod vault;
+pub mod wait;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -0,0 +1,127 @@
+//! Local resource limits that bound how many hosts can be worked on at
+//! once. Every host holds an `ssh` process and the pipes of its output, so
+//! running out of file descriptors shows up as confusing spawn errors on
+//! random hosts rather than as a clear failure.
+//!
+//! The soft open files limit is raised to the hard limit at startup, and
+//! runs that would still need more descriptors than that are refused
+//! before connecting anywhere.
+
+use anyhow::{bail, Result};
+
+/// File descriptors a host takes while its command runs: the stdin, stdout
+/// and stderr pipes of ssh, and the two files of `--output-dir`
+pub const FDS_PER_HOST: u64 = 6;
+
+/// File descriptors kept for everything else: inventories, the history
+/// database, the output thread, ...
+const RESERVED_FDS: u64 = 64;
+
+/// Hosts worked on at once per CPU with `--max-parallel auto`: ssh mostly
+/// waits on the network, only the handshakes take CPU time
+const HOSTS_PER_CPU: usize = 16;
+
+fn get_limit() -> Option<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct it is given
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    Some(limit)
+}
+
+fn finite(limit: libc::rlim_t) -> Option<u64> {
+    (limit != libc::RLIM_INFINITY).then_some(limit)
+}
+
+/// Current soft limit on open files (`ulimit -n`), `None` when unlimited
+/// or unknown
+pub fn open_files_limit() -> Option<u64> {
+    finite(get_limit()?.rlim_cur)
+}
+
+/// Raise the soft limit on open files as far as the hard limit allows,
+/// keeping it as is when that fails
+pub fn raise_open_files_limit() {
+    let Some(limit) = get_limit() else {
+        return;
+    };
+    if limit.rlim_cur == limit.rlim_max {
+        return;
+    }
+    let raised = libc::rlimit {
+        rlim_cur: limit.rlim_max,
+        ..limit
+    };
+    // SAFETY: setrlimit only reads the struct it is given
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+        // macOS refuses an unlimited soft limit, but takes OPEN_MAX
+        let capped = libc::rlimit {
+            rlim_cur: limit.rlim_max.min(10240),
+            ..limit
+        };
+        if capped.rlim_cur > limit.rlim_cur {
+            // SAFETY: as above
+            unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &capped) };
+        }
+    }
+}
+
+/// Fail with what to do about it when working on `hosts` hosts at once
+/// needs more file descriptors than the limit allows. `limited` tells
+/// whether `--max-parallel` applies, to suggest it.
+pub fn check_open_files(hosts: usize, limited: bool) -> Result<()> {
+    let Some(limit) = get_limit() else {
+        return Ok(());
+    };
+    let Some(soft) = finite(limit.rlim_cur) else {
+        return Ok(());
+    };
+    let needed = hosts as u64 * FDS_PER_HOST + RESERVED_FDS;
+    if needed <= soft {
+        return Ok(());
+    }
+    let alternative = match limited {
+        true => format!(
+            ", or use --max-parallel {} or --max-parallel auto",
+            fd_parallelism().unwrap_or(1)
+        ),
+        false => ", or use fewer targets".to_string(),
+    };
+    let hard = match finite(limit.rlim_max) {
+        Some(hard) if hard <= soft => ", which is also the hard limit".to_string(),
+        Some(hard) => format!(" (hard limit {})", hard),
+        None => String::new(),
+    };
+    bail!(
+        "Working on {} hosts at once needs about {} open files, but the limit is {}{}: \
+         raise it with `ulimit -n {}` (as root for more than the hard limit){}",
+        hosts,
+        needed,
+        soft,
+        hard,
+        needed,
+        alternative
+    );
+}
+
+/// Hosts the file descriptor limit leaves room for, `None` without a limit
+pub fn fd_parallelism() -> Option<usize> {
+    let limit = open_files_limit()?;
+    Some((limit.saturating_sub(RESERVED_FDS) / FDS_PER_HOST).max(1) as usize)
+}
+
+/// Hosts to work on at once for `--max-parallel auto` with `targets`
+/// targets: as many as there are, within what the CPUs and the file
+/// descriptor limit allow
+pub fn auto_parallelism(targets: usize) -> usize {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut hosts = targets.min(cpus * HOSTS_PER_CPU);
+    if let Some(fds) = fd_parallelism() {
+        hosts = hosts.min(fds);
+    }
+    hosts.max(1)
+}
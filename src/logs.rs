@@ -0,0 +1,336 @@
+//! Following and searching the logs of the targets.
+//!
+//! `multissh tail` runs `tail -F` on every host at once with
+//! [`runner::follow`](crate::runner::follow). The remote command also waits
+//! for its stdin to close, which happens whenever multissh goes away, Ctrl-C
+//! included, and then stops `tail`: without a terminal nothing else would
+//! tell the remote side, and quiet logs would keep their `tail` running
+//! forever.
+//!
+//! `multissh grep` greps the logs of every host and merges the matches into
+//! one timeline, reading the timestamps of the lines in the common log
+//! formats. Each host also prints its year and UTC offset first, so times
+//! without them are read as the host wrote them.
+
+use crate::output::Stream;
+use crate::runner::HostResult;
+use crate::ssh::quote;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDateTime, TimeDelta, Utc};
+
+/// Remote command following `files` from their last `lines` lines until the
+/// connection goes away
+pub fn tail_command(files: &[String], lines: u32) -> String {
+    let files: Vec<String> = files.iter().map(|f| quote(f)).collect();
+    format!(
+        "tail -n {} -F -- {} & t=$!; cat > /dev/null; kill $t 2>/dev/null",
+        lines,
+        files.join(" ")
+    )
+}
+
+/// Options of `multissh grep`
+#[derive(Clone, Debug, Default)]
+pub struct GrepOptions {
+    pub ignore_case: bool,
+    /// Match the pattern as a plain string instead of an extended regex
+    pub fixed_strings: bool,
+}
+
+/// Remote command printing the year and UTC offset of the host, used to
+/// read the timestamps of its logs, then the lines of `files` matching
+/// `pattern`. Only fails when grep does, not when nothing matches.
+pub fn grep_command(pattern: &str, files: &[String], options: &GrepOptions) -> String {
+    let mut flags = String::from(if options.fixed_strings { "-F" } else { "-E" });
+    if options.ignore_case {
+        flags.push_str(" -i");
+    }
+    let files: Vec<String> = files.iter().map(|f| quote(f)).collect();
+    format!(
+        "date '+%Y %z' && {{ grep -h {} -e {} -- {}; [ $? -le 1 ]; }}",
+        flags,
+        quote(pattern),
+        files.join(" ")
+    )
+}
+
+/// Start of a `--since` window: a duration before `now` such as
+/// `10 min ago`, `2h` or `3 days`, or a timestamp read like log timestamps
+/// in local time
+pub fn parse_since(text: &str, now: DateTime<Local>) -> Result<DateTime<FixedOffset>> {
+    let now = now.fixed_offset();
+    if let Some(time) = parse_timestamp(text.trim(), now.year(), *now.offset(), now) {
+        return Ok(time);
+    }
+    let duration = text.trim().trim_end_matches("ago").trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(split);
+    let amount: i64 = amount.parse().ok().with_context(|| {
+        format!(
+            "Invalid --since {:?}, use e.g. \"10 min ago\" or \"2024-05-01 12:00\"",
+            text
+        )
+    })?;
+    let seconds = match unit.trim() {
+        "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 3600,
+        "d" | "day" | "days" => 86400,
+        "w" | "week" | "weeks" => 7 * 86400,
+        unit => bail!("Invalid --since unit {:?}, use s, min, h, d or w", unit),
+    };
+    Ok(now - TimeDelta::seconds(amount * seconds))
+}
+
+/// A matching line in the merged timeline
+#[derive(Clone, Debug)]
+pub struct TimedLine {
+    pub host: String,
+    /// Time read from the line, or from the last line of the same host
+    /// before it that had one
+    pub time: Option<DateTime<FixedOffset>>,
+    pub line: String,
+}
+
+/// Matches of every host merged in chronological order
+#[derive(Debug, Default)]
+pub struct Timeline {
+    pub lines: Vec<TimedLine>,
+    /// Lines left out by `--since` because no time could be read for them
+    pub untimed: usize,
+}
+
+/// Merge the output of [`grep_command`] on every host, keeping the lines
+/// from `since` on. Lines without a time of their own keep their place
+/// after the line of the same host before them, lines without any time
+/// come first.
+pub fn timeline(results: &[HostResult], since: Option<DateTime<FixedOffset>>) -> Timeline {
+    let mut timeline = Timeline::default();
+    for result in results.iter().filter(|r| r.success()) {
+        // Merging needs every line, spilled ones included
+        let output = result.full_output(Stream::Stdout).unwrap_or_default();
+        let output = String::from_utf8_lossy(&output);
+        let mut lines = output.lines();
+        // `date` runs first: year and offset of the host
+        let (year, offset) = match lines.next().and_then(parse_clock) {
+            Some(clock) => clock,
+            None => {
+                let now = Local::now().fixed_offset();
+                (now.year(), *now.offset())
+            }
+        };
+        let now = Utc::now().with_timezone(&offset);
+        let mut last = None;
+        for line in lines {
+            if let Some(time) = find_timestamp(line, year, offset, now) {
+                last = Some(time);
+            }
+            match (since, last) {
+                (Some(_), None) => {
+                    timeline.untimed += 1;
+                    continue;
+                }
+                (Some(since), Some(time)) if time < since => continue,
+                _ => {}
+            }
+            timeline.lines.push(TimedLine {
+                host: result.host.clone(),
+                time: last,
+                line: line.to_string(),
+            });
+        }
+    }
+    // Stable, so lines with the same time stay in host and file order
+    timeline.lines.sort_by_key(|l| l.time);
+    timeline
+}
+
+fn parse_clock(line: &str) -> Option<(i32, FixedOffset)> {
+    let (year, offset) = line.trim().split_once(' ')?;
+    let offset =
+        DateTime::parse_from_str(&format!("2000-01-01 00:00 {}", offset), "%Y-%m-%d %H:%M %z")
+            .ok()?;
+    Some((year.parse().ok()?, *offset.offset()))
+}
+
+/// First timestamp of `line`, read at its start or after a `[`
+fn find_timestamp(
+    line: &str,
+    year: i32,
+    offset: FixedOffset,
+    now: DateTime<FixedOffset>,
+) -> Option<DateTime<FixedOffset>> {
+    let starts = std::iter::once(0).chain(line.match_indices('[').map(|(i, _)| i + 1));
+    starts
+        .take(4)
+        .find_map(|start| parse_timestamp(line[start..].trim_start(), year, offset, now))
+}
+
+/// Timestamp at the start of `text`, in one of the usual log formats:
+/// RFC 3339, `2024-05-01 12:34:56`, syslog's `May  1 12:34:56` and the
+/// `01/May/2024:12:34:56 +0000` of web server access logs. Times without
+/// an offset are in `offset`, syslog times without a year in `year` unless
+/// that would put them after `now`.
+fn parse_timestamp(
+    text: &str,
+    year: i32,
+    offset: FixedOffset,
+    now: DateTime<FixedOffset>,
+) -> Option<DateTime<FixedOffset>> {
+    let word = text.split([' ', ']']).next().unwrap_or_default();
+    if let Ok(time) = DateTime::parse_from_rfc3339(word) {
+        return Some(time);
+    }
+    if let Ok((time, _)) = DateTime::parse_and_remainder(text, "%d/%b/%Y:%H:%M:%S %z") {
+        return Some(time);
+    }
+    let local = |naive: NaiveDateTime| naive.and_local_timezone(offset).single();
+    for format in [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok((naive, _)) = NaiveDateTime::parse_and_remainder(text, format) {
+            return local(naive);
+        }
+    }
+    // Syslog has no year: the most recent one that isn't in the future
+    let prefix: String = text.chars().take(15).collect();
+    for year in [year, year - 1] {
+        let dated = format!("{} {}", year, prefix);
+        if let Ok((naive, _)) = NaiveDateTime::parse_and_remainder(&dated, "%Y %b %e %H:%M:%S") {
+            match local(naive) {
+                Some(time) if time <= now + TimeDelta::days(1) => return Some(time),
+                Some(_) => continue,
+                None => return None,
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(text: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(text).unwrap()
+    }
+
+    #[test]
+    fn reads_log_timestamps() {
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let plus2 = FixedOffset::east_opt(2 * 3600).unwrap();
+        let now = time("2024-06-15T12:00:00Z");
+        let new_year = time("2024-01-01T08:00:00Z");
+        for (text, offset, now, expected) in [
+            (
+                "2024-05-01T12:34:56Z rest",
+                utc,
+                now,
+                "2024-05-01T12:34:56Z",
+            ),
+            (
+                "2024-05-01T12:34:56.5+02:00",
+                utc,
+                now,
+                "2024-05-01T12:34:56.5+02:00",
+            ),
+            (
+                "2024-05-01 12:34:56 rest",
+                plus2,
+                now,
+                "2024-05-01T12:34:56+02:00",
+            ),
+            ("2024-05-01 12:34", utc, now, "2024-05-01T12:34:00Z"),
+            (
+                "May  1 12:34:56 web1 sshd[1]:",
+                utc,
+                now,
+                "2024-05-01T12:34:56Z",
+            ),
+            (
+                "Jun 16 01:00:00 web1 cron",
+                utc,
+                now,
+                "2024-06-16T01:00:00Z",
+            ),
+            // After `now`: last year's
+            (
+                "Dec 31 23:59:59 web1 cron",
+                utc,
+                new_year,
+                "2023-12-31T23:59:59Z",
+            ),
+            (
+                "Jul  1 00:00:00 web1 cron",
+                plus2,
+                now,
+                "2023-07-01T00:00:00+02:00",
+            ),
+            (
+                "01/May/2024:12:34:56 +0000] \"GET /",
+                plus2,
+                now,
+                "2024-05-01T12:34:56Z",
+            ),
+            (
+                "01/May/2024:12:34:56 -0700",
+                utc,
+                now,
+                "2024-05-01T12:34:56-07:00",
+            ),
+        ] {
+            assert_eq!(
+                parse_timestamp(text, 2024, offset, now),
+                Some(time(expected)),
+                "{}",
+                text
+            );
+        }
+        for text in [
+            "",
+            "web1 May  1 12:34:56",
+            "2024-13-01 00:00",
+            "Foo  1 12:34:56",
+        ] {
+            assert_eq!(parse_timestamp(text, 2024, utc, now), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn reads_since() {
+        let now = Local::now();
+        for (text, seconds) in [
+            ("10 min ago", 600),
+            ("10min", 600),
+            ("2h", 7200),
+            (" 3 days ago ", 3 * 86400),
+            ("1 week", 7 * 86400),
+            ("30 s ago", 30),
+        ] {
+            assert_eq!(
+                parse_since(text, now).unwrap(),
+                now.fixed_offset() - TimeDelta::seconds(seconds),
+                "{}",
+                text
+            );
+        }
+        let offset = *now.fixed_offset().offset();
+        let local = NaiveDateTime::parse_from_str("2024-05-01 12:00", "%Y-%m-%d %H:%M")
+            .unwrap()
+            .and_local_timezone(offset)
+            .unwrap();
+        assert_eq!(parse_since("2024-05-01 12:00", now).unwrap(), local);
+        assert_eq!(
+            parse_since("2024-05-01T12:00:00Z", now).unwrap(),
+            time("2024-05-01T12:00:00Z")
+        );
+        for text in ["", "ago", "ten min ago", "10 fortnights"] {
+            assert!(parse_since(text, now).is_err(), "{}", text);
+        }
+    }
+}
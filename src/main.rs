@@ -1,38 +1,111 @@
-use anyhow::{bail, Result};
-use clap::Parser;
-use rayon::prelude::*;
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::{ArgValueCompleter, CompleteEnv};
+use multissh_rs::api::{self, ApiOptions};
+use multissh_rs::bench;
+use multissh_rs::bootstrap::Keypair;
+use multissh_rs::completion::{self, Shell};
+use multissh_rs::config::{self, Config};
+use multissh_rs::daemon::{self, DaemonOptions, JobState, Request, Response};
+use multissh_rs::detach::{DetachedHost, DetachedRun};
+use multissh_rs::device::{self, DeviceOptions};
+use multissh_rs::dns;
+use multissh_rs::exclude::Excludes;
+use multissh_rs::expect::Script;
+use multissh_rs::filter::FilterScript;
+use multissh_rs::history::History;
+use multissh_rs::inventory::{Inventory, Severity, TagSelector, TreeFormat};
+use multissh_rs::limits;
+use multissh_rs::logs::{self, GrepOptions};
+use multissh_rs::output::{
+    self, exit_code_summary, exit_label, failure_reason, ColorChoice, Encoding, Event, Heartbeat,
+    OutputMode, OutputOptions, OutputWriter, SortOrder, StderrMode, Stream,
+};
+use multissh_rs::paths;
+use multissh_rs::ports::{self, PortState};
+use multissh_rs::progress::ProgressEvents;
+use multissh_rs::proxy::{self, Tunnel};
+use multissh_rs::record::Recorder;
+use multissh_rs::report::{ReportFormat, RunReport};
+use multissh_rs::runner::{
+    self, ByteSize, FailureThreshold, HostResult, Parallelism, RunOptions, Spill, TimeSpan,
+};
+use multissh_rs::scp;
+use multissh_rs::ssh::{self, AddressFamily, RemoteShell, SshOptions, Transport, ASKPASS_ENV};
+use multissh_rs::sys::Preset;
+use multissh_rs::targets::{self, read_targets_file, Target};
+use multissh_rs::tasks::{self, TaskFile};
+use multissh_rs::transfer::{self, BwLimit, FileAttrs, TransferProtocol, Verify};
+use multissh_rs::update;
+use multissh_rs::vault::Keys;
+use multissh_rs::wait::{self, WaitOptions, Waited};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind, IsTerminal, Write};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// Blazingly Fast Parallel SSH
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    /// Comma-separated list of target hostnames or IP addresses
-    /// (e.g. "host1,host2,host3")
-    #[clap(short, long)]
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+
+    /// Comma-separated list of target hostnames or IP addresses, ranges
+    /// like web[01-10], or @aliases from the config file
+    /// (e.g. "host1,host2,host3" or "@cache,@queue")
+    #[clap(short, long, add = ArgValueCompleter::new(completion::aliases))]
     targets: Option<String>,
 
     /// Path to a file containing a list of target hostnames or IP addresses to use as targets
     /// (default: ~/.config/multissh/targets; ~/.multissh/targets; /etc/multissh/targets)
     /// (e.g. "/path/to/targets.txt")
-    #[clap(short = 'f', long)]
+    #[clap(short = 'f', long, value_parser = paths::parse)]
     targets_file: Option<PathBuf>,
 
-    /// Path to a file containing an inventory of target hostnames or IP addresses
-    /// (default: ~/.config/multissh/inventory; ~/.multissh/inventory; /etc/multissh/inventory)
+    /// Path to a file containing an inventory of target hostnames or IP addresses,
+    /// or a directory of *.yml/*.yaml inventories, or "plugin:NAME[:ARG]" to
+    /// read the hosts from the program multissh-inventory-NAME, or
+    /// "wasm:FILE[:ARG]" from a WASM module (built with --features wasm); can
+    /// be given multiple times to merge inventories
+    /// (default: $MULTISSH_INVENTORY; ~/.config/multissh/inventory; ~/.multissh/inventory; /etc/multissh/inventory)
     /// (e.g. "/path/to/inventory.yml")
-    #[clap(short = 'i', long)]
-    inventory_file: Option<PathBuf>,
+    #[clap(short = 'i', long, global = true, value_parser = paths::parse, action = ArgAction::Append)]
+    inventory_file: Vec<PathBuf>,
+
+    /// Key for encrypted inventories: an age identity file, or a file with
+    /// the ansible-vault password on its first line. Without it the
+    /// passphrase is asked for when an encrypted inventory is read
+    /// (e.g. "~/.config/multissh/inventory.key")
+    #[clap(long, global = true, env = "MULTISSH_INVENTORY_KEY", value_parser = paths::parse)]
+    inventory_key: Option<PathBuf>,
 
     /// Name of an inventory group to use as targets
-    /// (required if -i/--inventory-file is used)
+    /// (required if -i/--inventory-file is used without --tags)
     /// (e.g. "web-servers")
-    #[clap(short = 'g', long)]
+    #[clap(short = 'g', long, add = ArgValueCompleter::new(completion::groups))]
     inventory_group: Option<String>,
 
+    /// Select the inventory hosts by their tags, out of -g/--inventory-group
+    /// or the whole inventory: "," separates alternatives, "+" joins tags
+    /// that must all be present, "!" negates a tag
+    /// (e.g. "gpu+rhel9,arm" for hosts tagged gpu and rhel9, or arm)
+    #[clap(long)]
+    tags: Option<String>,
+
     /// Username to use when connecting to target hosts
     /// (default: $USER)
-    #[clap(short, long)]
+    #[clap(short, long, env = "MULTISSH_USER")]
     user: Option<String>,
 
     /// Password to use when connecting to target hosts
@@ -45,123 +118,882 @@ struct Cli {
 
     /// Path to a private key to use when connecting to target hosts
     /// (default: ~/.ssh/id_rsa)
-    #[clap(short = 'k', long, default_value = "~/.ssh/id_rsa")]
+    #[clap(
+        short = 'k',
+        long,
+        env = "MULTISSH_PRIVATE_KEY",
+        value_parser = paths::parse,
+        default_value = "~/.ssh/id_rsa"
+    )]
     private_key: Option<PathBuf>,
 
     /// Port to use when connecting to target hosts
     /// (default: 22)
-    #[clap(short = 'P', long, default_value = "22")]
+    #[clap(short = 'P', long, env = "MULTISSH_PORT", default_value = "22")]
     port: Option<u16>,
 
+    /// Option passed to ssh as is, winning over the ones multissh sets; can
+    /// be given multiple times
+    /// (e.g. "-o IPQoS=throughput -o PubkeyAcceptedAlgorithms=+ssh-rsa")
+    #[clap(short = 'o', long = "ssh-option", value_name = "KEY=VALUE", action = ArgAction::Append)]
+    ssh_options: Vec<String>,
+
     /// Timeout in seconds to wait for a connection to a target host
     /// (default: 10)
-    #[clap(long, default_value = "10")]
+    #[clap(long, env = "MULTISSH_TIMEOUT", default_value = "10")]
     timeout: Option<u64>,
 
+    /// Seconds the command may run on a host before it is stopped and the
+    /// host fails; `connect_timeout` and `exec_timeout` variables of hosts
+    /// and groups in the inventory override both timeouts
+    #[clap(long, value_name = "SECONDS", env = "MULTISSH_EXEC_TIMEOUT")]
+    exec_timeout: Option<u64>,
+
+    /// Time the whole run may take: once it passed no more hosts are
+    /// started, the commands still running are stopped, and multissh
+    /// exits with 124
+    /// (e.g. "15m" or "1h30m")
+    #[clap(long, value_name = "TIME")]
+    deadline: Option<TimeSpan>,
+
+    /// How often to write how far the run got on stderr when stdout isn't
+    /// a terminal, e.g. "[5m] 320/400 done, 3 failed, 12 running"; 0 for
+    /// never
+    /// (default: 1m)
+    #[clap(long, value_name = "TIME", default_value = "1m")]
+    heartbeat: Option<TimeSpan>,
+
+    /// Write JSON events as the run goes to an open file descriptor (a
+    /// number) or a file, for wrappers and UIs: one object per line, each
+    /// with "event" and "time" (RFC 3339), written as it happens.
+    /// host_started has "host"; host_output_chunk "host", "stream" (stdout
+    /// or stderr) and "data", a line as received; host_finished "host",
+    /// "success", "exit_code", "error" and "duration" (seconds); and the
+    /// last, run_summary, "hosts", "succeeded", "failed", "skipped" and
+    /// "duration"
+    /// (e.g. "3" or "events.jsonl")
+    #[clap(long, value_name = "FD|FILE")]
+    progress_events: Option<String>,
+
+    /// Directory to write a transcript of the run to for every host, in
+    /// DIR/<host>/<start time>.log: "# " header lines with the host, start
+    /// time, local and remote user and the command sent, then a line per
+    /// event, the seconds since the host started first: "+0.000 started",
+    /// "+0.412 stdout | <line as received>" (or stderr, or notice), and
+    /// last "+0.415 done exit_code=1 duration=0.415s attempts=1" (or
+    /// error="...", skipped="..." or signal instead of exit_code)
+    /// (e.g. "/var/log/multissh")
+    #[clap(long, value_name = "DIR", env = "MULTISSH_RECORD", value_parser = paths::parse)]
+    record: Option<PathBuf>,
+
+    /// When the --deadline is, counted from the start of multissh
+    #[clap(skip)]
+    deadline_at: Option<Instant>,
+
+    /// Number of times to retry connecting to a host after a transient
+    /// failure (connection refused, timeout, DNS); these retries don't count
+    /// as command failures
+    /// (default: 0)
+    #[clap(long, env = "MULTISSH_CONNECT_RETRIES", default_value = "0")]
+    connect_retries: u32,
+
+    /// Seconds to wait before the first connection retry, doubled after
+    /// every retry
+    /// (default: 1)
+    #[clap(long, default_value = "1")]
+    connect_backoff: f64,
+
+    /// Hosts worked on at once, or "auto" to pick as many as the CPUs and
+    /// the open files limit (`ulimit -n`) allow
+    /// (default: one per CPU)
+    #[clap(long, value_name = "N|auto", env = "MULTISSH_MAX_PARALLEL")]
+    max_parallel: Option<Parallelism>,
+
+    /// Lines of every host shown on the terminal, the rest is left out with
+    /// a note, see --output-dir to keep all of it
+    #[clap(long, value_name = "LINES")]
+    max_output_lines: Option<usize>,
+
+    /// Bytes of every host shown on the terminal, e.g. 64K
+    #[clap(long, value_name = "SIZE")]
+    max_output_bytes: Option<ByteSize>,
+
+    /// Type the command at the prompt of an interactive session instead of
+    /// running it, for switches and routers without exec channels; every
+    /// line of the command is a device command
+    /// (default: false)
+    #[clap(long)]
+    device_mode: bool,
+
+    /// Regex matching the prompt of the devices in --device-mode
+    #[clap(long, value_name = "REGEX", requires = "device_mode", default_value = device::DEFAULT_PROMPT)]
+    prompt: Regex,
+
+    /// Device command turning paging off in --device-mode, run before the
+    /// others; can be given multiple times
+    /// (e.g. "terminal length 0")
+    #[clap(long, value_name = "COMMAND", requires = "device_mode", action = ArgAction::Append)]
+    disable_paging: Vec<String>,
+
+    /// Seconds to wait for the prompt in --device-mode, after logging in
+    /// and after every command
+    /// (default: 60)
+    #[clap(long, value_name = "SECONDS", default_value = "60")]
+    prompt_timeout: f64,
+
+    /// Answer the command on a terminal with a send/expect script, for
+    /// password changes, installers asking questions and the like. One step
+    /// per line, blank lines and # comments skipped: "expect REGEX" waits
+    /// for output matching REGEX, "expect eof" for the command to exit;
+    /// "send TEXT" types TEXT, "sendline TEXT" followed by Enter; "timeout
+    /// SECONDS" sets how long the following expects wait (30 at first);
+    /// "sleep SECONDS" pauses. TEXT and REGEX may be double-quoted to keep
+    /// spaces at the ends or use \n, \r, \t, \" and \\. {{name}} is
+    /// replaced with the host's inventory variable "name" (matched
+    /// literally in patterns), {{host}} with its name; a host missing a
+    /// variable fails unconnected. The host exits with the status of the
+    /// command
+    /// (e.g. "passwd.expect")
+    #[clap(long, value_name = "FILE", value_parser = load_expect_script, conflicts_with = "device_mode")]
+    expect_script: Option<Script>,
+
+    /// How push and fetch copy files: with cat and the remote shell, or
+    /// the classic SCP protocol for appliances allowing nothing else
+    /// (default: cat)
+    #[clap(long, value_enum, default_value_t)]
+    transfer_protocol: TransferProtocol,
+
+    /// Bytes per second push and fetch may copy to or from every host,
+    /// e.g. 5M
+    #[clap(long, value_name = "RATE")]
+    bwlimit: Option<ByteSize>,
+
+    /// Bytes per second push and fetch may copy across all hosts at once,
+    /// e.g. 50M to keep an uplink usable
+    #[clap(long, value_name = "RATE")]
+    bwlimit_total: Option<ByteSize>,
+
+    /// Check the files push and fetch copied on both ends, failing the
+    /// hosts where they differ; none for hosts without sha256sum
+    /// (default: sha256)
+    #[clap(long, value_enum, default_value_t)]
+    verify: Verify,
+
+    /// Carry over file modes and modification times with push and fetch,
+    /// and recreate symlinks as symlinks, as rsync -a does (default: false)
+    #[clap(long)]
+    preserve: bool,
+
+    /// With --preserve, copy the files symlinks point to instead of the
+    /// symlinks (default: false)
+    #[clap(long)]
+    follow_symlinks: bool,
+
+    /// Strip login banners, the MOTD and anything else hosts print before
+    /// running the command from its output, keeping them apart as the
+    /// banner of each host. The command is sent after
+    /// `printf '%s\n' __multissh_banner_end__; printf '%s\n'
+    /// __multissh_banner_end__ >&2` for that, which needs a POSIX shell on
+    /// the hosts; not done with --shell or --no-shell, for prechecks,
+    /// detached runs and transfers
+    /// (default: false)
+    #[clap(long)]
+    strip_banner: bool,
+
+    /// Show binary output as a hexdump instead of just its size
+    #[clap(long)]
+    hex: bool,
+
+    /// Output of a host larger than this, per stream, goes to a temporary
+    /// file instead of memory
+    /// (default: 64M)
+    #[clap(long, value_name = "SIZE", default_value = "64M")]
+    spill_threshold: ByteSize,
+
+    /// Run against the remaining targets when some target names can't be
+    /// resolved, instead of aborting before connecting to any host
+    /// (default: false)
+    #[clap(long)]
+    skip_unresolvable: bool,
+
+    /// Compress the SSH connection (zlib), useful for commands with large
+    /// output over slow links
+    /// (default: false)
+    #[clap(short = 'C', long)]
+    compress: bool,
+
+    /// Seconds of inactivity before sending a keepalive to the target host,
+    /// so idle connections aren't dropped by NAT or firewalls
+    /// (e.g. 30)
+    #[clap(long)]
+    keepalive_interval: Option<u64>,
+
+    /// Number of unanswered keepalives before giving up on the connection
+    /// (default: 3 when --keepalive-interval is set)
+    #[clap(long, requires = "keepalive_interval")]
+    keepalive_count: Option<u32>,
+
+    /// Directory to change to on the target hosts before running the
+    /// command; hosts where it doesn't exist fail without running it
+    /// (e.g. "/srv/app")
+    #[clap(long, value_name = "DIR")]
+    chdir: Option<String>,
+
+    /// Forward the local SSH agent, for commands that need SSH themselves
+    /// (git pulls, nested ssh); root on the target hosts can use it while
+    /// the command runs. Can also be set per group or host with the
+    /// `forward_agent` inventory variable
+    /// (default: false)
+    #[clap(short = 'A', long)]
+    forward_agent: bool,
+
+    /// Command ssh connects through instead of a TCP connection, like
+    /// ProxyCommand of ssh_config (which is honored too) with %h and %p for
+    /// the host and port, or "none" to connect directly; can also be set
+    /// per group or host with the `proxy_command` inventory variable. Hosts
+    /// behind a proxy aren't resolved locally
+    /// (e.g. "corp-broker connect %h:%p")
+    #[clap(long, value_name = "COMMAND")]
+    proxy_command: Option<String>,
+
+    /// Jump hosts to reach the targets through, comma-separated for a
+    /// chain connected through in order, each [USER@]HOST[:PORT]
+    /// Groups and hosts of the inventory can have their own with the
+    /// `jump_host` variable
+    /// (e.g. "bastion.example.com" or "bastion1,admin@bastion2:2222")
+    #[clap(short = 'J', long, value_name = "HOSTS", conflicts_with_all = ["proxy_command", "socks5", "http_proxy"])]
+    jump_host: Option<String>,
+
+    /// SOCKS5 proxy to connect to the targets through, e.g. one opened with
+    /// `ssh -D`; the proxy resolves the host names
+    /// (e.g. "localhost:1080" or "user:password@proxy.example.com:1080")
+    #[clap(long, value_name = "[USER:PASS@]HOST:PORT", env = "MULTISSH_SOCKS5", value_parser = Tunnel::socks5)]
+    socks5: Option<Tunnel>,
+
+    /// HTTP proxy to connect to the targets through with CONNECT, where
+    /// outbound connections must go through it; the proxy resolves the
+    /// host names
+    /// (e.g. "proxy.example.com:3128" or "user:password@proxy.example.com:3128")
+    #[clap(long, value_name = "[USER:PASS@]HOST:PORT", env = "MULTISSH_HTTP_PROXY", value_parser = Tunnel::http, conflicts_with = "socks5")]
+    http_proxy: Option<Tunnel>,
+
+    /// Connect to the targets over IPv4 only
+    #[clap(short = '4', long, conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Connect to the targets over IPv6 only
+    #[clap(short = '6', long)]
+    ipv6: bool,
+
+    /// Try the IPv6 and IPv4 addresses of the targets at once, IPv6 with a
+    /// short head start, and use whichever connects first, so dual-stack
+    /// hosts with broken IPv6 don't take the whole connect timeout; not for
+    /// hosts ssh_config proxies
+    #[clap(long, conflicts_with_all = ["ipv4", "ipv6", "proxy_command", "jump_host", "socks5", "http_proxy"])]
+    happy_eyeballs: bool,
+
+    /// Local address to connect to the targets, jump hosts and proxies
+    /// from, for firewalls letting only one of the addresses of this
+    /// machine in (e.g. "10.1.2.3")
+    #[clap(long, value_name = "ADDRESS", env = "MULTISSH_BIND_ADDRESS")]
+    bind_address: Option<IpAddr>,
+
+    /// Ciphers to negotiate with the targets, as for Ciphers of ssh_config;
+    /// defaults to `ciphers` of the config file
+    /// (e.g. "aes256-gcm@openssh.com,aes256-ctr")
+    #[clap(long, value_name = "LIST")]
+    ciphers: Option<String>,
+
+    /// Key exchange algorithms to negotiate with the targets, as for
+    /// KexAlgorithms of ssh_config; defaults to `kex` of the config file
+    /// (e.g. "ecdh-sha2-nistp384,ecdh-sha2-nistp256")
+    #[clap(long, value_name = "LIST")]
+    kex: Option<String>,
+
+    /// MACs to negotiate with the targets, as for MACs of ssh_config;
+    /// defaults to `macs` of the config file
+    /// (e.g. "hmac-sha2-512,hmac-sha2-256")
+    #[clap(long, value_name = "LIST")]
+    macs: Option<String>,
+
+    /// Host key algorithms to accept from the targets, the first one they
+    /// have preferred, as for HostKeyAlgorithms of ssh_config; defaults to
+    /// `host_key_algorithms` of the config file. Without it ssh prefers the
+    /// types of the keys known_hosts has for a host already
+    /// (e.g. "ssh-ed25519,ecdsa-sha2-nistp256" or "^ssh-ed25519" to only
+    /// move ed25519 to the front)
+    #[clap(long, value_name = "LIST")]
+    host_key_algorithms: Option<String>,
+
+    /// Also offer ssh-rsa (SHA-1) signatures, SHA-1 Diffie-Hellman groups
+    /// and CBC ciphers, for old switches and iLO/iDRAC interfaces; hosts of
+    /// the inventory can have it on their own with `legacy_crypto: true`
+    #[clap(long)]
+    legacy_crypto: bool,
+
+    /// SSH agent socket to authenticate with, and to forward with -A,
+    /// instead of $SSH_AUTH_SOCK and the IdentityAgent of ssh_config;
+    /// defaults to `agent_socket` of the config file, and can be set per
+    /// group or host with the `agent_socket` inventory variable
+    /// (e.g. "~/.1password/agent.sock")
+    #[clap(long, value_name = "PATH", env = "MULTISSH_AGENT_SOCKET", value_parser = paths::parse)]
+    agent_socket: Option<PathBuf>,
+
+    /// Locale the command runs in on the target hosts, set as LANG and
+    /// LC_ALL so output can be compared across hosts; defaults to `lang`
+    /// of the config file (e.g. "C.UTF-8")
+    #[clap(long, value_name = "LOCALE", env = "MULTISSH_LANG")]
+    lang: Option<String>,
+
+    /// Command line the command is passed to on the target hosts, instead
+    /// of the remote user's shell
+    /// (e.g. "/bin/bash -lc")
+    #[clap(long, conflicts_with_all = ["login", "no_shell"])]
+    shell: Option<String>,
+
+    /// Run the command in the remote user's login shell, so PATH and other
+    /// settings from the profile are loaded
+    /// (default: false)
+    #[clap(long, conflicts_with = "no_shell")]
+    login: bool,
+
+    /// Run the command directly instead of through a remote shell: it is
+    /// split into words locally and no globs, variables or pipes are
+    /// expanded remotely
+    /// (default: false)
+    #[clap(long)]
+    no_shell: bool,
+
+    /// Shell-quote each word given after `--` so the remote shell sees them
+    /// exactly as typed, without expanding `$`, quotes or globs
+    /// (e.g. --quote -- grep -r '$HOME' /etc)
+    #[clap(long)]
+    quote: bool,
+
+    /// Start the command in the background on every host and return right
+    /// away, printing a run id to use with `multissh status` and
+    /// `multissh collect`; useful for jobs that take hours
+    /// (default: false)
+    #[clap(long)]
+    detach: bool,
+
+    /// Run on these inventory groups one after the other, finishing every
+    /// host of a group before starting the next; hosts in several groups
+    /// run with the first one. --tags narrows down every group
+    /// (e.g. "db,app,web")
+    #[clap(
+        long,
+        value_name = "GROUPS",
+        conflicts_with_all = ["targets", "targets_file", "inventory_group"],
+        add = ArgValueCompleter::new(completion::group_lists)
+    )]
+    serial_groups: Option<String>,
+
+    /// With --serial-groups, don't start the next groups once more hosts
+    /// than this failed in a group: a number of hosts or a percentage
+    /// (e.g. "0" to stop at the first failure, or "10%")
+    #[clap(long, value_name = "THRESHOLD")]
+    group_failure_threshold: Option<FailureThreshold>,
+
+    /// Command run on every host first; hosts where it fails are skipped
+    /// and the command only runs on the others
+    /// (e.g. "systemctl is-active myapp")
+    #[clap(long, value_name = "COMMAND")]
+    precheck: Option<String>,
+
     /// Enable verbose output
     /// (default: false)
     #[clap(long)]
     verbose: bool,
 
+    /// How to display host output: interleaved lines prefixed with the host
+    /// name, one block per host once it finishes, one JSON object per host,
+    /// or GitHub Actions workflow commands
+    /// (default: stream)
+    #[clap(long, value_enum, env = "MULTISSH_OUTPUT", default_value_t = OutputMode::Stream)]
+    output: OutputMode,
+
+    /// Order buffered, json and gha output prints hosts in: as they
+    /// finish, or once all finish by host name, slowest first or failed
+    /// first
+    /// (default: completion)
+    #[clap(long, value_enum, default_value_t)]
+    sort: SortOrder,
+
+    /// Print the output of hosts one after the other in the order they
+    /// were given, each once the one before it finished, while they still
+    /// run in parallel; for transcripts that diff cleanly
+    /// (default: false)
+    #[clap(long, conflicts_with = "sort")]
+    ordered_output: bool,
+
+    /// Rhai script run on the result of every host before it's shown, to
+    /// change its output, add a note or hide it. It gets the variables
+    /// host, stdout and stderr (strings), exit_code and error (() when
+    /// there is none), duration (seconds) and success; assigning to stdout
+    /// or stderr replaces the output shown, assigning a string to note
+    /// shows it with the host, and evaluating to false hides the host. A
+    /// .wasm/.wat file is a module (built with --features wasm) exporting
+    /// memory, alloc(len) and transform(ptr, len), given the same fields as
+    /// JSON and answering any of {"stdout", "stderr", "note", "hide"}. Only
+    /// what is shown and saved with --output-dir changes, not reports,
+    /// history or the exit code
+    /// (e.g. "summarize.rhai")
+    #[clap(long, value_name = "FILE", value_parser = paths::parse)]
+    filter_script: Option<PathBuf>,
+
+    /// Print how many hosts returned each exit code once all hosts finish
+    /// (default: false)
+    #[clap(long)]
+    exit_code_summary: bool,
+
+    /// How to display the remote stderr: merged with stdout (in the order
+    /// the lines arrived in, buffered output too), merged and colored red,
+    /// on its own, or only saved to --output-dir
+    /// (default: merged)
+    #[clap(long, value_enum, default_value_t = StderrMode::Merged)]
+    stderr: StderrMode,
+
+    /// When to use colors: auto disables them when stdout is not a terminal
+    /// or NO_COLOR is set
+    /// (default: auto)
+    #[clap(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// How to decode host output on the terminal: UTF-8 with invalid bytes
+    /// replaced, the bytes as received, or Latin-1 for legacy systems
+    /// (default: utf8-lossy)
+    #[clap(long, value_enum, default_value_t = Encoding::Utf8Lossy)]
+    encoding: Encoding,
+
+    /// Write a report of the run to FILE once all hosts finish; can be
+    /// given multiple times. FORMAT is one of: markdown, html, junit
+    /// (e.g. "--report markdown report.md")
+    #[clap(long, num_args = 2, value_names = ["FORMAT", "FILE"], action = ArgAction::Append)]
+    report: Vec<String>,
+
+    /// Directory to save each host's stdout and stderr to, as
+    /// <host>.stdout and <host>.stderr
+    /// (e.g. "/tmp/multissh-output")
+    #[clap(long, env = "MULTISSH_OUTPUT_DIR", value_parser = paths::parse)]
+    output_dir: Option<PathBuf>,
+
+    /// Don't record the run in the history in ~/.multissh/history.db
+    /// (default: false)
+    #[clap(long, env = "MULTISSH_NO_HISTORY")]
+    no_history: bool,
+
     /// Command to run on target hosts
     /// (e.g. "uname -a")
-    #[clap()]
-    command: String,
+    #[clap(required_unless_present = "argv")]
+    command: Option<String>,
+
+    /// Command given as separate words after `--`. Starting with `exec`,
+    /// the remaining words are run as an argv array without any remote
+    /// shell interpretation; otherwise the words are joined with spaces
+    /// like ssh does, or quoted with --quote
+    /// (e.g. "-- exec systemctl restart nginx")
+    #[clap(last = true, conflicts_with = "command", value_name = "ARGV")]
+    argv: Vec<String>,
 }
 
-struct Config {
-    default_inventory_file: Vec<PathBuf>,
-    default_private_key: Vec<PathBuf>,
-    default_port: u16,
-    default_timeout: u64,
+#[derive(Subcommand)]
+enum Commands {
+    /// Inspect the inventory
+    Inventory {
+        #[command(subcommand)]
+        command: InventoryCommand,
+    },
+    /// Show whether the command of a --detach run is still running on each
+    /// host, or how it exited
+    Status {
+        /// Run id printed by --detach
+        run_id: String,
+    },
+    /// Print the output of a --detach run on each host and exit with its
+    /// exit codes, like a normal run
+    Collect {
+        /// Run id printed by --detach
+        run_id: String,
+    },
+    /// Run a local job queue that runs the jobs given to `multissh submit`
+    Daemon {
+        /// Hosts worked on at once, across all running jobs
+        /// (default: 32)
+        #[clap(long, default_value = "32")]
+        max_parallel: usize,
+        /// Jobs run at once, the others wait in the queue
+        /// (default: 4)
+        #[clap(long, default_value = "4")]
+        max_jobs: usize,
+    },
+    /// Run the daemon with an HTTP API to submit jobs and fetch their
+    /// results as JSON, and a web UI to browse them
+    Serve(ServeArgs),
+    /// Queue the command on the targets with the running daemon
+    Submit {
+        /// Command to run on target hosts
+        /// (e.g. "uname -a")
+        command: String,
+    },
+    /// List the jobs of the daemon
+    Jobs,
+    /// Print the output of a job of the daemon
+    Job {
+        /// Job id printed by `multissh submit`
+        id: u64,
+    },
+    /// Upload a local file or directory to the targets
+    Push {
+        /// Local file, or directory pushed with everything in it
+        #[clap(value_parser = paths::parse)]
+        src: PathBuf,
+        /// Remote path, or remote directory ending with "/" to keep the
+        /// file name; missing directories are created
+        /// (e.g. "/etc/nginx/nginx.conf" or "/tmp/")
+        dest: String,
+        /// Permissions of the remote file, in octal
+        /// (e.g. "0644")
+        #[clap(long)]
+        mode: Option<String>,
+        /// Owner of the remote file, set with sudo unless connecting as root
+        /// (e.g. "root")
+        #[clap(long)]
+        owner: Option<String>,
+        /// Group of the remote file, set with sudo unless connecting as root
+        /// (e.g. "root")
+        #[clap(long)]
+        group: Option<String>,
+        /// Continue interrupted pushes where they stopped, on the hosts
+        /// whose partial file is the start of SRC
+        /// (default: false)
+        #[clap(long)]
+        resume: bool,
+        /// Push large files in this many parts at once, each over a
+        /// connection of its own, for links with high latency
+        /// (default: 1)
+        #[clap(long, value_name = "N", default_value = "1", conflicts_with = "resume")]
+        chunks: u64,
+        /// List the files every host would have created or updated, and
+        /// the bytes that would be sent, without pushing anything
+        /// (default: false)
+        #[clap(long)]
+        dry_run: bool,
+        /// Leave out of a directory the files and directories matching a
+        /// gitignore-style pattern, may be repeated
+        /// (e.g. "*.log" or ".git/")
+        #[clap(long, value_name = "PATTERN", action = ArgAction::Append)]
+        exclude: Vec<String>,
+    },
+    /// Download a file from the targets, saved as DEST/<host>/<file name>,
+    /// or the files matching a glob, saved under DEST/<host>/ with their
+    /// remote paths
+    Fetch {
+        /// Remote file, or glob expanded on the remote hosts
+        /// (e.g. "/var/log/syslog" or "/var/log/app/*.log.1")
+        src: String,
+        /// Local directory
+        /// (default: .)
+        #[clap(default_value = ".", value_parser = paths::parse)]
+        dest: PathBuf,
+    },
+    /// Follow log files on every target at once, like `tail -F`, until
+    /// interrupted
+    Tail {
+        /// Remote files
+        /// (e.g. "/var/log/nginx/access.log")
+        #[clap(required = true)]
+        files: Vec<String>,
+        /// Lines of every file to show before following it
+        /// (default: 10)
+        #[clap(short = 'n', long, default_value = "10")]
+        lines: u32,
+    },
+    /// Grep log files on every target and print the matching lines of all
+    /// hosts as one timeline, ordered by their timestamps
+    Grep {
+        /// Extended regular expression
+        /// (e.g. "error|timeout")
+        pattern: String,
+        /// Remote files
+        /// (e.g. "/var/log/app.log")
+        #[clap(required = true)]
+        files: Vec<String>,
+        /// Only show lines from this time on: a duration ago or a timestamp
+        /// (e.g. "10 min ago", "2h" or "2024-05-01 12:00")
+        #[clap(long)]
+        since: Option<String>,
+        /// Ignore case
+        #[clap(long)]
+        ignore_case: bool,
+        /// Match the pattern as a plain string
+        #[clap(short = 'F', long)]
+        fixed_strings: bool,
+    },
+    /// Show a summary of every target as one table: disk, memory, load or
+    /// uptime
+    Sys {
+        preset: Preset,
+        /// Column to sort by, numbers largest first
+        /// (default: use% for disk and mem, load/cpu for load, days for uptime)
+        #[clap(long)]
+        sort: Option<String>,
+        /// Sort the other way around
+        #[clap(long)]
+        reverse: bool,
+    },
+    /// Time every step of connecting to each target over a few connections:
+    /// TCP connect, SSH handshake, authentication and running a command
+    Bench {
+        /// Connections to every host
+        #[clap(short = 'n', long, default_value = "5")]
+        iterations: u32,
+        /// Column to sort by, slowest first
+        #[clap(long, default_value = "total")]
+        sort: String,
+        /// Sort the other way around
+        #[clap(long)]
+        reverse: bool,
+    },
+    /// Check from this machine which TCP ports are open on every target,
+    /// shown as a matrix of hosts and ports
+    Portcheck {
+        /// Ports to check, e.g. `443,8443` or `8000-8010`
+        ports: String,
+        /// Seconds to wait for each connection before calling the port
+        /// filtered
+        #[clap(long = "timeout", default_value = "3")]
+        port_timeout: f64,
+        /// Exit with failure unless every port is open on every host
+        #[clap(long)]
+        require_open: bool,
+    },
+    /// Print the shell completion script, e.g. for bash:
+    /// `source <(multissh completions bash)` in ~/.bashrc
+    Completions { shell: Shell },
+    /// Replace this binary with the one of the latest GitHub release, after
+    /// checking its checksum
+    SelfUpdate {
+        /// Only tell whether a newer release exists
+        #[clap(long)]
+        check: bool,
+    },
+    /// Reboot the targets, through sudo unless connecting as root
+    Reboot {
+        /// Wait for every host to be back, and show how long it was down
+        #[clap(long)]
+        wait: bool,
+        /// Seconds to wait for the hosts to be back with --wait
+        /// (default: 600)
+        #[clap(long = "timeout", default_value = "600")]
+        wait_timeout: u64,
+    },
+    /// Install a new key on the targets over password auth (-p or -a),
+    /// then run the command with the key instead of the password
+    BootstrapKey {
+        /// Command to run with the key once it is installed
+        /// (e.g. "uname -a")
+        command: Option<String>,
+        /// Where the keypair is kept for later runs with -k; made there when
+        /// missing
+        /// (default: ~/.multissh/bootstrap_key)
+        #[clap(long, value_name = "FILE", value_parser = paths::parse)]
+        key_file: Option<PathBuf>,
+        /// Make a temporary key instead, taken off the targets and deleted
+        /// once the command is done
+        /// (default: false)
+        #[clap(long, conflicts_with = "key_file")]
+        remove: bool,
+    },
+    /// Wait until every target accepts ssh logins, e.g. after provisioning
+    Wait {
+        /// Seconds to wait for the hosts
+        #[clap(long = "timeout", default_value = "300")]
+        wait_timeout: u64,
+    },
+    /// Run the command of a past run again on the same hosts, with the
+    /// same remote shell and directory
+    Replay {
+        /// Run id from `multissh history list`, or "last"
+        run_id: String,
+        /// Only run on the hosts of the run matching these comma-separated
+        /// patterns: host names or globs, "failed" or "ok" for the hosts
+        /// that failed or succeeded in that run, "!PATTERN" to leave hosts
+        /// out
+        /// (e.g. "failed,!db-*")
+        #[clap(long)]
+        limit: Option<String>,
+    },
+    /// Run the steps of a task file in order: commands, scripts, pushes
+    /// and fetches, each on its own hosts or on the targets
+    Run {
+        /// Task file, YAML: "steps", a list run in order, and "handlers",
+        /// steps run once at the end on the hosts that notified them. A step
+        /// has exactly one of "command", "script" (a local script run with
+        /// sh), "push" ({src, dest, mode, owner, group}) and "fetch" ({src,
+        /// dest}), and optionally: "name"; "group" (inventory group) or
+        /// "targets" (like -t) to run on instead of the command line's
+        /// targets; "on_failure", what the hosts it failed on do: stop
+        /// (default, skip the remaining steps), continue, ignore (continue
+        /// without failing the run) or abort (no further step runs);
+        /// "register: NAME" and "when", an expression over host, vars.NAME
+        /// and earlier results NAME.ok, .failed, .changed, .skipped, .rc,
+        /// .stdout and .stderr with ==, !=, &&, ||, !, parentheses and
+        /// quoted strings; and "notify", handler names to trigger on the
+        /// hosts it changed.
+        /// Local paths are relative to the task file
+        /// (e.g. "deploy.yml")
+        #[clap(value_parser = paths::parse)]
+        file: PathBuf,
+    },
+    /// Browse and search the outputs of past runs
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            default_inventory_file: vec![
-                PathBuf::from("~/.multissh/inventory"),
-                PathBuf::from("~/.config/multissh/inventory"),
-                PathBuf::from("/etc/multissh/inventory"),
-            ],
-            default_private_key: vec![PathBuf::from("~/.ssh/id_rsa")],
-            default_port: 22,
-            default_timeout: 10,
-        }
-    }
+#[derive(Args)]
+struct ServeArgs {
+    /// Address to listen on
+    /// (default: 127.0.0.1:8080)
+    #[clap(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+    /// Address to serve the gRPC API on as well
+    /// (e.g. "127.0.0.1:50051")
+    #[cfg(feature = "grpc")]
+    #[clap(long)]
+    grpc_listen: Option<String>,
+    /// Bearer token every request must carry
+    /// (default: $MULTISSH_API_TOKEN, or one made up and printed at startup)
+    #[clap(long)]
+    token: Option<String>,
+    /// Hosts worked on at once, across all running jobs
+    /// (default: 32)
+    #[clap(long, default_value = "32")]
+    max_parallel: usize,
+    /// Jobs run at once, the others wait in the queue
+    /// (default: 4)
+    #[clap(long, default_value = "4")]
+    max_jobs: usize,
 }
 
-fn read_targets_file(targets_file: &PathBuf) -> Result<Vec<String>> {
-    // Read targets from file
-    if Path::new(targets_file).exists() {
-        let lines = std::fs::read_to_string(targets_file)?;
-        return Ok(lines
-            .lines()
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.starts_with("#"))
-            .collect());
-    }
-    bail!("File not found: {}", targets_file.display());
+#[derive(Subcommand)]
+enum InventoryCommand {
+    /// Check the inventory for syntax errors, duplicate hosts, empty groups,
+    /// undefined children and unknown variables
+    Lint,
+    /// List all group names with their host counts
+    Groups,
+    /// Show the group, children and host hierarchy
+    Tree {
+        /// Indented text tree or a Graphviz DOT graph
+        /// (default: text)
+        #[clap(long, value_enum, default_value_t = TreeFormat::Text)]
+        format: TreeFormat,
+    },
 }
 
-fn read_inventory_file(inventory_file: &PathBuf, group: String) -> Result<Vec<String>> {
-    // Read inventory from file
-    if Path::new(inventory_file).exists() {
-        let lines = std::fs::read_to_string(inventory_file)?;
-        return Ok(lines
-            .lines()
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.starts_with("#"))
-            .collect());
-    }
-    bail!("File not found: {}", inventory_file.display());
+#[derive(Subcommand)]
+enum HistoryCommand {
+    /// List the most recent runs
+    List {
+        /// Number of runs to list
+        /// (default: 20)
+        #[clap(long, default_value = "20")]
+        limit: usize,
+    },
+    /// Find the lines of output of past runs matching QUERY, with the run,
+    /// host and time they were printed at
+    Search {
+        /// Words or phrase to look for
+        /// (e.g. "error: disk full")
+        query: String,
+        /// Use QUERY as an SQLite FTS5 query instead of a phrase
+        /// (e.g. "disk AND (full OR quota)")
+        #[clap(long)]
+        raw: bool,
+        /// Maximum number of matching lines to print
+        /// (default: 100)
+        #[clap(long, default_value = "100")]
+        limit: usize,
+    },
 }
 
-trait OptionExt<T> {
-    fn to_int(&self) -> i32;
+/// Keys used to decrypt encrypted inventories
+fn inventory_keys(cli: &Cli) -> Keys {
+    Keys::new(cli.inventory_key.clone())
 }
 
-impl<T> OptionExt<T> for Option<T> {
-    fn to_int(&self) -> i32 {
-        match self {
-            Some(_) => 1,
-            None => 0,
-        }
+/// Paths of the inventories: every -i/--inventory-file, or the first default
+/// inventory that exists
+fn inventory_paths(cli: &Cli) -> Result<Vec<PathBuf>> {
+    if !cli.inventory_file.is_empty() {
+        return Ok(cli.inventory_file.clone());
+    }
+    // Not an `env` of -i: a -i on the command line requires -g or --tags
+    if let Some(path) = std::env::var_os("MULTISSH_INVENTORY").filter(|p| !p.is_empty()) {
+        return Ok(vec![paths::expand(Path::new(&path))]);
+    }
+    let config = Config::default();
+    match config.default_inventory_file.iter().find(|p| p.exists()) {
+        Some(path) => Ok(vec![path.clone()]),
+        None => bail!(
+            "No inventory found, use -i/--inventory-file (looked in: {})",
+            config
+                .default_inventory_file
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
     }
 }
 
-#[allow(dead_code)]
-fn get_targets(cli: &Cli) -> Result<Vec<String>> {
+fn get_targets(cli: &Cli) -> Result<Vec<Target>> {
     // If no target options were used, return an error
-    // If more than one target option was used, return an error
-    // If --targets was used, just return the targets as a vector of strings
-    // If --targets-file was used, read the targets from the file. If -f is an empty string, use the default file path
-    // If --inventory-file was used, read the inventory file and get the targets from the provided --inventory-group
+    // If --targets was used, add the targets from the comma-separated list
+    // If --targets-file was used, add the targets read from the file
+    // If --inventory-group was used, read the inventory file and add the targets from the provided group
+    // Targets from multiple options are combined, duplicates are removed later
 
     // Check if one of the target options was used
-    if cli.targets.is_none() && cli.targets_file.is_none() && cli.inventory_group.is_none() {
-        bail!("One of -t/--targets, -f/--targets-file, or -i/--inventory-file is required");
+    if cli.targets.is_none()
+        && cli.targets_file.is_none()
+        && cli.inventory_group.is_none()
+        && cli.tags.is_none()
+    {
+        bail!("One of -t/--targets, -f/--targets-file, -g/--inventory-group, --tags or --serial-groups is required");
     }
-
-    // Check if more than one target option was used
-    if cli.targets.to_int() + cli.targets_file.to_int() + cli.inventory_group.to_int() > 1 {
-        bail!("Only one of -t/--targets, -f/--targets-file, or -i/--inventory-file can be used");
+    if !cli.inventory_file.is_empty() && cli.inventory_group.is_none() && cli.tags.is_none() {
+        bail!("-g/--inventory-group or --tags is required when -i/--inventory-file is used");
     }
 
+    let config = Config::load()?;
+    let mut targets = Vec::new();
+
     // --targets was used
-    // just return the targets as a vector of strings
-    if let Some(targets) = &cli.targets {
-        return Ok(targets.split(',').map(|s| s.to_string()).collect());
+    // add the targets from the comma-separated list, expanding @aliases and ranges
+    if let Some(list) = &cli.targets {
+        let hosts = targets::expand(list, &config.aliases)?;
+        targets.extend(hosts.into_iter().map(Target::new));
     }
 
     // --targets-file was used
-    // read the targets from the file
+    // read the targets from the file, every line may be an @alias or a range too
     if let Some(targets_file) = &cli.targets_file {
-        return match read_targets_file(targets_file) {
-            Ok(targets) => Ok(targets),
+        let file_targets = read_targets_file(targets_file).and_then(|lines| {
+            let mut hosts = Vec::new();
+            for line in lines {
+                hosts.extend(targets::expand(&line, &config.aliases)?);
+            }
+            Ok(hosts)
+        });
+        match file_targets {
+            Ok(file_targets) => targets.extend(file_targets.into_iter().map(Target::new)),
             Err(e) => {
                 bail!(
-                    "Failed to use target file {}: {}",
+                    "Failed to use target file {}: {:#}",
                     targets_file.display(),
                     e
                 );
@@ -169,45 +1001,2045 @@ fn get_targets(cli: &Cli) -> Result<Vec<String>> {
         };
     }
 
-    // --inventory-file was used
-    // read the inventory file and get the targets from the provided inventory group
-    if let Some(inventory_file) = &cli.inventory_file {
-        // Read targets from inventory
-        unimplemented!()
+    // --inventory-group and/or --tags were used
+    // read the inventory file and get the targets from the provided inventory group,
+    // or the hosts of the group (or whole inventory) matching the tags
+    if cli.inventory_group.is_some() || cli.tags.is_some() {
+        let inventory = load_inventory(cli)?;
+        let group = cli.inventory_group.as_deref();
+        targets.extend(inventory_targets(cli, &inventory, group)?);
+    }
+
+    targets.retain(|t| !t.host.is_empty());
+    Ok(targets)
+}
+
+/// The inventory to take targets from, refusing one with errors
+fn load_inventory(cli: &Cli) -> Result<Inventory> {
+    let inventory = Inventory::load_all(&inventory_paths(cli)?, &inventory_keys(cli))?;
+    // Mistakes like misspelled variables would otherwise go unnoticed
+    for issue in &inventory.issues {
+        eprintln!("{}", issue);
+    }
+    if inventory
+        .issues
+        .iter()
+        .any(|i| i.severity == Severity::Error)
+    {
+        bail!("Inventory has errors, run `multissh inventory lint` for details");
+    }
+    Ok(inventory)
+}
+
+/// Targets of `group`, or of the whole inventory, matching --tags if given
+fn inventory_targets(cli: &Cli, inventory: &Inventory, group: Option<&str>) -> Result<Vec<Target>> {
+    match (&cli.tags, group) {
+        (Some(tags), _) => {
+            let selector = TagSelector::parse(tags)?;
+            let tagged = inventory.tagged_targets(group, &selector)?;
+            if tagged.is_empty() {
+                match group {
+                    Some(group) => bail!("No hosts of group {} match --tags {}", group, tags),
+                    None => bail!("No inventory hosts match --tags {}", tags),
+                }
+            }
+            Ok(tagged)
+        }
+        (None, Some(group)) => inventory.targets(group),
+        (None, None) => Ok(Vec::new()),
+    }
+}
+
+/// Hosts run together, in a run made of several stages the next one starts
+/// once every host of the previous one finished
+struct Stage {
+    /// Shown when the stage starts, unnamed stages are silent
+    name: Option<String>,
+    targets: Vec<Target>,
+}
+
+impl Stage {
+    fn all(targets: Vec<Target>) -> Vec<Stage> {
+        vec![Stage {
+            name: None,
+            targets,
+        }]
+    }
+}
+
+/// One stage per group of --serial-groups
+fn serial_stages(cli: &Cli, groups: &str, ssh: &SshOptions) -> Result<Vec<Stage>> {
+    let inventory = load_inventory(cli)?;
+    let mut stages: Vec<Stage> = Vec::new();
+    for group in groups.split(',').map(str::trim).filter(|g| !g.is_empty()) {
+        let mut targets = inventory_targets(cli, &inventory, Some(group))?;
+        // Members of several groups only run once, with the first group
+        targets.retain(|t| {
+            !stages
+                .iter()
+                .flat_map(|s| &s.targets)
+                .any(|done| targets::normalize(&done.host) == targets::normalize(&t.host))
+        });
+        for (duplicate, first) in targets::dedupe(&mut targets) {
+            eprintln!(
+                "Warning: skipping duplicate target {} (same as {})",
+                duplicate, first
+            );
+        }
+        check_resolvable(cli, ssh, &mut targets)?;
+        stages.push(Stage {
+            name: Some(format!("group {}", group)),
+            targets,
+        });
+    }
+    if stages.is_empty() {
+        bail!("--serial-groups needs at least one group");
+    }
+    Ok(stages)
+}
+
+/// Shell-quote every word and join them into a single command line
+fn join_words(words: &[String]) -> String {
+    let words: Vec<String> = words.iter().map(|w| ssh::quote(w)).collect();
+    words.join(" ")
+}
+
+/// Pair up the FORMAT and FILE values given to --report
+fn parse_reports(values: &[String]) -> Result<Vec<(ReportFormat, PathBuf)>> {
+    values
+        .chunks(2)
+        .map(|pair| match ReportFormat::from_str(&pair[0], true) {
+            Ok(format) => Ok((format, paths::expand(Path::new(&pair[1])))),
+            Err(_) => bail!("Unknown report format: {}", pair[0]),
+        })
+        .collect()
+}
+
+fn inventory_command(cli: &Cli, command: &InventoryCommand) -> Result<ExitCode> {
+    let paths = inventory_paths(cli)?;
+    let keys = inventory_keys(cli);
+    match command {
+        InventoryCommand::Lint => {
+            // Keep checking the other files when one has syntax errors
+            let mut inventory = Inventory::default();
+            let mut syntax_errors = 0;
+            for file in Inventory::files(&paths)? {
+                match Inventory::load(&file, &keys) {
+                    Ok(file_inventory) => inventory.merge(file_inventory),
+                    Err(e) => {
+                        println!("{}", e);
+                        syntax_errors += 1;
+                    }
+                }
+            }
+            let issues = inventory.lint();
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            let errors = issues.iter().filter(|i| i.severity == Severity::Error);
+            let errors = errors.count() + syntax_errors;
+            let warnings = issues.len() + syntax_errors - errors;
+            eprintln!("{} error(s), {} warning(s)", errors, warnings);
+            if errors > 0 {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        InventoryCommand::Groups => {
+            let inventory = Inventory::load_all(&paths, &keys)?;
+            let width = inventory.groups.iter().map(|g| g.name.len()).max();
+            for group in &inventory.groups {
+                let hosts = inventory.group_hosts(&group.name)?.len();
+                let noun = if hosts == 1 { "host" } else { "hosts" };
+                print!(
+                    "{:width$}  {:>4} {}",
+                    group.name,
+                    hosts,
+                    noun,
+                    width = width.unwrap_or(0)
+                );
+                if !group.children.is_empty() {
+                    print!(" (children: {})", group.children.join(", "));
+                }
+                println!();
+            }
+        }
+        InventoryCommand::Tree { format } => {
+            let inventory = Inventory::load_all(&paths, &keys)?;
+            print!("{}", inventory.tree(*format));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Targets given on the command line, without duplicates and names that
+/// don't resolve
+fn prepare_targets(cli: &Cli, ssh: &SshOptions) -> Result<Vec<Target>> {
+    let mut targets = get_targets(cli)?;
+
+    // Never run the command twice on one machine
+    for (duplicate, first) in targets::dedupe(&mut targets) {
+        eprintln!(
+            "Warning: skipping duplicate target {} (same as {})",
+            duplicate, first
+        );
+    }
+
+    check_resolvable(cli, ssh, &mut targets)?;
+    warn_insecure(cli, ssh, &targets);
+    Ok(targets)
+}
+
+/// Make it hard to miss that root on the targets can use the local agent,
+/// or that passwords and output cross the network in clear text
+fn warn_insecure(cli: &Cli, ssh: &SshOptions, targets: &[Target]) {
+    let count = |insecure: &dyn Fn(&SshOptions) -> bool| {
+        let hosts = targets
+            .iter()
+            .filter(|t| ssh.with_vars(&t.vars).is_ok_and(|ssh| insecure(&ssh)))
+            .count();
+        let noun = if hosts == 1 { "host" } else { "hosts" };
+        (hosts, noun)
+    };
+    let mut warnings = Vec::new();
+    if let (hosts @ 1.., noun) = count(&|ssh| ssh.forward_agent) {
+        warnings.push(format!(
+            "Warning: forwarding the local SSH agent to {} {}: anyone with root on them \
+             can use your keys while the command runs",
+            hosts, noun
+        ));
+    }
+    if let (hosts @ 1.., noun) = count(&|ssh| ssh.transport == Transport::Telnet) {
+        warnings.push(format!(
+            "Warning: reaching {} {} over telnet: the password and all output cross \
+             the network in clear text",
+            hosts, noun
+        ));
+    }
+    for warning in warnings {
+        if cli.color.enabled() {
+            eprintln!("\x1b[1;33m{}\x1b[0m", warning);
+        } else {
+            eprintln!("{}", warning);
+        }
+    }
+}
+
+/// Find bogus names before touching any host, and drop targets that are the
+/// same machine as an earlier one
+fn check_resolvable(cli: &Cli, ssh: &SshOptions, targets: &mut Vec<Target>) -> Result<()> {
+    let resolved = dns::resolve_all(targets, ssh);
+    let unresolvable = dns::unresolvable(&resolved);
+    if !unresolvable.is_empty() {
+        eprintln!("Could not resolve {} target(s):", unresolvable.len());
+        for (host, reason) in &unresolvable {
+            eprintln!("  {}: {}", host, reason);
+        }
+        if !cli.skip_unresolvable {
+            bail!("Aborting before connecting to any host (use --skip-unresolvable to run against the rest)");
+        }
+        targets.retain(|t| !unresolvable.iter().any(|(host, _)| *host == t.host));
+    }
+    for (duplicate, first) in dns::same_address(&resolved) {
+        eprintln!(
+            "Warning: skipping duplicate target {} (same address, port and user as {})",
+            duplicate, first
+        );
+        targets.retain(|t| t.host != duplicate);
+    }
+    Ok(())
+}
+
+/// SSH options given on the command line, asking for the password if needed
+fn ssh_options(cli: &Cli) -> Result<SshOptions> {
+    let password = match (&cli.password, cli.ask_password) {
+        (Some(password), _) => Some(password.clone()),
+        (None, true) => Some(rpassword::prompt_password("Password: ")?),
+        (None, false) => None,
+    };
+    for option in &cli.ssh_options {
+        let valid = option.split_once(['=', ' ']).is_some_and(|(key, _)| {
+            !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+        if !valid {
+            bail!("Invalid ssh option {:?}, expected KEY=VALUE", option);
+        }
+    }
+    let config = Config::load()?;
+    let options = SshOptions {
+        user: cli.user.clone(),
+        password,
+        private_key: cli.private_key.clone(),
+        port: cli.port.unwrap_or(22),
+        connect_timeout: cli.timeout.unwrap_or(10),
+        exec_timeout: cli.exec_timeout,
+        compress: cli.compress,
+        keepalive_interval: cli.keepalive_interval,
+        keepalive_count: cli.keepalive_count,
+        chdir: cli.chdir.clone(),
+        forward_agent: cli.forward_agent,
+        proxy_command: cli.proxy_command.clone(),
+        jump_hosts: match &cli.jump_host {
+            Some(hosts) => jump_hosts(hosts)?,
+            None => Vec::new(),
+        },
+        jump_user: None,
+        jump_port: None,
+        jump_private_key: None,
+        tunnel: if cli.happy_eyeballs {
+            Some(Tunnel::Direct)
+        } else {
+            cli.socks5.clone().or_else(|| cli.http_proxy.clone())
+        },
+        address_family: match (cli.ipv4, cli.ipv6) {
+            (true, _) => AddressFamily::Inet,
+            (false, true) => AddressFamily::Inet6,
+            (false, false) => AddressFamily::Any,
+        },
+        bind_address: cli.bind_address,
+        ciphers: cli.ciphers.clone().or(config.ciphers),
+        kex_algorithms: cli.kex.clone().or(config.kex),
+        macs: cli.macs.clone().or(config.macs),
+        host_key_algorithms: cli
+            .host_key_algorithms
+            .clone()
+            .or(config.host_key_algorithms),
+        legacy_crypto: cli.legacy_crypto,
+        agent_socket: match &cli.agent_socket {
+            Some(socket) => Some(socket.clone()),
+            None => config.agent_socket,
+        },
+        transport: Transport::Ssh,
+        extra_options: cli.ssh_options.clone(),
+        lang: match &cli.lang {
+            Some(lang) => Some(lang.clone()),
+            None => config.lang,
+        },
+        shell: match (&cli.shell, cli.login, cli.no_shell) {
+            (Some(shell), _, _) => RemoteShell::Custom(shell.clone()),
+            (None, true, _) => RemoteShell::Login,
+            (None, false, true) => RemoteShell::None,
+            (None, false, false) => RemoteShell::Default,
+        },
+        verbose: cli.verbose,
+        debug: false,
+    };
+    options.check_algorithms()?;
+    Ok(options)
+}
+
+/// The hops of `-J`, in the order they are connected through
+fn jump_hosts(hosts: &str) -> Result<Vec<String>> {
+    let hops = ssh::jump_hosts(hosts)?;
+    if hops.is_empty() {
+        bail!("-J/--jump-host needs at least one host");
+    }
+    Ok(hops)
+}
+
+/// Hosts worked on at once in a run on `targets` targets
+fn parallel_hosts(cli: &Cli, targets: usize) -> usize {
+    let hosts = match cli.max_parallel {
+        Some(max_parallel) => max_parallel.hosts(targets),
+        None => rayon::current_num_threads(),
+    };
+    hosts.min(targets)
+}
+
+fn run_options(cli: &Cli) -> RunOptions {
+    RunOptions {
+        connect_retries: cli.connect_retries,
+        connect_backoff: Duration::from_secs_f64(cli.connect_backoff),
+        precheck: cli.precheck.clone(),
+        stdin: None,
+        max_parallel: cli.max_parallel,
+        spill: Some(Spill::new(cli.spill_threshold.0 as usize)),
+        strip_banner: cli.strip_banner,
+        device: cli.device_mode.then(|| DeviceOptions {
+            prompt: cli.prompt.clone(),
+            setup: cli.disable_paging.clone(),
+            timeout: Duration::from_secs_f64(cli.prompt_timeout),
+        }),
+        expect: cli.expect_script.clone(),
+        scp: None,
+        bwlimit: None,
+        resume: None,
+        stdin_range: None,
+        deadline: cli.deadline_at,
+        abort: None,
+        #[cfg(feature = "mock")]
+        mock: None,
+    }
+}
+
+/// Read the script of --expect-script, so mistakes in it are reported
+/// before connecting anywhere
+fn load_expect_script(path: &str) -> Result<Script, String> {
+    Script::load(&paths::expand(Path::new(path))).map_err(|e| format!("{:#}", e))
+}
+
+/// Run `command` on the targets of every stage in turn, writing the output,
+/// reports and summaries asked for on the command line. `label` is the
+/// command as shown in reports.
+fn run_and_report(
+    cli: &Cli,
+    stages: &[Stage],
+    command: &str,
+    label: &str,
+    ssh: &SshOptions,
+) -> Result<ExitCode> {
+    if cli.stderr == StderrMode::Separate && cli.output_dir.is_none() {
+        bail!("--stderr separate requires --output-dir");
+    }
+    if cli.sort != SortOrder::Completion && cli.output == OutputMode::Stream {
+        bail!("--sort needs --output buffered, json or gha");
+    }
+    if cli.filter_script.is_some() && cli.output == OutputMode::Stream {
+        bail!("--filter-script needs --output buffered, json or gha");
+    }
+    let reports = parse_reports(&cli.report)?;
+
+    let targets: Vec<Target> = stages.iter().flat_map(|s| s.targets.clone()).collect();
+    let largest = stages.iter().map(|s| s.targets.len()).max().unwrap_or(0);
+    limits::check_open_files(parallel_hosts(cli, largest), true)?;
+    let options = OutputOptions {
+        mode: cli.output,
+        stderr: cli.stderr,
+        prefix_width: targets.iter().map(|t| t.host.len()).max().unwrap_or(0),
+        output_dir: cli.output_dir.clone(),
+        color: cli.color.enabled(),
+        host_colors: false,
+        max_lines: cli.max_output_lines,
+        max_bytes: cli.max_output_bytes.map(|size| size.0),
+        hex: cli.hex,
+        encoding: cli.encoding,
+        sort: cli.sort,
+        order: cli
+            .ordered_output
+            .then(|| targets.iter().map(|t| t.host.clone()).collect()),
+        heartbeat: cli
+            .heartbeat
+            .filter(|every| !every.0.is_zero() && !io::stdout().is_terminal())
+            .map(|every| Arc::new(Heartbeat::start(targets.len(), every.0))),
+        progress: match &cli.progress_events {
+            Some(target) => Some(Arc::new(ProgressEvents::open(target)?)),
+            None => None,
+        },
+        record: match &cli.record {
+            Some(dir) => Some(Arc::new(Recorder::new(dir, command, ssh)?)),
+            None => None,
+        },
+        filter: match &cli.filter_script {
+            Some(path) => Some(Arc::new(FilterScript::load(path)?)),
+            None => None,
+        },
+    };
+    let started = Local::now();
+    let start = Instant::now();
+    let mut results = Vec::new();
+    for (n, stage) in stages.iter().enumerate() {
+        if let Some(name) = &stage.name {
+            let noun = if stage.targets.len() == 1 {
+                "host"
+            } else {
+                "hosts"
+            };
+            eprintln!("==> {} ({} {})", name, stage.targets.len(), noun);
+        }
+        // A writer per stage, so its output is all written before the next
+        // stage is announced
+        let writer = OutputWriter::spawn(options.clone())?;
+        let stage_results = runner::run(
+            &stage.targets,
+            command,
+            ssh,
+            &run_options(cli),
+            &writer.sender(),
+        );
+        match writer.finish() {
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+        let failed = stage_results.iter().filter(|r| r.failed()).count();
+        results.extend(stage_results);
+        let Some(threshold) = cli.group_failure_threshold else {
+            continue;
+        };
+        let rest: Vec<&str> = stages[n + 1..]
+            .iter()
+            .filter_map(|s| s.name.as_deref())
+            .collect();
+        if threshold.exceeded(failed, stage.targets.len()) && !rest.is_empty() {
+            eprintln!(
+                "{} of {} hosts failed in {}, more than --group-failure-threshold {}; not running {}",
+                failed,
+                stage.targets.len(),
+                stage.name.as_deref().unwrap_or("the run"),
+                threshold,
+                rest.join(", ")
+            );
+            break;
+        }
+    }
+    if let Some(heartbeat) = &options.heartbeat {
+        heartbeat.finish();
+    }
+    if let Some(progress) = &options.progress {
+        progress.summary(&results, start.elapsed());
+    }
+    let targets = &targets[..];
+    if !cli.no_history {
+        // The run already happened, a broken history shouldn't fail it
+        let recorded = History::open().and_then(|mut history| {
+            history.record(command, ssh, started, start.elapsed(), targets, &results)
+        });
+        if let Err(e) = recorded {
+            eprintln!("Failed to record the run in the history: {:#}", e);
+        }
+    }
+
+    let report = RunReport {
+        command: label,
+        started,
+        duration: start.elapsed(),
+        results: &results,
+    };
+    for (format, path) in &reports {
+        report.write(*format, path)?;
+    }
+    if cli.output == OutputMode::Gha {
+        if let Some(path) = std::env::var_os("GITHUB_STEP_SUMMARY") {
+            let summary = format!("## multissh\n\n{}", report.markdown_summary());
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(summary.as_bytes()))
+                .with_context(|| format!("Failed to write step summary {:?}", path))?;
+        }
+    }
+
+    if cli.exit_code_summary {
+        // Keep stdout parseable when printing JSON
+        if cli.output == OutputMode::Json {
+            eprint!("{}", exit_code_summary(&results));
+        } else {
+            print!("{}", exit_code_summary(&results));
+        }
+    }
+
+    let cut = results.iter().filter(|r| r.cut_by_deadline()).count();
+    if cut > 0 {
+        let noun = if cut == 1 { "host" } else { "hosts" };
+        eprintln!(
+            "--deadline {} passed, {} {} not attempted or stopped",
+            wait::human_duration(cli.deadline.map(|d| d.0).unwrap_or_default()),
+            cut,
+            noun
+        );
+        return Ok(ExitCode::from(DEADLINE_EXIT_CODE));
+    }
+    if !results.iter().any(|r| r.failed()) {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+/// Exit code of runs the --deadline cut short, as for timeout(1)
+const DEADLINE_EXIT_CODE: u8 = 124;
+
+/// Start `command` in the background on every target, see [`DetachedRun`]
+fn detach(cli: &Cli, targets: &[Target], command: &str, ssh: &SshOptions) -> Result<ExitCode> {
+    let mut run = DetachedRun::new(command);
+    let launch = run.launch_command(&ssh.remote_command(command)?);
+    // The launch command already carries --chdir, --lang and the shell
+    // settings
+    let ssh = SshOptions {
+        chdir: None,
+        lang: None,
+        shell: RemoteShell::Default,
+        ..ssh.clone()
+    };
+    let (tx, _) = mpsc::channel();
+    // The launch is a helper of multissh, not the command
+    let options = RunOptions {
+        strip_banner: false,
+        ..run_options(cli)
+    };
+    let results = runner::run(targets, &launch, &ssh, &options, &tx);
+
+    let width = targets.iter().map(|t| t.host.len()).max().unwrap_or(0);
+    for (target, result) in targets.iter().zip(&results) {
+        // The last line, after whatever banner the host printed
+        let stdout = String::from_utf8_lossy(&result.stdout);
+        let pid = stdout.lines().rev().find(|l| !l.trim().is_empty());
+        let pid = pid.and_then(|pid| pid.trim().parse().ok());
+        let error = match pid {
+            Some(_) if result.success() => None,
+            _ => Some(failure_reason(result)),
+        };
+        match &error {
+            Some(e) => println!("{:width$}  failed to start: {}", target.host, e),
+            None => println!("{:width$}  started (pid {})", target.host, pid.unwrap_or(0)),
+        }
+        run.hosts.push(DetachedHost {
+            host: target.host.clone(),
+            vars: target.vars.clone(),
+            pid: if error.is_none() { pid } else { None },
+            error,
+        });
+    }
+    run.save()?;
+    println!("Run id: {}", run.id);
+    println!(
+        "Check on it with `multissh status {0}`, get the output with `multissh collect {0}`",
+        run.id
+    );
+
+    if run.hosts.iter().all(|h| h.error.is_none()) {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+/// SSH options for reaching the hosts of a detached run again
+fn detached_ssh_options(cli: &Cli) -> Result<SshOptions> {
+    Ok(SshOptions {
+        chdir: None,
+        lang: None,
+        shell: RemoteShell::Default,
+        ..ssh_options(cli)?
+    })
+}
+
+fn status_command(cli: &Cli, run_id: &str) -> Result<ExitCode> {
+    let run = DetachedRun::load(run_id)?;
+    let ssh = detached_ssh_options(cli)?;
+    println!(
+        "{} (started {})",
+        run.command,
+        run.started.format("%Y-%m-%d %H:%M:%S")
+    );
+
+    let targets: Vec<Target> = run
+        .hosts
+        .iter()
+        .filter(|h| h.pid.is_some())
+        .map(|h| h.target())
+        .collect();
+    let (tx, _) = mpsc::channel();
+    let results = runner::run(
+        &targets,
+        &run.status_command(),
+        &ssh,
+        &run_options(cli),
+        &tx,
+    );
+
+    let width = run.hosts.iter().map(|h| h.host.len()).max().unwrap_or(0);
+    for host in &run.hosts {
+        let status = match results.iter().find(|r| r.host == host.host) {
+            Some(result) if result.success() => {
+                String::from_utf8_lossy(&result.stdout).trim().to_string()
+            }
+            Some(result) => match &result.error {
+                Some(e) => format!("unknown: {}", e),
+                None => format!("unknown: {}", exit_label(result)),
+            },
+            None => format!(
+                "not started: {}",
+                host.error.as_deref().unwrap_or("no pid recorded")
+            ),
+        };
+        println!("{:width$}  {}", host.host, status);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn serve_command(cli: &Cli, args: &ServeArgs) -> Result<ExitCode> {
+    limits::check_open_files(args.max_parallel, true)?;
+    // Load the inventory first, it may ask for a passphrase
+    let inventory = match inventory_paths(cli) {
+        Ok(paths) => Some(Inventory::load_all(&paths, &inventory_keys(cli))?),
+        Err(_) => None,
+    };
+    let options = Arc::new(ApiOptions {
+        listen: args.listen.clone(),
+        token: match args
+            .token
+            .clone()
+            .or_else(|| std::env::var("MULTISSH_API_TOKEN").ok())
+        {
+            Some(token) => token,
+            None => {
+                let token = api::generate_token()?;
+                eprintln!("API token: {}", token);
+                token
+            }
+        },
+        inventory,
+        aliases: Config::load()?.aliases,
+        ssh: ssh_options(cli)?,
+        run: run_options(cli),
+    });
+    let daemon = daemon::start(DaemonOptions {
+        max_parallel: args.max_parallel,
+        max_jobs: args.max_jobs,
+    })?;
+    #[cfg(feature = "grpc")]
+    if let Some(listen) = &args.grpc_listen {
+        multissh_rs::grpc::start(listen, daemon.clone(), options.clone())?;
+    }
+    api::serve(&daemon, &options)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn submit_command(cli: &Cli, command: &str) -> Result<ExitCode> {
+    let ssh = ssh_options(cli)?;
+    ssh.remote_command(command)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let request = Request::Submit {
+        command: command.to_string(),
+        targets,
+        ssh: Box::new(ssh),
+        options: Box::new(run_options(cli)),
+    };
+    match daemon::request(&request)? {
+        Response::Submitted { id } => {
+            println!(
+                "Job {} queued, follow it with `multissh jobs` and `multissh job {}`",
+                id, id
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+        response => bail!("Unexpected response from the daemon: {:?}", response),
+    }
+}
+
+fn jobs_command() -> Result<ExitCode> {
+    let Response::Jobs { jobs } = daemon::request(&Request::Jobs)? else {
+        bail!("Unexpected response from the daemon");
+    };
+    for job in &jobs {
+        let state = match job.state {
+            JobState::Done if job.failed > 0 => format!("done, {} failed", job.failed),
+            state => state.to_string(),
+        };
+        let noun = if job.hosts == 1 { "host" } else { "hosts" };
+        println!(
+            "{:>4}  {}  {:<16}  {:>4} {:<5}  {}",
+            job.id,
+            job.submitted.format("%Y-%m-%d %H:%M:%S"),
+            state,
+            job.hosts,
+            noun,
+            job.command
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// SSH options for file transfers, which need the remote shell to run
+/// `cat` and friends. The SCP protocol is binary, the locale is left alone.
+fn transfer_ssh_options(cli: &Cli) -> Result<SshOptions> {
+    // `requires` is not checked with a subcommand
+    if cli.follow_symlinks && !cli.preserve {
+        bail!("--follow-symlinks requires --preserve");
     }
+    let ssh = ssh_options(cli)?;
+    Ok(SshOptions {
+        shell: RemoteShell::Default,
+        lang: match cli.transfer_protocol {
+            TransferProtocol::Cat => ssh.lang.clone(),
+            TransferProtocol::Scp => None,
+        },
+        ..ssh
+    })
+}
 
-    bail!("One of -t/--targets, -f/--targets-file, or -i/--inventory-file is required");
+/// Run options of file transfers, throttled as asked
+fn transfer_run_options(cli: &Cli) -> RunOptions {
+    let bwlimit = match (cli.bwlimit, cli.bwlimit_total) {
+        (None, None) => None,
+        (per_host, total) => Some(BwLimit::new(per_host.map(|s| s.0), total.map(|s| s.0))),
+    };
+    RunOptions {
+        bwlimit,
+        strip_banner: false,
+        ..run_options(cli)
+    }
+}
+
+/// Run options of a transfer over SCP, with nothing printed around the
+/// protocol
+fn scp_run_options(cli: &Cli, transfer: scp::Transfer) -> RunOptions {
+    RunOptions {
+        scp: Some(transfer),
+        ..transfer_run_options(cli)
+    }
 }
 
-fn main() -> Result<()> {
-    // let msgs = vec!["Hello", "World", "from", "Rayon"];
-    // msgs.par_iter().for_each(|msg| println!("{}", msg));
-    let cli = Cli::parse();
-    let targets = get_targets(&cli)?;
-    targets.par_iter().for_each(|target| {
-        println!("Running command on target: {}", target);
+fn push_command(
+    cli: &Cli,
+    src: &Path,
+    dest: &str,
+    attrs: &FileAttrs,
+    resume: bool,
+    chunks: u64,
+    excludes: &Excludes,
+) -> Result<ExitCode> {
+    let is_link = std::fs::symlink_metadata(src)
+        .with_context(|| format!("Failed to read {}", src.display()))?
+        .file_type()
+        .is_symlink();
+    if is_link && cli.preserve && !cli.follow_symlinks {
+        return push_link(cli, src, dest);
+    }
+    if src.is_dir() {
+        if resume || chunks > 1 || cli.transfer_protocol == TransferProtocol::Scp {
+            bail!("Directories are pushed whole, without --resume, --chunks or scp");
+        }
+        if attrs.mode.is_some() || attrs.owner.is_some() || attrs.group.is_some() {
+            bail!("--mode, --owner and --group only apply to files");
+        }
+        return push_dir(cli, src, dest, excludes);
+    }
+    let size = std::fs::metadata(src)
+        .with_context(|| format!("Failed to read {}", src.display()))?
+        .len();
+    let dest = transfer::push_dest(src, dest)?;
+    let attrs = &match cli.preserve {
+        true => attrs.clone().preserved(src)?,
+        false => attrs.clone(),
+    };
+    if resume && cli.transfer_protocol == TransferProtocol::Scp {
+        bail!("--resume needs --transfer-protocol cat");
+    }
+    let ranges = transfer::chunk_ranges(size, chunks);
+    if ranges.len() > 1 && cli.transfer_protocol == TransferProtocol::Scp {
+        bail!("--chunks needs --transfer-protocol cat");
+    }
+    let (command, mut options) = match cli.transfer_protocol {
+        TransferProtocol::Cat => (
+            transfer::push_command(&dest, attrs, resume),
+            RunOptions {
+                stdin: Some(src.to_path_buf()),
+                ..transfer_run_options(cli)
+            },
+        ),
+        TransferProtocol::Scp => (
+            scp::push_command(&dest, cli.preserve),
+            scp_run_options(cli, transfer::scp_push(src, &dest, attrs)?),
+        ),
+    };
+    let checksum = match cli.verify {
+        Verify::Sha256 => transfer::sha256_file(src)?,
+        Verify::None => String::new(),
+    };
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let (tx, _) = mpsc::channel();
+    if resume {
+        let partial = transfer::partial_command(&dest);
+        let checks = runner::run(&targets, &partial, &ssh, &run_options(cli), &tx);
+        options.resume = Some(transfer::resume_offsets(src, &checks)?);
+    }
+    let mut results = match ranges.len() {
+        0 | 1 => runner::run(&targets, &command, &ssh, &options, &tx),
+        _ => push_chunked(cli, &targets, &ssh, &options, &dest, attrs, &ranges),
+    };
+    let verify = transfer::checksum_command(&dest)?;
+    verify_transfer(cli, &targets, &ssh, &mut results, &verify, |_| {
+        Ok(vec![(dest.clone(), checksum.clone())])
+    })?;
+    let resumed = options.resume.unwrap_or_default();
+    let ok = transfer::print_results(&results, |r| {
+        let offset = resumed.get(&r.host).copied().unwrap_or(0);
+        Ok(transfer::pushed(&dest, size, offset, r))
     });
+    Ok(if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
 
+fn fetch_command(cli: &Cli, src: &str, dest: &Path) -> Result<ExitCode> {
+    let ssh = transfer_ssh_options(cli)?;
+    // Refuse paths without a file name before connecting anywhere
+    let (command, options) = match cli.transfer_protocol {
+        TransferProtocol::Cat => (
+            transfer::fetch_command(src, cli.preserve, cli.follow_symlinks)?,
+            transfer_run_options(cli),
+        ),
+        TransferProtocol::Scp => {
+            let (command, transfer) = transfer::scp_fetch(src, cli.preserve)?;
+            (command, scp_run_options(cli, transfer))
+        }
+    };
+    let targets = prepare_targets(cli, &ssh)?;
+    let (tx, _) = mpsc::channel();
+    let mut results = runner::run(&targets, &command, &ssh, &options, &tx);
+    let keep_links = cli.preserve && !cli.follow_symlinks;
+    let verify = transfer::fetch_checksum_command(src, keep_links)?;
+    verify_transfer(cli, &targets, &ssh, &mut results, &verify, |result| {
+        transfer::fetched_checksums(result, src, cli.preserve)
+    })?;
+    let ok = transfer::print_results(&results, |result| {
+        transfer::fetched(dest, result, src, cli.preserve)
+    });
+    Ok(if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Push the directory `src` and everything in it to `dest`, showing how far
+/// every host got
+fn push_dir(cli: &Cli, src: &Path, dest: &str, excludes: &Excludes) -> Result<ExitCode> {
+    let dest = transfer::push_dest(&src.canonicalize()?, dest)?;
+    let follow = !cli.preserve || cli.follow_symlinks;
+    let checksums = match cli.verify {
+        Verify::Sha256 => transfer::dir_checksums(src, follow, excludes)?
+            .into_iter()
+            .map(|(path, sum, _)| (path, sum))
+            .collect(),
+        Verify::None => Vec::new(),
+    };
+    let archive = transfer::pack_dir(src, follow, excludes)?;
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let options = RunOptions {
+        stdin: Some(archive.path.clone()),
+        ..transfer_run_options(cli)
+    };
+    let (tx, rx) = mpsc::channel();
+    let (hosts, files) = (targets.len(), archive.files);
+    let progress = std::thread::spawn(move || transfer::show_dir_progress(rx, hosts, files));
+    let command = transfer::push_dir_command(&dest, cli.preserve);
+    let mut results = runner::run(&targets, &command, &ssh, &options, &tx);
+    drop(tx);
+    let _ = progress.join();
+    let verify = transfer::dir_checksum_command(&dest);
+    verify_transfer(cli, &targets, &ssh, &mut results, &verify, |_| {
+        Ok(checksums.clone())
+    })?;
+    let ok = transfer::print_results(&results, |_| Ok(transfer::pushed_dir(&dest, &archive)));
+    Ok(if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Show what pushing `src` to `dest` would change on every host
+fn push_dry_run(cli: &Cli, src: &Path, dest: &str, excludes: &Excludes) -> Result<ExitCode> {
+    let is_link = std::fs::symlink_metadata(src)
+        .with_context(|| format!("Failed to read {}", src.display()))?
+        .file_type()
+        .is_symlink();
+    if is_link && cli.preserve && !cli.follow_symlinks {
+        bail!("--dry-run only compares files, not symlinks");
+    }
+    let dir = src.is_dir();
+    let (dest, local) = match dir {
+        true => {
+            let dest = transfer::push_dest(&src.canonicalize()?, dest)?;
+            let follow = !cli.preserve || cli.follow_symlinks;
+            (dest, transfer::dir_checksums(src, follow, excludes)?)
+        }
+        false => {
+            let dest = transfer::push_dest(src, dest)?;
+            let size = std::fs::metadata(src)?.len();
+            let local = vec![(dest.clone(), transfer::sha256_file(src)?, size)];
+            (dest, local)
+        }
+    };
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let (tx, _) = mpsc::channel();
+    let command = transfer::plan_command(&dest, dir);
+    let results = runner::run(&targets, &command, &ssh, &run_options(cli), &tx);
+    println!("Would push {} to {}", src.display(), dest);
+    let ok = transfer::print_results(&results, |check| transfer::planned(check, &local));
+    Ok(if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Recreate the symlink `src` at `dest`, for --preserve
+fn push_link(cli: &Cli, src: &Path, dest: &str) -> Result<ExitCode> {
+    let dest = transfer::push_dest(src, dest)?;
+    let command = transfer::push_link_command(src, &dest)?;
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let (tx, _) = mpsc::channel();
+    let results = runner::run(&targets, &command, &ssh, &run_options(cli), &tx);
+    let ok = transfer::print_results(&results, |_| Ok(format!("{} (symlink)", dest)));
+    Ok(if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Push the file of `options` to `dest` in `ranges`, each over connections
+/// of its own to the hosts
+fn push_chunked(
+    cli: &Cli,
+    targets: &[Target],
+    ssh: &SshOptions,
+    options: &RunOptions,
+    dest: &str,
+    attrs: &FileAttrs,
+    ranges: &[(u64, u64)],
+) -> Vec<HostResult> {
+    let (prepare, chunk, install) = transfer::chunked_push_commands(dest, attrs);
+    let (tx, _) = mpsc::channel();
+    let mut results = runner::run(targets, &prepare, ssh, &run_options(cli), &tx);
+    let succeeded = |results: &[HostResult]| -> Vec<Target> {
+        targets
+            .iter()
+            .filter(|t| results.iter().any(|r| r.host == t.host && r.success()))
+            .cloned()
+            .collect()
+    };
+    let prepared = succeeded(&results);
+    // As many hosts at once for every chunk as for a push in one piece
+    let hosts = Parallelism::Hosts(parallel_hosts(cli, prepared.len()).max(1));
+    let written: Vec<Vec<HostResult>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = ranges
+            .iter()
+            .map(|&range| {
+                let (command, tx, prepared) = (chunk(range.0), tx.clone(), &prepared);
+                let options = RunOptions {
+                    stdin_range: Some(range),
+                    max_parallel: Some(hosts),
+                    ..options.clone()
+                };
+                scope.spawn(move || runner::run(prepared, &command, ssh, &options, &tx))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_default())
+            .collect()
+    });
+    // A host failed with the first of its chunks that did
+    for result in written.into_iter().flatten() {
+        let slot = results.iter_mut().find(|r| r.host == result.host);
+        if let Some(slot) = slot.filter(|slot| slot.success() && !result.success()) {
+            *slot = result;
+        }
+    }
+    let installed = runner::run(&succeeded(&results), &install, ssh, &run_options(cli), &tx);
+    for result in installed {
+        if let Some(slot) = results.iter_mut().find(|r| r.host == result.host) {
+            *slot = result;
+        }
+    }
+    results
+}
+
+/// Unless --verify none, compare the checksums `command` prints on the
+/// hosts a transfer succeeded on with `expected`, the remote path and
+/// checksum of every file a host should have, and fail the hosts where
+/// they differ
+fn verify_transfer(
+    cli: &Cli,
+    targets: &[Target],
+    ssh: &SshOptions,
+    results: &mut [HostResult],
+    command: &str,
+    expected: impl Fn(&HostResult) -> Result<Vec<(String, String)>>,
+) -> Result<()> {
+    if cli.verify == Verify::None {
+        return Ok(());
+    }
+    let copied: Vec<Target> = targets
+        .iter()
+        .filter(|t| results.iter().any(|r| r.host == t.host && r.success()))
+        .cloned()
+        .collect();
+    if copied.is_empty() {
+        return Ok(());
+    }
+    let (tx, _) = mpsc::channel();
+    let checks = runner::run(&copied, command, ssh, &run_options(cli), &tx);
+    for check in checks {
+        let Some(result) = results.iter_mut().find(|r| r.host == check.host) else {
+            continue;
+        };
+        match expected(result) {
+            Ok(expected) => transfer::verify(result, &check, &expected),
+            Err(e) => result.error = Some(format!("{:#}", e)),
+        }
+    }
     Ok(())
 }
 
+fn tasks_command(cli: &Cli, file: &Path) -> Result<ExitCode> {
+    if cli.stderr == StderrMode::Separate && cli.output_dir.is_none() {
+        bail!("--stderr separate requires --output-dir");
+    }
+    let tasks = TaskFile::load(file)?;
+    let ssh = ssh_options(cli)?;
+    let config = Config::load()?;
+    // Loaded once, and only when some step needs them
+    let inventory = match tasks.steps.iter().any(|s| s.group.is_some()) {
+        true => Some(load_inventory(cli)?),
+        false => None,
+    };
+    let defaults = match tasks
+        .steps
+        .iter()
+        .any(|s| s.group.is_none() && s.targets.is_none())
+    {
+        true => prepare_targets(cli, &ssh)
+            .context("Some steps have no group or targets, they run on the targets")?,
+        false => Vec::new(),
+    };
+    let step_targets = |step: &tasks::Step| -> Result<Vec<Target>> {
+        let mut targets = match (&step.group, &step.targets) {
+            (Some(group), _) => inventory_targets(cli, inventory.as_ref().unwrap(), Some(group))?,
+            (None, Some(list)) => targets::expand(list, &config.aliases)?
+                .into_iter()
+                .map(Target::new)
+                .collect(),
+            (None, None) => return Ok(defaults.clone()),
+        };
+        targets.retain(|t| !t.host.is_empty());
+        targets::dedupe(&mut targets);
+        check_resolvable(cli, &ssh, &mut targets)?;
+        Ok(targets)
+    };
+    let output = OutputOptions {
+        mode: cli.output,
+        stderr: cli.stderr,
+        prefix_width: 0,
+        output_dir: cli.output_dir.clone(),
+        color: cli.color.enabled(),
+        host_colors: false,
+        max_lines: cli.max_output_lines,
+        max_bytes: cli.max_output_bytes.map(|size| size.0),
+        hex: cli.hex,
+        encoding: cli.encoding,
+        sort: cli.sort,
+        order: None,
+        heartbeat: None,
+        progress: None,
+        record: None,
+        filter: None,
+    };
+    let recap = tasks::run(&tasks, step_targets, &ssh, &run_options(cli), &output)?;
+    if !recap.hosts.is_empty() {
+        eprintln!("==> Recap");
+        recap.print();
+    }
+    Ok(if recap.success() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn tail_command(cli: &Cli, files: &[String], lines: u32) -> Result<ExitCode> {
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    // Every host at once, whatever --max-parallel says
+    limits::check_open_files(targets.len(), false)?;
+    // Lines only make sense as they come
+    let writer = OutputWriter::spawn(OutputOptions {
+        mode: OutputMode::Stream,
+        stderr: cli.stderr,
+        prefix_width: targets.iter().map(|t| t.host.len()).max().unwrap_or(0),
+        output_dir: None,
+        color: cli.color.enabled(),
+        host_colors: true,
+        max_lines: None,
+        max_bytes: None,
+        hex: cli.hex,
+        encoding: cli.encoding,
+        sort: SortOrder::Completion,
+        order: None,
+        heartbeat: None,
+        progress: None,
+        record: None,
+        filter: None,
+    })?;
+    let results = runner::follow(
+        &targets,
+        &logs::tail_command(files, lines),
+        &ssh,
+        &run_options(cli),
+        &writer.sender(),
+    );
+    match writer.finish() {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+        _ => {}
+    }
+    Ok(if results.iter().any(|r| r.failed()) {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+fn grep_command(
+    cli: &Cli,
+    pattern: &str,
+    files: &[String],
+    since: Option<&str>,
+    options: &GrepOptions,
+) -> Result<ExitCode> {
+    let since = since
+        .map(|since| logs::parse_since(since, Local::now()))
+        .transpose()?;
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let (tx, _) = mpsc::channel();
+    let results = runner::run(
+        &targets,
+        &logs::grep_command(pattern, files, options),
+        &ssh,
+        &run_options(cli),
+        &tx,
+    );
+    let timeline = logs::timeline(&results, since);
+
+    let writer = OutputWriter::spawn(OutputOptions {
+        mode: OutputMode::Stream,
+        stderr: cli.stderr,
+        prefix_width: targets.iter().map(|t| t.host.len()).max().unwrap_or(0),
+        output_dir: None,
+        color: cli.color.enabled(),
+        host_colors: true,
+        max_lines: None,
+        max_bytes: None,
+        hex: cli.hex,
+        encoding: cli.encoding,
+        sort: SortOrder::Completion,
+        order: None,
+        heartbeat: None,
+        progress: None,
+        record: None,
+        filter: None,
+    })?;
+    let sender = writer.sender();
+    for line in &timeline.lines {
+        let _ = sender.send(Event::Line {
+            host: line.host.clone(),
+            stream: Stream::Stdout,
+            line: line.line.clone().into_bytes(),
+        });
+    }
+    // Only failed hosts print anything when done
+    for result in &results {
+        let _ = sender.send(Event::Done(Box::new(result.clone())));
+    }
+    drop(sender);
+    match writer.finish() {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+        _ => {}
+    }
+    if timeline.untimed > 0 {
+        eprintln!(
+            "Left out {} matching lines without a timestamp that --since could be checked against",
+            timeline.untimed
+        );
+    }
+    Ok(
+        if timeline.lines.is_empty() || results.iter().any(|r| r.failed()) {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        },
+    )
+}
+
+fn sys_command(cli: &Cli, preset: Preset, sort: Option<&str>, reverse: bool) -> Result<ExitCode> {
+    let sort = sort.unwrap_or(preset.default_sort());
+    // Refuse unknown columns before connecting anywhere
+    preset.table(&[]).0.sort(sort, reverse)?;
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let (tx, _) = mpsc::channel();
+    let results = runner::run(&targets, preset.command(), &ssh, &run_options(cli), &tx);
+    let (mut table, unreadable) = preset.table(&results);
+    table.sort(sort, reverse)?;
+    print!("{}", table.render());
+
+    let width = targets.iter().map(|t| t.host.len()).max().unwrap_or(0);
+    for result in results.iter().filter(|r| !r.success()) {
+        eprintln!("{:width$} | error: {}", result.host, failure_reason(result));
+    }
+    for (host, reason) in &unreadable {
+        eprintln!("{:width$} | error: {}", host, reason);
+    }
+    Ok(
+        if unreadable.is_empty() && results.iter().all(|r| r.success()) {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        },
+    )
+}
+
+fn bench_command(cli: &Cli, iterations: u32, sort: &str, reverse: bool) -> Result<ExitCode> {
+    if iterations == 0 {
+        bail!("--iterations must be at least 1");
+    }
+    // Refuse unknown columns before connecting anywhere
+    bench::table(&[]).sort(sort, reverse)?;
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let results = bench::bench_all(&targets, &ssh, iterations);
+    let mut table = bench::table(&results);
+    table.sort(sort, reverse)?;
+    print!("{}", table.render());
+
+    let width = targets.iter().map(|t| t.host.len()).max().unwrap_or(0);
+    for result in &results {
+        // The same reason every time is only worth one line
+        let mut errors = result.errors.clone();
+        errors.dedup();
+        for error in errors {
+            eprintln!("{:width$} | error: {}", result.host, error);
+        }
+    }
+    Ok(if results.iter().all(|r| r.errors.is_empty()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn portcheck_command(cli: &Cli, ports: &str, timeout: f64, require_open: bool) -> Result<ExitCode> {
+    let ports = ports::parse_ports(ports)?;
+    if !timeout.is_finite() || timeout <= 0.0 {
+        bail!("--timeout must be more than 0 seconds");
+    }
+    let ssh = ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let results = ports::check_all(&targets, &ssh, &ports, Duration::from_secs_f64(timeout));
+    print!("{}", ports::render_matrix(&ports, &results));
+
+    let width = targets.iter().map(|t| t.host.len()).max().unwrap_or(0);
+    let errors = ports::errors(&ports, &results);
+    for (host, reason) in &errors {
+        eprintln!("{:width$} | error: {}", host, reason);
+    }
+    let all_open = results.iter().all(|r| {
+        r.ports
+            .as_ref()
+            .is_ok_and(|states| states.iter().all(|s| *s == PortState::Open))
+    });
+    Ok(if errors.is_empty() && (all_open || !require_open) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn self_update_command(check: bool) -> Result<ExitCode> {
+    let current = env!("CARGO_PKG_VERSION");
+    let release = update::latest_release()?;
+    if !update::is_newer(release.version(), current) {
+        println!("multissh {} is up to date", current);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if check {
+        println!(
+            "multissh {} is available (running {})",
+            release.version(),
+            current
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+    let exe = std::env::current_exe().context("Failed to find the running binary")?;
+    let data = update::download_binary(&release)?;
+    update::install(&exe, &data)?;
+    println!(
+        "Updated {} from {} to {}",
+        exe.display(),
+        current,
+        release.version()
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+fn reboot_command(cli: &Cli, wait: bool, timeout: Duration) -> Result<ExitCode> {
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let width = targets.iter().map(|t| t.host.len()).max().unwrap_or(0);
+    let (tx, _) = mpsc::channel();
+    let since = Instant::now();
+    let results = runner::run(&targets, wait::REBOOT_COMMAND, &ssh, &run_options(cli), &tx);
+    let mut boot_ids = HashMap::new();
+    let mut rebooted = Vec::new();
+    for (target, result) in targets.iter().zip(&results) {
+        if result.success() {
+            println!("{:width$} | rebooting", result.host);
+            let boot_id = String::from_utf8_lossy(&result.stdout).trim().to_string();
+            boot_ids.insert(result.host.clone(), boot_id);
+            rebooted.push(target.clone());
+        } else {
+            println!("{:width$} | error: {}", result.host, failure_reason(result));
+        }
+    }
+    if !wait || rebooted.is_empty() {
+        return Ok(if rebooted.len() == targets.len() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    // A host is back once it has a new boot id, or without one once it has
+    // been unreachable and is reachable again
+    let down = Mutex::new(HashSet::new());
+    let is_back = |host: &str, boot_id: Option<&str>| match (boot_id, boot_ids[host].as_str()) {
+        (None, _) => {
+            down.lock().unwrap().insert(host.to_string());
+            false
+        }
+        (Some(boot_id), "") => boot_id.is_empty() && down.lock().unwrap().contains(host),
+        (Some(boot_id), old) => !boot_id.is_empty() && boot_id != old,
+    };
+    let report = |waited: &Waited| match &waited.back {
+        Ok(after) => println!(
+            "{:width$} | back after {}",
+            waited.host,
+            wait::human_duration(*after)
+        ),
+        Err(reason) => println!(
+            "{:width$} | error: not back after {}: {}",
+            waited.host,
+            wait::human_duration(timeout),
+            reason
+        ),
+    };
+    let options = WaitOptions {
+        timeout,
+        interval: Duration::from_secs(5),
+    };
+    let waited = wait::wait_until(
+        &rebooted,
+        &ssh,
+        wait::BOOT_ID_COMMAND,
+        &options,
+        since,
+        is_back,
+        report,
+    );
+    let back = waited.iter().filter(|w| w.back.is_ok()).count();
+    Ok(if back == targets.len() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn bootstrap_key_command(
+    cli: &Cli,
+    command: Option<&str>,
+    key_file: Option<&Path>,
+    remove: bool,
+) -> Result<ExitCode> {
+    let ssh = ssh_options(cli)?;
+    if ssh.password.is_none() {
+        bail!("bootstrap-key installs the key over password auth, use -p/--password or -a/--ask-password");
+    }
+    let targets = prepare_targets(cli, &ssh)?;
+    let key = match (remove, key_file) {
+        (true, _) => Keypair::temporary()?,
+        (false, Some(path)) => Keypair::kept(path)?,
+        (false, None) => Keypair::kept(&config::state_dir()?.join("bootstrap_key"))?,
+    };
+    // authorized_keys is the same whatever the directory, shell and locale
+    let setup = SshOptions {
+        chdir: None,
+        lang: None,
+        shell: RemoteShell::Default,
+        ..ssh.clone()
+    };
+    let mut with_key = SshOptions {
+        password: None,
+        private_key: Some(key.path.clone()),
+        ..ssh.clone()
+    };
+    with_key
+        .extra_options
+        .push("IdentitiesOnly=yes".to_string());
+
+    let width = targets.iter().map(|t| t.host.len()).max().unwrap_or(0);
+    let (tx, _) = mpsc::channel();
+    let results = runner::run(
+        &targets,
+        &key.install_command(),
+        &setup,
+        &run_options(cli),
+        &tx,
+    );
+    let mut installed = Vec::new();
+    for (target, result) in targets.iter().zip(&results) {
+        if result.success() {
+            println!("{:width$} | key installed", result.host);
+            let mut target = target.clone();
+            // The key installed, not the one of the inventory
+            target.vars.remove("private_key");
+            installed.push(target);
+        } else {
+            println!("{:width$} | error: {}", result.host, failure_reason(result));
+        }
+    }
+    let mut code = match installed.len() == targets.len() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    };
+    if installed.is_empty() {
+        return Ok(code);
+    }
+    if let Some(command) = command {
+        let ran = run_and_report(
+            cli,
+            &Stage::all(installed.clone()),
+            command,
+            command,
+            &with_key,
+        )?;
+        if ran != ExitCode::SUCCESS {
+            code = ran;
+        }
+    }
+
+    if !remove {
+        eprintln!(
+            "The key stays installed, use -k {} for later runs",
+            key.path.display()
+        );
+        return Ok(code);
+    }
+    let removal = SshOptions {
+        chdir: None,
+        lang: None,
+        shell: RemoteShell::Default,
+        ..with_key
+    };
+    let results = runner::run(
+        &installed,
+        &key.remove_command(),
+        &removal,
+        &run_options(cli),
+        &tx,
+    );
+    for result in results.iter().filter(|r| !r.success()) {
+        println!(
+            "{:width$} | error: key not removed: {}",
+            result.host,
+            failure_reason(result)
+        );
+        code = ExitCode::FAILURE;
+    }
+    Ok(code)
+}
+
+fn wait_command(cli: &Cli, timeout: Duration) -> Result<ExitCode> {
+    let ssh = transfer_ssh_options(cli)?;
+    let targets = prepare_targets(cli, &ssh)?;
+    let width = targets.iter().map(|t| t.host.len()).max().unwrap_or(0);
+    let report = |waited: &Waited| match &waited.back {
+        Ok(after) => println!(
+            "{:width$} | up after {}",
+            waited.host,
+            wait::human_duration(*after)
+        ),
+        Err(reason) => println!(
+            "{:width$} | error: not up after {}: {}",
+            waited.host,
+            wait::human_duration(timeout),
+            reason
+        ),
+    };
+    let options = WaitOptions {
+        timeout,
+        interval: Duration::from_secs(5),
+    };
+    let waited = wait::wait_until(
+        &targets,
+        &ssh,
+        "true",
+        &options,
+        Instant::now(),
+        |_, out| out.is_some(),
+        report,
+    );
+    Ok(if waited.iter().all(|w| w.back.is_ok()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn replay_command(cli: &Cli, run_id: &str, limit: Option<&str>) -> Result<ExitCode> {
+    let history = History::open()?;
+    let id = match run_id {
+        "last" => history.last_run()?,
+        id => id
+            .parse()
+            .with_context(|| format!("Invalid run id: {}", id))?,
+    };
+    let run = history.run(id)?;
+    drop(history);
+
+    let mut ssh = ssh_options(cli)?;
+    // Settings given again on the command line win over the stored ones
+    if ssh.chdir.is_none() {
+        ssh.chdir = run.chdir.clone();
+    }
+    if ssh.shell == RemoteShell::Default {
+        ssh.shell = run.shell.clone();
+    }
+    ssh.remote_command(&run.command)?;
+
+    let mut targets = run.targets(limit)?;
+    check_resolvable(cli, &ssh, &mut targets)?;
+    eprintln!(
+        "Replaying run {} on {} of its {} hosts: {}",
+        run.id,
+        targets.len(),
+        run.hosts.len(),
+        run.command
+    );
+    run_and_report(cli, &Stage::all(targets), &run.command, &run.command, &ssh)
+}
+
+fn history_command(command: &HistoryCommand) -> Result<ExitCode> {
+    let history = History::open()?;
+    match command {
+        HistoryCommand::List { limit } => {
+            for run in history.runs(*limit)? {
+                let failed = match run.failed {
+                    0 => String::new(),
+                    n => format!("{} failed", n),
+                };
+                let noun = if run.hosts == 1 { "host" } else { "hosts" };
+                println!(
+                    "{:>4}  {}  {:>4} {:<5}  {:<10}  {}",
+                    run.id,
+                    run.started.format("%Y-%m-%d %H:%M:%S"),
+                    run.hosts,
+                    noun,
+                    failed,
+                    run.command
+                );
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        HistoryCommand::Search { query, raw, limit } => {
+            let matches = history.search(query, *raw, *limit)?;
+            let width = matches.iter().map(|m| m.host.len()).max().unwrap_or(0);
+            for m in &matches {
+                println!(
+                    "{}  run {:<4}  {:<width$}  {}:{:<4}  {}",
+                    m.started.format("%Y-%m-%d %H:%M:%S"),
+                    m.run_id,
+                    m.host,
+                    m.stream,
+                    m.number,
+                    m.line
+                );
+            }
+            // Like grep, so it can be used in scripts
+            if matches.is_empty() {
+                Ok(ExitCode::FAILURE)
+            } else {
+                Ok(ExitCode::SUCCESS)
+            }
+        }
+    }
+}
+
+fn job_command(cli: &Cli, id: u64) -> Result<ExitCode> {
+    let Response::Job { job } = daemon::request(&Request::Job { id })? else {
+        bail!("Unexpected response from the daemon");
+    };
+    if job.state != JobState::Done {
+        println!("Job {} is {}: {}", job.id, job.state, job.command);
+        return Ok(ExitCode::SUCCESS);
+    }
+    let writer = OutputWriter::spawn(OutputOptions {
+        mode: cli.output,
+        stderr: cli.stderr,
+        prefix_width: job.results.iter().map(|r| r.host.len()).max().unwrap_or(0),
+        output_dir: cli.output_dir.clone(),
+        color: cli.color.enabled(),
+        host_colors: false,
+        max_lines: cli.max_output_lines,
+        max_bytes: cli.max_output_bytes.map(|size| size.0),
+        hex: cli.hex,
+        encoding: cli.encoding,
+        sort: cli.sort,
+        order: None,
+        heartbeat: None,
+        progress: None,
+        record: None,
+        filter: None,
+    })?;
+    for result in &job.results {
+        output::replay(result, &writer.sender());
+    }
+    match writer.finish() {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+        _ => {}
+    }
+    if cli.exit_code_summary {
+        print!("{}", exit_code_summary(&job.results));
+    }
+    if job.failed == 0 {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+fn collect_command(cli: &Cli, run_id: &str) -> Result<ExitCode> {
+    let run = DetachedRun::load(run_id)?;
+    let ssh = detached_ssh_options(cli)?;
+    for host in run.hosts.iter().filter(|h| h.pid.is_none()) {
+        eprintln!(
+            "Warning: skipping {}, the command wasn't started there",
+            host.host
+        );
+    }
+    let targets: Vec<Target> = run
+        .hosts
+        .iter()
+        .filter(|h| h.pid.is_some())
+        .map(|h| h.target())
+        .collect();
+    run_and_report(
+        cli,
+        &Stage::all(targets),
+        &run.collect_command(),
+        &run.command,
+        &ssh,
+    )
+}
+
+/// Deletes the output spilled to disk during the run when main returns
+struct SpillCleanup;
+
+impl Drop for SpillCleanup {
+    fn drop(&mut self) {
+        Spill::new(0).remove();
+    }
+}
+
+fn main() -> Result<ExitCode> {
+    // ssh runs us as its ProxyCommand with --socks5, --http-proxy and
+    // --happy-eyeballs, first as the password may be set too
+    if let Ok(tunnel) = std::env::var(proxy::PROXY_ENV) {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        // One line on the stderr of ssh, which fails the host with it
+        return Ok(match proxy::serve(&tunnel, &args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                ExitCode::from(255)
+            }
+        });
+    }
+    // and as its askpass helper when a password was given
+    if let Ok(password) = std::env::var(ASKPASS_ENV) {
+        println!("{}", password);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // The completion script calls us back with COMPLETE set
+    CompleteEnv::with_factory(Cli::command)
+        .var(completion::COMPLETE_ENV)
+        .complete();
+
+    let mut cli = Cli::parse();
+    cli.deadline_at = cli.deadline.map(|deadline| Instant::now() + deadline.0);
+    limits::raise_open_files_limit();
+    let _spilled = SpillCleanup;
+    match &cli.subcommand {
+        Some(Commands::Inventory { command }) => return inventory_command(&cli, command),
+        Some(Commands::Status { run_id }) => return status_command(&cli, run_id),
+        Some(Commands::Collect { run_id }) => return collect_command(&cli, run_id),
+        Some(Commands::Daemon {
+            max_parallel,
+            max_jobs,
+        }) => {
+            limits::check_open_files(*max_parallel, true)?;
+            daemon::serve(DaemonOptions {
+                max_parallel: *max_parallel,
+                max_jobs: *max_jobs,
+            })?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Commands::Serve(args)) => return serve_command(&cli, args),
+        Some(Commands::Submit { command }) => return submit_command(&cli, command),
+        Some(Commands::Jobs) => return jobs_command(),
+        Some(Commands::Job { id }) => return job_command(&cli, *id),
+        Some(Commands::Push {
+            src,
+            dest,
+            mode,
+            owner,
+            group,
+            resume,
+            chunks,
+            dry_run,
+            exclude,
+        }) => {
+            let attrs = FileAttrs::new(mode.as_deref(), owner.as_deref(), group.as_deref())?;
+            let excludes = Excludes::new(exclude)?;
+            if !excludes.is_empty() && !src.is_dir() {
+                bail!("--exclude only applies to directories");
+            }
+            if *dry_run {
+                return push_dry_run(&cli, src, dest, &excludes);
+            }
+            return push_command(&cli, src, dest, &attrs, *resume, *chunks, &excludes);
+        }
+        Some(Commands::Fetch { src, dest }) => return fetch_command(&cli, src, dest),
+        Some(Commands::Tail { files, lines }) => return tail_command(&cli, files, *lines),
+        Some(Commands::Grep {
+            pattern,
+            files,
+            since,
+            ignore_case,
+            fixed_strings,
+        }) => {
+            let options = GrepOptions {
+                ignore_case: *ignore_case,
+                fixed_strings: *fixed_strings,
+            };
+            return grep_command(&cli, pattern, files, since.as_deref(), &options);
+        }
+        Some(Commands::Sys {
+            preset,
+            sort,
+            reverse,
+        }) => return sys_command(&cli, *preset, sort.as_deref(), *reverse),
+        Some(Commands::Bench {
+            iterations,
+            sort,
+            reverse,
+        }) => return bench_command(&cli, *iterations, sort, *reverse),
+        Some(Commands::Portcheck {
+            ports,
+            port_timeout,
+            require_open,
+        }) => return portcheck_command(&cli, ports, *port_timeout, *require_open),
+        Some(Commands::Completions { shell }) => {
+            // Complete the name we were installed and run as
+            let bin = std::env::args_os()
+                .next()
+                .map(PathBuf::from)
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .unwrap_or_else(|| Cli::command().get_name().to_string());
+            std::io::stdout().write_all(&completion::script(*shell, &bin)?)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Commands::SelfUpdate { check }) => return self_update_command(*check),
+        Some(Commands::Reboot { wait, wait_timeout }) => {
+            return reboot_command(&cli, *wait, Duration::from_secs(*wait_timeout))
+        }
+        Some(Commands::BootstrapKey {
+            command,
+            key_file,
+            remove,
+        }) => return bootstrap_key_command(&cli, command.as_deref(), key_file.as_deref(), *remove),
+        Some(Commands::Wait { wait_timeout }) => {
+            return wait_command(&cli, Duration::from_secs(*wait_timeout))
+        }
+        Some(Commands::Replay { run_id, limit }) => {
+            return replay_command(&cli, run_id, limit.as_deref())
+        }
+        Some(Commands::Run { file }) => return tasks_command(&cli, file),
+        Some(Commands::History { command }) => return history_command(command),
+        None => {}
+    }
+
+    let mut ssh = ssh_options(&cli)?;
+    if cli.quote && cli.argv.is_empty() {
+        bail!("--quote needs the command as separate words after `--`");
+    }
+    let command = match (&cli.command, cli.argv.split_first()) {
+        (Some(command), _) => command.clone(),
+        (None, Some((first, words))) if first == "exec" => {
+            if cli.shell.is_some() || cli.login {
+                bail!("`-- exec` runs the command without a shell, it can't be used with --shell or --login");
+            }
+            ssh.shell = RemoteShell::None;
+            join_words(words)
+        }
+        (None, _) if cli.quote => join_words(&cli.argv),
+        (None, _) => cli.argv.join(" "),
+    };
+    // Catch commands --no-shell can't split before connecting anywhere
+    ssh.remote_command(&command)?;
+    if let Some(precheck) = &cli.precheck {
+        ssh.remote_command(precheck)?;
+    }
+
+    if cli.group_failure_threshold.is_some() && cli.serial_groups.is_none() {
+        bail!("--group-failure-threshold only applies to --serial-groups");
+    }
+    if let Some(groups) = &cli.serial_groups {
+        if cli.detach {
+            bail!("--detach can't be used with --serial-groups");
+        }
+        let stages = serial_stages(&cli, groups, &ssh)?;
+        return run_and_report(&cli, &stages, &command, &command, &ssh);
+    }
+
+    let targets = prepare_targets(&cli, &ssh)?;
+
+    if cli.detach {
+        return detach(&cli, &targets, &command, &ssh);
+    }
+    run_and_report(&cli, &Stage::all(targets), &command, &command, &ssh)
+}
+
 // Usage:
 // multissh [OPTIONS] COMMAND
-//  
-//      ONE OF:
-//  -t/--targets (comma-separated list of target hostnames or IP addresses)
-//      OR
+// multissh [OPTIONS] -- exec PROGRAM [ARGS...] (argv, no remote shell)
+// multissh [OPTIONS] -- WORDS... (joined with spaces like ssh)
+// multissh [OPTIONS] --quote -- WORDS... (each word shell-quoted)
+// multissh --detach [OPTIONS] COMMAND (prints a run id)
+// multissh status RUN_ID
+// multissh collect RUN_ID
+// multissh daemon [--max-parallel N] [--max-jobs N]
+// multissh [OPTIONS] serve [--listen ADDR] [--token TOKEN] (HTTP API and web UI, see src/api.rs)
+//                          [--grpc-listen ADDR] (with the grpc feature, see proto/multissh.proto)
+// multissh [OPTIONS] submit COMMAND
+// multissh jobs
+// multissh job ID
+// multissh [OPTIONS] push SRC DEST [--mode 0644] [--owner USER] [--group GROUP] [--resume] [--chunks N] [--dry-run] [--exclude PATTERN]... (DEST ending with / keeps the file name; SRC may be a directory)
+// multissh [OPTIONS] fetch SRC [DEST] (saved as DEST/<host>/<file name>; a glob SRC keeps the remote paths, never overwriting)
+// multissh [OPTIONS] run FILE (task file of steps with handlers, see run --help)
+// multissh [OPTIONS] tail [-n LINES] FILE... (tail -F on every host at once, until Ctrl-C)
+// multissh [OPTIONS] grep [--since "10 min ago"] [--ignore-case] [-F] PATTERN FILE... (one timeline across hosts)
+// multissh [OPTIONS] sys disk|mem|load|uptime [--sort COLUMN] [--reverse] (one table across hosts)
+// multissh [OPTIONS] bench [-n ITERATIONS] [--sort COLUMN] [--reverse] (connect, handshake, auth and command times)
+// multissh [OPTIONS] portcheck PORTS [--timeout SECONDS] [--require-open] (e.g. 443,8443, checked from this machine)
+// multissh self-update [--check]
+// multissh completions bash|zsh|fish (e.g. `source <(multissh completions bash)`)
+// multissh [OPTIONS] reboot [--wait] [--timeout SECONDS] (default: 600, prints how long each host was down)
+// multissh [OPTIONS] -p|-a bootstrap-key [COMMAND] [--key-file FILE | --remove] (new key installed over password auth, COMMAND run with it;
+//     kept in ~/.multissh/bootstrap_key for -k, or taken off the hosts and deleted afterwards with --remove)
+// multissh [OPTIONS] wait [--timeout SECONDS] (default: 300, until ssh logins work on every host)
+// multissh [OPTIONS] replay RUN_ID|last [--limit PATTERNS] (e.g. --limit failed)
+// multissh history list [--limit N]
+// multissh history search QUERY [--raw] [--limit N] (SQLite FTS over past outputs)
+// multissh inventory lint [-i FILE]
+// multissh inventory groups [-i FILE]
+// multissh inventory tree [-i FILE] [--format text|dot]
+//
+//      ONE OR MORE OF (combined, duplicates removed):
+//  -t/--targets (comma-separated list of target hostnames or IP addresses, ranges like web[01-10], @aliases)
 //  -f/--targets-file (default: ~/.config/multissh/targets; ~/.multissh/targets; /etc/multissh/targets)
-//      OR
-//  -i/--inventory-file (default: ~/.config/multissh/inventory; ~/.multissh/inventory; /etc/multissh/inventory)
-//  -g/--inventory-group (required if -i/--inventory-file is used)
+//     (one target per line, "#include other.txt" or "#include racks/*.txt" reads other files, lines may be ranges or @aliases)
+//  -i/--inventory-file (repeatable, file, directory, plugin:NAME[:ARG] or wasm:FILE[:ARG]; default: $MULTISSH_INVENTORY; ~/.config/multissh/inventory; ~/.multissh/inventory; /etc/multissh/inventory)
+//  -g/--inventory-group (required if -i/--inventory-file is used without --tags)
+//  --tags (inventory hosts by tag, e.g. "gpu+rhel9,arm": "," = or, "+" = and, "!" = not)
+//
+// Paths (flags, environment, config, inventory, targets and task files) may start with ~ or $HOME;
+// multissh files are looked for in $XDG_CONFIG_HOME/multissh (~/.config/multissh), ~/.multissh,
+// $XDG_CONFIG_DIRS/multissh and /etc/multissh (see src/paths.rs)
+//
+// Config file (TOML, see src/config.rs; ~/.config/multissh/config.toml; ~/.multissh/config.toml; /etc/multissh/config.toml):
+//  [aliases]
+//  cache = "redis-0[1-9].prod" (used as -t @cache, in targets files and in API targets)
+//
+// Environment (over the config file, flags win over both):
+//  MULTISSH_AGENT_SOCKET, MULTISSH_BIND_ADDRESS, MULTISSH_USER, MULTISSH_PRIVATE_KEY, MULTISSH_PORT, MULTISSH_TIMEOUT,
+//  MULTISSH_EXEC_TIMEOUT, MULTISSH_CONNECT_RETRIES, MULTISSH_MAX_PARALLEL, MULTISSH_LANG, MULTISSH_OUTPUT, MULTISSH_OUTPUT_DIR,
+//  MULTISSH_NO_HISTORY (true|false), MULTISSH_INVENTORY_KEY, MULTISSH_RECORD: like the flags of the same names
+//  MULTISSH_INVENTORY: the inventory used when -i isn't given
+//  MULTISSH_API_TOKEN: the token of multissh serve
+//
+// Inventory (YAML, see src/inventory.rs):
+//  <group>:
+//    hosts: list of hosts, or mapping of hosts to variables
+//    children: list of group names
+//    vars: variables for every host of the group (user, port, private_key, tags, jump_host, jump_user, jump_port, jump_private_key)
+//  may be encrypted with age or ansible-vault (--inventory-key, or asks for the passphrase)
+//  plugin:NAME[:ARG] runs multissh-inventory-NAME, JSON request on stdin, JSON hosts on stdout (see src/plugin.rs)
+//  wasm:FILE[:ARG] calls the inventory export of a sandboxed WASM module (--features wasm, see src/wasm.rs)
 //
 //      OTIONAL:
+//  --inventory-key (age identity file or ansible-vault password file)
 //  -u/--user (default: $USER)
 //  -p/--password
 //  -k/--private-key (default: ~/.ssh/id_rsa)
 //  -P/--port (default: 22)
 //  -t/--timeout (default: 10)
+//  --exec-timeout SECONDS (stop the command and fail the host; connect_timeout / exec_timeout inventory variables override per host)
+//  --deadline TIME (e.g. 15m; no hosts started after it, running ones stopped, exit code 124)
+//  --heartbeat TIME (status line on stderr when stdout isn't a terminal, default: 1m, 0 for never)
+//  --progress-events FD|FILE (JSON lines: host_started, host_output_chunk, host_finished, run_summary)
+//  --record DIR (a timed transcript per host and run in DIR/<host>/<start>.log; also $MULTISSH_RECORD)
+//  --skip-unresolvable (default: false)
+//  --connect-retries (default: 0)
+//  --connect-backoff (default: 1)
+//  --max-output-lines N / --max-output-bytes SIZE (per host on the terminal, --output-dir keeps all)
+//  --encoding utf8-lossy|raw|latin1
+//  --device-mode [--prompt REGEX] [--disable-paging CMD]... [--prompt-timeout 60]
+//  --expect-script FILE (expect REGEX / send TEXT / sendline TEXT / timeout S / sleep S, {{var}} from the inventory)
+//  --transfer-protocol cat|scp (how push and fetch copy files, default: cat)
+//  --verify sha256|none (compare checksums after push and fetch, default: sha256)
+//  --bwlimit RATE / --bwlimit-total RATE (bytes per second of push and fetch, per host / across all hosts, e.g. 5M)
+//  --preserve [--follow-symlinks] (keep modes, mtimes and symlinks with push and fetch, like rsync -a)
+//  --strip-banner (move banners and MOTD out of the output; sends a printf marker first, POSIX shells only)
+//  --hex (hexdump binary output rather than printing its size)
+//  --spill-threshold SIZE (default: 64M, larger outputs go to temporary files)
+//  --max-parallel N|auto (default: one host per CPU, auto also heeds ulimit -n)
+//  -C/--compress (default: false)
+//  --keepalive-interval
+//  --keepalive-count
+//  --chdir DIR
+//  -o / --ssh-option KEY=VALUE (repeatable, passed to ssh)
+//  -A / --forward-agent (also the forward_agent inventory variable)
+//  --proxy-command COMMAND (ProxyCommand with %h and %p, also the proxy_command inventory variable; ssh_config's is honored too)
+//  -J/--jump-host HOSTS (e.g. "bastion1,admin@bastion2:2222", a chain connected through in order; also jump_host in the inventory)
+//  --socks5 [USER:PASS@]HOST:PORT (tunnel every connection through a SOCKS5 proxy, e.g. from ssh -D; also $MULTISSH_SOCKS5)
+//  --http-proxy [USER:PASS@]HOST:PORT (tunnel every connection through an HTTP proxy with CONNECT; also $MULTISSH_HTTP_PROXY)
+//  -4/--ipv4, -6/--ipv6 (connect over one address family only)
+//  --happy-eyeballs (race IPv6 and IPv4, IPv6 with a 250ms head start)
+//  --bind-address ADDRESS (e.g. "10.1.2.3", the local address connections come from; also $MULTISSH_BIND_ADDRESS)
+//  --ciphers LIST, --kex LIST, --macs LIST (e.g. "aes256-gcm@openssh.com", also ciphers, kex and macs of the config file)
+//  --host-key-algorithms LIST (e.g. "ssh-ed25519,ecdsa-sha2-nistp256", preferred in order; also host_key_algorithms of the config file)
+//  --legacy-crypto (also offer ssh-rsa, SHA-1 key exchange and CBC ciphers; also legacy_crypto in the inventory)
+//  --agent-socket PATH (agent used instead of $SSH_AUTH_SOCK; also agent_socket of the config file and inventory)
+//  --lang LOCALE (sets LANG and LC_ALL remotely, default: lang of the config file)
+//  --shell CMDLINE (e.g. "/bin/bash -lc")
+//  --login (run in the remote user's login shell)
+//  --no-shell (run the command's words directly, without a remote shell)
+//  --quote (shell-quote the words after --)
+//  --detach (run in the background, see status/collect)
+//  --precheck COMMAND (hosts where it fails are skipped)
+//  --serial-groups GROUPS (e.g. "db,app,web", one inventory group after the other)
+//  --group-failure-threshold N|N% (stop the serial groups once a group has more failures)
 //  -v/--verbose (default: false)
+//  --output (stream|buffered|json|gha, default: stream)
+//  --sort completion|host|duration|status (order buffered, json and gha output prints hosts in, default: completion)
+//  --ordered-output (print hosts one after the other in target order, still running in parallel)
+//  --filter-script FILE (Rhai script, or .wasm/.wat module, changing, annotating or hiding host results)
+//  --exit-code-summary
+//  --stderr (merged|highlight|only|separate, default: merged)
+//  --output-dir
+//  --color (auto|always|never, default: auto, honors NO_COLOR)
+//  --report FORMAT FILE (markdown|html|junit)
+//  --no-history (don't record the run in ~/.multissh/history.db)
 //  -h/--help
 //  -V/--version
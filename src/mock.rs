@@ -0,0 +1,162 @@
+//! Scripted hosts for testing code built on the library without SSH
+//! servers (feature `mock`). With [`RunOptions::mock`] set, the runner asks
+//! the [`MockTransport`] what each host answers instead of running ssh;
+//! retries, prechecks, exec timeouts, deadlines and the output events all
+//! work as with real hosts.
+//!
+//! ```no_run
+//! use multissh_rs::mock::{MockTransport, Response};
+//! use multissh_rs::runner::{self, RunOptions};
+//! use multissh_rs::ssh::SshOptions;
+//! use multissh_rs::targets::Target;
+//! use std::sync::{mpsc, Arc};
+//! use std::time::Duration;
+//!
+//! let mock = MockTransport::new()
+//!     .respond("web1", Response::ok("up\n"))
+//!     .respond("web2", Response::unreachable())
+//!     .respond("web2", Response::ok("up\n").delay(Duration::from_secs(2)))
+//!     .respond("db1", Response::exit(1).stderr("no such unit\n"));
+//! let options = RunOptions {
+//!     connect_retries: 1,
+//!     mock: Some(Arc::new(mock)),
+//!     ..Default::default()
+//! };
+//! let targets = ["web1", "web2", "db1"].map(Target::new);
+//! let (tx, _rx) = mpsc::channel();
+//! let results = runner::run(&targets, "uptime", &SshOptions::default(), &options, &tx);
+//! ```
+//!
+//! [`RunOptions::mock`]: crate::runner::RunOptions::mock
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What a host answers to one command
+#[derive(Clone, Debug, Default)]
+pub struct Response {
+    pub(crate) stdout: Vec<u8>,
+    pub(crate) stderr: Vec<u8>,
+    pub(crate) exit_code: Option<i32>,
+    pub(crate) error: Option<String>,
+    pub(crate) delay: Duration,
+}
+
+impl Response {
+    /// The command succeeds, writing `stdout`
+    pub fn ok(stdout: impl Into<Vec<u8>>) -> Response {
+        Response {
+            stdout: stdout.into(),
+            exit_code: Some(0),
+            ..Default::default()
+        }
+    }
+
+    /// The command exits with `code`
+    pub fn exit(code: i32) -> Response {
+        Response {
+            exit_code: Some(code),
+            ..Default::default()
+        }
+    }
+
+    /// The command is killed by a signal
+    pub fn killed() -> Response {
+        Response::default()
+    }
+
+    /// The connection is refused, the way ssh reports it, so it's retried
+    /// with `connect_retries`
+    pub fn unreachable() -> Response {
+        Response::exit(255).stderr("ssh: connect to host port 22: Connection refused\n")
+    }
+
+    /// The command can't be run at all, as when ssh is missing
+    pub fn error(message: impl Into<String>) -> Response {
+        Response {
+            error: Some(message.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn stdout(mut self, stdout: impl Into<Vec<u8>>) -> Response {
+        self.stdout = stdout.into();
+        self
+    }
+
+    pub fn stderr(mut self, stderr: impl Into<Vec<u8>>) -> Response {
+        self.stderr = stderr.into();
+        self
+    }
+
+    /// Take `delay` to answer, cut short by exec timeouts and deadlines
+    pub fn delay(mut self, delay: Duration) -> Response {
+        self.delay = delay;
+        self
+    }
+}
+
+/// Hosts answering scripted responses, see the [module](self) docs
+#[derive(Debug)]
+pub struct MockTransport {
+    fallback: Response,
+    responses: Mutex<HashMap<String, VecDeque<Response>>>,
+    calls: Mutex<Vec<(String, String)>>,
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        MockTransport::new()
+    }
+}
+
+impl MockTransport {
+    /// Hosts without responses succeed without output
+    pub fn new() -> MockTransport {
+        MockTransport {
+            fallback: Response::ok(""),
+            responses: Mutex::new(HashMap::new()),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Answer `response` for hosts without responses of their own
+    pub fn fallback(mut self, response: Response) -> MockTransport {
+        self.fallback = response;
+        self
+    }
+
+    /// Answer `response` on the next command on `host`. Responses are used
+    /// up in the order they were added, the last one is repeated.
+    pub fn respond(self, host: &str, response: Response) -> MockTransport {
+        if let Ok(mut responses) = self.responses.lock() {
+            responses
+                .entry(host.to_string())
+                .or_default()
+                .push_back(response);
+        }
+        self
+    }
+
+    /// Every host and command run so far, in the order they were run,
+    /// connection attempts and prechecks included
+    pub fn calls(&self) -> Vec<(String, String)> {
+        self.calls.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    /// Record `command` being run on `host`, and answer it
+    pub(crate) fn answer(&self, host: &str, command: &str) -> Response {
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push((host.to_string(), command.to_string()));
+        }
+        let Ok(mut responses) = self.responses.lock() else {
+            return self.fallback.clone();
+        };
+        match responses.get_mut(host) {
+            Some(queue) if queue.len() > 1 => queue.pop_front().unwrap_or_default(),
+            Some(queue) => queue.front().cloned().unwrap_or_default(),
+            None => self.fallback.clone(),
+        }
+    }
+}
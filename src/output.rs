@@ -0,0 +1,1071 @@
+use crate::filter::FilterScript;
+use crate::progress::ProgressEvents;
+use crate::record::Recorder;
+use crate::runner::HostResult;
+use crate::sys::human_size;
+use serde::Serialize;
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Bytes of buffered output written out at once when a spilled output is
+/// printed
+const FLUSH_SIZE: usize = 1 << 16;
+
+/// Bytes looked at to tell binary output from text
+const SNIFF_SIZE: usize = 8192;
+
+/// Bytes shown per line of `--hex` dumps
+const HEX_WIDTH: usize = 16;
+
+/// Host prefix colors with `host_colors`, given out in the order hosts
+/// first print something
+const HOST_COLORS: &[&str] = &[
+    "\x1b[36m", "\x1b[35m", "\x1b[34m", "\x1b[32m", "\x1b[33m", "\x1b[96m", "\x1b[95m", "\x1b[94m",
+    "\x1b[92m", "\x1b[93m",
+];
+
+/// When to use ANSI colors
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Use colors when stdout is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Resolve the choice against the environment
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                // https://no-color.org: any non-empty value disables color
+                let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+                !no_color && io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+/// How host output is written to the terminal
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputMode {
+    /// Print lines as they arrive, prefixed with the host name
+    #[default]
+    Stream,
+    /// Print each host's output as a single block once it finishes
+    Buffered,
+    /// Print one JSON object per host once it finishes
+    Json,
+    /// Print GitHub Actions workflow commands: a collapsed group per host,
+    /// and an error annotation for every failed host
+    Gha,
+}
+
+/// Order finished hosts are printed in, except in stream mode where lines
+/// are printed as they arrive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortOrder {
+    /// As hosts finish
+    #[default]
+    Completion,
+    /// By host name, once all hosts finish
+    Host,
+    /// Slowest hosts first, once all hosts finish
+    Duration,
+    /// Unreachable hosts first, then errors, failed commands, skipped
+    /// hosts and the ones that succeeded, once all hosts finish
+    Status,
+}
+
+impl SortOrder {
+    /// Sort `results`, keeping the order they finished in for ties
+    pub fn sort<R: Borrow<HostResult>>(self, results: &mut [R]) {
+        let rank = |result: &HostResult| match result {
+            r if r.unreachable => 0,
+            r if r.error.is_some() => 1,
+            r if r.skipped.is_some() => 3,
+            r if !r.success() => 2,
+            _ => 4,
+        };
+        match self {
+            SortOrder::Completion => {}
+            SortOrder::Host => results.sort_by(|a, b| a.borrow().host.cmp(&b.borrow().host)),
+            SortOrder::Duration => results.sort_by_key(|r| std::cmp::Reverse(r.borrow().duration)),
+            SortOrder::Status => results.sort_by_key(|r| rank(r.borrow())),
+        }
+    }
+}
+
+/// How the remote stderr is shown alongside stdout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StderrMode {
+    /// Show stderr together with stdout, the lines in the order they
+    /// arrived in
+    #[default]
+    Merged,
+    /// Show stderr together with stdout, colored red
+    Highlight,
+    /// Show only stderr
+    Only,
+    /// Show only stdout, stderr is only written to the output directory
+    Separate,
+}
+
+impl StderrMode {
+    fn shows(self, stream: Stream) -> bool {
+        match self {
+            StderrMode::Merged | StderrMode::Highlight => true,
+            StderrMode::Only => stream == Stream::Stderr,
+            StderrMode::Separate => stream == Stream::Stdout,
+        }
+    }
+}
+
+/// How host output is decoded before it's written to the terminal. Files
+/// of the output directory always get the bytes as received.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// UTF-8, invalid bytes replaced with U+FFFD
+    #[default]
+    Utf8Lossy,
+    /// The bytes as received, and never summed up as binary output
+    Raw,
+    /// ISO-8859-1, for legacy systems whose locale isn't UTF-8
+    Latin1,
+}
+
+impl Encoding {
+    /// `line` as UTF-8, or as is for raw output
+    pub fn decode(self, line: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            Encoding::Raw => Cow::Borrowed(line),
+            Encoding::Utf8Lossy => match String::from_utf8_lossy(line) {
+                Cow::Borrowed(_) => Cow::Borrowed(line),
+                Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+            },
+            Encoding::Latin1 if line.is_ascii() => Cow::Borrowed(line),
+            // Latin-1 bytes are the first 256 code points
+            Encoding::Latin1 => Cow::Owned(
+                line.iter()
+                    .map(|&b| b as char)
+                    .collect::<String>()
+                    .into_bytes(),
+            ),
+        }
+    }
+
+    /// Whether `data` is shown as binary output, see [`is_binary`]. Latin-1
+    /// is never invalid, only NUL bytes tell it from text.
+    fn is_binary(self, data: &[u8]) -> bool {
+        match self {
+            Encoding::Raw => false,
+            Encoding::Utf8Lossy => is_binary(data),
+            Encoding::Latin1 => data[..data.len().min(SNIFF_SIZE)].contains(&0),
+        }
+    }
+}
+
+/// Remote stream a line of output was read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Messages sent from the host workers to the writer thread
+pub enum Event {
+    /// A single line of output, without its trailing newline
+    Line {
+        host: String,
+        stream: Stream,
+        line: Vec<u8>,
+    },
+    /// Progress information about a host that isn't part of its output
+    Notice { host: String, message: String },
+    /// Work on a host has started
+    Started { host: String },
+    /// A host has finished running the command
+    Done(Box<HostResult>),
+}
+
+impl Event {
+    fn host(&self) -> &str {
+        match self {
+            Event::Line { host, .. } | Event::Notice { host, .. } | Event::Started { host } => host,
+            Event::Done(result) => &result.host,
+        }
+    }
+}
+
+/// Settings for the writer thread
+#[derive(Clone, Debug, Default)]
+pub struct OutputOptions {
+    pub mode: OutputMode,
+    pub stderr: StderrMode,
+    /// Width used to align the host prefixes in stream mode, usually the
+    /// length of the longest host name
+    pub prefix_width: usize,
+    /// Directory receiving `<host>.stdout` and `<host>.stderr` for every host
+    pub output_dir: Option<PathBuf>,
+    /// Whether ANSI colors may be written, see [`ColorChoice::enabled`]
+    pub color: bool,
+    /// Give every host its own color in the stream mode prefixes, to tell
+    /// interleaved hosts apart
+    pub host_colors: bool,
+    /// Lines of a host shown on the terminal, the rest is only counted
+    pub max_lines: Option<usize>,
+    /// Bytes of a host shown on the terminal, the rest is only counted
+    pub max_bytes: Option<u64>,
+    /// Show binary output as a hexdump rather than as its size
+    pub hex: bool,
+    pub encoding: Encoding,
+    /// Order finished hosts are printed in
+    pub sort: SortOrder,
+    /// Hosts in the order their output is released, each once the one
+    /// before it is done, for `--ordered-output`
+    pub order: Option<Vec<String>>,
+    /// Told about every event, for `--heartbeat`
+    pub heartbeat: Option<Arc<Heartbeat>>,
+    /// Told about every event, for `--progress-events`
+    pub progress: Option<Arc<ProgressEvents>>,
+    /// Told about every event, for `--record`
+    pub record: Option<Arc<Recorder>>,
+    /// Changes or hides the results before they're shown, for
+    /// `--filter-script`
+    pub filter: Option<Arc<FilterScript>>,
+}
+
+/// Status line written to stderr every so often while a run goes on, e.g.
+/// `[5m] 320/400 done, 3 failed, 12 running`, so logs of runs nobody
+/// watches show that it's alive and how far along it is
+#[derive(Debug)]
+pub struct Heartbeat {
+    progress: Arc<Mutex<Progress>>,
+    stop: Mutex<Option<Sender<()>>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    total: usize,
+    done: usize,
+    failed: usize,
+    running: HashSet<String>,
+}
+
+impl Heartbeat {
+    /// Write the status of a run on `total` hosts every `every`
+    pub fn start(total: usize, every: Duration) -> Heartbeat {
+        let progress = Arc::new(Mutex::new(Progress {
+            total,
+            ..Progress::default()
+        }));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let shared = Arc::clone(&progress);
+        let start = Instant::now();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                let Ok(progress) = shared.lock() else {
+                    return;
+                };
+                eprintln!(
+                    "[{}] {}/{} done, {} failed, {} running",
+                    short_duration(start.elapsed()),
+                    progress.done,
+                    progress.total,
+                    progress.failed,
+                    progress.running.len()
+                );
+            }
+        });
+        Heartbeat {
+            progress,
+            stop: Mutex::new(Some(stop)),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    fn observe(&self, event: &Event) {
+        let Ok(mut progress) = self.progress.lock() else {
+            return;
+        };
+        match event {
+            Event::Started { host } => {
+                progress.running.insert(host.clone());
+            }
+            Event::Done(result) => {
+                progress.running.remove(&result.host);
+                progress.done += 1;
+                if result.failed() {
+                    progress.failed += 1;
+                }
+            }
+            Event::Line { .. } | Event::Notice { .. } => {}
+        }
+    }
+
+    /// Stop writing the status
+    pub fn finish(&self) {
+        if let Ok(mut stop) = self.stop.lock() {
+            stop.take();
+        }
+        if let Some(handle) = self.handle.lock().ok().and_then(|mut h| h.take()) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Duration as e.g. `1h5m`, `5m` or `30s`
+fn short_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    let mut text = String::new();
+    if hours > 0 {
+        text.push_str(&format!("{}h", hours));
+    }
+    if minutes > 0 {
+        text.push_str(&format!("{}m", minutes));
+    }
+    if seconds > 0 || text.is_empty() {
+        text.push_str(&format!("{}s", seconds));
+    }
+    text
+}
+
+/// Whether `data` looks like binary rather than text: it has a NUL byte,
+/// or more than 30% of it is invalid UTF-8 or control characters, so that
+/// a stray Latin-1 letter still leaves a line readable. Only the start is
+/// looked at.
+pub fn is_binary(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(SNIFF_SIZE)];
+    if sample.contains(&0) {
+        return true;
+    }
+    let mut odd = 0;
+    for chunk in sample.utf8_chunks() {
+        odd += chunk.invalid().len();
+        odd += chunk
+            .valid()
+            .bytes()
+            .filter(|b| b.is_ascii_control() && !b"\t\n\r\x0c\x1b".contains(b))
+            .count();
+    }
+    odd * 10 > sample.len() * 3
+}
+
+/// How much of a host's output made it to the terminal
+#[derive(Default)]
+struct Shown {
+    lines: usize,
+    bytes: u64,
+    hidden: usize,
+    /// Streams found to be binary, no longer shown line by line
+    binary: Vec<Stream>,
+}
+
+impl Shown {
+    /// Whether the line of a host arriving in stream mode is shown. The
+    /// first binary line of a stream hides the rest of it, to be summed up
+    /// once the host is done.
+    fn admit_line(&mut self, stream: Stream, line: &[u8], options: &OutputOptions) -> bool {
+        if self.binary.contains(&stream) {
+            return false;
+        }
+        if options.encoding.is_binary(line) {
+            self.binary.push(stream);
+            return false;
+        }
+        self.admit(line, options)
+    }
+
+    /// Whether `line` is shown, counting it either way. Once a line is
+    /// over the limits the following ones are hidden too.
+    fn admit(&mut self, line: &[u8], options: &OutputOptions) -> bool {
+        let bytes = self.bytes + line.len() as u64 + 1;
+        let fits = self.hidden == 0
+            && options.max_lines.is_none_or(|max| self.lines < max)
+            && options.max_bytes.is_none_or(|max| bytes <= max);
+        if fits {
+            self.lines += 1;
+            self.bytes = bytes;
+        } else {
+            self.hidden += 1;
+        }
+        fits
+    }
+
+    /// Line telling about the hidden lines, if any
+    fn marker(&self, options: &OutputOptions) -> Option<String> {
+        let noun = if self.hidden == 1 { "line" } else { "lines" };
+        match (self.hidden, &options.output_dir) {
+            (0, _) => None,
+            (n, Some(dir)) => Some(format!("[... {} more {}, see {}]", n, noun, dir.display())),
+            (n, None) => Some(format!(
+                "[... {} more {}, use --output-dir to keep them]",
+                n, noun
+            )),
+        }
+    }
+}
+
+/// Single writer that owns stdout.
+///
+/// Every host worker sends its output through a channel, and only the writer
+/// thread touches stdout, so each line is written whole with its host prefix
+/// no matter how many hosts are producing output at once.
+pub struct OutputWriter {
+    tx: Sender<Event>,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+impl OutputWriter {
+    pub fn spawn(options: OutputOptions) -> io::Result<Self> {
+        if let Some(dir) = &options.output_dir {
+            fs::create_dir_all(dir)?;
+        }
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || write_events(rx, &options));
+        Ok(Self { tx, handle })
+    }
+
+    pub fn sender(&self) -> Sender<Event> {
+        self.tx.clone()
+    }
+
+    /// Wait for every pending event to be written
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.tx);
+        match self.handle.join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::other("output writer panicked")),
+        }
+    }
+}
+
+fn write_events(rx: Receiver<Event>, options: &OutputOptions) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut error = None;
+    let mut buf = Vec::new();
+    let mut host_colors: BTreeMap<String, &str> = BTreeMap::new();
+    let mut shown: BTreeMap<String, Shown> = BTreeMap::new();
+    // Buffered hosts: which stream their lines came on, one after the other
+    let mut arrivals: HashMap<String, Vec<(Stream, usize)>> = HashMap::new();
+    // Keep handling events after a write error (e.g. a closed pipe) so the
+    // output directory, progress events and transcripts still get every
+    // host, but remember the first error.
+    let (heartbeat, progress) = (options.heartbeat.clone(), options.progress.clone());
+    let record = options.record.clone();
+    let rx = rx.into_iter().inspect(move |event| {
+        if let Some(heartbeat) = &heartbeat {
+            heartbeat.observe(event);
+        }
+        if let Some(progress) = &progress {
+            progress.observe(event);
+        }
+        if let Some(record) = &record {
+            record.observe(event);
+        }
+    });
+    let events = sorted_events(rx, options).filter_map(|event| match (event, &options.filter) {
+        (Event::Done(result), Some(filter)) => {
+            filter.apply(*result).map(|r| Event::Done(Box::new(r)))
+        }
+        (event, _) => Some(event),
+    });
+    for event in events {
+        buf.clear();
+        if let (Event::Line { host, stream, .. }, OutputMode::Buffered | OutputMode::Gha) =
+            (&event, options.mode)
+        {
+            let runs = arrivals.entry(host.clone()).or_default();
+            match runs.last_mut() {
+                Some((last, lines)) if last == stream => *lines += 1,
+                _ => runs.push((*stream, 1)),
+            }
+        }
+        match (&event, options.mode) {
+            (Event::Line { host, stream, line }, OutputMode::Stream)
+                if options.stderr.shows(*stream)
+                    && shown
+                        .entry(host.clone())
+                        .or_default()
+                        .admit_line(*stream, line, options) =>
+            {
+                let prefix = format!("{:width$} |", host, width = options.prefix_width);
+                if options.color && options.host_colors {
+                    let next = HOST_COLORS[host_colors.len() % HOST_COLORS.len()];
+                    let color = *host_colors.entry(host.clone()).or_insert(next);
+                    write!(buf, "{} ", paint(&prefix, color, true))?;
+                } else {
+                    write!(buf, "{} ", prefix)?;
+                }
+                push_line(&mut buf, line, *stream, options);
+            }
+            (Event::Notice { host, message }, OutputMode::Stream) => {
+                writeln!(
+                    buf,
+                    "{:width$} | {}",
+                    host,
+                    paint(message, YELLOW, options.color),
+                    width = options.prefix_width
+                )?;
+            }
+            (Event::Done(result), OutputMode::Buffered) => {
+                let header = format!("===== {} ({}) =====", result.host, exit_label(result));
+                let color = match result.skipped {
+                    Some(_) => YELLOW,
+                    None if result.success() => GREEN,
+                    None => RED,
+                };
+                writeln!(buf, "{}", paint(&header, color, options.color))?;
+                if let Some(reason) = &result.skipped {
+                    let message = format!("skipped: {}", reason);
+                    writeln!(buf, "{}", paint(&message, YELLOW, options.color))?;
+                }
+                if let Some(note) = &result.note {
+                    writeln!(buf, "note: {}", note)?;
+                }
+                let runs = arrivals.remove(&result.host).unwrap_or_default();
+                push_output(&mut buf, result, options, &runs, &mut |buf| {
+                    flush(&stdout, buf, &mut error)
+                });
+                if let Some(e) = &result.error {
+                    let message = format!("error: {}", e);
+                    writeln!(buf, "{}", paint(&message, RED, options.color))?;
+                }
+            }
+            (Event::Done(result), OutputMode::Stream) => {
+                let mut host_shown = shown.remove(&result.host).unwrap_or_default();
+                let prefix = format!("{:width$} | ", result.host, width = options.prefix_width);
+                for stream in host_shown.binary.clone() {
+                    push_binary(
+                        &mut buf,
+                        &prefix,
+                        result,
+                        stream,
+                        &mut host_shown,
+                        options,
+                        &mut |buf| flush(&stdout, buf, &mut error),
+                    );
+                }
+                if let Some(marker) = host_shown.marker(options) {
+                    writeln!(
+                        buf,
+                        "{:width$} | {}",
+                        result.host,
+                        paint(&marker, YELLOW, options.color),
+                        width = options.prefix_width
+                    )?;
+                }
+                if let Some(reason) = &result.skipped {
+                    writeln!(
+                        buf,
+                        "{:width$} | {}",
+                        result.host,
+                        paint(&format!("skipped: {}", reason), YELLOW, options.color),
+                        width = options.prefix_width
+                    )?;
+                }
+                let message = match &result.error {
+                    Some(e) => Some(format!("error: {}", e)),
+                    None if !result.success() && result.skipped.is_none() => {
+                        Some(exit_label(result))
+                    }
+                    None => None,
+                };
+                if let Some(message) = message {
+                    writeln!(
+                        buf,
+                        "{:width$} | {}",
+                        result.host,
+                        paint(&message, RED, options.color),
+                        width = options.prefix_width
+                    )?;
+                }
+            }
+            (Event::Done(result), OutputMode::Gha) => {
+                writeln!(buf, "::group::{} ({})", result.host, exit_label(result))?;
+                let runs = arrivals.remove(&result.host).unwrap_or_default();
+                push_output(&mut buf, result, options, &runs, &mut |buf| {
+                    flush(&stdout, buf, &mut error)
+                });
+                writeln!(buf, "::endgroup::")?;
+                if let Some(note) = &result.note {
+                    writeln!(
+                        buf,
+                        "::notice title={}::{}",
+                        gha_property(&result.host),
+                        gha_data(note)
+                    )?;
+                }
+                let last_stderr = String::from_utf8_lossy(&result.stderr)
+                    .lines()
+                    .rfind(|l| !l.trim().is_empty())
+                    .map(str::to_string);
+                if let Some(reason) = &result.skipped {
+                    writeln!(
+                        buf,
+                        "::notice title={}::{}",
+                        gha_property(&format!("{} skipped", result.host)),
+                        gha_data(reason)
+                    )?;
+                } else if !result.success() {
+                    let message = match (&result.error, last_stderr) {
+                        (Some(e), _) => e.clone(),
+                        (None, Some(line)) => format!("{}: {}", exit_label(result), line),
+                        (None, None) => exit_label(result),
+                    };
+                    writeln!(
+                        buf,
+                        "::error title={}::{}",
+                        gha_property(&format!("{} failed", result.host)),
+                        gha_data(&message)
+                    )?;
+                } else if let Some(line) = last_stderr {
+                    writeln!(
+                        buf,
+                        "::warning title={}::{}",
+                        gha_property(&format!("{} wrote to stderr", result.host)),
+                        gha_data(&line)
+                    )?;
+                }
+            }
+            (Event::Done(result), OutputMode::Json) => {
+                // One host at a time in memory, and only while it's written
+                let spilled = result.stdout_spilled.is_some() || result.stderr_spilled.is_some();
+                let full = match spilled {
+                    true => result.unspilled().map(Cow::Owned).ok(),
+                    false => Some(Cow::Borrowed(result.as_ref())),
+                };
+                let full = match (full, options.encoding) {
+                    // JSON strings are UTF-8 anyway, only Latin-1 needs decoding
+                    (Some(full), Encoding::Latin1) => Cow::Owned(HostResult {
+                        stdout: Encoding::Latin1.decode(&full.stdout).into_owned(),
+                        stderr: Encoding::Latin1.decode(&full.stderr).into_owned(),
+                        ..full.into_owned()
+                    }),
+                    (Some(full), _) => full,
+                    (None, _) => Cow::Borrowed(result.as_ref()),
+                };
+                let line = JsonResult {
+                    result: &full,
+                    error_kind: result.check().err().map(|e| e.kind()),
+                };
+                serde_json::to_writer(&mut buf, &line)?;
+                buf.push(b'\n');
+            }
+            _ => {}
+        }
+        if let (Event::Done(result), Some(dir)) = (&event, &options.output_dir) {
+            if let Err(e) = save_host_output(dir, result) {
+                eprintln!("Failed to save output for {}: {}", result.host, e);
+            }
+        }
+        flush(&stdout, &mut buf, &mut error);
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// A host in `--output json`: its result, and what kind of failure it
+/// was, see [`crate::error::MultiSshError::kind`]
+#[derive(Serialize)]
+struct JsonResult<'a> {
+    #[serde(flatten)]
+    result: &'a HostResult,
+    error_kind: Option<&'static str>,
+}
+
+/// The events of `rx`, in the order of `options.order` or else with
+/// finished hosts held back until `rx` closes and then given out in the
+/// order of `options.sort`
+fn sorted_events(
+    rx: impl Iterator<Item = Event> + 'static,
+    options: &OutputOptions,
+) -> Box<dyn Iterator<Item = Event>> {
+    if let Some(order) = &options.order {
+        return Box::new(ordered_events(rx, order.clone()));
+    }
+    let hold = options.sort != SortOrder::Completion && options.mode != OutputMode::Stream;
+    let sort = options.sort;
+    let mut rx = rx;
+    let mut held = Vec::new();
+    let mut sorted: Option<std::vec::IntoIter<Box<HostResult>>> = None;
+    Box::new(std::iter::from_fn(move || loop {
+        if let Some(sorted) = &mut sorted {
+            return sorted.next().map(Event::Done);
+        }
+        match rx.next() {
+            Some(Event::Done(result)) if hold => held.push(result),
+            Some(event) => return Some(event),
+            None => {
+                sort.sort(&mut held);
+                sorted = Some(std::mem::take(&mut held).into_iter());
+            }
+        }
+    }))
+}
+
+/// The events of `rx` one host at a time in the order of `hosts`: those
+/// of the first host not done yet as they come, the others held back
+/// until its turn. Hosts not in `hosts` aren't held back.
+fn ordered_events(
+    mut rx: impl Iterator<Item = Event>,
+    hosts: Vec<String>,
+) -> impl Iterator<Item = Event> {
+    // Where every host is in `hosts`, the last place for hosts given twice
+    let turns: HashMap<String, usize> = hosts
+        .iter()
+        .enumerate()
+        .map(|(turn, host)| (host.clone(), turn))
+        .collect();
+    let mut next = 0;
+    let mut held: HashMap<String, Vec<Event>> = HashMap::new();
+    let mut done: HashSet<String> = HashSet::new();
+    let mut ready = VecDeque::new();
+    let mut closed = false;
+    std::iter::from_fn(move || loop {
+        if let Some(event) = ready.pop_front() {
+            return Some(event);
+        }
+        if closed {
+            return None;
+        }
+        let Some(event) = rx.next() else {
+            // What is left of hosts that never finished
+            for host in &hosts[next.min(hosts.len())..] {
+                ready.extend(held.remove(host).unwrap_or_default());
+            }
+            closed = true;
+            continue;
+        };
+        let host = event.host().to_string();
+        let waiting = turns.get(&host).is_some_and(|&turn| turn >= next);
+        if !waiting {
+            ready.push_back(event);
+            continue;
+        }
+        if matches!(event, Event::Done(_)) {
+            done.insert(host.clone());
+        }
+        held.entry(host).or_default().push(event);
+        // Release the first host, and the ones after it that are done too
+        while let Some(first) = hosts.get(next) {
+            ready.extend(held.remove(first).unwrap_or_default());
+            if !done.contains(first) {
+                break;
+            }
+            next += 1;
+        }
+    })
+}
+
+/// Write out and empty `buf`, unless writing already failed
+fn flush(stdout: &io::Stdout, buf: &mut Vec<u8>, error: &mut Option<io::Error>) {
+    if !buf.is_empty() && error.is_none() {
+        let mut out = stdout.lock();
+        if let Err(e) = out.write_all(buf).and_then(|_| out.flush()) {
+            *error = Some(e);
+        }
+    }
+    buf.clear();
+}
+
+/// Send the output of a finished host to the writer as if it was arriving
+/// from the host, followed by its result
+pub fn replay(result: &HostResult, tx: &Sender<Event>) {
+    for (stream, output) in [
+        (Stream::Stdout, &result.stdout),
+        (Stream::Stderr, &result.stderr),
+    ] {
+        for line in output.split_inclusive(|b| *b == b'\n') {
+            let _ = tx.send(Event::Line {
+                host: result.host.clone(),
+                stream,
+                line: line.strip_suffix(b"\n").unwrap_or(line).to_vec(),
+            });
+        }
+    }
+    let _ = tx.send(Event::Done(Box::new(result.clone())));
+}
+
+/// Short description of how the host finished, e.g. "exit 0"
+pub fn exit_label(result: &HostResult) -> String {
+    if result.skipped.is_some() {
+        return "precheck failed".to_string();
+    }
+    if result.not_attempted {
+        return "not attempted".to_string();
+    }
+    match (&result.error, result.exit_code) {
+        (Some(_), _) if result.unreachable => "unreachable".to_string(),
+        (Some(_), _) => "error".to_string(),
+        (None, Some(code)) => format!("exit {}", code),
+        (None, None) if result.exit_unknown => "exit status unknown".to_string(),
+        (None, None) => "killed by signal".to_string(),
+    }
+}
+
+/// Why a host failed, in one line: the error, or how it exited with the
+/// last line it wrote to stderr
+pub fn failure_reason(result: &HostResult) -> String {
+    if let Some(e) = &result.error {
+        return e.clone();
+    }
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    match stderr.lines().rfind(|l| !l.trim().is_empty()) {
+        Some(line) => format!("{}: {}", exit_label(result), line.trim()),
+        None => exit_label(result),
+    }
+}
+
+/// Breakdown of how many hosts returned each exit code, with the hosts
+/// listed for every code other than 0
+pub fn exit_code_summary(results: &[HostResult]) -> String {
+    let mut codes: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for result in results {
+        let key = match (&result.error, result.exit_code) {
+            _ if result.skipped.is_some() => "skipped".to_string(),
+            (None, Some(code)) => code.to_string(),
+            _ => exit_label(result),
+        };
+        codes.entry(key).or_default().push(&result.host);
+    }
+    // Numeric codes sort numerically, and before errors and signals
+    let mut codes: Vec<_> = codes.into_iter().collect();
+    codes.sort_by_key(|(key, _)| key.parse::<i32>().map_err(|_| key.clone()));
+
+    let width = codes.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    let mut summary = String::from("Exit code summary:\n");
+    for (key, hosts) in codes {
+        let noun = if hosts.len() == 1 { "host" } else { "hosts" };
+        summary.push_str(&format!("  {:>width$}: {} {}", key, hosts.len(), noun));
+        if key != "0" {
+            summary.push_str(&format!(" ({})", hosts.join(", ")));
+        }
+        summary.push('\n');
+    }
+    summary
+}
+
+/// Write the host's stdout and stderr as shown by the stderr mode, their
+/// lines in the order of `runs` (the stream and number of lines of every
+/// stretch of output as it arrived) and then what `runs` leaves out,
+/// stdout first. Spilled output is read back from its file bit by bit,
+/// handing `buf` to `flush` whenever it fills up.
+fn push_output(
+    buf: &mut Vec<u8>,
+    result: &HostResult,
+    options: &OutputOptions,
+    runs: &[(Stream, usize)],
+    flush: &mut dyn FnMut(&mut Vec<u8>),
+) {
+    let mut shown = Shown::default();
+    let (mut readers, mut binary) = (Vec::new(), Vec::new());
+    for stream in [Stream::Stdout, Stream::Stderr] {
+        if !options.stderr.shows(stream) {
+            continue;
+        }
+        let mut reader = match result.open_output(stream) {
+            Ok(reader) => BufReader::new(reader),
+            Err(e) => {
+                let message = format!("[failed to read the {}: {}]", stream.name(), e);
+                push_line(buf, message.as_bytes(), stream, options);
+                continue;
+            }
+        };
+        match reader.fill_buf() {
+            Ok(data) if options.encoding.is_binary(data) => binary.push(stream),
+            _ => readers.push((stream, reader)),
+        }
+    }
+    let rest = [(Stream::Stdout, usize::MAX), (Stream::Stderr, usize::MAX)];
+    let mut line = Vec::new();
+    for &(stream, lines) in runs.iter().chain(&rest) {
+        let Some((_, reader)) = readers.iter_mut().find(|(s, _)| *s == stream) else {
+            continue;
+        };
+        for _ in 0..lines {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            if shown.admit(line, options) {
+                push_line(buf, line, stream, options);
+            }
+            if buf.len() >= FLUSH_SIZE {
+                flush(buf);
+            }
+        }
+    }
+    for stream in binary {
+        push_binary(buf, "", result, stream, &mut shown, options, flush);
+    }
+    if let Some(marker) = shown.marker(options) {
+        let _ = writeln!(buf, "{}", paint(&marker, YELLOW, options.color));
+    }
+}
+
+/// Write binary `stream` of the host as its size, or as a hexdump with
+/// `--hex`, every line starting with `prefix`
+fn push_binary(
+    buf: &mut Vec<u8>,
+    prefix: &str,
+    result: &HostResult,
+    stream: Stream,
+    shown: &mut Shown,
+    options: &OutputOptions,
+    flush: &mut dyn FnMut(&mut Vec<u8>),
+) {
+    if !options.hex {
+        let size = human_size(result.output_len(stream) as f64);
+        let summary = match &options.output_dir {
+            Some(dir) => format!(
+                "<binary {}, {}, saved to {}>",
+                stream.name(),
+                size,
+                output_path(dir, &result.host, stream).display()
+            ),
+            None => format!("<binary {}, {}>", stream.name(), size),
+        };
+        let _ = writeln!(buf, "{}{}", prefix, paint(&summary, YELLOW, options.color));
+        return;
+    }
+    let mut reader = match result.open_output(stream) {
+        Ok(reader) => BufReader::new(reader),
+        Err(e) => {
+            let _ = writeln!(
+                buf,
+                "{}[failed to read the {}: {}]",
+                prefix,
+                stream.name(),
+                e
+            );
+            return;
+        }
+    };
+    let mut offset = 0;
+    let mut chunk = Vec::with_capacity(HEX_WIDTH);
+    loop {
+        chunk.clear();
+        match reader
+            .by_ref()
+            .take(HEX_WIDTH as u64)
+            .read_to_end(&mut chunk)
+        {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let line = hex_line(offset, &chunk);
+        if shown.admit(line.as_bytes(), options) {
+            buf.extend_from_slice(prefix.as_bytes());
+            push_line(buf, line.as_bytes(), stream, options);
+        }
+        offset += chunk.len();
+        if buf.len() >= FLUSH_SIZE {
+            flush(buf);
+        }
+    }
+}
+
+/// One line of a hexdump like `hexdump -C`: the offset, the bytes in hex and
+/// the printable ones as is
+fn hex_line(offset: usize, bytes: &[u8]) -> String {
+    let mut line = format!("{:08x} ", offset);
+    for i in 0..HEX_WIDTH {
+        if i % 8 == 0 {
+            line.push(' ');
+        }
+        match bytes.get(i) {
+            Some(b) => line.push_str(&format!("{:02x} ", b)),
+            None => line.push_str("   "),
+        }
+    }
+    line.push_str(" |");
+    line.extend(bytes.iter().map(|&b| match b {
+        0x20..=0x7e => b as char,
+        _ => '.',
+    }));
+    line.push('|');
+    line
+}
+
+/// Escape the message of a GitHub Actions workflow command
+fn gha_data(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape a property value of a GitHub Actions workflow command
+fn gha_property(text: &str) -> String {
+    gha_data(text).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// Wrap `text` in the given ANSI color when colors are enabled
+fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("{}{}{}", color, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+fn push_line(buf: &mut Vec<u8>, line: &[u8], stream: Stream, options: &OutputOptions) {
+    let highlight =
+        options.color && stream == Stream::Stderr && options.stderr == StderrMode::Highlight;
+    if highlight {
+        buf.extend_from_slice(RED.as_bytes());
+    }
+    buf.extend_from_slice(&options.encoding.decode(line));
+    if highlight {
+        buf.extend_from_slice(RESET.as_bytes());
+    }
+    buf.push(b'\n');
+}
+
+/// Write the host's stdout and stderr to `<dir>/<host>.stdout` and
+/// `<dir>/<host>.stderr`
+pub fn save_host_output(dir: &Path, result: &HostResult) -> io::Result<()> {
+    for stream in [Stream::Stdout, Stream::Stderr] {
+        let path = output_path(dir, &result.host, stream);
+        io::copy(
+            &mut result.open_output(stream)?,
+            &mut fs::File::create(path)?,
+        )?;
+    }
+    Ok(())
+}
+
+/// File of `--output-dir` receiving `stream` of `host`
+fn output_path(dir: &Path, host: &str, stream: Stream) -> PathBuf {
+    dir.join(format!("{}.{}", host.replace('/', "_"), stream.name()))
+}
@@ -0,0 +1,70 @@
+//! Local paths as they are written: a leading `~`, `$HOME` or `${HOME}`
+//! stands for the home directory wherever a path is given, on the command
+//! line, in the environment, the config file, inventories, targets files
+//! and task files. `~user` and other variables are left alone.
+//!
+//! The config file, inventory and targets file are looked for in the XDG
+//! base directories: `$XDG_CONFIG_HOME/multissh` (`~/.config/multissh`
+//! when unset), then `~/.multissh`, then `multissh` in every directory of
+//! `$XDG_CONFIG_DIRS` if set, then `/etc/multissh`.
+
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+
+/// The home directory, `$HOME`
+pub fn home() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// `path` with a leading `~`, `$HOME` or `${HOME}` replaced by the home
+/// directory; as it is otherwise, or when `HOME` isn't set
+pub fn expand(path: &Path) -> PathBuf {
+    let Some(text) = path.to_str() else {
+        return path.to_path_buf();
+    };
+    let rest = ["~", "${HOME}", "$HOME"]
+        .iter()
+        .find_map(|prefix| text.strip_prefix(prefix))
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'));
+    match (rest, home()) {
+        (Some(rest), Some(home)) => home.join(rest.trim_start_matches('/')),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// [`expand`] as a clap value parser, so defaults are expanded too
+pub fn parse(value: &str) -> Result<PathBuf, Infallible> {
+    Ok(expand(Path::new(value)))
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config`; relative values are ignored, as the
+/// spec asks
+pub fn config_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| home().map(|home| home.join(".config")))
+}
+
+/// Directories the files of multissh are looked for in, in order
+pub fn config_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    dirs.extend(config_home().map(|dir| dir.join("multissh")));
+    dirs.extend(home().map(|home| home.join(".multissh")));
+    if let Some(system) = std::env::var_os("XDG_CONFIG_DIRS") {
+        let system = std::env::split_paths(&system).filter(|dir| dir.is_absolute());
+        dirs.extend(system.map(|dir| dir.join("multissh")));
+    }
+    dirs.push(PathBuf::from("/etc/multissh"));
+    dirs
+}
+
+/// `name` in each of the [`config_dirs`]
+pub fn config_files(name: &str) -> Vec<PathBuf> {
+    config_dirs()
+        .into_iter()
+        .map(|dir| dir.join(name))
+        .collect()
+}
@@ -0,0 +1,219 @@
+//! Inventories from external programs, for sources like a company CMDB.
+//! `-i plugin:NAME` or `-i plugin:NAME:ARG` runs `multissh-inventory-NAME`
+//! from the `PATH` with a request on its stdin (WASM modules given with
+//! `-i wasm:FILE[:ARG]` get the same request, see [`crate::wasm`]):
+//!
+//! ```text
+//! {"version": 1, "arg": "region=eu"}
+//! ```
+//!
+//! `arg` is `null` when none was given. The program answers with the hosts
+//! on its stdout, with the groups they are in and their variables, and
+//! optionally the groups with children or variables of their own:
+//!
+//! ```text
+//! {
+//!   "hosts": [
+//!     {"name": "web1.example.com", "groups": ["web"], "vars": {"port": 2222}},
+//!     {"name": "db1.example.com", "groups": ["db"], "vars": {"tags": ["ssd"]}}
+//!   ],
+//!   "groups": {"prod": {"children": ["web", "db"], "vars": {"user": "deploy"}}}
+//! }
+//! ```
+//!
+//! Hosts without groups are put in the group `ungrouped`. The result is
+//! used like an inventory file: combined with the others,
+//! selected from with `-g` and `--tags`, and checked by `inventory lint`. A
+//! program exiting with an error fails the run, with what it wrote to
+//! stderr.
+
+use crate::inventory::{Group, Inventory, Vars};
+use crate::paths;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Group of the hosts the plugin put in none
+const UNGROUPED: &str = "ungrouped";
+
+/// Version of the protocol sent in the request
+const VERSION: u32 = 1;
+
+/// What a plugin answers
+#[derive(Deserialize)]
+struct Answer {
+    hosts: Vec<PluginHost>,
+    #[serde(default)]
+    groups: BTreeMap<String, PluginGroup>,
+}
+
+#[derive(Deserialize)]
+struct PluginHost {
+    name: String,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    vars: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Default, Deserialize)]
+struct PluginGroup {
+    #[serde(default)]
+    children: Vec<String>,
+    #[serde(default)]
+    vars: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Where an inventory plugin is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source<'a> {
+    /// `plugin:NAME`, the program `multissh-inventory-NAME`
+    Program(&'a str),
+    /// `wasm:FILE`, a module exporting `inventory`, see [`crate::wasm`]
+    Wasm(&'a Path),
+}
+
+/// The plugin `-i` names, if it names one: `plugin:NAME[:ARG]` or
+/// `wasm:FILE[:ARG]`
+pub fn parse_source(path: &Path) -> Option<(Source<'_>, Option<&str>)> {
+    let text = path.to_str()?;
+    let (spec, wasm) = match text.strip_prefix("wasm:") {
+        Some(spec) => (spec, true),
+        None => (text.strip_prefix("plugin:")?, false),
+    };
+    let (name, arg) = match spec.split_once(':') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (spec, None),
+    };
+    match wasm {
+        true => Some((Source::Wasm(Path::new(name)), arg)),
+        false => Some((Source::Program(name), arg)),
+    }
+}
+
+/// Run the inventory plugin at `source` and read the inventory it answers
+pub fn load(source: Source, arg: Option<&str>) -> Result<Inventory> {
+    let request = json!({ "version": VERSION, "arg": arg });
+    let (answer, path) = match source {
+        Source::Program(name) => (run_program(name, &request)?, format!("plugin:{}", name)),
+        Source::Wasm(path) => (
+            call_wasm(&paths::expand(path), &request)?,
+            format!("wasm:{}", path.display()),
+        ),
+    };
+    inventory(answer, PathBuf::from(path))
+}
+
+#[cfg(feature = "wasm")]
+fn call_wasm(path: &Path, request: &serde_json::Value) -> Result<Answer> {
+    let plugin = crate::wasm::WasmPlugin::load(path)?;
+    let answer = plugin.call("inventory", request.to_string().as_bytes())?;
+    serde_json::from_slice(&answer)
+        .with_context(|| format!("Invalid answer from WASM plugin {}", path.display()))
+}
+
+#[cfg(not(feature = "wasm"))]
+fn call_wasm(path: &Path, _: &serde_json::Value) -> Result<Answer> {
+    bail!(
+        "Can't load WASM plugin {}, multissh was built without the wasm feature",
+        path.display()
+    )
+}
+
+fn run_program(name: &str, request: &serde_json::Value) -> Result<Answer> {
+    if name.is_empty() || name.contains('/') {
+        bail!("Invalid inventory plugin name {:?}", name);
+    }
+    let program = format!("multissh-inventory-{}", name);
+    let mut child = Command::new(&program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run inventory plugin {}", program))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Plugins needing nothing from the request may not read it
+        let _ = writeln!(stdin, "{}", request);
+    }
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Failed to run inventory plugin {}", program))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "Inventory plugin {} failed ({}): {}",
+            program,
+            output.status,
+            stderr.trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Invalid answer from inventory plugin {}", program))
+}
+
+fn inventory(answer: Answer, path: PathBuf) -> Result<Inventory> {
+    let mut groups: Vec<Group> = Vec::new();
+    let group = |name: &str, groups: &mut Vec<Group>| -> usize {
+        match groups.iter().position(|g| g.name == name) {
+            Some(i) => i,
+            None => {
+                groups.push(Group {
+                    name: name.to_string(),
+                    hosts: Vec::new(),
+                    children: Vec::new(),
+                    vars: Vars::new(),
+                    path: path.clone(),
+                    line: None,
+                });
+                groups.len() - 1
+            }
+        }
+    };
+    let mut host_vars = BTreeMap::new();
+    for host in answer.hosts {
+        let ungrouped = [UNGROUPED.to_string()];
+        let names = match host.groups.is_empty() {
+            true => &ungrouped[..],
+            false => &host.groups[..],
+        };
+        for name in names {
+            let i = group(name, &mut groups);
+            if !groups[i].hosts.contains(&host.name) {
+                groups[i].hosts.push(host.name.clone());
+            }
+        }
+        host_vars.insert(host.name.clone(), vars(host.vars, &host.name)?);
+    }
+    for (name, plugin_group) in answer.groups {
+        let i = group(&name, &mut groups);
+        groups[i].children.extend(plugin_group.children);
+        groups[i].vars = vars(plugin_group.vars, &name)?;
+    }
+    let mut inventory = Inventory {
+        paths: vec![path],
+        groups,
+        host_vars,
+        ..Default::default()
+    };
+    for (host, vars) in inventory.host_vars.clone() {
+        inventory.check_vars(&vars, &format!("host \"{}\"", host), None);
+    }
+    for group in inventory.groups.clone() {
+        inventory.check_vars(&group.vars, &format!("group \"{}\"", group.name), None);
+    }
+    Ok(inventory)
+}
+
+fn vars(vars: serde_json::Map<String, serde_json::Value>, owner: &str) -> Result<Vars> {
+    vars.into_iter()
+        .map(|(key, value)| {
+            let value = serde_yaml::to_value(value)
+                .with_context(|| format!("Invalid variable {} of {}", key, owner))?;
+            Ok((key, value))
+        })
+        .collect()
+}
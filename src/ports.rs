@@ -0,0 +1,175 @@
+//! TCP port checks for `multissh portcheck`, made from the local machine
+//! rather than over ssh: the point is to see what the network lets through
+//! to each target.
+
+use crate::ssh::SshOptions;
+use crate::targets::Target;
+use anyhow::{bail, Context, Result};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// What connecting to a port said
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortState {
+    Open,
+    /// Refused: nothing listening, or rejected by a firewall
+    Closed,
+    /// No answer before the timeout, usually dropped by a firewall
+    Filtered,
+    Error(String),
+}
+
+impl PortState {
+    pub fn label(&self) -> &str {
+        match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::Error(_) => "error",
+        }
+    }
+}
+
+/// Ports of a target, in the order they were asked for, or why the target
+/// couldn't be resolved
+pub struct HostPorts {
+    pub host: String,
+    pub ports: Result<Vec<PortState>, String>,
+}
+
+/// Ports of a list like `443,8443` or `8000-8010`
+pub fn parse_ports(list: &str) -> Result<Vec<u16>> {
+    let mut ports = Vec::new();
+    for item in list.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let (start, end) = item.split_once('-').unwrap_or((item, item));
+        let parse = |port: &str| -> Result<u16> {
+            port.trim()
+                .parse()
+                .ok()
+                .filter(|port| *port > 0)
+                .with_context(|| format!("Invalid port {:?}", port.trim()))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            bail!("Invalid port range {}", item);
+        }
+        for port in start..=end {
+            if !ports.contains(&port) {
+                ports.push(port);
+            }
+        }
+    }
+    if ports.is_empty() {
+        bail!("No ports given");
+    }
+    Ok(ports)
+}
+
+/// Connect to every port of every target, the hosts in parallel, in target
+/// order. Targets are connected to at the hostname ssh would use.
+pub fn check_all(
+    targets: &[Target],
+    ssh: &SshOptions,
+    ports: &[u16],
+    timeout: Duration,
+) -> Vec<HostPorts> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = targets
+            .iter()
+            .map(|target| {
+                scope.spawn(move || {
+                    let ports = match ssh.with_vars(&target.vars) {
+                        Ok(ssh) => check_host(&ssh.hostname(&target.host), ports, timeout),
+                        Err(e) => Err(format!("{:#}", e)),
+                    };
+                    HostPorts {
+                        host: target.host.clone(),
+                        ports,
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
+
+fn check_host(hostname: &str, ports: &[u16], timeout: Duration) -> Result<Vec<PortState>, String> {
+    let addrs: Vec<SocketAddr> = (hostname, 0)
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .collect();
+    let Some(addr) = addrs.first() else {
+        return Err("no addresses found".to_string());
+    };
+    Ok(ports
+        .iter()
+        .map(|port| {
+            let addr = SocketAddr::new(addr.ip(), *port);
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(_) => PortState::Open,
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => PortState::Closed,
+                Err(e) if e.kind() == ErrorKind::TimedOut => PortState::Filtered,
+                Err(e) => PortState::Error(e.to_string()),
+            }
+        })
+        .collect())
+}
+
+/// One row per host and one column per port
+pub fn render_matrix(ports: &[u16], results: &[HostPorts]) -> String {
+    let headers: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+    let host_width = results
+        .iter()
+        .map(|r| r.host.len())
+        .chain(std::iter::once("host".len()))
+        .max()
+        .unwrap_or(0);
+    let widths: Vec<usize> = headers.iter().map(|h| h.len().max(8)).collect();
+    let mut out = format!("{:host_width$}", "host");
+    for (header, width) in headers.iter().zip(&widths) {
+        out.push_str(&format!("  {:width$}", header));
+    }
+    out = out.trim_end().to_string();
+    out.push('\n');
+    for result in results {
+        let mut line = format!("{:host_width$}", result.host);
+        match &result.ports {
+            Ok(states) => {
+                for (state, width) in states.iter().zip(&widths) {
+                    line.push_str(&format!("  {:width$}", state.label()));
+                }
+            }
+            Err(_) => line.push_str("  unresolvable"),
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Why each failed check failed, as (host, reason)
+pub fn errors(ports: &[u16], results: &[HostPorts]) -> Vec<(String, String)> {
+    let mut errors = Vec::new();
+    for result in results {
+        match &result.ports {
+            Ok(states) => {
+                for (state, port) in states.iter().zip(ports) {
+                    if let PortState::Error(e) = state {
+                        errors.push((result.host.clone(), format!("port {}: {}", port, e)));
+                    }
+                }
+            }
+            Err(e) => errors.push((result.host.clone(), e.clone())),
+        }
+    }
+    errors
+}
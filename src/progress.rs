@@ -0,0 +1,105 @@
+//! Machine-readable progress of a run (`--progress-events FD|FILE`), for
+//! wrappers and UIs that track runs without parsing the output meant for
+//! people. One JSON object per line, each with its `event` and `time`:
+//!
+//! ```text
+//! {"event":"host_started","host":"web1","time":"..."}
+//! {"event":"host_output_chunk","host":"web1","stream":"stdout","data":"ok\n","time":"..."}
+//! {"event":"host_finished","host":"web1","success":true,"exit_code":0,"error":null,"duration":0.42,"time":"..."}
+//! {"event":"run_summary","hosts":1,"succeeded":1,"failed":0,"skipped":0,"duration":0.45,"time":"..."}
+//! ```
+//!
+//! Every line is written as soon as it happens. Output chunks carry the
+//! lines as received, decoded as UTF-8.
+
+use crate::output::Event;
+use crate::runner::HostResult;
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::FromRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Where progress events are written
+#[derive(Debug)]
+pub struct ProgressEvents {
+    out: Mutex<File>,
+}
+
+impl ProgressEvents {
+    /// Write to the open file descriptor `target` when it is a number, or
+    /// else to the file at `target`, replaced
+    pub fn open(target: &str) -> Result<ProgressEvents> {
+        let file = match target.parse::<i32>() {
+            Ok(fd) => {
+                // SAFETY: only checks whether `fd` is open
+                if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                    bail!("File descriptor {} isn't open", fd);
+                }
+                // SAFETY: the descriptor is open, and left to the events
+                unsafe { File::from_raw_fd(fd) }
+            }
+            Err(_) => {
+                let path = crate::paths::expand(Path::new(target));
+                File::create(&path).with_context(|| format!("Failed to create {}", target))?
+            }
+        };
+        Ok(ProgressEvents {
+            out: Mutex::new(file),
+        })
+    }
+
+    /// Write the event of the host workers `event`
+    pub fn observe(&self, event: &Event) {
+        let line = match event {
+            Event::Started { host } => json!({ "event": "host_started", "host": host }),
+            Event::Line { host, stream, line } => {
+                let mut data = String::from_utf8_lossy(line).into_owned();
+                data.push('\n');
+                json!({
+                    "event": "host_output_chunk",
+                    "host": host,
+                    "stream": stream.name(),
+                    "data": data,
+                })
+            }
+            Event::Done(result) => json!({
+                "event": "host_finished",
+                "host": result.host,
+                "success": result.success(),
+                "exit_code": result.exit_code,
+                "error": result.error,
+                "duration": result.duration.as_secs_f64(),
+            }),
+            Event::Notice { .. } => return,
+        };
+        self.write(line);
+    }
+
+    /// Write the outcome of the whole run, once it's over
+    pub fn summary(&self, results: &[HostResult], duration: Duration) {
+        let skipped = results.iter().filter(|r| r.skipped.is_some()).count();
+        let failed = results.iter().filter(|r| r.failed()).count();
+        self.write(json!({
+            "event": "run_summary",
+            "hosts": results.len(),
+            "succeeded": results.len() - failed - skipped,
+            "failed": failed,
+            "skipped": skipped,
+            "duration": duration.as_secs_f64(),
+        }));
+    }
+
+    fn write(&self, mut line: Value) {
+        line["time"] = json!(Local::now().to_rfc3339());
+        let Ok(mut out) = self.out.lock() else {
+            return;
+        };
+        // Whoever reads the events going away mustn't stop the run
+        let _ = out.write_all(format!("{}\n", line).as_bytes());
+    }
+}
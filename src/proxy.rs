@@ -0,0 +1,578 @@
+//! Reaching the targets through a SOCKS5 proxy (`--socks5`), like the ones
+//! `ssh -D` opens into isolated networks, or an HTTP proxy allowing CONNECT
+//! (`--http-proxy`), where outbound connections have to go through the
+//! corporate proxy. multissh is the ProxyCommand of ssh itself: ssh runs
+//! it with [`PROXY_ENV`] set to the proxy, and it connects to the host
+//! through the proxy and relays the connection over its stdin and stdout.
+//! Host names are resolved by the proxy.
+//!
+//! With `--happy-eyeballs` there is no proxy, multissh is the ProxyCommand
+//! to race the IPv6 and IPv4 addresses of dual-stack hosts, where ssh
+//! would wait out the whole connect timeout on a broken IPv6 route.
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Environment variable telling multissh it was run as the ProxyCommand of
+/// ssh, with the proxy to connect through. Set on ssh rather than given as
+/// an argument so proxy passwords don't show up in `ps`.
+pub const PROXY_ENV: &str = "MULTISSH_PROXY";
+
+/// A proxy the connections to the targets go through
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Tunnel {
+    Socks5 {
+        /// `HOST:PORT` of the proxy
+        addr: String,
+        /// User name and password, for proxies asking for them
+        auth: Option<(String, String)>,
+    },
+    Http {
+        /// `HOST:PORT` of the proxy
+        addr: String,
+        /// User name and password, sent with basic authentication
+        auth: Option<(String, String)>,
+    },
+    /// No proxy, the addresses of the host raced with [`happy_eyeballs`]
+    Direct,
+}
+
+impl fmt::Display for Tunnel {
+    /// The `[USER:PASS@]HOST:PORT` the tunnel was parsed from, prefixed
+    /// with its kind
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (scheme, addr, auth) = match self {
+            Tunnel::Socks5 { addr, auth } => ("socks5", addr, auth),
+            Tunnel::Http { addr, auth } => ("http", addr, auth),
+            Tunnel::Direct => return write!(f, "direct://"),
+        };
+        write!(f, "{}://", scheme)?;
+        if let Some((user, password)) = auth {
+            write!(f, "{}:{}@", user, password)?;
+        }
+        write!(f, "{}", addr)
+    }
+}
+
+impl Tunnel {
+    /// Parse `--socks5 [USER:PASS@]HOST:PORT`
+    pub fn socks5(value: &str) -> Result<Tunnel, String> {
+        let (auth, addr) = split_auth(value)?;
+        Ok(Tunnel::Socks5 { addr, auth })
+    }
+
+    /// Parse `--http-proxy [http://][USER:PASS@]HOST:PORT`
+    pub fn http(value: &str) -> Result<Tunnel, String> {
+        let value = value.strip_prefix("http://").unwrap_or(value);
+        let (auth, addr) = split_auth(value.trim_end_matches('/'))?;
+        Ok(Tunnel::Http { addr, auth })
+    }
+
+    /// The tunnel from the value of [`PROXY_ENV`]
+    fn from_env(value: &str) -> Result<Tunnel> {
+        let tunnel = match value.split_once("://") {
+            Some(("socks5", rest)) => Tunnel::socks5(rest),
+            Some(("http", rest)) => Tunnel::http(rest),
+            Some(("direct", "")) => Ok(Tunnel::Direct),
+            _ => bail!("Invalid {}: {}", PROXY_ENV, value),
+        };
+        tunnel.map_err(anyhow::Error::msg)
+    }
+
+    /// The ProxyCommand ssh runs us as, with the seconds to wait for a
+    /// connection and the local address to connect from, if any
+    pub fn proxy_command(&self, connect_timeout: u64, bind: Option<IpAddr>) -> Option<String> {
+        let exe = std::env::current_exe().ok()?;
+        let exe = crate::ssh::quote(&exe.to_string_lossy());
+        Some(match bind {
+            Some(bind) => format!("{} %h %p {} {}", exe, connect_timeout, bind),
+            None => format!("{} %h %p {}", exe, connect_timeout),
+        })
+    }
+
+    /// Connect to `host` on `port` through the proxy, from `bind`
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+        bind: Option<IpAddr>,
+    ) -> Result<TcpStream> {
+        let stream = match self {
+            Tunnel::Socks5 { addr, auth } => {
+                let mut stream = connect_proxy(addr, timeout, bind)?;
+                socks5_handshake(&mut stream, host, port, auth.as_ref())
+                    .with_context(|| format!("SOCKS5 proxy {}", addr))?;
+                stream
+            }
+            Tunnel::Http { addr, auth } => {
+                let mut stream = connect_proxy(addr, timeout, bind)?;
+                http_connect(&mut stream, host, port, auth.as_ref())
+                    .with_context(|| format!("HTTP proxy {}", addr))?;
+                stream
+            }
+            Tunnel::Direct => return happy_eyeballs(host, port, timeout, bind),
+        };
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+}
+
+/// Split `[USER:PASS@]HOST:PORT`, checking the port
+fn split_auth(value: &str) -> Result<(Option<(String, String)>, String), String> {
+    let (auth, addr) = match value.rsplit_once('@') {
+        Some((auth, addr)) => match auth.split_once(':') {
+            Some((user, password)) => (Some((user.to_string(), password.to_string())), addr),
+            None => return Err(format!("expected USER:PASS@HOST:PORT, got {:?}", value)),
+        },
+        None => (None, value),
+    };
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok((auth, addr.to_string()))
+        }
+        _ => Err(format!("expected HOST:PORT, got {:?}", addr)),
+    }
+}
+
+/// Run as the ProxyCommand of ssh: `args` are the host, the port, the
+/// connect timeout in seconds and optionally the address to connect from
+pub fn serve(tunnel: &str, args: &[String]) -> Result<()> {
+    let tunnel = Tunnel::from_env(tunnel)?;
+    let (host, port, timeout, bind) = match args {
+        [host, port, timeout] => (host, port, timeout, None),
+        [host, port, timeout, bind] => (host, port, timeout, Some(bind)),
+        _ => bail!("Expected HOST PORT TIMEOUT [BIND_ADDRESS] as the ProxyCommand of ssh"),
+    };
+    let port = port
+        .parse()
+        .with_context(|| format!("Invalid port {}", port))?;
+    let timeout = Duration::from_secs(timeout.parse().unwrap_or(10).max(1));
+    let bind = match bind {
+        Some(bind) => Some(
+            bind.parse()
+                .with_context(|| format!("Invalid bind address {}", bind))?,
+        ),
+        None => None,
+    };
+    let stream = tunnel.connect(host, port, timeout, bind)?;
+    relay(stream)
+}
+
+/// Connect to `addr`, from the local address `bind` if given
+fn connect_from(
+    addr: &SocketAddr,
+    timeout: Duration,
+    bind: Option<IpAddr>,
+) -> io::Result<TcpStream> {
+    let Some(bind) = bind else {
+        return TcpStream::connect_timeout(addr, timeout);
+    };
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket
+        .bind(&SocketAddr::new(bind, 0).into())
+        .map_err(|e| io::Error::new(e.kind(), format!("bind to {}: {}", bind, e)))?;
+    socket.connect_timeout(&(*addr).into(), timeout)?;
+    Ok(socket.into())
+}
+
+/// Whether a connection from `bind` can go to `addr`, which must be of the
+/// same family
+fn reachable_from(addr: &SocketAddr, bind: Option<IpAddr>) -> bool {
+    bind.is_none_or(|bind| bind.is_ipv4() == addr.is_ipv4())
+}
+
+fn connect_proxy(addr: &str, timeout: Duration, bind: Option<IpAddr>) -> Result<TcpStream> {
+    let context = || format!("Failed to connect to proxy {}", addr);
+    let mut last = None;
+    let sockets = addr.to_socket_addrs().with_context(context)?;
+    for socket in sockets.filter(|socket| reachable_from(socket, bind)) {
+        match connect_from(&socket, timeout, bind) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last = Some(e),
+        }
+    }
+    match last {
+        Some(e) => Err(e).with_context(context),
+        None => bail!("Failed to connect to proxy {}: no addresses found", addr),
+    }
+}
+
+/// RFC 1928 CONNECT, with the RFC 1929 user name and password when given
+fn socks5_handshake(
+    stream: &mut (impl Read + Write),
+    host: &str,
+    port: u16,
+    auth: Option<&(String, String)>,
+) -> Result<()> {
+    let methods: &[u8] = match auth {
+        Some(_) => &[0x00, 0x02],
+        None => &[0x00],
+    };
+    stream.write_all(&[0x05, methods.len() as u8])?;
+    stream.write_all(methods)?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    match (reply, auth) {
+        ([0x05, 0x00], _) => {}
+        ([0x05, 0x02], Some((user, password))) => {
+            if user.len() > 255 || password.len() > 255 {
+                bail!("user name and password must be at most 255 bytes");
+            }
+            let mut request = vec![0x01, user.len() as u8];
+            request.extend(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend(password.as_bytes());
+            stream.write_all(&request)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0x00 {
+                bail!("authentication failed");
+            }
+        }
+        ([0x05, 0xff], None) => bail!("the proxy asks for a user name and password"),
+        ([0x05, _], _) => bail!("the proxy accepts none of our authentication methods"),
+        _ => bail!("not a SOCKS5 proxy"),
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend(ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend(ip.octets());
+        }
+        Err(_) if host.len() <= 255 => {
+            request.extend([0x03, host.len() as u8]);
+            request.extend(host.as_bytes());
+        }
+        Err(_) => bail!("host name {} is too long", host),
+    }
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 0x05 {
+        bail!("not a SOCKS5 proxy");
+    }
+    let reason = match reply[1] {
+        0x00 => None,
+        0x01 => Some("general failure"),
+        0x02 => Some("connection not allowed by ruleset"),
+        0x03 => Some("network unreachable"),
+        0x04 => Some("host unreachable"),
+        0x05 => Some("connection refused"),
+        0x06 => Some("TTL expired"),
+        0x07 => Some("command not supported"),
+        0x08 => Some("address type not supported"),
+        _ => Some("unknown error"),
+    };
+    if let Some(reason) = reason {
+        bail!("connecting to {} port {}: {}", host, port, reason);
+    }
+    // The address the proxy connected from, which nothing needs
+    let len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => bail!("invalid reply from the proxy"),
+    };
+    stream.read_exact(&mut vec![0; len + 2])?;
+    Ok(())
+}
+
+/// Head start of every connection attempt over the next one, as RFC 8305
+/// recommends
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to `host` like RFC 8305: its addresses alternating between IPv6
+/// and IPv4, IPv6 first, each attempt started once the one before it
+/// failed or had a short head start, and the first connection made wins.
+/// A family that doesn't work costs a moment instead of the whole timeout.
+/// With `bind` only the addresses of its family are tried.
+pub fn happy_eyeballs(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    bind: Option<IpAddr>,
+) -> Result<TcpStream> {
+    let resolved: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Could not resolve hostname {}", host))?
+        .filter(|addr| reachable_from(addr, bind))
+        .collect();
+    let (v6, v4): (Vec<_>, Vec<_>) = resolved.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut addrs = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+    match bind {
+        _ if !addrs.is_empty() => {}
+        Some(bind) => bail!("{} has no addresses to connect to from {}", host, bind),
+        None => bail!("Could not resolve hostname {}: no addresses found", host),
+    }
+
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = mpsc::channel();
+    let mut addrs = addrs.into_iter();
+    let (mut running, mut last) = (0, None);
+    loop {
+        if let Some(addr) = addrs.next() {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let _ = tx.send(connect_from(&addr, timeout, bind).map_err(|e| (addr, e)));
+            });
+            running += 1;
+        } else if running == 0 {
+            break;
+        }
+        let wait = match addrs.len() {
+            0 => deadline.saturating_duration_since(Instant::now()),
+            _ => ATTEMPT_DELAY,
+        };
+        match rx.recv_timeout(wait) {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(failed)) => {
+                running -= 1;
+                last = Some(failed);
+            }
+            Err(_) if addrs.len() == 0 => break,
+            Err(_) => {}
+        }
+    }
+    match last {
+        Some((addr, e)) if running == 0 => {
+            bail!(
+                "connect to host {} port {}: {} ({})",
+                host,
+                port,
+                e,
+                addr.ip()
+            )
+        }
+        _ => bail!(
+            "connect to host {} port {}: Connection timed out",
+            host,
+            port
+        ),
+    }
+}
+
+/// Largest response header of an HTTP proxy read
+const MAX_HEADER: usize = 16 * 1024;
+
+/// `CONNECT` request of HTTP/1.1, with basic authentication when given
+fn http_connect(
+    stream: &mut (impl Read + Write),
+    host: &str,
+    port: u16,
+    auth: Option<&(String, String)>,
+) -> Result<()> {
+    let target = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some((user, password)) = auth {
+        let credentials = format!("{}:{}", user, password);
+        let credentials = base64::engine::general_purpose::STANDARD.encode(credentials);
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    // Byte by byte, what follows the header is already the connection
+    let mut header = Vec::new();
+    let mut byte = [0; 1];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() > MAX_HEADER {
+            bail!("response header too large");
+        }
+        match stream.read(&mut byte)? {
+            0 => bail!("connection closed by the proxy"),
+            _ => header.push(byte[0]),
+        }
+    }
+    let header = String::from_utf8_lossy(&header);
+    let status = header.lines().next().unwrap_or_default();
+    let mut words = status.splitn(3, ' ');
+    let (version, code) = (words.next().unwrap_or_default(), words.next());
+    if !version.starts_with("HTTP/") {
+        bail!("not an HTTP proxy");
+    }
+    match code {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some("407") if auth.is_none() => bail!("the proxy asks for a user name and password"),
+        _ => bail!("connecting to {}: {}", target, status),
+    }
+}
+
+/// Copy stdin to `stream` and `stream` to stdout until the connection is
+/// closed, flushing everything right away
+fn relay(stream: TcpStream) -> Result<()> {
+    let mut upstream = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin().lock(), &mut upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+    let mut downstream = stream;
+    let mut stdout = io::stdout().lock();
+    let mut buf = [0; 32 * 1024];
+    loop {
+        let n = match downstream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        stdout.write_all(&buf[..n])?;
+        stdout.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A proxy answering `replies`, keeping what it was sent
+    struct Mock {
+        replies: io::Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Mock {
+        fn new(replies: &[u8]) -> Mock {
+            Mock {
+                replies: io::Cursor::new(replies.to_vec()),
+                sent: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn auth() -> Option<(String, String)> {
+        Some(("alice".to_string(), "secret".to_string()))
+    }
+
+    #[test]
+    fn socks5_connects() {
+        let mut proxy = Mock::new(&[0x05, 0x00, 0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x1f, 0x90]);
+        socks5_handshake(&mut proxy, "web1", 22, None).unwrap();
+        let mut expected = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 4];
+        expected.extend(b"web1");
+        expected.extend([0x00, 22]);
+        assert_eq!(proxy.sent, expected);
+        assert_eq!(proxy.replies.position(), 12);
+    }
+
+    #[test]
+    fn socks5_authenticates() {
+        let mut proxy = Mock::new(&[
+            0x05, 0x02, 0x01, 0x00, 0x05, 0x00, 0x00, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 1, 0x1f, 0x90,
+        ]);
+        socks5_handshake(&mut proxy, "10.0.0.2", 2222, auth().as_ref()).unwrap();
+        let mut expected = vec![0x05, 0x02, 0x00, 0x02, 0x01, 5];
+        expected.extend(b"alice");
+        expected.push(6);
+        expected.extend(b"secret");
+        expected.extend([0x05, 0x01, 0x00, 0x01, 10, 0, 0, 2, 0x08, 0xae]);
+        assert_eq!(proxy.sent, expected);
+    }
+
+    #[test]
+    fn socks5_reports_failures() {
+        let error = |replies: &[u8], auth: Option<&(String, String)>| {
+            let e = socks5_handshake(&mut Mock::new(replies), "web1", 22, auth).unwrap_err();
+            format!("{:#}", e)
+        };
+        assert!(error(&[0x05, 0xff], None).contains("asks for a user name and password"));
+        assert!(error(&[0x05, 0x02, 0x01, 0x01], auth().as_ref()).contains("authentication failed"));
+        assert!(error(&[0x05, 0x00, 0x05, 0x05, 0x00, 0x01], None)
+            .contains("web1 port 22: connection refused"));
+        assert!(error(&[0x04, 0x5a], None).contains("not a SOCKS5 proxy"));
+    }
+
+    #[test]
+    fn socks5_fails_on_short_reads() {
+        for replies in [&[0x05][..], &[0x05, 0x00, 0x05, 0x00, 0x00, 0x01, 10, 0]] {
+            let e = socks5_handshake(&mut Mock::new(replies), "web1", 22, None).unwrap_err();
+            let e = e.downcast::<io::Error>().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn http_connects() {
+        let mut proxy = Mock::new(b"HTTP/1.1 200 Connection established\r\n\r\nSSH-2.0-");
+        http_connect(&mut proxy, "::1", 22, auth().as_ref()).unwrap();
+        assert_eq!(
+            String::from_utf8(proxy.sent).unwrap(),
+            "CONNECT [::1]:22 HTTP/1.1\r\nHost: [::1]:22\r\n\
+             Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n"
+        );
+        // What follows the header is left for ssh
+        let mut rest = String::new();
+        proxy.replies.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "SSH-2.0-");
+    }
+
+    #[test]
+    fn http_reports_failures() {
+        let error = |replies: &[u8], auth: Option<&(String, String)>| {
+            let e = http_connect(&mut Mock::new(replies), "web1", 22, auth).unwrap_err();
+            format!("{:#}", e)
+        };
+        assert!(error(b"HTTP/1.1 403 Forbidden\r\n\r\n", None)
+            .contains("connecting to web1:22: HTTP/1.1 403 Forbidden"));
+        assert!(
+            error(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n", None)
+                .contains("asks for a user name and password")
+        );
+        assert!(error(b"SSH-2.0-OpenSSH_9.2\r\n\r\n", None).contains("not an HTTP proxy"));
+        assert!(error(b"HTTP/1.1 200 OK\r\n", None).contains("connection closed by the proxy"));
+    }
+}
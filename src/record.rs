@@ -0,0 +1,150 @@
+//! Transcripts of runs (`--record DIR`), an auditable record of what every
+//! host was sent and what it answered. Each run writes
+//! `DIR/<host>/<start time>.log` for every host, as it goes:
+//!
+//! ```text
+//! # multissh transcript
+//! # host: web1
+//! # started: 2026-10-14T10:15:00.123+02:00
+//! # local user: alice
+//! # remote user: deploy
+//! # command: systemctl restart nginx
+//! +0.000 started
+//! +0.412 stderr | Job for nginx.service failed.
+//! +0.415 done exit_code=1 duration=0.415s attempts=1
+//! ```
+//!
+//! The times are the seconds since the host started; output lines are kept
+//! as received, bytes that aren't UTF-8 included.
+
+use crate::output::Event;
+use crate::runner::HostResult;
+use crate::ssh::SshOptions;
+use anyhow::{Context, Result};
+use chrono::Local;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Where the transcripts of a run are written
+#[derive(Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+    /// Start of the run, naming the transcripts
+    stamp: String,
+    /// Header lines of every transcript
+    header: Vec<String>,
+    hosts: Mutex<HashMap<String, Transcript>>,
+}
+
+#[derive(Debug)]
+struct Transcript {
+    out: BufWriter<File>,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Record the run of `command` with `ssh` in `dir`, made if missing
+    pub fn new(dir: &Path, command: &str, ssh: &SshOptions) -> Result<Recorder> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let mut header = Vec::new();
+        if let Ok(user) = std::env::var("USER") {
+            header.push(format!("local user: {}", user));
+        }
+        if let Some(user) = &ssh.user {
+            header.push(format!("remote user: {}", user));
+        }
+        // Every line of a script is a comment of the header too
+        header.push(format!("command: {}", command.replace('\n', "\n#   ")));
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            stamp: Local::now().format("%Y%m%dT%H%M%S%.3f").to_string(),
+            header,
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Write the event of the host workers `event` to its host's transcript
+    pub fn observe(&self, event: &Event) {
+        let (host, line) = match event {
+            Event::Started { host } => (host, b"started".to_vec()),
+            Event::Line { host, stream, line } => {
+                let mut text = format!("{} | ", stream.name()).into_bytes();
+                text.extend_from_slice(line);
+                (host, text)
+            }
+            Event::Notice { host, message } => (host, format!("notice | {}", message).into_bytes()),
+            Event::Done(result) => (&result.host, outcome(result).into_bytes()),
+        };
+        let Ok(mut hosts) = self.hosts.lock() else {
+            return;
+        };
+        if !hosts.contains_key(host) {
+            match self.open(host) {
+                Ok(transcript) => hosts.insert(host.clone(), transcript),
+                Err(e) => {
+                    eprintln!("Failed to record the transcript of {}: {:#}", host, e);
+                    return;
+                }
+            };
+        }
+        let Some(transcript) = hosts.get_mut(host) else {
+            return;
+        };
+        let elapsed = transcript.started.elapsed().as_secs_f64();
+        let mut written = write!(transcript.out, "+{:.3} ", elapsed)
+            .and_then(|_| transcript.out.write_all(&line))
+            .and_then(|_| transcript.out.write_all(b"\n"));
+        if let Event::Done(_) = event {
+            written = written.and_then(|_| transcript.out.flush());
+            hosts.remove(host);
+        }
+        if let Err(e) = written {
+            eprintln!("Failed to record the transcript of {}: {}", host, e);
+        }
+    }
+
+    /// Start the transcript of `host`, with the header
+    fn open(&self, host: &str) -> Result<Transcript> {
+        let dir = self.dir.join(host.replace('/', "_"));
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = dir.join(format!("{}.log", self.stamp));
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        writeln!(out, "# multissh transcript")?;
+        writeln!(out, "# host: {}", host)?;
+        writeln!(out, "# started: {}", Local::now().to_rfc3339())?;
+        for line in &self.header {
+            writeln!(out, "# {}", line)?;
+        }
+        Ok(Transcript {
+            out,
+            started: Instant::now(),
+        })
+    }
+}
+
+/// Last line of a transcript: how the host ended up
+fn outcome(result: &HostResult) -> String {
+    let mut line = String::from("done");
+    match (&result.skipped, &result.error, result.exit_code) {
+        (Some(reason), _, _) => line.push_str(&format!(" skipped={:?}", reason)),
+        (None, Some(error), _) => line.push_str(&format!(" error={:?}", error)),
+        (None, None, Some(code)) => line.push_str(&format!(" exit_code={}", code)),
+        (None, None, None) if result.exit_unknown => line.push_str(" exit_code=unknown"),
+        (None, None, None) => line.push_str(" signal"),
+    }
+    if result.unreachable {
+        line.push_str(" unreachable");
+    }
+    line.push_str(&format!(
+        " duration={:.3}s attempts={}",
+        result.duration.as_secs_f64(),
+        result.attempts
+    ));
+    line
+}
@@ -0,0 +1,393 @@
+use crate::output::{exit_label, Stream};
+use crate::runner::HostResult;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// Format of a report written after the run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// Summary table and per-host sections, failures first
+    Markdown,
+    /// Standalone page with a sortable host table and collapsible output
+    Html,
+    /// JUnit XML with one test case per host, for CI systems
+    Junit,
+}
+
+/// Everything a report needs to know about a finished run
+pub struct RunReport<'a> {
+    pub command: &'a str,
+    pub started: DateTime<Local>,
+    pub duration: Duration,
+    pub results: &'a [HostResult],
+}
+
+impl RunReport<'_> {
+    /// Results with failed hosts first, otherwise in target order
+    fn failures_first(&self) -> Vec<&HostResult> {
+        let mut results: Vec<_> = self.results.iter().collect();
+        results.sort_by_key(|r| (!r.failed(), r.success()));
+        results
+    }
+
+    fn failed(&self) -> usize {
+        self.results.iter().filter(|r| r.failed()).count()
+    }
+
+    fn skipped(&self) -> usize {
+        self.results.iter().filter(|r| r.skipped.is_some()).count()
+    }
+
+    /// e.g. "3 total, 1 succeeded, 1 failed, 1 skipped"
+    fn host_counts(&self) -> String {
+        let mut counts = format!(
+            "{} total, {} succeeded, {} failed",
+            self.results.len(),
+            self.results.len() - self.failed() - self.skipped(),
+            self.failed()
+        );
+        if self.skipped() > 0 {
+            counts.push_str(&format!(", {} skipped", self.skipped()));
+        }
+        counts
+    }
+
+    pub fn write(&self, format: ReportFormat, path: &Path) -> Result<()> {
+        let contents = match format {
+            ReportFormat::Markdown => self.markdown(),
+            ReportFormat::Html => self.html(),
+            ReportFormat::Junit => self.junit(),
+        };
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write report {}", path.display()))
+    }
+
+    fn markdown(&self) -> String {
+        let mut md = String::from("# multissh report\n\n");
+        md.push_str(&self.markdown_summary());
+
+        let _ = writeln!(md, "\n## Hosts");
+        for r in self.failures_first() {
+            let stdout = r.kept_output(Stream::Stdout);
+            let stderr = r.kept_output(Stream::Stderr);
+            let _ = writeln!(md, "\n### {} ({}, {})\n", r.host, status(r), exit_label(r));
+            if let Some(reason) = &r.skipped {
+                let _ = writeln!(md, "**Skipped:** {}\n", reason);
+            }
+            if let Some(e) = &r.error {
+                let _ = writeln!(md, "**Error:** {}\n", e);
+            }
+            if stdout.is_empty() && stderr.is_empty() {
+                let _ = writeln!(md, "_No output_");
+            }
+            if !stdout.is_empty() {
+                md.push_str(&code_block(&String::from_utf8_lossy(&stdout)));
+            }
+            if !stderr.is_empty() {
+                if !stdout.is_empty() {
+                    md.push('\n');
+                }
+                let _ = writeln!(md, "**stderr:**\n");
+                md.push_str(&code_block(&String::from_utf8_lossy(&stderr)));
+            }
+        }
+        md
+    }
+
+    /// Run details and the host summary table, without the host output.
+    /// Also used for the GitHub Actions step summary.
+    pub fn markdown_summary(&self) -> String {
+        let mut md = String::new();
+        let _ = writeln!(md, "- **Command:** {}", inline_code(self.command));
+        let _ = writeln!(
+            md,
+            "- **Started:** {}",
+            self.started.format("%Y-%m-%d %H:%M:%S %Z")
+        );
+        let _ = writeln!(md, "- **Duration:** {:.1}s", self.duration.as_secs_f64());
+        let _ = writeln!(md, "- **Hosts:** {}\n", self.host_counts());
+
+        let _ = writeln!(md, "## Summary\n");
+        let _ = writeln!(md, "| Host | Status | Duration |");
+        let _ = writeln!(md, "| --- | --- | --- |");
+        for r in self.failures_first() {
+            let status = match status(r) {
+                "failed" => "**failed**",
+                status => status,
+            };
+            let _ = writeln!(
+                md,
+                "| {} | {} ({}) | {:.1}s |",
+                r.host.replace('|', "\\|"),
+                status,
+                exit_label(r),
+                r.duration.as_secs_f64()
+            );
+        }
+        md
+    }
+}
+
+pub(crate) const HTML_STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.8em; text-align: left; }
+th { cursor: pointer; background: #f3f3f3; }
+.failed { color: #b00; font-weight: bold; }
+.ok { color: #070; }
+.skipped { color: #a60; }
+details { margin: 0.5em 0; }
+summary { cursor: pointer; }
+pre { background: #f6f6f6; padding: 0.8em; overflow-x: auto; }
+pre.stderr { background: #fdf0f0; }
+";
+
+// Sort the host table by the clicked column, toggling the direction
+const HTML_SCRIPT: &str = "
+document.querySelectorAll('th').forEach((th, col) => th.addEventListener('click', () => {
+  const body = th.closest('table').tBodies[0];
+  const asc = th.dataset.asc !== 'true';
+  th.dataset.asc = asc;
+  const key = row => row.cells[col].dataset.sort ?? row.cells[col].textContent;
+  const rows = Array.from(body.rows).sort((a, b) => {
+    const [x, y] = [key(a), key(b)];
+    const cmp = isNaN(x) || isNaN(y) ? x.localeCompare(y) : x - y;
+    return asc ? cmp : -cmp;
+  });
+  rows.forEach(row => body.appendChild(row));
+}));
+";
+
+impl RunReport<'_> {
+    fn html(&self) -> String {
+        let mut html = String::new();
+        let _ = writeln!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">"
+        );
+        let _ = writeln!(
+            html,
+            "<title>multissh report: {}</title>",
+            escape_html(self.command)
+        );
+        let _ = writeln!(html, "<style>{}</style>\n</head>\n<body>", HTML_STYLE);
+        let _ = writeln!(html, "<h1>multissh report</h1>\n<ul>");
+        let _ = writeln!(
+            html,
+            "<li><b>Command:</b> <code>{}</code></li>",
+            escape_html(self.command)
+        );
+        let _ = writeln!(
+            html,
+            "<li><b>Started:</b> {}</li>",
+            self.started.format("%Y-%m-%d %H:%M:%S %Z")
+        );
+        let _ = writeln!(
+            html,
+            "<li><b>Duration:</b> {:.1}s</li>",
+            self.duration.as_secs_f64()
+        );
+        let _ = writeln!(html, "<li><b>Hosts:</b> {}</li>\n</ul>", self.host_counts());
+
+        let results = self.failures_first();
+        let _ = writeln!(html, "<table>\n<thead><tr><th>Host</th><th>Status</th><th>Exit code</th><th>Duration</th></tr></thead>\n<tbody>");
+        for r in &results {
+            let status = status(r);
+            let _ = writeln!(
+                html,
+                "<tr><td><a href=\"#{id}\">{host}</a></td><td class=\"{status}\">{status}</td><td>{code}</td><td data-sort=\"{secs:.3}\">{secs:.1}s</td></tr>",
+                id = html_id(&r.host),
+                host = escape_html(&r.host),
+                code = r.exit_code.map(|c| c.to_string()).unwrap_or_default(),
+                secs = r.duration.as_secs_f64(),
+            );
+        }
+        let _ = writeln!(html, "</tbody>\n</table>\n<h2>Output</h2>");
+
+        for r in &results {
+            let stdout = r.kept_output(Stream::Stdout);
+            let stderr = r.kept_output(Stream::Stderr);
+            let status = status(r);
+            // Failed hosts start expanded since that's what people look for
+            let open = if r.failed() { " open" } else { "" };
+            let _ = writeln!(
+                html,
+                "<details id=\"{}\"{}>\n<summary><b>{}</b> <span class=\"{}\">{} ({})</span></summary>",
+                html_id(&r.host),
+                open,
+                escape_html(&r.host),
+                status,
+                status,
+                exit_label(r)
+            );
+            if let Some(e) = &r.error {
+                let _ = writeln!(html, "<p class=\"failed\">Error: {}</p>", escape_html(e));
+            }
+            if stdout.is_empty() && stderr.is_empty() {
+                let _ = writeln!(html, "<p><i>No output</i></p>");
+            }
+            if !stdout.is_empty() {
+                let _ = writeln!(
+                    html,
+                    "<pre>{}</pre>",
+                    escape_html(&String::from_utf8_lossy(&stdout))
+                );
+            }
+            if !stderr.is_empty() {
+                let _ = writeln!(
+                    html,
+                    "<pre class=\"stderr\">{}</pre>",
+                    escape_html(&String::from_utf8_lossy(&stderr))
+                );
+            }
+            let _ = writeln!(html, "</details>");
+        }
+        let _ = writeln!(html, "<script>{}</script>\n</body>\n</html>", HTML_SCRIPT);
+        html
+    }
+}
+
+impl RunReport<'_> {
+    fn junit(&self) -> String {
+        let errors = self.results.iter().filter(|r| r.error.is_some()).count();
+        let failures = self.failed() - errors;
+        let mut xml = String::new();
+        let _ = writeln!(xml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+        let _ = writeln!(
+            xml,
+            "<testsuites name=\"multissh\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+            self.results.len(),
+            failures,
+            errors,
+            self.duration.as_secs_f64()
+        );
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\" timestamp=\"{}\">",
+            escape_xml(self.command),
+            self.results.len(),
+            failures,
+            errors,
+            self.skipped(),
+            self.duration.as_secs_f64(),
+            self.started.format("%Y-%m-%dT%H:%M:%S")
+        );
+        for r in self.results {
+            let _ = writeln!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"multissh\" time=\"{:.3}\">",
+                escape_xml(&r.host),
+                r.duration.as_secs_f64()
+            );
+            let (stdout, stderr) = (r.kept_output(Stream::Stdout), r.kept_output(Stream::Stderr));
+            let stdout = String::from_utf8_lossy(&stdout);
+            let stderr = String::from_utf8_lossy(&stderr);
+            if let Some(e) = &r.error {
+                let _ = writeln!(
+                    xml,
+                    "      <error message=\"{}\">{}</error>",
+                    escape_xml(e),
+                    escape_xml(&stderr)
+                );
+            } else if let Some(reason) = &r.skipped {
+                let _ = writeln!(xml, "      <skipped message=\"{}\"/>", escape_xml(reason));
+            } else if !r.success() {
+                // The output is what people need to see in the CI UI
+                let _ = writeln!(
+                    xml,
+                    "      <failure message=\"{}\">{}{}</failure>",
+                    exit_label(r),
+                    escape_xml(&stdout),
+                    escape_xml(&stderr)
+                );
+            }
+            if !stdout.is_empty() {
+                let _ = writeln!(
+                    xml,
+                    "      <system-out>{}</system-out>",
+                    escape_xml(&stdout)
+                );
+            }
+            if !stderr.is_empty() {
+                let _ = writeln!(
+                    xml,
+                    "      <system-err>{}</system-err>",
+                    escape_xml(&stderr)
+                );
+            }
+            let _ = writeln!(xml, "    </testcase>");
+        }
+        let _ = writeln!(xml, "  </testsuite>\n</testsuites>");
+        xml
+    }
+}
+
+/// "ok", "failed" or "skipped"
+fn status(result: &HostResult) -> &'static str {
+    match result.skipped {
+        Some(_) => "skipped",
+        None if result.success() => "ok",
+        None => "failed",
+    }
+}
+
+/// Escape text for XML, dropping control characters (such as ANSI escapes)
+/// that are not allowed in XML 1.0 documents
+fn escape_xml(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    escape_html(&text)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Anchor id for a host's output section
+fn html_id(host: &str) -> String {
+    let id: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("host-{}", id)
+}
+
+/// Longest run of consecutive backticks in `text`
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+/// Inline code span that survives backticks in `text`
+fn inline_code(text: &str) -> String {
+    let fence = "`".repeat(longest_backtick_run(text) + 1);
+    format!("{} {} {}", fence, text, fence)
+}
+
+/// Fenced code block that survives backtick fences in `text`
+fn code_block(text: &str) -> String {
+    let fence = "`".repeat(longest_backtick_run(text).max(2) + 1);
+    let newline = if text.ends_with('\n') { "" } else { "\n" };
+    format!("{}\n{}{}{}\n", fence, text, newline, fence)
+}
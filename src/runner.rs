@@ -0,0 +1,1360 @@
+use crate::device::{self, DeviceCommand, DeviceOptions, Interactive};
+use crate::error::MultiSshError;
+use crate::expect::{HostScript, Script};
+use crate::limits;
+#[cfg(feature = "mock")]
+use crate::mock::MockTransport;
+use crate::output::{Event, Stream};
+use crate::scp;
+use crate::ssh::{RemoteShell, SshOptions, Transport};
+use crate::targets::Target;
+use crate::telnet;
+use crate::transfer::BwLimit;
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{File, Permissions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Outcome of running the command on a single host
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HostResult {
+    pub host: String,
+    #[serde(serialize_with = "lossy_string", deserialize_with = "string_bytes")]
+    pub stdout: Vec<u8>,
+    #[serde(serialize_with = "lossy_string", deserialize_with = "string_bytes")]
+    pub stderr: Vec<u8>,
+    /// Exit code of the remote command, `None` if ssh was killed by a signal
+    /// or the transport has no exit status
+    pub exit_code: Option<i32>,
+    /// The transport has no exit status (telnet): `exit_code` is `None`
+    /// without anything having been killed, and the host succeeded unless
+    /// `error` is set
+    #[serde(default)]
+    pub exit_unknown: bool,
+    /// Set when the command could not be run at all
+    pub error: Option<String>,
+    /// Wall-clock time from starting ssh until it exited, across all
+    /// connection attempts
+    #[serde(serialize_with = "seconds", deserialize_with = "from_seconds")]
+    pub duration: Duration,
+    /// Number of connection attempts made
+    pub attempts: u32,
+    /// The host could not be reached, as opposed to the command failing
+    pub unreachable: bool,
+    /// Set when the command wasn't run because the precheck failed, the
+    /// output is the precheck's
+    #[serde(default)]
+    pub skipped: Option<String>,
+    /// The command wasn't run because the `--deadline` of the run passed
+    /// before the host's turn
+    #[serde(default)]
+    pub not_attempted: bool,
+    /// Complete stdout when it outgrew [`Spill::threshold`], `stdout` then
+    /// only holds its start
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_spilled: Option<Spilled>,
+    /// Complete stderr when it outgrew [`Spill::threshold`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_spilled: Option<Spilled>,
+    /// Login banner, MOTD and whatever else the host wrote before running
+    /// the command, left out of `stdout` and `stderr`
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "lossy_string",
+        deserialize_with = "string_bytes"
+    )]
+    pub banner: Vec<u8>,
+    /// Output of every command on its own in `--device-mode`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<DeviceCommand>,
+    /// Shown along with the host, set by a `--filter-script`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// What the run API returns for every host: the output, exit code,
+/// duration, connection attempts and error, see [`HostResult`]
+pub type ExecResult = HostResult;
+
+/// Line the command is preceded by on both streams, everything before it
+/// is taken for a banner
+const BANNER_END: &str = "__multissh_banner_end__";
+
+/// Output of a stream written to a file once it outgrew the spill threshold
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Spilled {
+    pub path: PathBuf,
+    /// Bytes in the file, all of the stream
+    pub len: u64,
+}
+
+/// Where the output of a host goes once one of its streams gets larger than
+/// `threshold` bytes, instead of memory
+#[derive(Clone, Debug)]
+pub struct Spill {
+    /// Where the directory of the spilled files is made
+    pub parent: PathBuf,
+    pub threshold: usize,
+}
+
+/// Directory of the files spilled by this process, made by the first spill
+static SPILL_DIR: Mutex<Option<TempDir>> = Mutex::new(None);
+
+impl Spill {
+    /// Spill to a directory of this process in the temp directory, see
+    /// [`Spill::remove`]
+    pub fn new(threshold: usize) -> Spill {
+        Spill {
+            parent: std::env::temp_dir(),
+            threshold,
+        }
+    }
+
+    /// The directory of the spilled files, made on first use with a random
+    /// name and only for us to read, like the output itself
+    fn dir(&self) -> io::Result<PathBuf> {
+        let mut dir = SPILL_DIR.lock().unwrap_or_else(PoisonError::into_inner);
+        let dir = match &mut *dir {
+            Some(dir) => dir,
+            None => dir.insert(
+                tempfile::Builder::new()
+                    .prefix("multissh-")
+                    .permissions(Permissions::from_mode(0o700))
+                    .tempdir_in(&self.parent)?,
+            ),
+        };
+        Ok(dir.path().to_path_buf())
+    }
+
+    /// Delete the spilled files, once nothing reads them anymore
+    pub fn remove(&self) {
+        SPILL_DIR
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+}
+
+/// A size in bytes, given as e.g. `512`, `64K`, `100M` or `2G`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let unit = match unit.trim().to_ascii_uppercase().trim_end_matches("IB") {
+            "" | "B" => 1,
+            "K" | "KB" => 1 << 10,
+            "M" | "MB" => 1 << 20,
+            "G" | "GB" => 1 << 30,
+            _ => return Err(format!("invalid size unit, use K, M or G: {}", s)),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(unit))
+            .map(ByteSize)
+            .ok_or_else(|| format!("expected a size like 512K or 64M: {}", s))
+    }
+}
+
+const STOPPED_AT_DEADLINE: &str = "stopped at the deadline";
+
+/// A length of time, given as e.g. `90` or `90s`, `15m`, `2h` or `1h30m`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeSpan(pub Duration);
+
+impl std::str::FromStr for TimeSpan {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected a time like 90s, 15m or 1h30m: {}", s);
+        let mut rest = s.trim();
+        if !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()) {
+            return rest
+                .parse()
+                .map(|n| TimeSpan(Duration::from_secs(n)))
+                .map_err(|_| invalid());
+        }
+        let mut seconds: u64 = 0;
+        while !rest.is_empty() {
+            let split = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let number: u64 = rest[..split].parse().map_err(|_| invalid())?;
+            let unit = match rest[split..].chars().next() {
+                Some('s') => 1,
+                Some('m') => 60,
+                Some('h') => 60 * 60,
+                Some('d') => 24 * 60 * 60,
+                _ => return Err(invalid()),
+            };
+            seconds = number
+                .checked_mul(unit)
+                .and_then(|n| seconds.checked_add(n))
+                .ok_or_else(invalid)?;
+            rest = &rest[split + 1..];
+        }
+        match seconds {
+            0 => Err(invalid()),
+            _ => Ok(TimeSpan(Duration::from_secs(seconds))),
+        }
+    }
+}
+
+impl HostResult {
+    /// Result of `host` before anything ran
+    fn new(host: &str) -> HostResult {
+        HostResult {
+            host: host.to_string(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            exit_code: None,
+            exit_unknown: false,
+            error: None,
+            duration: Duration::ZERO,
+            attempts: 1,
+            unreachable: false,
+            skipped: None,
+            not_attempted: false,
+            stdout_spilled: None,
+            stderr_spilled: None,
+            banner: Vec::new(),
+            commands: Vec::new(),
+            note: None,
+        }
+    }
+
+    /// Whether the deadline of the run kept the host from running the
+    /// command, or from finishing it
+    pub fn cut_by_deadline(&self) -> bool {
+        self.not_attempted || self.error.as_deref() == Some(STOPPED_AT_DEADLINE)
+    }
+
+    /// Result of a host left alone because the `--deadline` passed
+    pub fn not_attempted(host: &str) -> HostResult {
+        let mut result = HostResult::new(host);
+        result.error = Some("not attempted, the deadline passed".to_string());
+        result.not_attempted = true;
+        result
+    }
+
+    pub fn success(&self) -> bool {
+        self.error.is_none() && (self.exit_code == Some(0) || self.exit_unknown)
+    }
+
+    /// Whether the host counts as failed: skipped hosts neither succeeded
+    /// nor failed
+    pub fn failed(&self) -> bool {
+        !self.success() && self.skipped.is_none()
+    }
+
+    /// Why the host failed, if it did
+    pub fn check(&self) -> Result<(), MultiSshError> {
+        MultiSshError::of(self).map_or(Ok(()), Err)
+    }
+
+    fn stream(&self, stream: Stream) -> (&[u8], Option<&Spilled>) {
+        match stream {
+            Stream::Stdout => (&self.stdout, self.stdout_spilled.as_ref()),
+            Stream::Stderr => (&self.stderr, self.stderr_spilled.as_ref()),
+        }
+    }
+
+    /// Bytes in `stream`, spilled ones included
+    pub fn output_len(&self, stream: Stream) -> u64 {
+        match self.stream(stream) {
+            (_, Some(spilled)) => spilled.len,
+            (output, None) => output.len() as u64,
+        }
+    }
+
+    /// All of `stream`, read back from its file when it was spilled
+    pub fn full_output(&self, stream: Stream) -> io::Result<Cow<'_, [u8]>> {
+        match self.stream(stream) {
+            (_, Some(spilled)) => Ok(Cow::Owned(std::fs::read(&spilled.path)?)),
+            (output, None) => Ok(Cow::Borrowed(output)),
+        }
+    }
+
+    /// `stream` as kept in memory, ending with a line telling how much is
+    /// missing when it was spilled
+    pub fn kept_output(&self, stream: Stream) -> Cow<'_, [u8]> {
+        match self.stream(stream) {
+            (output, Some(spilled)) => {
+                let mut kept = output.to_vec();
+                if !kept.is_empty() && !kept.ends_with(b"\n") {
+                    kept.push(b'\n');
+                }
+                let missing = spilled.len - output.len() as u64;
+                kept.extend(format!("[... {} more bytes not kept]\n", missing).bytes());
+                Cow::Owned(kept)
+            }
+            (output, None) => Cow::Borrowed(output),
+        }
+    }
+
+    /// The result with all of its output back in memory
+    pub fn unspilled(&self) -> io::Result<HostResult> {
+        Ok(HostResult {
+            stdout: self.full_output(Stream::Stdout)?.into_owned(),
+            stderr: self.full_output(Stream::Stderr)?.into_owned(),
+            stdout_spilled: None,
+            stderr_spilled: None,
+            ..self.clone()
+        })
+    }
+
+    /// Open `stream` for reading, from its file when it was spilled
+    pub fn open_output(&self, stream: Stream) -> io::Result<Box<dyn Read + '_>> {
+        match self.stream(stream) {
+            (_, Some(spilled)) => Ok(Box::new(File::open(&spilled.path)?)),
+            (output, None) => Ok(Box::new(output)),
+        }
+    }
+}
+
+/// Settings for how the command is run across the targets
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RunOptions {
+    /// Extra connection attempts after a transient connection failure
+    pub connect_retries: u32,
+    /// Delay before the first retry, doubled on every following retry
+    pub connect_backoff: Duration,
+    /// Command run on every host first, the command only runs where it
+    /// succeeds
+    #[serde(default)]
+    pub precheck: Option<String>,
+    /// Local file fed to the stdin of the command, e.g. for uploads
+    #[serde(skip)]
+    pub stdin: Option<PathBuf>,
+    /// Hosts worked on at once, by default one per CPU or as many as the
+    /// thread pool the run is started in has
+    #[serde(skip)]
+    pub max_parallel: Option<Parallelism>,
+    /// Keep large outputs in files rather than in memory
+    #[serde(skip)]
+    pub spill: Option<Spill>,
+    /// Move what hosts write before running the command (login banners,
+    /// MOTD, output of shell startup files) out of the output, into
+    /// [`HostResult::banner`]. The command is then sent after
+    /// `printf '%s\n' __multissh_banner_end__; printf '%s\n'
+    /// __multissh_banner_end__ >&2`, marking where the banner ends, which
+    /// needs a POSIX shell on the hosts. Never done for prechecks.
+    #[serde(default)]
+    pub strip_banner: bool,
+    /// Type the command at the prompt of an interactive session, for
+    /// devices without exec channels
+    #[serde(skip)]
+    pub device: Option<DeviceOptions>,
+    /// Answer the command on a terminal with a send/expect script
+    #[serde(skip)]
+    pub expect: Option<Script>,
+    /// Copy a file with the remote `scp` run by the command
+    #[serde(skip)]
+    pub scp: Option<scp::Transfer>,
+    /// Throttle the file of `stdin` and the output, for transfers
+    #[serde(skip)]
+    pub bwlimit: Option<BwLimit>,
+    /// Bytes of the file of `stdin` each host already has, for resumed
+    /// uploads: they are skipped, and the number is sent first on a line
+    /// of its own (0 for hosts not listed)
+    #[serde(skip)]
+    pub resume: Option<HashMap<String, u64>>,
+    /// Start and length of the part of the file of `stdin` fed, for
+    /// uploads in chunks
+    #[serde(skip)]
+    pub stdin_range: Option<(u64, u64)>,
+    /// End of the run: hosts aren't started anymore once it passed, and
+    /// the commands still running are stopped
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
+    /// Once set, hosts aren't started anymore and are skipped, see
+    /// [`crate::hooks`]
+    #[serde(skip)]
+    pub abort: Option<Arc<AtomicBool>>,
+    /// Answer commands from scripted responses rather than running ssh
+    #[cfg(feature = "mock")]
+    #[serde(skip)]
+    pub mock: Option<Arc<MockTransport>>,
+}
+
+/// How many hosts are worked on at once
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parallelism {
+    Hosts(usize),
+    /// Picked from the number of targets, the CPUs and the file descriptor
+    /// limit, see [`limits::auto_parallelism`]
+    Auto,
+}
+
+impl Parallelism {
+    /// Hosts at once for a run on `targets` targets
+    pub fn hosts(&self, targets: usize) -> usize {
+        match *self {
+            Parallelism::Hosts(hosts) => hosts,
+            Parallelism::Auto => limits::auto_parallelism(targets),
+        }
+    }
+}
+
+impl std::str::FromStr for Parallelism {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Parallelism::Auto),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err(format!("expected a number of hosts or auto: {}", s)),
+                Ok(hosts) => Ok(Parallelism::Hosts(hosts)),
+            },
+        }
+    }
+}
+
+/// How many failed hosts are tolerated before giving up: a number of hosts,
+/// or a percentage of them (e.g. `2` or `10%`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureThreshold {
+    Hosts(usize),
+    Percent(f64),
+}
+
+impl FailureThreshold {
+    /// Whether `failed` out of `total` hosts is more than tolerated
+    pub fn exceeded(&self, failed: usize, total: usize) -> bool {
+        match *self {
+            FailureThreshold::Hosts(hosts) => failed > hosts,
+            FailureThreshold::Percent(percent) => failed as f64 * 100.0 > percent * total as f64,
+        }
+    }
+}
+
+impl std::str::FromStr for FailureThreshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => {
+                    Ok(FailureThreshold::Percent(percent))
+                }
+                _ => Err(format!("invalid percentage: {}", s)),
+            },
+            None => s
+                .trim()
+                .parse()
+                .map(FailureThreshold::Hosts)
+                .map_err(|_| format!("expected a number of hosts or a percentage: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for FailureThreshold {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FailureThreshold::Hosts(1) => write!(f, "1 host"),
+            FailureThreshold::Hosts(hosts) => write!(f, "{} hosts", hosts),
+            FailureThreshold::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+/// Messages ssh prints when it could not reach the host. These failures are
+/// transient and worth retrying, unlike e.g. authentication failures.
+const CONNECTION_ERRORS: &[&str] = &[
+    "Could not resolve hostname",
+    "Temporary failure in name resolution",
+    "Connection refused",
+    "Connection timed out",
+    "Operation timed out",
+    "No route to host",
+    "Network is unreachable",
+    "Connection reset by peer",
+    "Connection closed by remote host",
+    "kex_exchange_identification",
+];
+
+/// Whether ssh failed to connect rather than the remote command failing.
+/// ssh exits with 255 on its own errors, and prints the reason to stderr.
+fn connection_failure(result: &HostResult) -> Option<String> {
+    if result.exit_code != Some(255) {
+        return None;
+    }
+    String::from_utf8_lossy(&result.stderr)
+        .lines()
+        .find(|line| CONNECTION_ERRORS.iter().any(|e| line.contains(e)))
+        .map(|line| line.trim().to_string())
+}
+
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+fn lossy_string<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(bytes))
+}
+
+fn from_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    f64::deserialize(deserializer).map(Duration::from_secs_f64)
+}
+
+fn string_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    String::deserialize(deserializer).map(String::into_bytes)
+}
+
+/// Run `command` on every target in parallel, sending output to `tx` as it
+/// arrives. Results are returned in target order.
+pub fn run(
+    targets: &[Target],
+    command: &str,
+    ssh: &SshOptions,
+    options: &RunOptions,
+    tx: &Sender<Event>,
+) -> Vec<HostResult> {
+    let Some(max_parallel) = options.max_parallel else {
+        return run_all(targets, command, ssh, options, tx);
+    };
+    let hosts = max_parallel.hosts(targets.len());
+    if ssh.verbose {
+        eprintln!("Working on up to {} hosts at once", hosts);
+    }
+    match rayon::ThreadPoolBuilder::new().num_threads(hosts).build() {
+        Ok(pool) => pool.install(|| run_all(targets, command, ssh, options, tx)),
+        Err(_) => run_all(targets, command, ssh, options, tx),
+    }
+}
+
+fn run_all(
+    targets: &[Target],
+    command: &str,
+    ssh: &SshOptions,
+    options: &RunOptions,
+    tx: &Sender<Event>,
+) -> Vec<HostResult> {
+    targets
+        .par_iter()
+        .map(|target| {
+            if options
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                let result = HostResult::not_attempted(&target.host);
+                let _ = tx.send(Event::Done(Box::new(result.clone())));
+                return result;
+            }
+            if options
+                .abort
+                .as_ref()
+                .is_some_and(|abort| abort.load(Ordering::Relaxed))
+            {
+                let mut result = HostResult::new(&target.host);
+                result.skipped = Some("the run was aborted".to_string());
+                let _ = tx.send(Event::Done(Box::new(result.clone())));
+                return result;
+            }
+            let _ = tx.send(Event::Started {
+                host: target.host.clone(),
+            });
+            let ssh = match ssh.with_vars(&target.vars) {
+                Ok(ssh) => ssh,
+                Err(e) => return invalid_vars(&target.host, &e, tx),
+            };
+            let script = options
+                .expect
+                .as_ref()
+                .map(|script| script.for_host(&target.host, &target.vars));
+            let checked = match &script {
+                Some(Err(e)) => {
+                    let mut result = HostResult::new(&target.host);
+                    result.error = Some(format!("{:#}", e));
+                    Some(result)
+                }
+                _ => options
+                    .precheck
+                    .as_deref()
+                    .and_then(|precheck| run_precheck(&target.host, precheck, &ssh, options)),
+            };
+            let result = match checked {
+                Some(result) => result,
+                None => {
+                    let input = match (&script, &options.stdin) {
+                        (Some(Ok(script)), _) => Input::Expect(script),
+                        (_, Some(path)) => Input::File(path),
+                        _ => Input::None,
+                    };
+                    run_host(&target.host, command, &ssh, options, input, tx)
+                }
+            };
+            let _ = tx.send(Event::Done(Box::new(result.clone())));
+            result
+        })
+        .collect()
+}
+
+/// Result of a host whose inventory variables can't be used, without
+/// connecting to it
+fn invalid_vars(host: &str, e: &anyhow::Error, tx: &Sender<Event>) -> HostResult {
+    let mut result = HostResult::new(host);
+    result.error = Some(format!("{:#}", e));
+    let _ = tx.send(Event::Done(Box::new(result.clone())));
+    result
+}
+
+/// Run `command` on every target at once, each on its own thread, for
+/// commands that run until multissh is interrupted like `tail -F`. The
+/// remote stdin is held open for as long as multissh runs, so the remote
+/// side can tell when the connection goes away, and the output is only
+/// forwarded, not kept in the results. Results are returned in target order.
+pub fn follow(
+    targets: &[Target],
+    command: &str,
+    ssh: &SshOptions,
+    options: &RunOptions,
+    tx: &Sender<Event>,
+) -> Vec<HostResult> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = targets
+            .iter()
+            .map(|target| {
+                scope.spawn(move || {
+                    let ssh = match ssh.with_vars(&target.vars) {
+                        Ok(ssh) => ssh,
+                        Err(e) => return invalid_vars(&target.host, &e, tx),
+                    };
+                    let result = run_host(&target.host, command, &ssh, options, Input::Held, tx);
+                    let _ = tx.send(Event::Done(Box::new(result.clone())));
+                    result
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
+
+/// What the stdin of the remote command is connected to
+#[derive(Clone, Copy)]
+enum Input<'a> {
+    None,
+    /// A local file, see [`RunOptions::stdin`]
+    File(&'a Path),
+    /// A pipe nothing is written to, closed when multissh exits
+    Held,
+    /// A terminal the script answers, see [`RunOptions::expect`]
+    Expect(&'a HostScript),
+}
+
+/// Run the command on `host`, retrying transient connection failures
+fn run_host(
+    host: &str,
+    command: &str,
+    ssh: &SshOptions,
+    options: &RunOptions,
+    input: Input,
+    tx: &Sender<Event>,
+) -> HostResult {
+    let start = Instant::now();
+    let mut backoff = options.connect_backoff;
+    let mut attempt = 1;
+    loop {
+        let mut result = exec(host, command, ssh, options, input, tx);
+        result.attempts = attempt;
+        let Some(reason) = connection_failure(&result) else {
+            result.duration = start.elapsed();
+            return result;
+        };
+        let past_deadline = options
+            .deadline
+            .is_some_and(|deadline| Instant::now() + backoff >= deadline);
+        if attempt > options.connect_retries || past_deadline {
+            let noun = if attempt == 1 { "attempt" } else { "attempts" };
+            result.error = Some(format!(
+                "unreachable after {} {}: {}",
+                attempt, noun, reason
+            ));
+            result.unreachable = true;
+            result.duration = start.elapsed();
+            return result;
+        }
+        let _ = tx.send(Event::Notice {
+            host: host.to_string(),
+            message: format!(
+                "connection failed, retrying in {:.1}s (attempt {}/{})",
+                backoff.as_secs_f64(),
+                attempt + 1,
+                options.connect_retries + 1
+            ),
+        });
+        thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Run the precheck on `host` without showing its output. Returns the
+/// result to report instead of running the command when it didn't pass: a
+/// skipped host when it failed, or the error when `host` couldn't be
+/// reached or the precheck couldn't be run.
+fn run_precheck(
+    host: &str,
+    precheck: &str,
+    ssh: &SshOptions,
+    options: &RunOptions,
+) -> Option<HostResult> {
+    let (tx, _) = mpsc::channel();
+    let options = RunOptions {
+        strip_banner: false,
+        ..options.clone()
+    };
+    let mut result = run_host(host, precheck, ssh, &options, Input::None, &tx);
+    if result.success() || result.error.is_some() {
+        return result.error.is_some().then_some(result);
+    }
+    result.skipped = Some(match result.exit_code {
+        Some(code) => format!("precheck exited with {}", code),
+        None => "precheck was killed by a signal".to_string(),
+    });
+    Some(result)
+}
+
+/// Run the command on `host` once
+fn exec(
+    host: &str,
+    command: &str,
+    ssh: &SshOptions,
+    options: &RunOptions,
+    input: Input,
+    tx: &Sender<Event>,
+) -> HostResult {
+    let spill = options.spill.as_ref();
+    let mut result = HostResult::new(host);
+    #[cfg(feature = "mock")]
+    if let Some(mock) = &options.mock {
+        return exec_mock(host, command, ssh, mock, options, tx, result);
+    }
+    if ssh.transport == Transport::Telnet {
+        if matches!(input, Input::File(_) | Input::Expect(_)) || options.scp.is_some() {
+            result.error = Some("telnet can only run commands, not send files".to_string());
+            return result;
+        }
+        return exec_telnet(host, command, ssh, spill, tx, result);
+    }
+    if let Some(device) = &options.device {
+        if let Input::File(_) = input {
+            result.error = Some("--device-mode can only run commands, not send files".to_string());
+            return result;
+        }
+        return exec_device(host, command, ssh, device, spill, tx, result);
+    }
+    if let Input::Expect(script) = input {
+        return exec_expect(host, command, ssh, script, spill, tx, result);
+    }
+    if let Some(transfer) = &options.scp {
+        return exec_scp(host, command, ssh, transfer, options, result);
+    }
+
+    // The end of the banner can only be marked where the command is
+    // given to a shell
+    let marked = match ssh.shell {
+        RemoteShell::Default | RemoteShell::Login if options.strip_banner => Some(format!(
+            "printf '%s\\n' {0}; printf '%s\\n' {0} >&2\n{1}",
+            BANNER_END, command
+        )),
+        _ => None,
+    };
+    let banner_end = marked.is_some().then_some(BANNER_END);
+    let mut cmd = match ssh.command(host, marked.as_deref().unwrap_or(command)) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            result.error = Some(format!("{:#}", e));
+            return result;
+        }
+    };
+    // Written to the command from a thread of its own when throttled or
+    // resumed, after a header
+    let mut feed = None;
+    match input {
+        Input::None => {}
+        Input::File(path) => match File::open(path) {
+            Ok(mut file)
+                if options.resume.is_some()
+                    || options.stdin_range.is_some()
+                    || options.bwlimit.is_some() =>
+            {
+                let (start, len, header) = match (&options.resume, options.stdin_range) {
+                    (Some(resume), _) => {
+                        let offset = resume.get(host).copied().unwrap_or(0);
+                        (offset, u64::MAX, format!("{}\n", offset))
+                    }
+                    (None, Some((start, len))) => (start, len, String::new()),
+                    (None, None) => (0, u64::MAX, String::new()),
+                };
+                if let Err(e) = file.seek(SeekFrom::Start(start)) {
+                    result.error = Some(format!("failed to read {}: {}", path.display(), e));
+                    return result;
+                }
+                cmd.stdin(Stdio::piped());
+                feed = Some((header, file.take(len)));
+            }
+            Ok(file) => {
+                cmd.stdin(file);
+            }
+            Err(e) => {
+                result.error = Some(format!("failed to open {}: {}", path.display(), e));
+                return result;
+            }
+        },
+        Input::Held => {
+            cmd.stdin(Stdio::piped());
+        }
+        Input::Expect(_) => unreachable!("expect scripts run on a terminal"),
+    }
+    let keep = !matches!(input, Input::Held);
+    if ssh.verbose {
+        eprintln!("{}: {:?}", host, cmd);
+    }
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            result.error = Some(format!("failed to run ssh: {}", e));
+            return result;
+        }
+    };
+    // Commands that run until multissh is interrupted aren't timed out,
+    // but still end at the deadline
+    let timeout = ssh.exec_timeout.filter(|_| keep).map(Duration::from_secs);
+    let left = options
+        .deadline
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    let watchdog = match (timeout, left) {
+        (Some(timeout), Some(left)) if left < timeout => Some((left, true)),
+        (Some(timeout), _) => Some((timeout, false)),
+        (None, Some(left)) => Some((left, true)),
+        (None, None) => None,
+    }
+    .map(|(after, deadline)| (Watchdog::start(&child, after), deadline));
+
+    if let (Some((header, mut file)), Some(mut stdin)) = (feed, child.stdin.take()) {
+        let (host, limit) = (host.to_string(), options.bwlimit.clone());
+        thread::spawn(move || -> io::Result<u64> {
+            stdin.write_all(header.as_bytes())?;
+            match limit {
+                Some(limit) => io::copy(&mut limit.throttle(&host, file), &mut stdin),
+                None => io::copy(&mut file, &mut stdin),
+            }
+        });
+    }
+    let stderr = child.stderr.take().map(|stderr| {
+        let (host, tx, spill) = (host.to_string(), tx.clone(), spill.cloned());
+        thread::spawn(move || {
+            let sink = Sink::new(&host, Stream::Stderr, keep, spill.as_ref(), &tx);
+            forward_lines(stderr, sink, banner_end)
+        })
+    });
+    if let Some(stdout) = child.stdout.take() {
+        let sink = Sink::new(host, Stream::Stdout, keep, spill, tx);
+        let forwarded = match &options.bwlimit {
+            Some(limit) => forward_lines(limit.throttle(host, stdout), sink, banner_end),
+            None => forward_lines(stdout, sink, banner_end),
+        };
+        (result.stdout, result.stdout_spilled) = (forwarded.output, forwarded.spilled);
+        result.banner = forwarded.banner;
+    }
+    if let Some(handle) = stderr {
+        let forwarded = handle.join().unwrap_or_default();
+        (result.stderr, result.stderr_spilled) = (forwarded.output, forwarded.spilled);
+        result.banner.extend(forwarded.banner);
+    }
+
+    // Stopped before ssh is waited for, its pid may be reused after
+    let stopped = watchdog.and_then(|(watchdog, deadline)| watchdog.stop().then_some(deadline));
+    match child.wait() {
+        Ok(status) => result.exit_code = status.code(),
+        Err(e) => result.error = Some(format!("failed to wait for ssh: {}", e)),
+    }
+    match stopped {
+        Some(true) => result.error = Some(STOPPED_AT_DEADLINE.to_string()),
+        Some(false) => {
+            let timeout = ssh.exec_timeout.unwrap_or_default();
+            result.error = Some(format!("timed out after {}s", timeout));
+        }
+        None => {}
+    }
+    result
+}
+
+/// Stops ssh once the exec timeout of the host is over
+struct Watchdog {
+    cancel: Sender<()>,
+    handle: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(child: &Child, timeout: Duration) -> Watchdog {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let pid = child.id() as libc::pid_t;
+        let handle = thread::spawn(move || match cancelled.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                // SAFETY: the child isn't waited for until the watchdog
+                // is stopped, so `pid` is still ssh
+                unsafe { libc::kill(pid, libc::SIGTERM) };
+                true
+            }
+            _ => false,
+        });
+        Watchdog { cancel, handle }
+    }
+
+    /// Whether ssh was stopped
+    fn stop(self) -> bool {
+        drop(self.cancel);
+        self.handle.join().unwrap_or(false)
+    }
+}
+
+/// Answer the command on `host` from the scripted responses, see
+/// [`crate::mock`]
+#[cfg(feature = "mock")]
+fn exec_mock(
+    host: &str,
+    command: &str,
+    ssh: &SshOptions,
+    mock: &MockTransport,
+    options: &RunOptions,
+    tx: &Sender<Event>,
+    mut result: HostResult,
+) -> HostResult {
+    let response = mock.answer(host, command);
+    if let Some(error) = response.error {
+        result.error = Some(error);
+        return result;
+    }
+    // Cut short like ssh by the watchdog
+    let timeout = ssh.exec_timeout.map(|s| (Duration::from_secs(s), false));
+    let left = options
+        .deadline
+        .map(|deadline| (deadline.saturating_duration_since(Instant::now()), true));
+    let cut = [left, timeout]
+        .into_iter()
+        .flatten()
+        .filter(|(after, _)| *after < response.delay)
+        .min_by_key(|(after, _)| *after);
+    if let Some((after, deadline)) = cut {
+        thread::sleep(after);
+        result.error = Some(match deadline {
+            true => STOPPED_AT_DEADLINE.to_string(),
+            false => format!("timed out after {}s", ssh.exec_timeout.unwrap_or_default()),
+        });
+        return result;
+    }
+    thread::sleep(response.delay);
+    let spill = options.spill.as_ref();
+    let sink = Sink::new(host, Stream::Stdout, true, spill, tx);
+    let forwarded = forward_lines(&response.stdout[..], sink, None);
+    (result.stdout, result.stdout_spilled) = (forwarded.output, forwarded.spilled);
+    let sink = Sink::new(host, Stream::Stderr, true, spill, tx);
+    let forwarded = forward_lines(&response.stderr[..], sink, None);
+    (result.stderr, result.stderr_spilled) = (forwarded.output, forwarded.spilled);
+    result.exit_code = response.exit_code;
+    result
+}
+
+/// Run the command on `host` over telnet, see [`crate::telnet`]
+fn exec_telnet(
+    host: &str,
+    command: &str,
+    ssh: &SshOptions,
+    spill: Option<&Spill>,
+    tx: &Sender<Event>,
+    mut result: HostResult,
+) -> HostResult {
+    let user = ssh
+        .user
+        .clone()
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_default();
+    let timeout = Duration::from_secs(ssh.connect_timeout);
+    let session = telnet::Session::login(host, ssh.port, &user, ssh.password.as_deref(), timeout);
+    let mut session = match session {
+        Ok(session) => session,
+        // Like ssh, so connection failures are retried the same way
+        Err(e) if CONNECTION_ERRORS.iter().any(|c| e.to_string().contains(c)) => {
+            result.stderr = format!("telnet: {}\n", e).into_bytes();
+            result.exit_code = Some(255);
+            return result;
+        }
+        Err(e) => {
+            result.error = Some(format!("telnet: {}", e));
+            return result;
+        }
+    };
+    if let Err(e) = session.start(command, timeout) {
+        result.error = Some(format!("telnet: {}", e));
+        return result;
+    }
+    let sink = Sink::new(host, Stream::Stdout, true, spill, tx);
+    let forwarded = forward_lines(&mut session, sink, None);
+    (result.stdout, result.stdout_spilled) = (forwarded.output, forwarded.spilled);
+    session.close();
+    // Only the output tells whether the command failed
+    result.exit_unknown = true;
+    if let Some(line) = telnet::error_line(&result.stdout) {
+        result.error = Some(format!("the device reported an error: {}", line));
+    }
+    result
+}
+
+/// Run the device commands of `command` on `host` in an interactive
+/// session, see [`crate::device`]
+fn exec_device(
+    host: &str,
+    command: &str,
+    ssh: &SshOptions,
+    device: &DeviceOptions,
+    spill: Option<&Spill>,
+    tx: &Sender<Event>,
+    mut result: HostResult,
+) -> HostResult {
+    let io = ssh
+        .interactive(host, None)
+        .and_then(|cmd| Ok(Interactive::spawn(cmd)?));
+    let mut session = match io {
+        Ok(io) => device::Session::new(io, device),
+        Err(e) => {
+            result.error = Some(format!("failed to run ssh: {:#}", e));
+            return result;
+        }
+    };
+    let mut sink = Sink::new(host, Stream::Stdout, true, spill, tx);
+    let ran = run_device_commands(&mut session, command, &mut sink, &mut result);
+    (result.stdout, result.stdout_spilled) = sink.finish();
+    let status;
+    (status, result.stderr) = session.close(ran.is_ok());
+    match ran {
+        Ok(()) => result.exit_code = Some(0),
+        // ssh gave up on its own, e.g. failing to connect
+        Err(_) if status == Some(255) => result.exit_code = status,
+        Err(e) => {
+            result.exit_code = status;
+            result.error = Some(format!("device mode: {}", e));
+        }
+    }
+    result
+}
+
+/// Run `command` on a terminal on `host`, answered by `script`, see
+/// [`crate::expect`]
+fn exec_expect(
+    host: &str,
+    command: &str,
+    ssh: &SshOptions,
+    script: &HostScript,
+    spill: Option<&Spill>,
+    tx: &Sender<Event>,
+    mut result: HostResult,
+) -> HostResult {
+    // Without a command the script talks to the login shell
+    let command = Some(command).filter(|c| !c.trim().is_empty());
+    let io = ssh
+        .interactive(host, command)
+        .and_then(|cmd| Ok(Interactive::spawn(cmd)?));
+    let mut io = match io {
+        Ok(io) => io,
+        Err(e) => {
+            result.error = Some(format!("failed to run ssh: {:#}", e));
+            return result;
+        }
+    };
+    let mut sink = Sink::new(host, Stream::Stdout, true, spill, tx);
+    let ran = script.run(&mut io, &mut |line| sink.push(&mut line.to_vec()));
+    (result.stdout, result.stdout_spilled) = sink.finish();
+    let wait = match &ran {
+        Ok(timeout) => *timeout,
+        // Enough to get the status of a command that just exited
+        Err(_) => Duration::from_secs(1),
+    };
+    let status;
+    (status, result.stderr) = io.close(None, wait);
+    result.exit_code = status;
+    match ran {
+        Ok(_) if status.is_none() => {
+            result.error = Some(format!(
+                "expect: still running {}s after the script ended",
+                wait.as_secs_f64()
+            ))
+        }
+        Ok(_) => {}
+        // ssh gave up on its own, e.g. failing to connect
+        Err(_) if status == Some(255) => {}
+        Err(e) => result.error = Some(format!("expect script {:#}", e)),
+    }
+    result
+}
+
+/// Copy a file with the remote `scp` that `command` runs, see [`crate::scp`]
+fn exec_scp(
+    host: &str,
+    command: &str,
+    ssh: &SshOptions,
+    transfer: &scp::Transfer,
+    options: &RunOptions,
+    mut result: HostResult,
+) -> HostResult {
+    let mut cmd = match ssh.command(host, command) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            result.error = Some(format!("{:#}", e));
+            return result;
+        }
+    };
+    cmd.stdin(Stdio::piped());
+    if ssh.verbose {
+        eprintln!("{}: {:?}", host, cmd);
+    }
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            result.error = Some(format!("failed to run ssh: {}", e));
+            return result;
+        }
+    };
+    let (Some(stdin), Some(stdout), Some(mut stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        result.error = Some("ssh has no pipes".to_string());
+        return result;
+    };
+    let stderr = thread::spawn(move || {
+        let mut data = Vec::new();
+        let _ = stderr.read_to_end(&mut data);
+        data
+    });
+    // Closes stdin once done, which lets the remote scp exit
+    let ran = match &options.bwlimit {
+        Some(limit) => scp::run(
+            transfer,
+            limit.throttle(host, stdin),
+            limit.throttle(host, stdout),
+        ),
+        None => scp::run(transfer, stdin, stdout),
+    };
+    result.exit_code = child.wait().ok().and_then(|status| status.code());
+    result.stderr = stderr.join().unwrap_or_default();
+    match ran {
+        Ok((output, warnings)) => {
+            result.stdout = output;
+            for warning in warnings {
+                result
+                    .stderr
+                    .extend_from_slice(format!("{}\n", warning).as_bytes());
+            }
+        }
+        // ssh or the remote shell said what went wrong, e.g. failing to
+        // connect or not finding scp
+        Err(_) if result.exit_code != Some(0) && !result.stderr.trim_ascii().is_empty() => {}
+        Err(e) => result.error = Some(format!("{:#}", e)),
+    }
+    result
+}
+
+fn run_device_commands(
+    session: &mut device::Session,
+    command: &str,
+    sink: &mut Sink,
+    result: &mut HostResult,
+) -> io::Result<()> {
+    result.banner = session.login()?;
+    session.setup()?;
+    for command in device::commands(command) {
+        let mut typed = format!("{}{}\n", session.prompt, command).into_bytes();
+        sink.push(&mut typed);
+        let mut output = Vec::new();
+        session.run(command, &mut |line| {
+            output.extend_from_slice(line);
+            sink.push(&mut line.to_vec());
+        })?;
+        result.commands.push(DeviceCommand {
+            command: command.to_string(),
+            output: String::from_utf8_lossy(&output).into_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// What was read from a stream of ssh
+#[derive(Default)]
+struct Forwarded {
+    output: Vec<u8>,
+    spilled: Option<Spilled>,
+    banner: Vec<u8>,
+}
+
+/// Read `reader` line by line, passing each line to `sink`. With
+/// `banner_end`, the lines before the first one equal to it are held back
+/// as the banner, unless it never comes: then they were output after all.
+fn forward_lines(reader: impl Read, mut sink: Sink, banner_end: Option<&str>) -> Forwarded {
+    let mut reader = BufReader::new(reader);
+    let mut held = banner_end.map(|end| (end, Vec::new()));
+    let mut banner = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        match &mut held {
+            Some((end, _)) if line.strip_suffix(b"\n") == Some(end.as_bytes()) => {
+                banner = held.take().map(|(_, lines)| lines).unwrap_or_default();
+            }
+            Some((_, lines)) => lines.extend_from_slice(&line),
+            None => sink.push(&mut line),
+        }
+    }
+    if let Some((_, lines)) = held {
+        for line in lines.split_inclusive(|b| *b == b'\n') {
+            sink.push(&mut line.to_vec());
+        }
+    }
+    let (output, spilled) = sink.finish();
+    Forwarded {
+        output,
+        spilled,
+        banner,
+    }
+}
+
+/// Where the lines of a stream go: the writer, and everything that was read
+/// if `keep`. Past the threshold of `spill`, everything goes to a file and
+/// only the start is kept in memory.
+struct Sink<'a> {
+    host: &'a str,
+    stream: Stream,
+    keep: bool,
+    spill: Option<&'a Spill>,
+    tx: &'a Sender<Event>,
+    output: Vec<u8>,
+    file: Option<(BufWriter<File>, Spilled)>,
+}
+
+impl<'a> Sink<'a> {
+    fn new(
+        host: &'a str,
+        stream: Stream,
+        keep: bool,
+        spill: Option<&'a Spill>,
+        tx: &'a Sender<Event>,
+    ) -> Self {
+        Sink {
+            host,
+            stream,
+            keep,
+            spill,
+            tx,
+            output: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Take `line`, as read with its newline
+    fn push(&mut self, line: &mut Vec<u8>) {
+        let (host, stream) = (self.host, self.stream);
+        if self.keep {
+            match (&mut self.file, self.spill) {
+                (Some((writer, spilled)), _) => {
+                    match writer.write_all(line) {
+                        Ok(()) => spilled.len += line.len() as u64,
+                        Err(e) => {
+                            // What made it to the file is all there is,
+                            // the rest is only forwarded
+                            spill_failed(host, stream, &e);
+                            self.keep = false;
+                        }
+                    }
+                }
+                (None, Some(spill)) if self.output.len() + line.len() > spill.threshold => {
+                    match start_spill(spill, host, stream, &self.output, line) {
+                        Ok(started) => self.file = Some(started),
+                        Err(e) => {
+                            // Better a large memory footprint than no output
+                            spill_failed(host, stream, &e);
+                            self.output.extend_from_slice(line);
+                        }
+                    }
+                }
+                (None, _) => self.output.extend_from_slice(line),
+            }
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        let _ = self.tx.send(Event::Line {
+            host: host.to_string(),
+            stream,
+            line: line.clone(),
+        });
+    }
+
+    fn finish(self) -> (Vec<u8>, Option<Spilled>) {
+        let Some((mut writer, spilled)) = self.file else {
+            return (self.output, None);
+        };
+        if let Err(e) = writer.flush() {
+            spill_failed(self.host, self.stream, &e);
+        }
+        (self.output, Some(spilled))
+    }
+}
+
+/// Open a new spill file holding `kept` then `line`
+fn start_spill(
+    spill: &Spill,
+    host: &str,
+    stream: Stream,
+    kept: &[u8],
+    line: &[u8],
+) -> io::Result<(BufWriter<File>, Spilled)> {
+    static SPILLS: AtomicUsize = AtomicUsize::new(0);
+    let dir = spill.dir()?;
+    // Retries and stages run the same host again, every attempt gets a file
+    let n = SPILLS.fetch_add(1, Ordering::Relaxed);
+    let name = format!("{}-{}.{}", n, host.replace('/', "_"), stream.name());
+    let path = dir.join(name);
+    let mut writer = BufWriter::new(File::create(&path)?);
+    writer.write_all(kept)?;
+    writer.write_all(line)?;
+    let len = (kept.len() + line.len()) as u64;
+    Ok((writer, Spilled { path, len }))
+}
+
+fn spill_failed(host: &str, stream: Stream, e: &io::Error) {
+    eprintln!(
+        "Failed to write the {} of {} to disk: {}",
+        stream.name(),
+        host,
+        e
+    );
+}
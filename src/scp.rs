@@ -0,0 +1,185 @@
+//! The classic SCP protocol, for `push` and `fetch` with
+//! `--transfer-protocol scp` on appliances that allow `scp` but no shell to
+//! run `cat` in.
+//!
+//! The remote `scp` is run over an exec channel like any command: `scp -t
+//! DEST` receives a file, `scp -f SRC` sends the files SRC names. Both sides
+//! then talk over stdin and stdout: every file is announced with a
+//! `C<mode> <size> <name>` line followed by its contents, and each step is
+//! acknowledged with a NUL byte, or a byte 1 (warning) or 2 (error)
+//! followed by a message.
+
+use crate::ssh::quote;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+
+/// What is copied over SCP
+#[derive(Clone, Debug)]
+pub enum Transfer {
+    /// The local file `src`, saved on the remote host under `name`, with
+    /// its modification time when `mtime` is set
+    Push {
+        src: PathBuf,
+        name: String,
+        mode: u32,
+        mtime: Option<u64>,
+    },
+    /// The files the remote `scp -f` sends, all of them in a tar archive
+    /// with their modes and times when `archive`, as for globs, or else the
+    /// contents of the only one
+    Fetch { archive: bool },
+}
+
+/// Remote command receiving a file at `dest`, keeping the time it is sent
+/// with when `preserve`. Missing parent directories are not created.
+pub fn push_command(dest: &str, preserve: bool) -> String {
+    format!("scp {}-t {}", preserve_flag(preserve), quote(dest))
+}
+
+/// Remote command sending the files at `src`, an escaped glob or a quoted
+/// path, see [`crate::transfer::scp_fetch`], with their times when
+/// `preserve`
+pub fn fetch_command(src: &str, preserve: bool) -> String {
+    format!("scp {}-f {}", preserve_flag(preserve), src)
+}
+
+fn preserve_flag(preserve: bool) -> &'static str {
+    match preserve {
+        true => "-p ",
+        false => "",
+    }
+}
+
+/// Run `transfer` with the remote `scp` at the other end of `input` and
+/// `output`. Returns what a fetch received, see [`Transfer::Fetch`], and
+/// the warnings of the remote side.
+pub fn run(
+    transfer: &Transfer,
+    mut input: impl Write,
+    output: impl Read,
+) -> Result<(Vec<u8>, Vec<String>)> {
+    let mut output = BufReader::new(output);
+    match transfer {
+        Transfer::Push {
+            src,
+            name,
+            mode,
+            mtime,
+        } => {
+            let mut file =
+                File::open(src).with_context(|| format!("failed to open {}", src.display()))?;
+            let size = file.metadata()?.len();
+            ack(&mut output)?;
+            if let Some(mtime) = mtime {
+                writeln!(input, "T{} 0 {} 0", mtime, mtime)?;
+                input.flush()?;
+                ack(&mut output)?;
+            }
+            writeln!(input, "C{:04o} {} {}", mode & 0o7777, size, name)?;
+            input.flush()?;
+            ack(&mut output)?;
+            let copied = io::copy(&mut file, &mut input)?;
+            if copied != size {
+                bail!("{} changed while being sent", src.display());
+            }
+            input.write_all(&[0])?;
+            input.flush()?;
+            ack(&mut output)?;
+            Ok((Vec::new(), Vec::new()))
+        }
+        Transfer::Fetch { archive } => {
+            let mut files = Vec::new();
+            let mut warnings = Vec::new();
+            let mut mtime = 0;
+            send_ack(&mut input)?;
+            loop {
+                let mut line = Vec::new();
+                output.read_until(b'\n', &mut line)?;
+                let Some((&kind, rest)) = line.split_first() else {
+                    break;
+                };
+                let text = String::from_utf8_lossy(rest).trim_end().to_string();
+                match kind {
+                    b'C' => {
+                        let (mode, size, name) = parse_file_line(&text)
+                            .with_context(|| format!("invalid file line {:?}", text))?;
+                        send_ack(&mut input)?;
+                        let mut contents = Vec::new();
+                        (&mut output).take(size).read_to_end(&mut contents)?;
+                        if contents.len() as u64 != size {
+                            bail!("connection closed in the middle of {}", name);
+                        }
+                        ack(&mut output)?;
+                        send_ack(&mut input)?;
+                        files.push((name, mode, std::mem::take(&mut mtime), contents));
+                    }
+                    // Times of the next file, sent for `scp -p`
+                    b'T' => {
+                        mtime = text
+                            .split(' ')
+                            .next()
+                            .and_then(|t| t.parse().ok())
+                            .with_context(|| format!("invalid times line {:?}", text))?;
+                        send_ack(&mut input)?;
+                    }
+                    1 => warnings.push(text),
+                    2 => bail!(text),
+                    _ => bail!("unexpected {:?}", String::from_utf8_lossy(&line)),
+                }
+            }
+            if files.is_empty() && !warnings.is_empty() {
+                bail!(warnings.join("; "));
+            }
+            if !archive {
+                return match files.pop() {
+                    Some((_, _, _, contents)) if files.is_empty() => Ok((contents, warnings)),
+                    _ => bail!("expected a single file"),
+                };
+            }
+            let mut builder = tar::Builder::new(Vec::new());
+            for (name, mode, mtime, contents) in files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(mode);
+                header.set_mtime(mtime);
+                builder.append_data(&mut header, name, contents.as_slice())?;
+            }
+            Ok((builder.into_inner()?, warnings))
+        }
+    }
+}
+
+/// Mode, size and name of a `C<mode> <size> <name>` line, without the `C`
+fn parse_file_line(text: &str) -> Option<(u32, u64, String)> {
+    let mut parts = text.splitn(3, ' ');
+    let mode = u32::from_str_radix(parts.next()?, 8).ok()?;
+    let size = parts.next()?.parse().ok()?;
+    let name = parts.next()?;
+    // Names are saved locally, they must be plain file names
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return None;
+    }
+    Some((mode, size, name.to_string()))
+}
+
+/// Wait for the other side to acknowledge the last step
+fn ack(output: &mut impl BufRead) -> Result<()> {
+    let mut byte = [0];
+    if output.read(&mut byte)? == 0 {
+        bail!("scp closed the connection");
+    }
+    if byte[0] == 0 {
+        return Ok(());
+    }
+    let mut message = String::new();
+    output.read_line(&mut message)?;
+    bail!(message.trim_end().to_string())
+}
+
+fn send_ack(input: &mut impl Write) -> Result<()> {
+    input.write_all(&[0])?;
+    input.flush()?;
+    Ok(())
+}
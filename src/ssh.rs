@@ -0,0 +1,736 @@
+use crate::inventory::Vars;
+use crate::proxy::{Tunnel, PROXY_ENV};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Environment variable used to hand the password to the askpass helper.
+/// When it is set and the binary is invoked by ssh as `SSH_ASKPASS`, the
+/// password is printed on stdout and the process exits.
+pub const ASKPASS_ENV: &str = "MULTISSH_ASKPASS_PASSWORD";
+
+/// How the command is run on the remote host
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RemoteShell {
+    /// Sent as is, so it runs in the remote user's shell (`$SHELL -c`)
+    #[default]
+    Default,
+    /// Run in the remote user's shell as a login shell, so the profile is
+    /// loaded first (`$SHELL -lc`)
+    Login,
+    /// Passed as a single argument to the given command line, e.g.
+    /// `/bin/bash -lc`
+    Custom(String),
+    /// Split into words locally and run directly with `exec`, without any
+    /// shell interpreting them
+    None,
+}
+
+/// How a host is reached
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transport {
+    #[default]
+    Ssh,
+    /// Insecure, for devices without SSH, see [`crate::telnet`]
+    Telnet,
+}
+
+/// Addresses connected to, `-4` and `-6`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Inet,
+    Inet6,
+}
+
+impl AddressFamily {
+    /// Whether `addr` is of the family
+    pub fn allows(self, addr: &IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Inet => addr.is_ipv4(),
+            AddressFamily::Inet6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// How ssh_config says a host is reached
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Destination {
+    /// `HostName`, after `Host` aliases
+    pub hostname: String,
+    /// `ProxyCommand` or `ProxyJump` connected through, which resolves the
+    /// host name itself
+    pub proxy: Option<String>,
+    /// The `ProxyCommand` of them, as written
+    pub proxy_command: Option<String>,
+    /// `Port` connected to
+    pub port: u16,
+    /// `User` logged in as, `None` when ssh didn't say
+    pub user: Option<String>,
+}
+
+/// Options used to build the `ssh` invocation for every target host
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SshOptions {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub private_key: Option<PathBuf>,
+    pub port: u16,
+    pub connect_timeout: u64,
+    /// Seconds the command may run before ssh is stopped and the host
+    /// counted as failed, connecting included
+    #[serde(default)]
+    pub exec_timeout: Option<u64>,
+    /// Compress the connection with zlib, which helps with large text output
+    /// over slow links
+    pub compress: bool,
+    /// Seconds of inactivity before sending a keepalive through the
+    /// encrypted channel (ServerAliveInterval)
+    pub keepalive_interval: Option<u64>,
+    /// Unanswered keepalives before the connection is dropped
+    /// (ServerAliveCountMax)
+    pub keepalive_count: Option<u32>,
+    /// Directory to change to on the remote host before running the command
+    pub chdir: Option<String>,
+    /// Locale the command runs in, set as both `LANG` and `LC_ALL` so
+    /// output looks the same whatever the locale of each host
+    #[serde(default)]
+    pub lang: Option<String>,
+    pub shell: RemoteShell,
+    /// Forward the local SSH agent (`-A`), so the command can use it to
+    /// reach further hosts. Anyone with root on the host can use the agent
+    /// while the command runs.
+    #[serde(default)]
+    pub forward_agent: bool,
+    /// Agent socket used instead of `SSH_AUTH_SOCK` and the `IdentityAgent`
+    /// of ssh_config, for authenticating and for forwarding
+    #[serde(default)]
+    pub agent_socket: Option<PathBuf>,
+    /// Command connected through instead of TCP, as ssh_config's
+    /// `ProxyCommand` with `%h` and `%p`, "none" for a direct connection
+    #[serde(default)]
+    pub proxy_command: Option<String>,
+    /// Jump hosts connected through one after the other to reach the host
+    /// (ProxyJump), each `[USER@]HOST[:PORT]`
+    #[serde(default)]
+    pub jump_hosts: Vec<String>,
+    /// User on the jump hosts not given one of their own
+    #[serde(default)]
+    pub jump_user: Option<String>,
+    /// Port of the jump hosts not given one of their own
+    #[serde(default)]
+    pub jump_port: Option<u16>,
+    /// Key to log in to the jump hosts with, rather than the keys of the
+    /// agent and ssh_config
+    #[serde(default)]
+    pub jump_private_key: Option<PathBuf>,
+    /// Proxy every connection goes through, see [`crate::proxy`]
+    #[serde(default)]
+    pub tunnel: Option<Tunnel>,
+    #[serde(default)]
+    pub address_family: AddressFamily,
+    /// Local address every connection is made from
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+    /// `Ciphers` of ssh_config, e.g. the FIPS approved ones
+    #[serde(default)]
+    pub ciphers: Option<String>,
+    /// `KexAlgorithms` of ssh_config
+    #[serde(default)]
+    pub kex_algorithms: Option<String>,
+    /// `MACs` of ssh_config
+    #[serde(default)]
+    pub macs: Option<String>,
+    /// `HostKeyAlgorithms` of ssh_config, in the order they are preferred
+    #[serde(default)]
+    pub host_key_algorithms: Option<String>,
+    /// Also offer the SHA-1 and CBC algorithms of old appliances, see
+    /// [`legacy_options`]
+    #[serde(default)]
+    pub legacy_crypto: bool,
+    /// Set per host with the `transport` inventory variable
+    #[serde(default)]
+    pub transport: Transport,
+    /// Options handed to ssh as `-o KEY=VALUE`, before the ones multissh
+    /// sets so they win over them
+    #[serde(default)]
+    pub extra_options: Vec<String>,
+    pub verbose: bool,
+    /// Have ssh log its progress on stderr (`-v`), which `multissh bench`
+    /// reads to time each step of the connection
+    pub debug: bool,
+}
+
+impl SshOptions {
+    /// Options for a single host, with inventory variables overriding the
+    /// options given on the command line, e.g. a longer `connect_timeout`
+    /// and `exec_timeout` for hosts behind slow links. Fails on variables
+    /// that can't be used, such as a port out of range.
+    pub fn with_vars(&self, vars: &Vars) -> Result<SshOptions> {
+        let mut options = self.clone();
+        if let Some(user) = vars.get("user").and_then(|v| v.as_str()) {
+            options.user = Some(user.to_string());
+        }
+        if vars.get("transport").and_then(|v| v.as_str()) == Some("telnet") {
+            options.transport = Transport::Telnet;
+            options.port = crate::telnet::DEFAULT_PORT;
+        }
+        if let Some(port) = port_var(vars, "port")? {
+            options.port = port;
+        }
+        if let Some(key) = vars.get("private_key").and_then(|v| v.as_str()) {
+            options.private_key = Some(crate::paths::expand(Path::new(key)));
+        }
+        if let Some(forward_agent) = vars.get("forward_agent").and_then(|v| v.as_bool()) {
+            options.forward_agent = forward_agent;
+        }
+        if let Some(legacy) = vars.get("legacy_crypto").and_then(|v| v.as_bool()) {
+            options.legacy_crypto = legacy;
+        }
+        if let Some(hops) = vars.get("jump_host").and_then(jump_chain) {
+            options.jump_hosts = hops;
+        }
+        if let Some(user) = vars.get("jump_user").and_then(|v| v.as_str()) {
+            options.jump_user = Some(user.to_string());
+        }
+        if let Some(port) = port_var(vars, "jump_port")? {
+            options.jump_port = Some(port);
+        }
+        if let Some(key) = vars.get("jump_private_key").and_then(|v| v.as_str()) {
+            options.jump_private_key = Some(crate::paths::expand(Path::new(key)));
+        }
+        if let Some(proxy) = vars.get("proxy_command").and_then(|v| v.as_str()) {
+            options.proxy_command = Some(proxy.to_string());
+        }
+        if let Some(socket) = vars.get("agent_socket").and_then(|v| v.as_str()) {
+            options.agent_socket = Some(crate::paths::expand(Path::new(socket)));
+        }
+        if let Some(timeout) = vars.get("connect_timeout").and_then(|v| v.as_u64()) {
+            options.connect_timeout = timeout;
+        }
+        if let Some(timeout) = vars.get("exec_timeout").and_then(|v| v.as_u64()) {
+            options.exec_timeout = Some(timeout);
+        }
+        Ok(options)
+    }
+
+    /// Hostname ssh will actually connect to for `host`, after applying
+    /// ssh_config (`Host` aliases, `HostName`, ...). Falls back to `host`
+    /// when ssh can't tell.
+    pub fn hostname(&self, host: &str) -> String {
+        self.destination(host).hostname
+    }
+
+    /// How ssh reaches `host` after applying ssh_config: its
+    /// [`hostname`](Self::hostname) and the proxy in the way, if any
+    pub fn destination(&self, host: &str) -> Destination {
+        let output = Command::new("ssh")
+            .args(self.config_options().iter().flat_map(|o| ["-o", o]))
+            .arg("-G")
+            .arg("-p")
+            .arg(self.port.to_string())
+            .args(self.user.iter().flat_map(|user| ["-l", user]))
+            .arg("--")
+            .arg(host)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        let config = output
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .unwrap_or_default();
+        let value = |key: &str| {
+            config
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+                .map(str::to_string)
+        };
+        let proxy_command = value("proxycommand").filter(|proxy| proxy != "none");
+        Destination {
+            hostname: value("hostname").unwrap_or_else(|| host.to_string()),
+            proxy: proxy_command
+                .clone()
+                .or_else(|| value("proxyjump"))
+                .filter(|proxy| proxy != "none"),
+            proxy_command,
+            port: value("port")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(self.port),
+            user: value("user").or_else(|| self.user.clone()),
+        }
+    }
+
+    /// Options of ssh_config given as `-o`: the ones given as is first, so
+    /// they win, then the ones multissh has flags for
+    fn config_options(&self) -> Vec<String> {
+        let mut options = self.extra_options.clone();
+        if let Some(proxy) = &self.proxy_command {
+            options.push(format!("ProxyCommand={}", proxy));
+        }
+        options.extend(self.jump_option());
+        // Racing the addresses is only for hosts ssh_config doesn't proxy,
+        // which ssh() asks destination() about
+        let tunnel = self.tunnel.as_ref().filter(|t| **t != Tunnel::Direct);
+        let timeout = self.connect_timeout;
+        if let Some(proxy) = tunnel.and_then(|t| t.proxy_command(timeout, self.bind_address)) {
+            options.push(format!("ProxyCommand={}", proxy));
+        }
+        if let Some(bind) = self.bind_address {
+            options.push(format!("BindAddress={}", bind));
+        }
+        options.extend(self.algorithm_options());
+        if self.legacy_crypto {
+            // After the lists given, which win over these additions
+            options.extend(legacy_options().iter().cloned());
+        }
+        match self.address_family {
+            AddressFamily::Any => {}
+            AddressFamily::Inet => options.push("AddressFamily=inet".to_string()),
+            AddressFamily::Inet6 => options.push("AddressFamily=inet6".to_string()),
+        }
+        options
+    }
+
+    /// The algorithms to negotiate, as options of ssh_config
+    fn algorithm_options(&self) -> Vec<String> {
+        [
+            ("Ciphers", &self.ciphers),
+            ("KexAlgorithms", &self.kex_algorithms),
+            ("MACs", &self.macs),
+            ("HostKeyAlgorithms", &self.host_key_algorithms),
+        ]
+        .into_iter()
+        .filter_map(|(key, list)| list.as_ref().map(|list| format!("{}={}", key, list)))
+        .collect()
+    }
+
+    /// Fail on algorithms ssh doesn't know, before every host fails on
+    /// them; ssh checks the lists itself, with `-G` to connect nowhere
+    pub fn check_algorithms(&self) -> Result<()> {
+        let options = self.algorithm_options();
+        if options.is_empty() {
+            return Ok(());
+        }
+        let output = Command::new("ssh")
+            .args(options.iter().flat_map(|o| ["-o", o]))
+            .args(["-G", "localhost"])
+            .stdin(Stdio::null())
+            .output()
+            .context("Failed to run ssh")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let errors: Vec<&str> = stderr
+                .lines()
+                .map(|line| line.trim_start_matches("command-line line 0: "))
+                .collect();
+            bail!("Invalid algorithms: {}", errors.join("; "));
+        }
+        Ok(())
+    }
+
+    /// ProxyJump through the jump hosts, with the jump user and port for
+    /// hops without theirs. With a jump key it's a ProxyCommand running ssh
+    /// with the key instead, as ssh gives the hops of ProxyJump no keys;
+    /// the hops before the last one then log in with the agent and
+    /// ssh_config. With a bind address every hop is a ProxyCommand, as
+    /// ssh gives them no bind address either.
+    fn jump_option(&self) -> Option<String> {
+        let mut hops: Vec<Hop> = self
+            .jump_hosts
+            .iter()
+            .filter_map(|hop| Hop::parse(hop).ok())
+            .map(|hop| Hop {
+                user: hop.user.or_else(|| self.jump_user.clone()),
+                port: hop.port.or(self.jump_port),
+                ..hop
+            })
+            .collect();
+        if hops.is_empty() {
+            return None;
+        }
+        if self.jump_private_key.is_none() && self.bind_address.is_none() {
+            let hops: Vec<String> = hops.iter().map(Hop::to_string).collect();
+            return Some(format!("ProxyJump={}", hops.join(",")));
+        }
+        let last = hops.pop()?;
+        let key = self.jump_private_key.as_deref();
+        Some(format!(
+            "ProxyCommand={}",
+            self.hop_command(&hops, &last, key)
+        ))
+    }
+
+    /// ssh connecting through `last` to `%h` and `%p`, after the hops before
+    /// it, logging in to `last` with `key` if given
+    fn hop_command(&self, hops: &[Hop], last: &Hop, key: Option<&Path>) -> String {
+        let mut command = vec!["ssh".to_string()];
+        if let Some(key) = key {
+            command.extend(["-i".to_string(), quote(&key.to_string_lossy())]);
+        }
+        command.extend(["-o".to_string(), "BatchMode=yes".to_string()]);
+        if let Some(bind) = self.bind_address {
+            command.extend(["-b".to_string(), bind.to_string()]);
+        }
+        if let Some((before, earlier)) = hops.split_last() {
+            command.extend(match self.bind_address {
+                // ssh expands the tokens of the command it runs, %% to %,
+                // so the ones of the inner command survive for its own ssh
+                Some(_) => {
+                    let inner = self.hop_command(earlier, before, None);
+                    let inner = format!("ProxyCommand={}", inner.replace('%', "%%"));
+                    ["-o".to_string(), quote(&inner)]
+                }
+                None => {
+                    let hops: Vec<String> = hops.iter().map(Hop::to_string).collect();
+                    ["-J".to_string(), quote(&hops.join(","))]
+                }
+            });
+        }
+        if let Some(user) = &last.user {
+            command.extend(["-l".to_string(), quote(user)]);
+        }
+        if let Some(port) = last.port {
+            command.extend(["-p".to_string(), port.to_string()]);
+        }
+        let host = last.host.trim_start_matches('[').trim_end_matches(']');
+        command.extend(["-W".to_string(), "'[%h]:%p'".to_string()]);
+        command.extend(["--".to_string(), quote(host)]);
+        command.join(" ")
+    }
+
+    /// Command line sent to the remote host to run `command`
+    pub fn remote_command(&self, command: &str) -> Result<String> {
+        let command = match &self.shell {
+            RemoteShell::Default => command.to_string(),
+            RemoteShell::Login => format!("exec \"$SHELL\" -lc {}", quote(command)),
+            RemoteShell::Custom(shell) => format!("exec {} {}", shell, quote(command)),
+            RemoteShell::None => {
+                let words = shell_words::split(command)
+                    .with_context(|| format!("Failed to split command into words: {}", command))?;
+                let words: Vec<String> = words.iter().map(|w| quote(w)).collect();
+                format!("exec {}", words.join(" "))
+            }
+        };
+        let command = match &self.chdir {
+            // `exit` without a status keeps the one from `cd`, and the
+            // newline keeps `command` intact whatever it contains
+            Some(dir) => format!("cd -- {} || exit\n{}", quote(dir), command),
+            None => command,
+        };
+        Ok(match &self.lang {
+            Some(lang) => format!("export LANG={0} LC_ALL={0}\n{1}", quote(lang), command),
+            None => command,
+        })
+    }
+
+    /// Build the `ssh` process that runs `remote_command` on `host`.
+    /// Stdin is closed and both stdout and stderr are piped.
+    pub fn command(&self, host: &str, remote_command: &str) -> Result<Command> {
+        let mut cmd = self.ssh(host)?;
+        cmd.arg("--")
+            .arg(host)
+            .arg(self.remote_command(remote_command)?);
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        Ok(cmd)
+    }
+
+    /// Build the `ssh` process running `remote_command` on `host` on a
+    /// terminal, or opening an interactive session without it, for devices
+    /// without exec channels. All of stdin, stdout and stderr are piped.
+    pub fn interactive(&self, host: &str, remote_command: Option<&str>) -> Result<Command> {
+        let mut cmd = self.ssh(host)?;
+        // A terminal even though stdin isn't one
+        cmd.arg("-tt").arg("--").arg(host);
+        if let Some(remote_command) = remote_command {
+            cmd.arg(self.remote_command(remote_command)?);
+        }
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        Ok(cmd)
+    }
+
+    /// The ProxyCommand of `host` when multissh has to set it itself: the
+    /// one racing the addresses, or with a password whichever ssh would
+    /// run, without the password in its environment. That is for the
+    /// askpass helper only, not for the proxy and what it runs.
+    fn proxy_command_for(&self, host: &str) -> Option<String> {
+        let direct = self.tunnel == Some(Tunnel::Direct);
+        if !direct && self.password.is_none() {
+            return None;
+        }
+        let destination = self.destination(host);
+        let proxy = match destination.proxy {
+            None if direct => Tunnel::Direct.proxy_command(self.connect_timeout, self.bind_address),
+            _ => destination.proxy_command,
+        }?;
+        Some(match self.password {
+            Some(_) => format!("env -u {} sh -c {}", ASKPASS_ENV, quote(&proxy)),
+            None => proxy,
+        })
+    }
+
+    /// `ssh` with every connection option, up to the host
+    fn ssh(&self, host: &str) -> Result<Command> {
+        if self.transport == Transport::Telnet {
+            bail!(
+                "{} is reached over telnet, which can only run commands",
+                host
+            );
+        }
+        let mut cmd = Command::new("ssh");
+        // ssh keeps the first value it gets for an option, so this one wins
+        if let Some(proxy) = self.proxy_command_for(host) {
+            cmd.arg("-o").arg(format!("ProxyCommand={}", proxy));
+        }
+        for option in self.config_options() {
+            cmd.arg("-o").arg(option);
+        }
+        cmd.arg("-p").arg(self.port.to_string());
+        cmd.arg("-o")
+            .arg(format!("ConnectTimeout={}", self.connect_timeout));
+        if self.compress {
+            cmd.arg("-C");
+        }
+        if self.debug {
+            cmd.arg("-v");
+        }
+        if let Some(tunnel) = &self.tunnel {
+            cmd.env(PROXY_ENV, tunnel.to_string());
+        }
+        if let Some(socket) = &self.agent_socket {
+            // The environment is what -A forwards, the option wins over
+            // ssh_config; quoted for paths with spaces
+            cmd.env("SSH_AUTH_SOCK", socket);
+            cmd.arg("-o")
+                .arg(format!("IdentityAgent=\"{}\"", socket.display()));
+        }
+        if self.forward_agent {
+            cmd.arg("-A");
+        }
+        if let Some(interval) = self.keepalive_interval {
+            cmd.arg("-o")
+                .arg(format!("ServerAliveInterval={}", interval));
+        }
+        if let Some(count) = self.keepalive_count {
+            cmd.arg("-o").arg(format!("ServerAliveCountMax={}", count));
+        }
+        if let Some(user) = &self.user {
+            cmd.arg("-l").arg(user);
+        }
+        if let Some(private_key) = &self.private_key {
+            cmd.arg("-i").arg(private_key);
+        }
+        match &self.password {
+            Some(password) => {
+                // Let ssh ask us for the password instead of the terminal
+                if let Ok(exe) = std::env::current_exe() {
+                    cmd.env("SSH_ASKPASS", exe);
+                }
+                cmd.env("SSH_ASKPASS_REQUIRE", "force");
+                cmd.env(ASKPASS_ENV, password);
+                cmd.arg("-o").arg("BatchMode=no");
+            }
+            None => {
+                cmd.arg("-o").arg("BatchMode=yes");
+            }
+        }
+        Ok(cmd)
+    }
+}
+
+/// Algorithms of old switches and iLO/iDRAC interfaces, which OpenSSH no
+/// longer offers by default, for hosts with `legacy_crypto`: by kind of
+/// `ssh -Q`, the options they are added to and the algorithms
+const LEGACY_ALGORITHMS: &[(&str, &[&str], &[&str])] = &[
+    (
+        "kex",
+        &["KexAlgorithms"],
+        &[
+            "diffie-hellman-group14-sha1",
+            "diffie-hellman-group-exchange-sha1",
+            "diffie-hellman-group1-sha1",
+        ],
+    ),
+    (
+        "key-sig",
+        &["HostKeyAlgorithms", "PubkeyAcceptedAlgorithms"],
+        &["ssh-rsa", "ssh-dss"],
+    ),
+    (
+        "cipher",
+        &["Ciphers"],
+        &["aes128-cbc", "aes256-cbc", "3des-cbc"],
+    ),
+];
+
+/// Options adding the legacy algorithms the local ssh still supports to
+/// the ones it offers (`+`); asked once, as newer releases drop some and
+/// refuse options naming them
+pub fn legacy_options() -> &'static [String] {
+    static OPTIONS: OnceLock<Vec<String>> = OnceLock::new();
+    OPTIONS.get_or_init(|| {
+        let mut options = Vec::new();
+        for (query, keys, algorithms) in LEGACY_ALGORITHMS {
+            let output = Command::new("ssh")
+                .args(["-Q", query])
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output();
+            let supported = output
+                .ok()
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+                .unwrap_or_default();
+            let supported: Vec<&str> = algorithms
+                .iter()
+                .copied()
+                .filter(|algorithm| supported.lines().any(|line| line.trim() == *algorithm))
+                .collect();
+            if !supported.is_empty() {
+                for key in *keys {
+                    options.push(format!("{}=+{}", key, supported.join(",")));
+                }
+            }
+        }
+        options
+    })
+}
+
+/// A jump host, `[USER@]HOST[:PORT]`
+struct Hop {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+}
+
+impl Hop {
+    fn parse(text: &str) -> Result<Hop> {
+        let invalid =
+            || anyhow::anyhow!("Invalid jump host {:?}, expected [USER@]HOST[:PORT]", text);
+        let (user, rest) = match text.rsplit_once('@') {
+            Some((user, rest)) => (Some(user.to_string()), rest),
+            None => (None, text),
+        };
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) if !rest.ends_with(']') => {
+                (host, Some(port.parse().map_err(|_| invalid())?))
+            }
+            _ => (rest, None),
+        };
+        if host.is_empty() || text.contains(char::is_whitespace) || user.as_deref() == Some("") {
+            return Err(invalid());
+        }
+        Ok(Hop {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        write!(f, "{}", self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+/// The hops of a chain of jump hosts like `bastion1,admin@bastion2:2222`,
+/// in the order they are connected through
+pub fn jump_hosts(chain: &str) -> Result<Vec<String>> {
+    let hops = crate::targets::split_list(chain);
+    for hop in &hops {
+        Hop::parse(hop)?;
+    }
+    Ok(hops)
+}
+
+/// The port number the variable `name` of a host is set to, if any
+fn port_var(vars: &Vars, name: &str) -> Result<Option<u16>> {
+    let Some(value) = vars.get(name) else {
+        return Ok(None);
+    };
+    match value.as_u64().and_then(|port| u16::try_from(port).ok()) {
+        Some(port) if port != 0 => Ok(Some(port)),
+        _ => bail!(
+            "{} must be a port number (1-65535), got {}",
+            name,
+            serde_yaml::to_string(value).unwrap_or_default().trim_end()
+        ),
+    }
+}
+
+/// The hops of the `jump_host` inventory variable: a chain like `-J`, a
+/// list of hops, or "none" for none. `None` when it's none of these.
+pub fn jump_chain(value: &Value) -> Option<Vec<String>> {
+    let hops = match value {
+        Value::String(chain) if chain == "none" => Vec::new(),
+        Value::String(chain) => crate::targets::split_list(chain),
+        Value::Sequence(hops) => hops
+            .iter()
+            .map(|hop| hop.as_str().map(str::to_string))
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    hops.iter()
+        .all(|hop| Hop::parse(hop).is_ok())
+        .then_some(hops)
+}
+
+/// Quote `word` for a POSIX shell, leaving it as is when that's safe
+pub fn quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(yaml: &str) -> Vars {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn with_vars_checks_ports() {
+        let ssh = SshOptions {
+            port: 22,
+            ..Default::default()
+        };
+        assert_eq!(ssh.with_vars(&vars("port: 2222")).unwrap().port, 2222);
+        assert_eq!(ssh.with_vars(&vars("user: deploy")).unwrap().port, 22);
+        for port in ["65558", "0", "-1", "'2222'", "~"] {
+            let e = ssh
+                .with_vars(&vars(&format!("port: {}", port)))
+                .unwrap_err();
+            assert!(
+                e.to_string().starts_with("port must be a port number"),
+                "{}",
+                e
+            );
+        }
+        let jump = ssh.with_vars(&vars("jump_port: 2200")).unwrap();
+        assert_eq!(jump.jump_port, Some(2200));
+        let e = ssh.with_vars(&vars("jump_port: 70000")).unwrap_err();
+        assert!(e.to_string().starts_with("jump_port must be a port number"));
+    }
+}
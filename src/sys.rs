@@ -0,0 +1,331 @@
+//! System summaries for `multissh sys`: a probe run on every host, parsed
+//! into a table with a row per host (or per filesystem for `disk`) that can
+//! be sorted by any column, so the fullest disk of the fleet is on top
+//! instead of somewhere in hundreds of `df` outputs.
+//!
+//! The probes read `/proc`, so `mem`, `load` and `uptime` need Linux hosts.
+
+use crate::runner::HostResult;
+use anyhow::{bail, Context, Result};
+use std::cmp::Ordering;
+
+/// What `multissh sys` shows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preset {
+    /// Filesystem usage, one row per filesystem, fullest first
+    Disk,
+    /// Memory and swap usage, most used first
+    Mem,
+    /// Load averages against the number of CPUs, busiest first
+    Load,
+    /// Time since boot, longest first
+    Uptime,
+}
+
+/// Filesystems left out of `disk`: memory and kernel ones
+const PSEUDO_FILESYSTEMS: &[&str] = &["tmpfs", "devtmpfs", "udev", "none", "overlay", "shm"];
+const PSEUDO_MOUNTS: &[&str] = &["/dev", "/run", "/sys", "/proc", "/snap"];
+
+/// A table cell: numbers sort by value, everything else by text
+#[derive(Clone, Debug)]
+pub enum Cell {
+    Text(String),
+    Number { value: f64, text: String },
+}
+
+impl Cell {
+    fn text(&self) -> &str {
+        match self {
+            Cell::Text(text) | Cell::Number { text, .. } => text,
+        }
+    }
+
+    fn number(value: f64, text: String) -> Cell {
+        Cell::Number { value, text }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Table {
+    pub columns: Vec<&'static str>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Preset {
+    /// Remote command printing what [`Preset::parse`] reads
+    pub fn command(self) -> &'static str {
+        match self {
+            Preset::Disk => "df -P -k",
+            Preset::Mem => "cat /proc/meminfo",
+            Preset::Load => {
+                "cat /proc/loadavg && { nproc 2>/dev/null || getconf _NPROCESSORS_ONLN; }"
+            }
+            Preset::Uptime => "cat /proc/uptime",
+        }
+    }
+
+    /// Columns after the host column
+    pub fn columns(self) -> &'static [&'static str] {
+        match self {
+            Preset::Disk => &["mount", "size", "used", "avail", "use%"],
+            Preset::Mem => &["total", "used", "avail", "use%", "swap", "swap%"],
+            Preset::Load => &["load1", "load5", "load15", "cpus", "load/cpu"],
+            Preset::Uptime => &["uptime", "days"],
+        }
+    }
+
+    /// Column sorted by when none is given
+    pub fn default_sort(self) -> &'static str {
+        match self {
+            Preset::Disk | Preset::Mem => "use%",
+            Preset::Load => "load/cpu",
+            Preset::Uptime => "days",
+        }
+    }
+
+    /// Rows of the probe output of a host, without the host column
+    pub fn parse(self, output: &str) -> Result<Vec<Vec<Cell>>> {
+        match self {
+            Preset::Disk => parse_df(output),
+            Preset::Mem => parse_meminfo(output).map(|row| vec![row]),
+            Preset::Load => parse_loadavg(output).map(|row| vec![row]),
+            Preset::Uptime => parse_uptime(output).map(|row| vec![row]),
+        }
+    }
+
+    /// Table of the hosts the probe succeeded on, and the hosts whose output
+    /// couldn't be read with why
+    pub fn table(self, results: &[HostResult]) -> (Table, Vec<(String, String)>) {
+        let mut columns = vec!["host"];
+        columns.extend(self.columns());
+        let mut table = Table {
+            columns,
+            rows: Vec::new(),
+        };
+        let mut unreadable = Vec::new();
+        for result in results.iter().filter(|r| r.success()) {
+            match self.parse(&String::from_utf8_lossy(&result.stdout)) {
+                Ok(rows) => table.rows.extend(rows.into_iter().map(|row| {
+                    let mut cells = vec![Cell::Text(result.host.clone())];
+                    cells.extend(row);
+                    cells
+                })),
+                Err(e) => unreadable.push((result.host.clone(), format!("{:#}", e))),
+            }
+        }
+        (table, unreadable)
+    }
+}
+
+impl Table {
+    /// Sort by `column`: numbers largest first and text in alphabetical
+    /// order, or the other way around when `reverse`. Ties keep their order.
+    pub fn sort(&mut self, column: &str, reverse: bool) -> Result<()> {
+        let Some(index) = self.columns.iter().position(|c| *c == column) else {
+            bail!(
+                "No column {}, sort by one of {}",
+                column,
+                self.columns.join(", ")
+            );
+        };
+        self.rows.sort_by(|a, b| {
+            let order = match (&a[index], &b[index]) {
+                (Cell::Number { value: a, .. }, Cell::Number { value: b, .. }) => {
+                    b.partial_cmp(a).unwrap_or(Ordering::Equal)
+                }
+                (a, b) => a.text().cmp(b.text()),
+            };
+            if reverse {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+        Ok(())
+    }
+
+    /// The table with aligned columns, numbers right-aligned
+    pub fn render(&self) -> String {
+        let mut widths: Vec<usize> = self.columns.iter().map(|c| c.len()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.text().len());
+            }
+        }
+        let numeric: Vec<bool> = (0..self.columns.len())
+            .map(|i| {
+                self.rows
+                    .iter()
+                    .any(|row| matches!(row[i], Cell::Number { .. }))
+            })
+            .collect();
+        let mut out = String::new();
+        let header: Vec<Cell> = self
+            .columns
+            .iter()
+            .map(|c| Cell::Text(c.to_string()))
+            .collect();
+        for row in std::iter::once(&header).chain(&self.rows) {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .zip(&numeric)
+                .map(|((cell, width), numeric)| match numeric {
+                    true => format!("{:>width$}", cell.text()),
+                    false => format!("{:width$}", cell.text()),
+                })
+                .collect();
+            out.push_str(cells.join("  ").trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// `df -P -k`: filesystem, 1024-blocks, used, available, capacity, mount
+fn parse_df(output: &str) -> Result<Vec<Vec<Cell>>> {
+    let mut rows = Vec::new();
+    for line in output.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 {
+            continue;
+        }
+        let mount = fields[5..].join(" ");
+        let pseudo = PSEUDO_FILESYSTEMS.contains(&fields[0])
+            || PSEUDO_MOUNTS
+                .iter()
+                .any(|m| mount == *m || mount.starts_with(&format!("{}/", m)));
+        if pseudo {
+            continue;
+        }
+        let kib = |field: &str| -> Result<f64> {
+            field
+                .parse::<f64>()
+                .with_context(|| format!("Unexpected df output: {}", line))
+        };
+        let (size, used, avail) = (kib(fields[1])?, kib(fields[2])?, kib(fields[3])?);
+        // Like df: used against what non-root users can use
+        let percent = match used + avail {
+            total if total > 0.0 => used * 100.0 / total,
+            _ => 0.0,
+        };
+        rows.push(vec![
+            Cell::Text(mount),
+            Cell::number(size, human_size(size * 1024.0)),
+            Cell::number(used, human_size(used * 1024.0)),
+            Cell::number(avail, human_size(avail * 1024.0)),
+            Cell::number(percent, format!("{:.0}%", percent)),
+        ]);
+    }
+    if rows.is_empty() {
+        bail!("No filesystems in the df output");
+    }
+    Ok(rows)
+}
+
+/// `/proc/meminfo`, in kB
+fn parse_meminfo(output: &str) -> Result<Vec<Cell>> {
+    let field = |name: &str| -> Result<f64> {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.split_whitespace().next()?.parse().ok())
+            .with_context(|| format!("No {} in /proc/meminfo", name))
+    };
+    let total = field("MemTotal")?;
+    let avail = field("MemAvailable")?;
+    let swap_total = field("SwapTotal")?;
+    let swap_used = swap_total - field("SwapFree")?;
+    let percent = |part: f64, total: f64| {
+        if total > 0.0 {
+            part * 100.0 / total
+        } else {
+            0.0
+        }
+    };
+    let used = total - avail;
+    Ok(vec![
+        Cell::number(total, human_size(total * 1024.0)),
+        Cell::number(used, human_size(used * 1024.0)),
+        Cell::number(avail, human_size(avail * 1024.0)),
+        Cell::number(
+            percent(used, total),
+            format!("{:.0}%", percent(used, total)),
+        ),
+        Cell::number(swap_used, human_size(swap_used * 1024.0)),
+        Cell::number(
+            percent(swap_used, swap_total),
+            format!("{:.0}%", percent(swap_used, swap_total)),
+        ),
+    ])
+}
+
+/// `/proc/loadavg` then the number of CPUs
+fn parse_loadavg(output: &str) -> Result<Vec<Cell>> {
+    let mut lines = output.lines();
+    let loads: Vec<f64> = lines
+        .next()
+        .unwrap_or_default()
+        .split_whitespace()
+        .take(3)
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .context("Unexpected /proc/loadavg")?;
+    if loads.len() < 3 {
+        bail!("Unexpected /proc/loadavg");
+    }
+    let cpus: f64 = lines
+        .next()
+        .and_then(|line| line.trim().parse().ok())
+        .filter(|cpus| *cpus > 0.0)
+        .context("Could not read the number of CPUs")?;
+    let mut row: Vec<Cell> = loads
+        .iter()
+        .map(|load| Cell::number(*load, format!("{:.2}", load)))
+        .collect();
+    row.push(Cell::number(cpus, cpus.to_string()));
+    row.push(Cell::number(
+        loads[0] / cpus,
+        format!("{:.2}", loads[0] / cpus),
+    ));
+    Ok(row)
+}
+
+/// `/proc/uptime`: seconds since boot first
+fn parse_uptime(output: &str) -> Result<Vec<Cell>> {
+    let seconds: f64 = output
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse().ok())
+        .context("Unexpected /proc/uptime")?;
+    let days = seconds / 86400.0;
+    Ok(vec![
+        Cell::Text(human_duration(seconds as u64)),
+        Cell::number(days, format!("{:.1}", days)),
+    ])
+}
+
+/// Bytes in binary units, e.g. `12.3G`
+pub fn human_size(bytes: f64) -> String {
+    let mut size = bytes;
+    for unit in ["B", "K", "M", "G", "T"] {
+        if size < 1024.0 {
+            return match unit {
+                "B" => format!("{:.0}{}", size, unit),
+                _ => format!("{:.1}{}", size, unit),
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1}P", size)
+}
+
+/// Seconds as e.g. `12d 3h` or `4h 10m`
+fn human_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
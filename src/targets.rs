@@ -0,0 +1,352 @@
+use crate::config::Aliases;
+use crate::inventory::Vars;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A host to run the command on
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    pub host: String,
+    /// Variables from the inventory, group variables merged under host
+    /// variables. Empty for targets given with -t/--targets or a targets file.
+    pub vars: Vars,
+}
+
+impl Target {
+    pub fn new(host: impl Into<String>) -> Target {
+        Target {
+            host: host.into(),
+            vars: Vars::new(),
+        }
+    }
+}
+
+/// Read the targets listed in a targets file, one per line. Lines starting
+/// with `#` are comments, except `#include <path>` which reads the targets of
+/// another file in place. The path is relative to the including file and may
+/// be a glob (e.g. `#include racks/*.txt`), whose matches are read in name
+/// order.
+pub fn read_targets_file(targets_file: &PathBuf) -> Result<Vec<String>> {
+    // Read targets from file
+    if Path::new(targets_file).exists() {
+        let mut targets = Vec::new();
+        read_targets_into(targets_file, &mut Vec::new(), &mut targets)?;
+        return Ok(targets);
+    }
+    bail!("File not found: {}", targets_file.display());
+}
+
+/// Append the targets of `path` to `targets`, following includes. `stack`
+/// holds the files currently being read, to reject include cycles.
+fn read_targets_into(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    targets: &mut Vec<String>,
+) -> Result<()> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Failed to read targets file {}", path.display()))?;
+    if stack.contains(&canonical) {
+        bail!("Include cycle through {}", path.display());
+    }
+    let lines = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read targets file {}", path.display()))?;
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for (number, line) in lines.lines().enumerate() {
+        let line = line.trim();
+        let include = line
+            .strip_prefix("#include")
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+        if let Some(pattern) = include {
+            let pattern = pattern.trim();
+            let location = || format!("{}:{}", path.display(), number + 1);
+            if pattern.is_empty() {
+                bail!("{}: #include without a path", location());
+            }
+            let pattern = dir.join(crate::paths::expand(Path::new(pattern)));
+            for included in include_paths(&pattern).with_context(location)? {
+                read_targets_into(&included, stack, targets).with_context(location)?;
+            }
+        } else if !line.starts_with('#') {
+            targets.push(line.to_string());
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Files matched by an `#include` path. A glob may match nothing, a plain
+/// path must exist.
+fn include_paths(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let pattern = pattern.to_string_lossy();
+    if !pattern.contains(['*', '?', '[']) {
+        let path = PathBuf::from(pattern.as_ref());
+        if !path.exists() {
+            bail!("File not found: {}", path.display());
+        }
+        return Ok(vec![path]);
+    }
+    let mut paths = glob::glob(&pattern)
+        .with_context(|| format!("Invalid include pattern {}", pattern))?
+        .filter_map(|entry| entry.ok())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Most hosts a single range may expand to, to catch typos like
+/// `web[1-100000]`
+const MAX_RANGE: usize = 10_000;
+
+/// Split a comma-separated list of targets, keeping commas inside ranges
+/// like `web[1,3,5]`
+pub fn split_list(list: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut depth = 0;
+    for c in list.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(std::mem::take(&mut item));
+                continue;
+            }
+            _ => {}
+        }
+        item.push(c);
+    }
+    items.push(item);
+    items
+        .into_iter()
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Host names given by a comma-separated list of targets, expanding
+/// `@alias` references and ranges (e.g. `@cache,web[01-03],db1` with
+/// `cache = "redis-[1-2]"` is redis-1, redis-2, web01, web02, web03, db1)
+pub fn expand(list: &str, aliases: &Aliases) -> Result<Vec<String>> {
+    let mut hosts = Vec::new();
+    for item in split_list(list) {
+        expand_into(&item, aliases, &mut Vec::new(), &mut hosts)?;
+    }
+    Ok(hosts)
+}
+
+/// Append the hosts of a single target to `hosts`. `stack` holds the aliases
+/// currently being expanded, to reject aliases referencing themselves.
+fn expand_into(
+    item: &str,
+    aliases: &Aliases,
+    stack: &mut Vec<String>,
+    hosts: &mut Vec<String>,
+) -> Result<()> {
+    let Some(name) = item.strip_prefix('@') else {
+        hosts.extend(expand_ranges(item)?);
+        return Ok(());
+    };
+    if stack.iter().any(|n| n == name) {
+        bail!("Alias @{} references itself", name);
+    }
+    let Some(items) = aliases.get(name) else {
+        bail!(
+            "Unknown alias @{}, aliases are defined in the config file",
+            name
+        );
+    };
+    stack.push(name.to_string());
+    for item in items {
+        expand_into(item, aliases, stack, hosts).with_context(|| format!("In alias @{}", name))?;
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Expand the ranges in a host name: `[1-3]`, `[01-10]` (zero-padded),
+/// `[a-c]` and lists like `[1,4-6]`. Brackets with anything else in them,
+/// like IPv6 addresses, are left as they are.
+pub fn expand_ranges(name: &str) -> Result<Vec<String>> {
+    let Some(start) = name.find('[') else {
+        return Ok(vec![name.to_string()]);
+    };
+    let Some(len) = name[start..].find(']') else {
+        return Ok(vec![name.to_string()]);
+    };
+    let (prefix, inner, rest) = (
+        &name[..start],
+        &name[start + 1..start + len],
+        &name[start + len + 1..],
+    );
+    if inner.is_empty()
+        || !inner
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ',' || c == '-')
+    {
+        return Ok(vec![name.to_string()]);
+    }
+    let mut values = Vec::new();
+    for part in inner.split(',') {
+        values.extend(range_values(part).with_context(|| format!("Invalid range in {}", name))?);
+    }
+    let rests = expand_ranges(rest)?;
+    if values.len() * rests.len() > MAX_RANGE {
+        bail!("{} expands to more than {} hosts", name, MAX_RANGE);
+    }
+    Ok(values
+        .iter()
+        .flat_map(|value| {
+            rests
+                .iter()
+                .map(move |rest| format!("{}{}{}", prefix, value, rest))
+        })
+        .collect())
+}
+
+/// Values of one part of a range: `7`, `01-10` or `a-c`
+fn range_values(part: &str) -> Result<Vec<String>> {
+    let Some((first, last)) = part.split_once('-') else {
+        if part.is_empty() {
+            bail!("empty value");
+        }
+        return Ok(vec![part.to_string()]);
+    };
+    if let (Ok(a), Ok(b)) = (first.parse::<u64>(), last.parse::<u64>()) {
+        if a > b {
+            bail!("{} is after {}", first, last);
+        }
+        if b - a >= MAX_RANGE as u64 {
+            bail!("{} has more than {} values", part, MAX_RANGE);
+        }
+        // [01-10] keeps the zero padding of the first number
+        let width = if first.starts_with('0') {
+            first.len()
+        } else {
+            0
+        };
+        return Ok((a..=b).map(|n| format!("{:0width$}", n)).collect());
+    }
+    let letters = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c),
+            _ => None,
+        }
+    };
+    match (letters(first), letters(last)) {
+        (Some(a), Some(b)) if a <= b => Ok((a..=b).map(String::from).collect()),
+        _ => bail!("{} is not a range of numbers or letters", part),
+    }
+}
+
+/// Normalized form of a target name used to spot duplicates: host names are
+/// case-insensitive and may carry a trailing dot
+pub fn normalize(target: &str) -> String {
+    target.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// Remove targets whose normalized name was already seen, keeping the first
+/// occurrence. Returns the removed targets as `(duplicate, kept target)` pairs.
+pub fn dedupe(targets: &mut Vec<Target>) -> Vec<(String, String)> {
+    let mut seen: HashMap<String, String> = HashMap::new();
+    let mut duplicates = Vec::new();
+    targets.retain(|target| match seen.get(&normalize(&target.host)) {
+        Some(first) => {
+            duplicates.push((target.host.clone(), first.clone()));
+            false
+        }
+        None => {
+            seen.insert(normalize(&target.host), target.host.clone());
+            true
+        }
+    });
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(pairs: &[(&str, &[&str])]) -> Aliases {
+        pairs
+            .iter()
+            .map(|(name, items)| {
+                (
+                    name.to_string(),
+                    items.iter().map(|i| i.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn expands_ranges() {
+        for (name, hosts) in [
+            ("web1", vec!["web1"]),
+            ("web[1-3]", vec!["web1", "web2", "web3"]),
+            ("web[08-10]", vec!["web08", "web09", "web10"]),
+            ("web[1,4-5].lan", vec!["web1.lan", "web4.lan", "web5.lan"]),
+            (
+                "rack[a-b]-[1-2]",
+                vec!["racka-1", "racka-2", "rackb-1", "rackb-2"],
+            ),
+            ("[::1]", vec!["[::1]"]),
+            ("web[]", vec!["web[]"]),
+            ("web[1-2", vec!["web[1-2"]),
+        ] {
+            assert_eq!(expand_ranges(name).unwrap(), hosts, "{}", name);
+        }
+        let padded = expand_ranges("web[01-10]").unwrap();
+        assert_eq!(padded.len(), 10);
+        assert_eq!((padded[0].as_str(), padded[9].as_str()), ("web01", "web10"));
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        let max = format!("web[1-{}]", MAX_RANGE);
+        assert_eq!(expand_ranges(&max).unwrap().len(), MAX_RANGE);
+        let over = format!("web[0-{}]", MAX_RANGE);
+        let tall = format!("web[1-{}]-[1-2]", MAX_RANGE / 2 + 1);
+        for (name, error) in [
+            ("web[3-1]", "3 is after 1"),
+            ("web[c-a]", "c-a is not a range"),
+            ("web[1-b]", "1-b is not a range"),
+            ("web[1,,2]", "empty value"),
+            (over.as_str(), "has more than 10000 values"),
+            (tall.as_str(), "expands to more than 10000 hosts"),
+        ] {
+            let e = format!("{:#}", expand_ranges(name).unwrap_err());
+            assert!(e.contains(error), "{}: {}", name, e);
+        }
+    }
+
+    #[test]
+    fn expands_aliases() {
+        let aliases = aliases(&[
+            ("cache", &["redis-[1-2]"]),
+            ("all", &["@cache", "db1"]),
+            ("loop", &["web1", "@back"]),
+            ("back", &["@loop"]),
+            ("me", &["@me"]),
+            ("lost", &["@nowhere"]),
+        ]);
+        assert_eq!(
+            expand("@all, web[1-2],db[2]", &aliases).unwrap(),
+            ["redis-1", "redis-2", "db1", "web1", "web2", "db2"]
+        );
+        for (list, error) in [
+            ("@me", "Alias @me references itself"),
+            ("@loop", "Alias @loop references itself"),
+            ("@lost", "Unknown alias @nowhere"),
+            ("@nope", "Unknown alias @nope"),
+        ] {
+            let e = format!("{:#}", expand(list, &aliases).unwrap_err());
+            assert!(e.contains(error), "{}: {}", list, e);
+        }
+    }
+}
@@ -0,0 +1,1036 @@
+//! Task files for `multissh run`: an ordered list of steps run one after the
+//! other, every step on all of its hosts in parallel.
+//!
+//! ```yaml
+//! steps:
+//!   - name: Upload the config
+//!     push: {src: nginx.conf, dest: /etc/nginx/nginx.conf, mode: "0644", owner: root}
+//!     group: web
+//!     notify: restart nginx
+//!   - name: Check the config
+//!     command: nginx -t
+//!     group: web
+//!     on_failure: abort
+//!   - command: cat /etc/debian_version
+//!     register: debian
+//!     on_failure: continue
+//!   - name: Migrate
+//!     script: migrate.sh
+//!     targets: db1
+//!     when: debian.ok && vars.role != 'replica'
+//!   - fetch: {src: /var/log/nginx/error.log, dest: logs}
+//!     group: web
+//! handlers:
+//!   - name: restart nginx
+//!     command: systemctl restart nginx
+//! ```
+//!
+//! Every step has exactly one of `command`, `script` (a local script run
+//! with `sh` on the hosts), `push` and `fetch`. Steps run on their inventory
+//! `group` or their `targets` (with the syntax of `-t`), or on the targets
+//! given on the command line. Local paths are relative to the task file.
+//!
+//! `on_failure` decides what happens with the hosts a step failed on:
+//! - `stop` (default): they skip the remaining steps
+//! - `continue`: they keep going with the next steps
+//! - `ignore`: like `continue`, and the failure doesn't fail the run
+//! - `abort`: no further step runs, on any host
+//!
+//! A step with `when` only runs on the hosts where the expression holds.
+//! It can use `host`, the inventory variables as `vars.NAME`, and the result
+//! of an earlier step with `register: NAME` as `NAME.ok`, `NAME.failed`,
+//! `NAME.changed`, `NAME.skipped`, `NAME.rc`, `NAME.stdout` and
+//! `NAME.stderr`, with `==`, `!=`, `&&`, `||`, `!`, parentheses and quoted
+//! strings.
+//!
+//! A step with `notify` triggers the `handlers` of these names on the hosts
+//! where it changed something. Handlers run once, after all the steps, on
+//! the hosts that notified them. Pushes change something when they replace
+//! the file with a different one, commands and scripts whenever they
+//! succeed, fetches never.
+
+use crate::output::{Event, OutputOptions, OutputWriter};
+use crate::paths;
+use crate::runner::{self, HostResult, RunOptions};
+use crate::ssh::{RemoteShell, SshOptions};
+use crate::targets::Target;
+use crate::transfer::{self, FileAttrs};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TaskFile {
+    pub steps: Vec<Step>,
+    /// Steps run at the end on the hosts that notified them
+    #[serde(default)]
+    pub handlers: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub script: Option<PathBuf>,
+    #[serde(default)]
+    pub push: Option<Push>,
+    #[serde(default)]
+    pub fetch: Option<Fetch>,
+    /// Inventory group to run on
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Targets to run on, like `-t`
+    #[serde(default)]
+    pub targets: Option<String>,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    /// Only run on the hosts where this holds
+    #[serde(default)]
+    pub when: Option<String>,
+    /// Name later `when` expressions use for the results of this step
+    #[serde(default)]
+    pub register: Option<String>,
+    /// Handlers to run on the hosts this step changed
+    #[serde(default)]
+    pub notify: Notify,
+    #[serde(skip)]
+    condition: Option<Condition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Push {
+    pub src: PathBuf,
+    /// Remote path, or directory ending with `/`
+    pub dest: String,
+    /// Octal permissions, as with `multissh push --mode`
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl Push {
+    pub fn attrs(&self) -> Result<FileAttrs> {
+        FileAttrs::new(
+            self.mode.as_deref(),
+            self.owner.as_deref(),
+            self.group.as_deref(),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fetch {
+    pub src: String,
+    /// Local directory, the file is saved as `<dest>/<host>/<file name>`,
+    /// the files matching a glob under `<dest>/<host>/` with their paths
+    #[serde(default = "current_dir")]
+    pub dest: PathBuf,
+}
+
+fn current_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+/// What happens with the hosts a step failed on, see the module documentation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailure {
+    #[default]
+    Stop,
+    Continue,
+    Ignore,
+    Abort,
+}
+
+/// Handlers notified by a step: one name, or a list
+#[derive(Debug, Default, Deserialize)]
+#[serde(untagged)]
+pub enum Notify {
+    #[default]
+    None,
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Notify {
+    pub fn names(&self) -> &[String] {
+        match self {
+            Notify::None => &[],
+            Notify::One(name) => std::slice::from_ref(name),
+            Notify::Many(names) => names,
+        }
+    }
+}
+
+/// What a step does
+pub enum Action<'a> {
+    Command(&'a str),
+    Script(&'a Path),
+    Push(&'a Push),
+    Fetch(&'a Fetch),
+}
+
+impl TaskFile {
+    /// Read and check a task file, making its local paths relative to the
+    /// current directory
+    pub fn load(path: &Path) -> Result<TaskFile> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read task file {}", path.display()))?;
+        let mut file: TaskFile = serde_yaml::from_str(&text)
+            .with_context(|| format!("Invalid task file {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        let handlers: Vec<&str> = file
+            .handlers
+            .iter()
+            .filter_map(|h| h.name.as_deref())
+            .collect();
+        let mut registered = BTreeSet::new();
+        for (n, step) in file.steps.iter_mut().enumerate() {
+            step.check(dir, &mut registered)
+                .with_context(|| format!("{}: step {}", path.display(), n + 1))?;
+            for name in step.notify.names() {
+                if !handlers.contains(&name.as_str()) {
+                    bail!(
+                        "{}: step {} notifies {}, which is not a handler",
+                        path.display(),
+                        n + 1,
+                        name
+                    );
+                }
+            }
+        }
+        let mut names = HashSet::new();
+        for (n, handler) in file.handlers.iter_mut().enumerate() {
+            handler
+                .check_handler(dir, &mut registered, &mut names)
+                .with_context(|| format!("{}: handler {}", path.display(), n + 1))?;
+        }
+        Ok(file)
+    }
+}
+
+impl Step {
+    pub fn action(&self) -> Result<Action<'_>> {
+        let actions = [
+            self.command.as_deref().map(Action::Command),
+            self.script.as_deref().map(Action::Script),
+            self.push.as_ref().map(Action::Push),
+            self.fetch.as_ref().map(Action::Fetch),
+        ];
+        let mut actions = actions.into_iter().flatten();
+        match (actions.next(), actions.next()) {
+            (Some(action), None) => Ok(action),
+            (None, _) => bail!("needs one of command, script, push or fetch"),
+            (Some(_), Some(_)) => bail!("has more than one of command, script, push and fetch"),
+        }
+    }
+
+    /// Check the step as part of loading the task file: `registered` has
+    /// the names registered by the steps before it, and gets its own
+    fn check(&mut self, dir: &Path, registered: &mut BTreeSet<String>) -> Result<()> {
+        self.action()?;
+        if self.group.is_some() && self.targets.is_some() {
+            bail!("has both group and targets");
+        }
+        if let Some(when) = &self.when {
+            let condition = Condition::parse(when, registered)
+                .with_context(|| format!("Invalid when: {}", when))?;
+            self.condition = Some(condition);
+        }
+        if let Some(name) = &self.register {
+            let valid = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid || ["host", "vars", "true", "false"].contains(&name.as_str()) {
+                bail!(
+                    "Invalid register name {:?}, use letters, digits and _",
+                    name
+                );
+            }
+            if !registered.insert(name.clone()) {
+                bail!("{} is already registered by an earlier step", name);
+            }
+        }
+        if let Some(script) = &mut self.script {
+            *script = dir.join(paths::expand(script));
+        }
+        if let Some(push) = &mut self.push {
+            push.attrs()?;
+            push.src = dir.join(paths::expand(&push.src));
+        }
+        if let Some(fetch) = &mut self.fetch {
+            fetch.dest = dir.join(paths::expand(&fetch.dest));
+        }
+        Ok(())
+    }
+
+    /// Check a handler like [`Step::check`], `names` has the names of the
+    /// handlers before it
+    fn check_handler(
+        &mut self,
+        dir: &Path,
+        registered: &mut BTreeSet<String>,
+        names: &mut HashSet<String>,
+    ) -> Result<()> {
+        let Some(name) = &self.name else {
+            bail!("needs a name to be notified by");
+        };
+        if !names.insert(name.clone()) {
+            bail!("there is already a handler named {}", name);
+        }
+        if self.group.is_some() || self.targets.is_some() || !self.notify.names().is_empty() {
+            bail!("runs on the hosts that notified it, it can't have group, targets or notify");
+        }
+        // Every step has run before handlers do
+        self.check(dir, registered)
+    }
+
+    /// Name shown when the step starts
+    pub fn label(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        match self.action() {
+            Ok(Action::Command(command)) => command.to_string(),
+            Ok(Action::Script(script)) => format!("script {}", script.display()),
+            Ok(Action::Push(push)) => format!("push {} to {}", push.src.display(), push.dest),
+            Ok(Action::Fetch(fetch)) => format!("fetch {}", fetch.src),
+            Err(_) => String::new(),
+        }
+    }
+}
+
+/// How every host fared over the whole run
+#[derive(Debug, Default)]
+pub struct Recap {
+    /// Steps that succeeded and failed on every host
+    pub hosts: BTreeMap<String, HostRecap>,
+    /// The run stopped early because of a step with `on_failure: abort`
+    pub aborted: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct HostRecap {
+    pub ok: usize,
+    /// Successful steps that changed something, counted in `ok` too
+    pub changed: usize,
+    pub failed: usize,
+    /// Failures of steps with `on_failure: ignore`
+    pub ignored: usize,
+    /// Steps left out by their `when`
+    pub skipped: usize,
+}
+
+impl Recap {
+    pub fn success(&self) -> bool {
+        !self.aborted && self.hosts.values().all(|h| h.failed == 0)
+    }
+
+    /// Print a line per host with how many steps succeeded and failed
+    pub fn print(&self) {
+        let width = self.hosts.keys().map(|h| h.len()).max().unwrap_or(0);
+        for (host, recap) in &self.hosts {
+            let mut line = format!(
+                "{} ok, {} changed, {} failed",
+                recap.ok, recap.changed, recap.failed
+            );
+            if recap.ignored > 0 {
+                line.push_str(&format!(", {} ignored", recap.ignored));
+            }
+            if recap.skipped > 0 {
+                line.push_str(&format!(", {} skipped", recap.skipped));
+            }
+            println!("{:width$} | {}", host, line);
+        }
+    }
+}
+
+/// Result of a step on a host, as seen by later `when` expressions
+#[derive(Clone, Debug, Default)]
+struct Outcome {
+    ok: bool,
+    failed: bool,
+    changed: bool,
+    rc: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+/// State of a run shared by its steps and handlers
+struct Progress {
+    recap: Recap,
+    /// Hosts that skip the remaining steps
+    dropped: HashSet<String>,
+    /// Per registered name, the outcome on every host the step ran on
+    registered: BTreeMap<String, BTreeMap<String, Outcome>>,
+    /// Per handler, the hosts that notified it, in the order they did
+    notified: BTreeMap<String, Vec<Target>>,
+}
+
+/// Run the steps of `file` in order, then its handlers. `targets` gives the
+/// hosts of a step.
+pub fn run(
+    file: &TaskFile,
+    targets: impl Fn(&Step) -> Result<Vec<Target>>,
+    ssh: &SshOptions,
+    options: &RunOptions,
+    output: &OutputOptions,
+) -> Result<Recap> {
+    // Check every step first, so a typo in the last step is caught before
+    // the first one touches anything
+    let mut step_targets = Vec::new();
+    for (n, step) in file.steps.iter().enumerate() {
+        let checked = check_command(step, ssh);
+        let resolved = checked.and_then(|_| targets(step));
+        step_targets.push(resolved.with_context(|| format!("Step {}", n + 1))?);
+    }
+    for (n, handler) in file.handlers.iter().enumerate() {
+        check_command(handler, ssh).with_context(|| format!("Handler {}", n + 1))?;
+    }
+
+    let output = OutputOptions {
+        prefix_width: step_targets
+            .iter()
+            .flatten()
+            .map(|t| t.host.len())
+            .max()
+            .unwrap_or(0),
+        ..output.clone()
+    };
+    let mut progress = Progress {
+        recap: Recap::default(),
+        dropped: HashSet::new(),
+        registered: BTreeMap::new(),
+        notified: BTreeMap::new(),
+    };
+    for (n, (step, targets)) in file.steps.iter().zip(&step_targets).enumerate() {
+        let label = format!("[{}/{}] {}", n + 1, file.steps.len(), step.label());
+        let failed = run_on(step, &label, targets, ssh, options, &output, &mut progress)?;
+        if failed > 0 && step.on_failure == OnFailure::Abort {
+            eprintln!(
+                "Step failed on {} hosts, not running the remaining steps",
+                failed
+            );
+            progress.recap.aborted = n + 1 < file.steps.len() || !progress.notified.is_empty();
+            return Ok(progress.recap);
+        }
+    }
+    for handler in &file.handlers {
+        let Some(targets) = handler
+            .name
+            .as_ref()
+            .and_then(|n| progress.notified.remove(n))
+        else {
+            continue;
+        };
+        let label = format!("[handler] {}", handler.label());
+        let failed = run_on(
+            handler,
+            &label,
+            &targets,
+            ssh,
+            options,
+            &output,
+            &mut progress,
+        )?;
+        if failed > 0 && handler.on_failure == OnFailure::Abort {
+            eprintln!(
+                "Handler failed on {} hosts, not running the remaining handlers",
+                failed
+            );
+            progress.recap.aborted = !progress.notified.is_empty();
+            break;
+        }
+    }
+    Ok(progress.recap)
+}
+
+fn check_command(step: &Step, ssh: &SshOptions) -> Result<()> {
+    match step.action()? {
+        Action::Command(command) => ssh.remote_command(command).map(|_| ()),
+        Action::Fetch(fetch) => transfer::fetch_command(&fetch.src, false, false).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Run `step` on those of `targets` still in the run where its `when`
+/// holds, and account for the results. Returns on how many hosts it failed.
+fn run_on(
+    step: &Step,
+    label: &str,
+    targets: &[Target],
+    ssh: &SshOptions,
+    options: &RunOptions,
+    output: &OutputOptions,
+    progress: &mut Progress,
+) -> Result<usize> {
+    let mut skipped = 0;
+    let mut selected = Vec::new();
+    for target in targets
+        .iter()
+        .filter(|t| !progress.dropped.contains(&t.host))
+    {
+        let holds = match &step.condition {
+            Some(condition) => condition.holds(&Facts {
+                target,
+                registered: &progress.registered,
+            }),
+            None => true,
+        };
+        if holds {
+            selected.push(target.clone());
+        } else {
+            skipped += 1;
+            progress
+                .recap
+                .hosts
+                .entry(target.host.clone())
+                .or_default()
+                .skipped += 1;
+        }
+    }
+    let noun = if selected.len() == 1 { "host" } else { "hosts" };
+    match skipped {
+        0 => eprintln!("==> {} ({} {})", label, selected.len(), noun),
+        _ => eprintln!(
+            "==> {} ({} {}, {} skipped by when)",
+            label,
+            selected.len(),
+            noun,
+            skipped
+        ),
+    }
+    if selected.is_empty() {
+        return Ok(0);
+    }
+    let results = run_step(step, &selected, ssh, options, output)?;
+
+    let mut failed = 0;
+    for (target, result) in selected.iter().zip(&results) {
+        let changed = result.success()
+            && match step.action()? {
+                Action::Push(_) => transfer::push_changed(result),
+                Action::Command(_) | Action::Script(_) => true,
+                Action::Fetch(_) => false,
+            };
+        if let Some(name) = &step.register {
+            let outcome = Outcome {
+                ok: result.success(),
+                failed: result.failed(),
+                changed,
+                rc: result.exit_code,
+                stdout: trim_output(&result.stdout),
+                stderr: trim_output(&result.stderr),
+            };
+            progress
+                .registered
+                .entry(name.clone())
+                .or_default()
+                .insert(result.host.clone(), outcome);
+        }
+        let host = progress.recap.hosts.entry(result.host.clone()).or_default();
+        if changed {
+            host.changed += 1;
+            for handler in step.notify.names() {
+                let notified = progress.notified.entry(handler.clone()).or_default();
+                if !notified.iter().any(|t| t.host == target.host) {
+                    notified.push(target.clone());
+                }
+            }
+        }
+        if !result.failed() {
+            host.ok += 1;
+            continue;
+        }
+        failed += 1;
+        match step.on_failure {
+            OnFailure::Ignore => host.ignored += 1,
+            OnFailure::Continue => host.failed += 1,
+            OnFailure::Stop | OnFailure::Abort => {
+                host.failed += 1;
+                progress.dropped.insert(result.host.clone());
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Output of a step as compared in `when`, without the final newline
+fn trim_output(output: &[u8]) -> String {
+    String::from_utf8_lossy(output)
+        .trim_end_matches(['\n', '\r'])
+        .to_string()
+}
+
+/// Run one step on `targets`, returns the results in the order of `targets`
+fn run_step(
+    step: &Step,
+    targets: &[Target],
+    ssh: &SshOptions,
+    options: &RunOptions,
+    output: &OutputOptions,
+) -> Result<Vec<HostResult>> {
+    // Transfers rely on the remote shell to run `cat` and friends
+    let transfer_ssh = SshOptions {
+        shell: RemoteShell::Default,
+        ..ssh.clone()
+    };
+    let results = match step.action()? {
+        Action::Command(command) => {
+            with_writer(output, |tx| runner::run(targets, command, ssh, options, tx))?
+        }
+        Action::Script(script) => {
+            if !script.is_file() {
+                bail!("Script not found: {}", script.display());
+            }
+            let options = RunOptions {
+                stdin: Some(script.to_path_buf()),
+                ..options.clone()
+            };
+            with_writer(output, |tx| {
+                runner::run(targets, "sh -s", &transfer_ssh, &options, tx)
+            })?
+        }
+        Action::Push(push) => {
+            let size = std::fs::metadata(&push.src)
+                .with_context(|| format!("Failed to read {}", push.src.display()))?
+                .len();
+            let dest = transfer::push_dest(&push.src, &push.dest)?;
+            let options = RunOptions {
+                stdin: Some(push.src.clone()),
+                ..options.clone()
+            };
+            let (tx, _) = mpsc::channel();
+            let command = transfer::push_command(&dest, &push.attrs()?, false);
+            let results = runner::run(targets, &command, &transfer_ssh, &options, &tx);
+            transfer::print_results(&results, |r| Ok(transfer::pushed(&dest, size, 0, r)));
+            results
+        }
+        Action::Fetch(fetch) => {
+            let command = transfer::fetch_command(&fetch.src, false, false)?;
+            let (tx, _) = mpsc::channel();
+            let mut results = runner::run(targets, &command, &transfer_ssh, options, &tx);
+            let mut saved = BTreeMap::new();
+            for result in &mut results {
+                if !result.success() {
+                    continue;
+                }
+                // A host whose file couldn't be saved failed the step too
+                match transfer::fetched(&fetch.dest, result, &fetch.src, false) {
+                    Ok(message) => {
+                        saved.insert(result.host.clone(), message);
+                    }
+                    Err(e) => result.error = Some(format!("{:#}", e)),
+                }
+            }
+            transfer::print_results(&results, |r| Ok(saved[&r.host].clone()));
+            results
+        }
+    };
+    Ok(results)
+}
+
+/// Run `f` with the output of the hosts going through a writer
+fn with_writer(
+    output: &OutputOptions,
+    f: impl FnOnce(&Sender<Event>) -> Vec<HostResult>,
+) -> Result<Vec<HostResult>> {
+    let writer = OutputWriter::spawn(output.clone())?;
+    let results = f(&writer.sender());
+    match writer.finish() {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => Err(e.into()),
+        _ => Ok(results),
+    }
+}
+
+/// A parsed `when` expression
+#[derive(Clone, Debug)]
+enum Condition {
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    /// `left == right`, or `!=` when not `equal`
+    Compare {
+        left: Operand,
+        right: Operand,
+        equal: bool,
+    },
+    Truthy(Operand),
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Literal(Fact),
+    Host,
+    Var(String),
+    Registered { name: String, field: String },
+}
+
+/// A value in a `when` expression
+#[derive(Clone, Debug)]
+enum Fact {
+    Bool(bool),
+    Text(String),
+    Null,
+}
+
+impl Fact {
+    fn truthy(&self) -> bool {
+        match self {
+            Fact::Bool(b) => *b,
+            Fact::Text(text) => !text.is_empty(),
+            Fact::Null => false,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Fact::Bool(b) => b.to_string(),
+            Fact::Text(text) => text.clone(),
+            Fact::Null => String::new(),
+        }
+    }
+}
+
+const FIELDS: [&str; 7] = [
+    "ok", "failed", "changed", "skipped", "rc", "stdout", "stderr",
+];
+
+/// What a `when` expression is evaluated against, for one host
+struct Facts<'a> {
+    target: &'a Target,
+    registered: &'a BTreeMap<String, BTreeMap<String, Outcome>>,
+}
+
+impl Condition {
+    /// Parse `text`, which may use the results of the `registered` names
+    fn parse(text: &str, registered: &BTreeSet<String>) -> Result<Condition> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            registered,
+        };
+        let condition = parser.or()?;
+        if let Some(token) = parser.tokens.first() {
+            bail!("unexpected {}", token);
+        }
+        Ok(condition)
+    }
+
+    fn holds(&self, facts: &Facts) -> bool {
+        match self {
+            Condition::Not(c) => !c.holds(facts),
+            Condition::And(a, b) => a.holds(facts) && b.holds(facts),
+            Condition::Or(a, b) => a.holds(facts) || b.holds(facts),
+            Condition::Compare { left, right, equal } => {
+                (facts.value(left).text() == facts.value(right).text()) == *equal
+            }
+            Condition::Truthy(operand) => facts.value(operand).truthy(),
+        }
+    }
+}
+
+impl Facts<'_> {
+    fn value(&self, operand: &Operand) -> Fact {
+        match operand {
+            Operand::Literal(fact) => fact.clone(),
+            Operand::Host => Fact::Text(self.target.host.clone()),
+            Operand::Var(name) => match self.target.vars.get(name) {
+                None | Some(serde_yaml::Value::Null) => Fact::Null,
+                Some(serde_yaml::Value::Bool(b)) => Fact::Bool(*b),
+                Some(serde_yaml::Value::String(s)) => Fact::Text(s.clone()),
+                Some(serde_yaml::Value::Number(n)) => Fact::Text(n.to_string()),
+                Some(value) => Fact::Text(
+                    serde_yaml::to_string(value)
+                        .unwrap_or_default()
+                        .trim_end()
+                        .to_string(),
+                ),
+            },
+            Operand::Registered { name, field } => {
+                // Hosts the step didn't run on only have `skipped` set
+                let Some(outcome) = self
+                    .registered
+                    .get(name)
+                    .and_then(|hosts| hosts.get(&self.target.host))
+                else {
+                    return Fact::Bool(field == "skipped");
+                };
+                match field.as_str() {
+                    "ok" => Fact::Bool(outcome.ok),
+                    "failed" => Fact::Bool(outcome.failed),
+                    "changed" => Fact::Bool(outcome.changed),
+                    "skipped" => Fact::Bool(!outcome.ok && !outcome.failed),
+                    "rc" => outcome
+                        .rc
+                        .map_or(Fact::Null, |rc| Fact::Text(rc.to_string())),
+                    "stdout" => Fact::Text(outcome.stdout.clone()),
+                    _ => Fact::Text(outcome.stderr.clone()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    /// A name, number, `true` or `false`
+    Word(String),
+    /// A quoted string
+    Text(String),
+    Op(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Text(text) => write!(f, "{:?}", text),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    const OPS: [&str; 7] = ["==", "!=", "&&", "||", "!", "(", ")"];
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if c == '\'' || c == '"' {
+            let Some(end) = rest[1..].find(c) else {
+                bail!("unterminated string {}", rest);
+            };
+            tokens.push(Token::Text(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || "_.-".contains(c)))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                bail!("unexpected {:?}", c);
+            }
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens of a `when` expression, `!` binding
+/// tighter than `&&`, which binds tighter than `||`
+struct Parser<'a> {
+    tokens: &'a [Token],
+    registered: &'a BTreeSet<String>,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, op: &str) -> bool {
+        match self.tokens.first() {
+            Some(Token::Op(o)) if *o == op => {
+                self.tokens = &self.tokens[1..];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition> {
+        let mut condition = self.and()?;
+        while self.eat("||") {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition> {
+        let mut condition = self.not()?;
+        while self.eat("&&") {
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition> {
+        if self.eat("!") {
+            return Ok(Condition::Not(Box::new(self.not()?)));
+        }
+        if self.eat("(") {
+            let condition = self.or()?;
+            if !self.eat(")") {
+                bail!("missing )");
+            }
+            return Ok(condition);
+        }
+        let left = self.operand()?;
+        for (op, equal) in [("==", true), ("!=", false)] {
+            if self.eat(op) {
+                let right = self.operand()?;
+                return Ok(Condition::Compare { left, right, equal });
+            }
+        }
+        Ok(Condition::Truthy(left))
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        let Some((token, rest)) = self.tokens.split_first() else {
+            bail!("unexpected end");
+        };
+        self.tokens = rest;
+        let word = match token {
+            Token::Text(text) => return Ok(Operand::Literal(Fact::Text(text.clone()))),
+            Token::Op(op) => bail!("unexpected {}", op),
+            Token::Word(word) => word,
+        };
+        if word == "true" || word == "false" {
+            return Ok(Operand::Literal(Fact::Bool(word == "true")));
+        }
+        if word.parse::<f64>().is_ok() {
+            return Ok(Operand::Literal(Fact::Text(word.clone())));
+        }
+        let parts: Vec<&str> = word.split('.').collect();
+        match parts.as_slice() {
+            ["host"] => Ok(Operand::Host),
+            ["vars", name] => Ok(Operand::Var(name.to_string())),
+            [name, field] if self.registered.contains(*name) => {
+                if !FIELDS.contains(field) {
+                    bail!("{} has no {}, use one of {}", name, field, FIELDS.join(", "));
+                }
+                Ok(Operand::Registered {
+                    name: name.to_string(),
+                    field: field.to_string(),
+                })
+            }
+            _ => bail!(
+                "unknown name {}, use host, vars.NAME or NAME.FIELD with the register name of an earlier step",
+                word
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: &str, vars: &[(&str, &str)]) -> Target {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), serde_yaml::Value::String(v.to_string())))
+            .collect();
+        Target {
+            host: host.to_string(),
+            vars,
+        }
+    }
+
+    /// `debian` ran on web1, succeeding, and failed on web2; web3 skipped it
+    fn registered() -> BTreeMap<String, BTreeMap<String, Outcome>> {
+        let ok = Outcome {
+            ok: true,
+            rc: Some(0),
+            stdout: "12.5".to_string(),
+            ..Default::default()
+        };
+        let failed = Outcome {
+            failed: true,
+            rc: Some(1),
+            stderr: "no such file".to_string(),
+            ..Default::default()
+        };
+        let hosts = BTreeMap::from([("web1".to_string(), ok), ("web2".to_string(), failed)]);
+        BTreeMap::from([("debian".to_string(), hosts)])
+    }
+
+    fn holds(text: &str, target: &Target) -> bool {
+        let names = BTreeSet::from(["debian".to_string()]);
+        let condition = Condition::parse(text, &names).unwrap();
+        condition.holds(&Facts {
+            target,
+            registered: &registered(),
+        })
+    }
+
+    fn parse_error(text: &str) -> String {
+        let names = BTreeSet::from(["debian".to_string()]);
+        format!("{:#}", Condition::parse(text, &names).unwrap_err())
+    }
+
+    #[test]
+    fn precedence() {
+        let web1 = target("web1", &[]);
+        // a || (b && !c)
+        assert!(holds("true || false && !true", &web1));
+        assert!(!holds("false || true && !true", &web1));
+        assert!(holds("false || true && !false", &web1));
+        assert!(!holds("(true || true) && !true", &web1));
+        assert!(holds("!false && !false", &web1));
+        assert!(holds("!!true", &web1));
+    }
+
+    #[test]
+    fn compares_hosts_vars_and_strings() {
+        let db = target("db1", &[("role", "replica"), ("port", "5432")]);
+        assert!(holds("host == 'db1'", &db));
+        assert!(holds("host != \"web1\"", &db));
+        assert!(holds("vars.role == 'replica' && vars.port == 5432", &db));
+        assert!(holds("vars.role != 'primary'", &db));
+        assert!(holds("vars.missing == ''", &db));
+        assert!(!holds("vars.missing", &db));
+        assert!(holds("vars.role == 'a b' || host == 'db1'", &db));
+    }
+
+    #[test]
+    fn registered_results() {
+        let (web1, web2, web3) = (
+            target("web1", &[]),
+            target("web2", &[]),
+            target("web3", &[]),
+        );
+        assert!(holds(
+            "debian.ok && debian.stdout == '12.5' && debian.rc == 0",
+            &web1
+        ));
+        assert!(holds(
+            "debian.failed && debian.rc == 1 && debian.stderr",
+            &web2
+        ));
+        assert!(!holds("debian.skipped", &web1));
+        assert!(!holds("debian.skipped", &web2));
+        // web3 didn't run the step
+        assert!(holds("debian.skipped", &web3));
+        assert!(!holds(
+            "debian.ok || debian.failed || debian.changed",
+            &web3
+        ));
+        assert!(!holds("debian.rc", &web3));
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        assert!(parse_error("host == 'web1").contains("unterminated string"));
+        assert!(parse_error("host == \"web1").contains("unterminated string"));
+        assert!(parse_error("role == 'db'").contains("unknown name role"));
+        assert!(parse_error("ubuntu.ok").contains("unknown name ubuntu.ok"));
+        assert!(parse_error("debian.version").contains("debian has no version"));
+        assert!(parse_error("(host == 'a'").contains("missing )"));
+        assert!(parse_error("host ==").contains("unexpected end"));
+        assert!(parse_error("host == 'a' 'b'").contains("unexpected \"b\""));
+        assert!(parse_error("host = 'a'").contains("unexpected '='"));
+    }
+}
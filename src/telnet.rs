@@ -0,0 +1,319 @@
+//! Telnet transport for legacy devices that don't speak SSH, opted into per
+//! host with the `transport: telnet` inventory variable.
+//!
+//! Telnet is insecure: the password and all output cross the network in
+//! clear text. A session logs in by answering the `login:` and `Password:`
+//! prompts, waits for the prompt of the device, types the command and reads
+//! what it prints up to the next prompt. Device CLIs have no exit status, a
+//! host's exit status is left unknown and its command taken to have failed
+//! when the output has a line like the ones devices answer errors with
+//! ([`error_line`]), e.g. `% Invalid input detected`. Its output all arrives
+//! on stdout.
+
+use regex::Regex;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Port telnet hosts are reached on unless their `port` variable says
+/// otherwise
+pub const DEFAULT_PORT: u16 = 23;
+
+/// Lines device CLIs answer a command they couldn't run with: the `%`
+/// messages of Cisco style CLIs, and lines starting with an error word
+const ERROR_LINE: &str = r"^\s*(%\s*(Invalid|Incomplete|Ambiguous|Unknown|Unrecognized|Bad|Error)\b|(Error|ERROR|error):|Invalid (input|command)\b|Unknown command\b|Syntax error\b)";
+
+/// Time without new output after which a line looking like a prompt is
+/// taken for one
+const QUIET: Duration = Duration::from_millis(500);
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+/// Options the server may have: it echoes, and doesn't wait for go-aheads
+const ACCEPTED: &[u8] = &[1, 3];
+
+/// First line of `output` reporting an error, see [`ERROR_LINE`]
+pub fn error_line(output: &[u8]) -> Option<String> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| Regex::new(ERROR_LINE).expect("valid error pattern"));
+    String::from_utf8_lossy(output)
+        .lines()
+        .find(|line| pattern.is_match(line))
+        .map(|line| line.trim().to_string())
+}
+
+/// Where the decoder is in the byte stream, across reads
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    Iac,
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+/// What the last line of the device asks for
+#[derive(PartialEq)]
+enum Prompt {
+    Login,
+    Password,
+    Shell,
+}
+
+/// Result of waiting for more output
+enum Fill {
+    Data,
+    Idle,
+    Closed,
+}
+
+/// A logged in telnet connection, read for the output of the command given
+/// to [`Session::start`] up to the next prompt
+pub struct Session {
+    stream: TcpStream,
+    state: State,
+    /// Output received and not handed out yet
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl Session {
+    /// Connect to `host` and log in as `user`. Errors connecting read like
+    /// the ones of ssh.
+    pub fn login(
+        host: &str,
+        port: u16,
+        user: &str,
+        password: Option<&str>,
+        timeout: Duration,
+    ) -> io::Result<Session> {
+        let addr: SocketAddr = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Could not resolve hostname {}: {}", host, e),
+                )
+            })?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("Could not resolve hostname {}", host)))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| {
+            let reason = match e.kind() {
+                ErrorKind::TimedOut => "Connection timed out".to_string(),
+                _ => e.to_string(),
+            };
+            io::Error::new(
+                e.kind(),
+                format!("connect to host {} port {}: {}", host, port, reason),
+            )
+        })?;
+        stream.set_read_timeout(Some(QUIET))?;
+        let mut session = Session {
+            stream,
+            state: State::Data,
+            pending: Vec::new(),
+            done: false,
+        };
+        let deadline = Instant::now() + timeout;
+        let mut logins = 0;
+        loop {
+            match session.wait_prompt(deadline)? {
+                Prompt::Login if logins > 0 => return Err(io::Error::other("login incorrect")),
+                Prompt::Login => {
+                    logins += 1;
+                    session.send(user)?;
+                }
+                Prompt::Password => match password {
+                    Some(password) => session.send(password)?,
+                    None => {
+                        return Err(io::Error::other(
+                            "asks for a password, give it with --ask-password",
+                        ))
+                    }
+                },
+                Prompt::Shell => return Ok(session),
+            }
+        }
+    }
+
+    /// Type `command`, its output is then read from the session
+    pub fn start(&mut self, command: &str, timeout: Duration) -> io::Result<()> {
+        self.send(command)?;
+        // The device echoes the command back first
+        let deadline = Instant::now() + timeout;
+        while !self.pending.contains(&b'\n') {
+            if Instant::now() > deadline {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "the device didn't take the command",
+                ));
+            }
+            if let Fill::Closed = self.fill()? {
+                return Err(io::Error::other("connection closed by the device"));
+            }
+        }
+        let echo = self.pending.iter().position(|&b| b == b'\n').unwrap_or(0);
+        self.pending.drain(..=echo);
+        Ok(())
+    }
+
+    /// Log out once the command is done, without waiting for the device
+    pub fn close(mut self) {
+        let _ = self.send("exit");
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    fn wait_prompt(&mut self, deadline: Instant) -> io::Result<Prompt> {
+        loop {
+            let last = String::from_utf8_lossy(last_line(&self.pending)).to_lowercase();
+            let last = last.trim_end();
+            let prompt = if last.ends_with("login:") || last.ends_with("username:") {
+                Some(Prompt::Login)
+            } else if last.ends_with("password:") {
+                Some(Prompt::Password)
+            } else {
+                None
+            };
+            if let Some(prompt) = prompt {
+                self.pending.clear();
+                return Ok(prompt);
+            }
+            if Instant::now() > deadline {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "timed out waiting for a prompt",
+                ));
+            }
+            match self.fill()? {
+                Fill::Data => {}
+                Fill::Idle if is_prompt(&self.pending) => {
+                    self.pending.clear();
+                    return Ok(Prompt::Shell);
+                }
+                Fill::Idle => {}
+                Fill::Closed => {
+                    let last = String::from_utf8_lossy(last_line(&self.pending)).to_string();
+                    let message = match last.trim() {
+                        "" => "Connection closed by remote host".to_string(),
+                        last => format!("Connection closed by remote host: {}", last),
+                    };
+                    return Err(io::Error::new(ErrorKind::ConnectionAborted, message));
+                }
+            }
+        }
+    }
+
+    /// Type `line` and Enter
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        let mut data = Vec::with_capacity(line.len() + 2);
+        for &b in line.as_bytes() {
+            data.push(b);
+            if b == IAC {
+                data.push(IAC);
+            }
+        }
+        data.extend_from_slice(b"\r\n");
+        self.stream.write_all(&data)
+    }
+
+    /// Read what arrived, answering option negotiations on the way
+    fn fill(&mut self) -> io::Result<Fill> {
+        let mut buf = [0; 4096];
+        let n = match self.stream.read(&mut buf) {
+            Ok(0) => return Ok(Fill::Closed),
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(Fill::Idle)
+            }
+            Err(e) => return Err(e),
+        };
+        let mut reply = Vec::new();
+        for &b in &buf[..n] {
+            self.state = match (self.state, b) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, b'\r' | 0) => State::Data,
+                (State::Data, b) => {
+                    self.pending.push(b);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    self.pending.push(IAC);
+                    State::Data
+                }
+                (State::Iac, SB) => State::Sub,
+                (State::Iac, DO | DONT | WILL | WONT) => State::Option(b),
+                (State::Iac, _) => State::Data,
+                (State::Option(command), option) => {
+                    // Refuse all but the accepted options, stopping needs
+                    // no answer
+                    match command {
+                        WILL if ACCEPTED.contains(&option) => {
+                            reply.extend_from_slice(&[IAC, DO, option])
+                        }
+                        WILL => reply.extend_from_slice(&[IAC, DONT, option]),
+                        DO => reply.extend_from_slice(&[IAC, WONT, option]),
+                        _ => {}
+                    }
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => State::Sub,
+                (State::SubIac, SE) => State::Data,
+                (State::SubIac, _) => State::Sub,
+            };
+        }
+        if !reply.is_empty() {
+            self.stream.write_all(&reply)?;
+        }
+        Ok(Fill::Data)
+    }
+}
+
+impl Read for Session {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let ready = match self.pending.iter().rposition(|&b| b == b'\n') {
+                Some(end) => end + 1,
+                None if self.done => self.pending.len(),
+                None => 0,
+            };
+            if ready > 0 || self.done {
+                let n = ready.min(out.len());
+                out[..n].copy_from_slice(&self.pending[..n]);
+                self.pending.drain(..n);
+                return Ok(n);
+            }
+            match self.fill()? {
+                Fill::Data => {}
+                Fill::Idle if is_prompt(&self.pending) => {
+                    self.pending.clear();
+                    self.done = true;
+                }
+                Fill::Idle => {}
+                Fill::Closed => self.done = true,
+            }
+        }
+    }
+}
+
+/// The line being received, after the last newline
+fn last_line(data: &[u8]) -> &[u8] {
+    match data.iter().rposition(|&b| b == b'\n') {
+        Some(i) => &data[i + 1..],
+        None => data,
+    }
+}
+
+/// Whether the line being received ends like a prompt: `$`, `#`, `>` or
+/// `%`, maybe followed by a space
+fn is_prompt(data: &[u8]) -> bool {
+    let last = last_line(data);
+    let last = last.strip_suffix(b" ").unwrap_or(last);
+    matches!(last.last(), Some(b'$' | b'#' | b'>' | b'%'))
+}
@@ -0,0 +1,1190 @@
+//! Copying files to and from the targets.
+//!
+//! Files are streamed through the same `ssh` invocation commands use, so
+//! uploads and downloads get the connection options, retries and
+//! parallelism of normal runs: `push` feeds the local file to `cat` on the
+//! remote host, `fetch` saves what `cat` prints. With `--transfer-protocol
+//! scp` the remote `scp` is spoken to instead, see [`crate::scp`].
+
+use crate::exclude::Excludes;
+use crate::output::{failure_reason, Event};
+use crate::runner::HostResult;
+use crate::scp;
+use crate::ssh::quote;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, ErrorKind, IsTerminal, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::TempPath;
+
+/// How files are copied
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TransferProtocol {
+    /// Through `cat` and a few other commands run by the remote shell
+    #[default]
+    Cat,
+    /// The classic SCP protocol, with the remote `scp`
+    Scp,
+}
+
+/// How transferred files are checked once copied
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Verify {
+    /// Compare the SHA-256 of every file on both ends, with `sha256sum` on
+    /// the remote host
+    #[default]
+    Sha256,
+    None,
+}
+
+/// Most bytes read or written at once through a [`Throttled`], so the
+/// pauses stay short
+const THROTTLE_CHUNK: usize = 16 * 1024;
+
+/// Transfer rate limits of `--bwlimit` and `--bwlimit-total`, in bytes per
+/// second
+#[derive(Clone, Debug, Default)]
+pub struct BwLimit {
+    /// Rate of every host on its own
+    per_host: Option<u64>,
+    /// Buckets of the hosts, shared by all their connections
+    hosts: Arc<Mutex<HashMap<String, Arc<TokenBucket>>>>,
+    /// Rate of all hosts together
+    total: Option<Arc<TokenBucket>>,
+}
+
+impl BwLimit {
+    pub fn new(per_host: Option<u64>, total: Option<u64>) -> BwLimit {
+        BwLimit {
+            per_host,
+            hosts: Arc::default(),
+            total: total.map(|rate| Arc::new(TokenBucket::new(rate))),
+        }
+    }
+
+    /// `inner` throttled for a transfer of `host`: by the bucket of the
+    /// host and by the one shared by all hosts
+    pub fn throttle<T>(&self, host: &str, inner: T) -> Throttled<T> {
+        let own = self.per_host.map(|rate| {
+            let mut hosts = self.hosts.lock().unwrap();
+            hosts
+                .entry(host.to_string())
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate)))
+                .clone()
+        });
+        let buckets = own.into_iter().chain(self.total.clone()).collect();
+        Throttled { inner, buckets }
+    }
+}
+
+/// Bytes allowed through per second, with bursts of a tenth of a second.
+/// Bytes taken beyond what is in the bucket are paid back by waiting, so
+/// any number of threads may share one.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    /// Tokens left, negative when in debt, as of the instant
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        let rate = rate.max(1) as f64;
+        let burst = (rate / 10.0).max(THROTTLE_CHUNK as f64);
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Take `n` bytes worth of tokens, waiting for them if the bucket is
+    /// short
+    pub fn take(&self, n: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, then) = *state;
+            let now = Instant::now();
+            let tokens = (tokens + now.duration_since(then).as_secs_f64() * self.rate)
+                .min(self.burst)
+                - n as f64;
+            *state = (tokens, now);
+            Duration::from_secs_f64((-tokens).max(0.0) / self.rate)
+        };
+        thread::sleep(wait);
+    }
+}
+
+/// A reader or writer going no faster than its token buckets allow
+pub struct Throttled<T> {
+    inner: T,
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl<T> Throttled<T> {
+    fn pace(&self, n: usize) {
+        for bucket in &self.buckets {
+            bucket.take(n);
+        }
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(THROTTLE_CHUNK);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pace(n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(THROTTLE_CHUNK);
+        let n = self.inner.write(&buf[..len])?;
+        self.pace(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Remote path a local file is pushed to: `dest` itself, or the file's name
+/// in `dest` when it ends with a `/`
+pub fn push_dest(local: &Path, dest: &str) -> Result<String> {
+    if !dest.ends_with('/') {
+        return Ok(dest.to_string());
+    }
+    let name = local
+        .file_name()
+        .with_context(|| format!("{} is not a file", local.display()))?;
+    Ok(format!("{}{}", dest, name.to_string_lossy()))
+}
+
+/// Permissions and ownership a pushed file is given
+#[derive(Clone, Debug, Default)]
+pub struct FileAttrs {
+    /// Octal mode, e.g. `0644`
+    pub mode: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    /// Modification time, in seconds since the epoch, for `--preserve`
+    pub mtime: Option<i64>,
+}
+
+impl FileAttrs {
+    pub fn new(mode: Option<&str>, owner: Option<&str>, group: Option<&str>) -> Result<FileAttrs> {
+        if let Some(mode) = mode {
+            let octal = mode.chars().all(|c| ('0'..='7').contains(&c));
+            if !octal || !(3..=4).contains(&mode.len()) {
+                bail!("Invalid mode {}, give it in octal like 0644", mode);
+            }
+        }
+        for name in [owner, group].into_iter().flatten() {
+            if name.is_empty() || name.contains([':', '/']) || name.contains(char::is_whitespace) {
+                bail!("Invalid user or group name {:?}", name);
+            }
+        }
+        Ok(FileAttrs {
+            mode: mode.map(str::to_string),
+            owner: owner.map(str::to_string),
+            group: group.map(str::to_string),
+            mtime: None,
+        })
+    }
+
+    /// The attributes with the modification time of `local`, and its mode
+    /// unless one is given, for `--preserve`
+    pub fn preserved(mut self, local: &Path) -> Result<FileAttrs> {
+        let meta = std::fs::metadata(local)
+            .with_context(|| format!("Failed to read {}", local.display()))?;
+        if self.mode.is_none() {
+            self.mode = Some(format!("{:04o}", meta.permissions().mode() & 0o7777));
+        }
+        self.mtime = Some(meta.mtime());
+        Ok(self)
+    }
+
+    /// Commands applying the attributes to the file at `$F`. Ownership is
+    /// changed through `sudo -n` unless connected as root, since only root
+    /// may give files away.
+    fn commands(&self) -> Vec<String> {
+        let mut commands = Vec::new();
+        if let Some(mode) = &self.mode {
+            commands.push(format!("chmod {} -- \"$F\"", mode));
+        }
+        let become_root = "$([ \"$(id -u)\" = 0 ] || echo sudo -n)";
+        match (&self.owner, &self.group) {
+            (Some(owner), Some(group)) => commands.push(format!(
+                "{} chown {}:{} -- \"$F\"",
+                become_root,
+                quote(owner),
+                quote(group)
+            )),
+            (Some(owner), None) => {
+                commands.push(format!("{} chown {} -- \"$F\"", become_root, quote(owner)))
+            }
+            (None, Some(group)) => {
+                commands.push(format!("{} chgrp {} -- \"$F\"", become_root, quote(group)))
+            }
+            (None, None) => {}
+        }
+        if let Some(time) = self
+            .mtime
+            .and_then(|t| DateTime::<Utc>::from_timestamp(t, 0))
+        {
+            commands.push(format!(
+                "TZ=UTC0 touch -m -t {} -- \"$F\"",
+                time.format("%Y%m%d%H%M.%S")
+            ));
+        }
+        commands
+    }
+}
+
+/// Push of `local` to `dest` over SCP. Its mode is given, or else the one
+/// of the local file, and its modification time is sent when set; the
+/// owners can't be set.
+pub fn scp_push(local: &Path, dest: &str, attrs: &FileAttrs) -> Result<scp::Transfer> {
+    if attrs.owner.is_some() || attrs.group.is_some() {
+        bail!("--owner and --group need --transfer-protocol cat");
+    }
+    let mode = match &attrs.mode {
+        Some(mode) => u32::from_str_radix(mode, 8)?,
+        None => std::fs::metadata(local)
+            .with_context(|| format!("Failed to read {}", local.display()))?
+            .permissions()
+            .mode(),
+    };
+    let name = Path::new(dest)
+        .file_name()
+        .with_context(|| format!("{} is not a file", dest))?;
+    Ok(scp::Transfer::Push {
+        src: local.to_path_buf(),
+        name: name.to_string_lossy().to_string(),
+        mode,
+        mtime: attrs.mtime.map(|t| t.max(0) as u64),
+    })
+}
+
+/// Remote command recreating the local symlink `local` at `dest`, pointing
+/// to the same path, for `--preserve`
+pub fn push_link_command(local: &Path, dest: &str) -> Result<String> {
+    let target =
+        std::fs::read_link(local).with_context(|| format!("Failed to read {}", local.display()))?;
+    Ok(format!(
+        "mkdir -p -- \"$(dirname -- {dest})\" && ln -sfn -- {} {dest}",
+        quote(&target.to_string_lossy()),
+        dest = quote(dest)
+    ))
+}
+
+/// A directory packed to be pushed, in a temporary tar archive deleted
+/// when dropped
+pub struct DirArchive {
+    pub path: PathBuf,
+    /// Regular files in the directory and their bytes, symlinks are pushed
+    /// as they are unless followed
+    pub files: u64,
+    pub bytes: u64,
+    /// Deletes the archive when dropped
+    _file: TempPath,
+}
+
+/// Pack the directory `dir` for [`push_dir_command`], with the files
+/// symlinks point to rather than the symlinks when `follow`, and without
+/// what `excludes` leaves out
+pub fn pack_dir(dir: &Path, follow: bool, excludes: &Excludes) -> Result<DirArchive> {
+    // A new file only we can read, never one someone put there
+    let (file, temp) = tempfile::Builder::new()
+        .prefix("multissh-")
+        .suffix(".tar")
+        .tempfile()
+        .context("Failed to create the archive of the directory")?
+        .into_parts();
+    let mut archive = DirArchive {
+        path: temp.to_path_buf(),
+        files: 0,
+        bytes: 0,
+        _file: temp,
+    };
+    let mut builder = tar::Builder::new(io::BufWriter::new(file));
+    builder.follow_symlinks(follow);
+    let packed = (|| -> Result<()> {
+        builder.append_dir(".", dir)?;
+        for entry in walk_dir(dir, follow, excludes)? {
+            if entry.meta.is_dir() {
+                builder.append_dir(&entry.name, &entry.path)?;
+                continue;
+            }
+            builder.append_path_with_name(&entry.path, &entry.name)?;
+            if entry.meta.is_file() {
+                archive.files += 1;
+                archive.bytes += entry.meta.len();
+            }
+        }
+        builder.finish()?;
+        Ok(())
+    })();
+    packed.with_context(|| format!("Failed to pack {}", dir.display()))?;
+    builder.into_inner()?.flush()?;
+    Ok(archive)
+}
+
+/// A file, directory or symlink under a directory being pushed
+struct DirEntry {
+    /// Path in the archive, e.g. `./bin/app`
+    name: String,
+    path: PathBuf,
+    meta: std::fs::Metadata,
+}
+
+/// Everything under `dir` but what `excludes` leaves out, directories
+/// before what is in them. Symlinks are followed when `follow`.
+fn walk_dir(dir: &Path, follow: bool, excludes: &Excludes) -> Result<Vec<DirEntry>> {
+    fn walk(
+        dir: &Path,
+        prefix: &str,
+        follow: bool,
+        excludes: &Excludes,
+        entries: &mut Vec<DirEntry>,
+    ) -> Result<()> {
+        let mut children = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .collect::<io::Result<Vec<_>>>()?;
+        children.sort_by_key(|entry| entry.file_name());
+        for child in children {
+            let name = format!("{}/{}", prefix, child.file_name().to_string_lossy());
+            let path = child.path();
+            let meta = match follow {
+                true => std::fs::metadata(&path),
+                false => child.metadata(),
+            }
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+            if excludes.excluded(&name[2..], meta.is_dir()) {
+                continue;
+            }
+            let is_dir = meta.is_dir();
+            entries.push(DirEntry {
+                name: name.clone(),
+                path: path.clone(),
+                meta,
+            });
+            if is_dir {
+                walk(&path, &name, follow, excludes, entries)?;
+            }
+        }
+        Ok(())
+    }
+    let mut entries = Vec::new();
+    walk(dir, ".", follow, excludes, &mut entries)?;
+    Ok(entries)
+}
+
+/// Remote command unpacking the archive of a directory on stdin into
+/// `dest`, created as needed, and printing every file as it goes. Files
+/// already there are overwritten, others are left alone, and the owner is
+/// the remote user. Modes and modification times are kept when `preserve`,
+/// or else the files get the remote umask and the current time.
+pub fn push_dir_command(dest: &str, preserve: bool) -> String {
+    let keep = match preserve {
+        true => "p",
+        false => "m",
+    };
+    format!(
+        "mkdir -p -- {dest} && tar -xv{keep}of - -C {dest}",
+        dest = quote(dest)
+    )
+}
+
+/// Describe a successful push of a directory to `dest` for [`print_results`]
+pub fn pushed_dir(dest: &str, archive: &DirArchive) -> String {
+    let noun = if archive.files == 1 { "file" } else { "files" };
+    format!(
+        "{} ({} {}, {} bytes)",
+        dest, archive.files, noun, archive.bytes
+    )
+}
+
+/// Remote command printing the checksums of the files of the directory
+/// `dest`, relative to it, for [`verify`]
+pub fn dir_checksum_command(dest: &str) -> String {
+    format!(
+        "cd -- {} && find . -type f -exec sha256sum -- {{}} +",
+        quote(dest)
+    )
+}
+
+/// Checksums of the files in the local directory `dir`, by their paths in
+/// the output of [`dir_checksum_command`], with their sizes, see
+/// [`pack_dir`] for `follow` and `excludes`
+pub fn dir_checksums(
+    dir: &Path,
+    follow: bool,
+    excludes: &Excludes,
+) -> Result<Vec<(String, String, u64)>> {
+    let mut checksums = Vec::new();
+    for entry in walk_dir(dir, follow, excludes)? {
+        if entry.meta.is_file() {
+            let sum = sha256_file(&entry.path)?;
+            checksums.push((entry.name, sum, entry.meta.len()));
+        }
+    }
+    Ok(checksums)
+}
+
+/// Show how many of its `files` every host unpacked so far on a status line
+/// of stderr, from the output of [`push_dir_command`] on `rx`, until `rx`
+/// closes. Nothing is shown unless stderr is a terminal.
+pub fn show_dir_progress(rx: Receiver<Event>, hosts: usize, files: u64) {
+    let terminal = io::stderr().is_terminal();
+    let mut unpacked: Vec<(String, u64)> = Vec::new();
+    let mut done = 0;
+    let mut shown = Instant::now();
+    loop {
+        match rx.recv_timeout(Duration::from_millis(200)) {
+            // tar lists the directories too, with a trailing slash
+            Ok(Event::Line { line, .. }) if line.ends_with(b"/") => {}
+            Ok(Event::Line { host, .. }) => match unpacked.iter_mut().find(|(h, _)| *h == host) {
+                Some((_, n)) => *n += 1,
+                None => unpacked.push((host, 1)),
+            },
+            Ok(Event::Done(result)) => {
+                done += 1;
+                unpacked.retain(|(host, _)| *host != result.host);
+            }
+            Ok(Event::Notice { .. } | Event::Started { .. }) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if terminal && shown.elapsed() >= Duration::from_millis(200) {
+            shown = Instant::now();
+            let mut line = format!("{}/{} hosts done", done, hosts);
+            for (host, n) in &unpacked {
+                line.push_str(&format!(", {} {}/{}", host, n.min(&files), files));
+            }
+            let line: String = line.chars().take(100).collect();
+            eprint!("\r\x1b[K{}", line);
+        }
+    }
+    if terminal {
+        eprint!("\r\x1b[K");
+    }
+}
+
+/// Remote command writing its stdin to `dest`. The file is written next to
+/// `dest` first, given its attributes and then moved in place, so nothing
+/// ever reads it half written, and missing parent directories are created.
+/// A file that is already there with the same content is left alone but
+/// for its attributes, the command prints whether it changed anything for
+/// [`push_changed`].
+///
+/// When `resume`, stdin starts with a line giving how many bytes of the
+/// file were left next to `dest` by an interrupted push, see
+/// [`partial_command`]: the rest of the file is appended to them, or the
+/// file is written from scratch for 0.
+pub fn push_command(dest: &str, attrs: &FileAttrs, resume: bool) -> String {
+    let tmp = tmp_path(dest);
+    let write = match resume {
+        false => format!("cat > {}", quote(&tmp)),
+        true => format!(
+            "IFS= read -r O && if [ \"$O\" = 0 ]; then cat > {tmp}; \
+             elif [ \"$(wc -c < {tmp})\" -eq \"$O\" ]; then cat >> {tmp}; \
+             else echo \"{tmp} changed, push again\" >&2; exit 1; fi",
+            tmp = quote(&tmp)
+        ),
+    };
+    format!(
+        "mkdir -p -- \"$(dirname -- {})\" && {} && {}",
+        quote(dest),
+        write,
+        install_command(dest, attrs)
+    )
+}
+
+/// Where a file pushed to `dest` is written before being moved in place
+fn tmp_path(dest: &str) -> String {
+    format!("{}.multissh-tmp", dest)
+}
+
+/// Remote command moving the file written next to `dest` in place, see
+/// [`push_command`]
+fn install_command(dest: &str, attrs: &FileAttrs) -> String {
+    let tmp = tmp_path(dest);
+    let mut command = format!(
+        "if cmp -s -- {tmp} {dest}; then rm -f -- {tmp}; F={dest}; S={UNCHANGED}; \
+         else F={tmp}; S=changed; fi",
+        dest = quote(dest),
+        tmp = quote(&tmp)
+    );
+    let apply = attrs.commands();
+    if !apply.is_empty() {
+        // `ls -ld` shows both the mode and the owners
+        command.push_str(&format!(
+            " && B=$(ls -ld -- \"$F\") && {} && \
+             if [ \"$(ls -ld -- \"$F\")\" != \"$B\" ]; then S=changed; fi",
+            apply.join(" && ")
+        ));
+    }
+    command.push_str(&format!(
+        " && {{ [ \"$F\" = {dest} ] || mv -f -- {tmp} {dest}; }} && echo \"$S\"",
+        dest = quote(dest),
+        tmp = quote(&tmp)
+    ));
+    command
+}
+
+const UNCHANGED: &str = "unchanged";
+
+/// Chunks of a chunked push start at multiples of this, the block size of
+/// `dd`
+const CHUNK_ALIGN: u64 = 1 << 20;
+
+/// Start and length of the up to `chunks` ranges a file of `size` bytes is
+/// pushed in at once, none for an empty file
+pub fn chunk_ranges(size: u64, chunks: u64) -> Vec<(u64, u64)> {
+    if size == 0 {
+        return Vec::new();
+    }
+    let len = size.div_ceil(chunks.max(1)).next_multiple_of(CHUNK_ALIGN);
+    (0..size)
+        .step_by(len as usize)
+        .map(|start| (start, len.min(size - start)))
+        .collect()
+}
+
+/// Remote commands of a chunked push to `dest`: the first empties the file
+/// the chunks are written to, the second writes its stdin at `start` of
+/// it, on as many connections at once as there are chunks, and the third
+/// moves it in place like [`push_command`]
+pub fn chunked_push_commands(
+    dest: &str,
+    attrs: &FileAttrs,
+) -> (String, impl Fn(u64) -> String, String) {
+    let tmp = quote(&tmp_path(dest));
+    let prepare = format!(
+        "mkdir -p -- \"$(dirname -- {})\" && : > {}",
+        quote(dest),
+        tmp
+    );
+    let chunk = move |start: u64| {
+        // dd reports how much it copied even when failing, only its
+        // error is kept
+        format!(
+            "E=$(dd of={} bs={} seek={} conv=notrunc 2>&1) || \
+             {{ printf '%s\\n' \"$E\" | head -n 1 >&2; exit 1; }}",
+            tmp,
+            CHUNK_ALIGN,
+            start / CHUNK_ALIGN
+        )
+    };
+    (prepare, chunk, install_command(dest, attrs))
+}
+
+/// Whether a successful push replaced the remote file. Hosts without `cmp`
+/// always replace it.
+pub fn push_changed(result: &HostResult) -> bool {
+    String::from_utf8_lossy(&result.stdout).trim() != UNCHANGED
+}
+
+/// Describe a successful push of `size` bytes to `dest`, the first
+/// `resumed` of which were already there, for [`print_results`]
+pub fn pushed(dest: &str, size: u64, resumed: u64, result: &HostResult) -> String {
+    if !push_changed(result) {
+        format!("{} (unchanged)", dest)
+    } else if resumed > 0 {
+        format!("{} ({} bytes, resumed after {})", dest, size, resumed)
+    } else {
+        format!("{} ({} bytes)", dest, size)
+    }
+}
+
+/// Remote command printing the size and checksum of what an interrupted
+/// push to `dest` left, and nothing when there is nothing
+pub fn partial_command(dest: &str) -> String {
+    format!(
+        "F={}; if [ -f \"$F\" ]; then wc -c < \"$F\" && sha256sum < \"$F\"; fi",
+        quote(&tmp_path(dest))
+    )
+}
+
+/// Bytes of `local` a push can resume after on each host of `checks`, the
+/// results of [`partial_command`]: what is there already when it is the
+/// start of `local`, 0 otherwise
+pub fn resume_offsets(local: &Path, checks: &[HostResult]) -> Result<HashMap<String, u64>> {
+    let size = std::fs::metadata(local)
+        .with_context(|| format!("Failed to read {}", local.display()))?
+        .len();
+    // Hosts interrupted at the same point share the checksum
+    let mut prefixes: HashMap<u64, String> = HashMap::new();
+    let mut offsets = HashMap::new();
+    for check in checks.iter().filter(|c| c.success()) {
+        let output = String::from_utf8_lossy(&check.stdout);
+        let mut lines = output.lines();
+        let (Some(Ok(partial)), Some(sum)) = (
+            lines.next().map(|l| l.trim().parse::<u64>()),
+            lines.next().and_then(|l| l.split_whitespace().next()),
+        ) else {
+            continue;
+        };
+        if partial == 0 || partial > size {
+            continue;
+        }
+        let prefix = match prefixes.get(&partial) {
+            Some(prefix) => prefix.clone(),
+            None => {
+                let prefix = sha256_prefix(local, partial)?;
+                prefixes.insert(partial, prefix.clone());
+                prefix
+            }
+        };
+        if prefix.eq_ignore_ascii_case(sum) {
+            offsets.insert(check.host.clone(), partial);
+        }
+    }
+    Ok(offsets)
+}
+
+/// SHA-256 of the first `len` bytes of the local file at `path`
+fn sha256_prefix(path: &Path, len: u64) -> Result<String> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file.take(len), &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Whether `src` is a glob, fetched as every file it matches
+pub fn is_glob(src: &str) -> bool {
+    src.contains(['*', '?', '['])
+}
+
+/// Remote command printing the file at `src`, or a tar archive of the files
+/// matching it when it is a glob. Fails for sources that can't be fetched.
+/// When `preserve`, the file is sent in an archive too, to keep its mode
+/// and times, and symlinks are kept as such unless `follow`.
+pub fn fetch_command(src: &str, preserve: bool, follow: bool) -> Result<String> {
+    let follow = follow || !preserve;
+    if !is_glob(src) {
+        fetch_path(Path::new(""), "", src)?;
+        return Ok(match (preserve, follow) {
+            (false, _) => format!("cat -- {}", quote(src)),
+            (true, true) => format!("tar -chf - -- {}", quote(src)),
+            (true, false) => format!("tar -cf - -- {}", quote(src)),
+        });
+    }
+    let (wanted, tar) = match follow {
+        true => ("[ -f \"$f\" ]", "tar -chf - -T -"),
+        false => ("{ [ -f \"$f\" ] || [ -L \"$f\" ]; }", "tar -cf - -T -"),
+    };
+    Ok(format!(
+        "for f in {}; do {} && printf '%s\\n' \"$f\"; done | {}",
+        remote_glob(src)?,
+        wanted,
+        tar
+    ))
+}
+
+/// Whether a fetch of `src` receives an archive rather than the file
+pub fn fetches_archive(src: &str, preserve: bool) -> bool {
+    preserve || is_glob(src)
+}
+
+/// Fetch of `src` over SCP, with the remote command running it. The
+/// files matching a glob are saved without their directories. SCP has no
+/// symlinks, they are always followed.
+pub fn scp_fetch(src: &str, preserve: bool) -> Result<(String, scp::Transfer)> {
+    let archive = fetches_archive(src, preserve);
+    let src = match is_glob(src) {
+        true => remote_glob(src)?,
+        false => {
+            fetch_path(Path::new(""), "", src)?;
+            quote(src)
+        }
+    };
+    Ok((
+        scp::fetch_command(&src, preserve),
+        scp::Transfer::Fetch { archive },
+    ))
+}
+
+/// `src` escaped for the remote shell to expand it, everything but its
+/// wildcards is escaped
+fn remote_glob(src: &str) -> Result<String> {
+    if src.contains(['\n', '\0']) {
+        bail!("Invalid glob {:?}", src);
+    }
+    Ok(src
+        .chars()
+        .map(|c| match c {
+            '*' | '?' | '[' | ']' | '!' | '^' | '/' | '.' | '-' | '_' => c.to_string(),
+            c if c.is_ascii_alphanumeric() => c.to_string(),
+            c => format!("\\{}", c),
+        })
+        .collect())
+}
+
+/// Remote command printing the checksums of the files at `path`, or of the
+/// files matching it when it is a glob, for [`verify`]
+pub fn checksum_command(path: &str) -> Result<String> {
+    if !is_glob(path) {
+        return Ok(format!("sha256sum -- {}", quote(path)));
+    }
+    Ok(format!(
+        "for f in {}; do [ -f \"$f\" ] && sha256sum -- \"$f\"; done; true",
+        remote_glob(path)?
+    ))
+}
+
+/// [`checksum_command`] for a fetch of `src`, leaving out a symlink when
+/// `--preserve` recreates it rather than fetching what it points to
+pub fn fetch_checksum_command(src: &str, keep_links: bool) -> Result<String> {
+    let command = checksum_command(src)?;
+    Ok(match keep_links && !is_glob(src) {
+        true => format!("[ -L {} ] || {}", quote(src), command),
+        false => command,
+    })
+}
+
+/// SHA-256 of `data`, as `sha256sum` prints it
+pub fn sha256(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// SHA-256 of the local file at `path`
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Checksums of what a successful fetch of `src` received, by remote path:
+/// the file itself, or every file of the archive of a glob or `--preserve`
+pub fn fetched_checksums(
+    result: &HostResult,
+    src: &str,
+    preserve: bool,
+) -> Result<Vec<(String, String)>> {
+    if !fetches_archive(src, preserve) {
+        return Ok(vec![(src.to_string(), sha256(&result.stdout))]);
+    }
+    let mut checksums = Vec::new();
+    let mut archive = tar::Archive::new(result.stdout.as_slice());
+    for entry in archive.entries().context("Invalid archive")? {
+        let mut entry = entry.context("Invalid archive")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().context("Invalid archive")?;
+        let path = path.to_string_lossy().to_string();
+        let mut contents = Vec::new();
+        entry
+            .read_to_end(&mut contents)
+            .context("Invalid archive")?;
+        checksums.push((path, sha256(&contents)));
+    }
+    Ok(checksums)
+}
+
+/// Fail `result` unless every file of `expected`, given as its remote path
+/// and checksum, has the same checksum in `check`, the result of
+/// [`checksum_command`] on the host. Archives drop the leading `/` of
+/// paths and SCP all directories, the remote paths are matched the same.
+pub fn verify(result: &mut HostResult, check: &HostResult, expected: &[(String, String)]) {
+    if !check.success() {
+        result.error = Some(format!("could not verify: {}", failure_reason(check)));
+        return;
+    }
+    let output = String::from_utf8_lossy(&check.stdout);
+    let remote = remote_checksums(&output);
+    let mut mismatched = Vec::new();
+    for (path, sum) in expected {
+        match find_checksum(&remote, path) {
+            Some((_, remote)) if remote.eq_ignore_ascii_case(sum) => {}
+            Some((_, remote)) => mismatched.push(format!(
+                "{} (local {}, remote {})",
+                path,
+                &sum[..12],
+                &remote[..remote.len().min(12)]
+            )),
+            None => mismatched.push(format!("{} (missing on the host)", path)),
+        }
+    }
+    if !mismatched.is_empty() {
+        result.error = Some(format!("checksum mismatch: {}", mismatched.join(", ")));
+    }
+}
+
+/// Paths and checksums in the output of `sha256sum`
+fn remote_checksums(output: &str) -> Vec<(&str, &str)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (sum, path) = line.split_once(char::is_whitespace)?;
+            Some((path.trim().trim_start_matches('*'), sum))
+        })
+        .collect()
+}
+
+/// The checksum of `remote` for the file at `path`, see [`verify`]
+fn find_checksum<'a>(remote: &[(&'a str, &'a str)], path: &str) -> Option<(&'a str, &'a str)> {
+    remote.iter().copied().find(|(remote, _)| {
+        *remote == path
+            || remote.trim_start_matches('/') == path
+            || (!path.contains('/') && Path::new(remote).file_name() == Some(OsStr::new(path)))
+    })
+}
+
+/// Remote command printing the checksums of what is at `dest` for
+/// [`planned`]: the file, or the files of the directory when `dir`.
+/// Nothing is printed when there is nothing there yet.
+pub fn plan_command(dest: &str, dir: bool) -> String {
+    match dir {
+        false => format!(
+            "if [ -e {dest} ]; then sha256sum -- {dest}; fi",
+            dest = quote(dest)
+        ),
+        true => format!(
+            "if [ -e {} ]; then {}; fi",
+            quote(dest),
+            dir_checksum_command(dest)
+        ),
+    }
+}
+
+/// Describe what a push of `local`, the remote path, checksum and size of
+/// every file, would change on the host `check` ran [`plan_command`] on.
+/// Only contents are compared, and pushes never delete anything.
+pub fn planned(check: &HostResult, local: &[(String, String, u64)]) -> Result<String> {
+    if !check.success() {
+        bail!(failure_reason(check));
+    }
+    let output = String::from_utf8_lossy(&check.stdout);
+    let remote = remote_checksums(&output);
+    let (mut created, mut updated, mut bytes) = (0, 0, 0);
+    let mut changes = Vec::new();
+    for (path, sum, size) in local {
+        let change = match find_checksum(&remote, path) {
+            None => {
+                created += 1;
+                "create"
+            }
+            Some((_, remote)) if !remote.eq_ignore_ascii_case(sum) => {
+                updated += 1;
+                "update"
+            }
+            Some(_) => continue,
+        };
+        bytes += size;
+        changes.push(format!("  {} {} ({} bytes)", change, path, size));
+    }
+    if changes.is_empty() {
+        return Ok("up to date".to_string());
+    }
+    let noun = if created + updated == 1 {
+        "file"
+    } else {
+        "files"
+    };
+    let mut message = format!(
+        "would create {} and update {} {}, sending {} bytes",
+        created, updated, noun, bytes
+    );
+    for change in changes {
+        message.push('\n');
+        message.push_str(&change);
+    }
+    Ok(message)
+}
+
+/// Local path the file `src` fetched from `host` is saved to:
+/// `<dir>/<host>/<file name>`
+pub fn fetch_path(dir: &Path, host: &str, src: &str) -> Result<PathBuf> {
+    let Some(name) = Path::new(src).file_name() else {
+        bail!("{} is not a file", src);
+    };
+    Ok(dir.join(host).join(name))
+}
+
+/// Save the output of a successful fetch of `src` and describe it for
+/// [`print_results`]
+pub fn fetched(dir: &Path, result: &HostResult, src: &str, preserve: bool) -> Result<String> {
+    if !is_glob(src) && !preserve {
+        let path = save_fetched(dir, result, src)?;
+        return Ok(format!(
+            "{} ({} bytes)",
+            path.display(),
+            result.stdout.len()
+        ));
+    }
+    if !is_glob(src) {
+        let path = fetch_path(dir, &result.host, src)?;
+        return save_preserved(&path, &result.stdout);
+    }
+    let host_dir = dir.join(&result.host);
+    let saved = unpack_fetched(&host_dir, &result.stdout, preserve)?;
+    if saved.files == 0 {
+        bail!("no files match {}", src);
+    }
+    let noun = if saved.files == 1 { "file" } else { "files" };
+    let mut message = format!(
+        "{} {} in {} ({} bytes)",
+        saved.files,
+        noun,
+        host_dir.display(),
+        saved.bytes
+    );
+    if saved.links > 0 {
+        message.push_str(&format!(", {} symlinks", saved.links));
+    }
+    for (path, renamed) in &saved.renamed {
+        message.push_str(&format!(
+            "\n  {} exists, saved as {}",
+            path.display(),
+            renamed.display()
+        ));
+    }
+    Ok(message)
+}
+
+/// Save the output of a successful fetch of `src`, returns where it was saved
+pub fn save_fetched(dir: &Path, result: &HostResult, src: &str) -> Result<PathBuf> {
+    let path = fetch_path(dir, &result.host, src)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    std::fs::write(&path, &result.stdout)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// Save the single file or symlink of the archive of a fetch with
+/// `--preserve` as `path`, replacing what is there, and describe it for
+/// [`print_results`]
+pub fn save_preserved(path: &Path, archive: &[u8]) -> Result<String> {
+    let mut archive = tar::Archive::new(archive);
+    let mut entries = archive.entries().context("Invalid archive")?;
+    let mut entry = entries
+        .next()
+        .context("Invalid archive, it is empty")?
+        .context("Invalid archive")?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    // Never write through a symlink an earlier fetch left
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+    if let Some(target) = entry.link_name().context("Invalid archive")? {
+        let _ = std::fs::remove_file(path);
+        std::os::unix::fs::symlink(&target, path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        return Ok(format!("{} -> {}", path.display(), target.display()));
+    }
+    let mut contents = Vec::new();
+    entry
+        .read_to_end(&mut contents)
+        .context("Invalid archive")?;
+    std::fs::write(path, &contents)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    preserve_attrs(path, entry.header())?;
+    Ok(format!("{} ({} bytes)", path.display(), contents.len()))
+}
+
+/// Give the file at `path` the mode and modification time of `header`
+fn preserve_attrs(path: &Path, header: &tar::Header) -> Result<()> {
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?);
+    // Before the mode, which may not let it be written anymore
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(mtime))
+        .with_context(|| format!("Failed to set the time of {}", path.display()))?;
+    let mode = header.mode()? & 0o7777;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set the mode of {}", path.display()))?;
+    Ok(())
+}
+
+/// Files saved out of the archive of a glob fetch
+#[derive(Debug, Default)]
+pub struct Unpacked {
+    pub files: usize,
+    pub bytes: u64,
+    /// Symlinks recreated, with `--preserve`
+    pub links: usize,
+    /// Files that were saved under another name, see [`unpack_fetched`]
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Save the files of the tar archive of a glob fetch in `dir`, keeping
+/// their remote paths (without any leading `/`). Files are never
+/// overwritten: one that is already there with other contents, e.g. from an
+/// earlier fetch or because the local file system ignores case, gets the
+/// next free `.~N~` name. When `preserve`, files keep their mode and
+/// modification time, and symlinks are recreated once all files are saved,
+/// nothing is ever written through one.
+pub fn unpack_fetched(dir: &Path, archive: &[u8], preserve: bool) -> Result<Unpacked> {
+    let mut unpacked = Unpacked::default();
+    let mut links = Vec::new();
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries().context("Invalid archive")? {
+        let mut entry = entry.context("Invalid archive")?;
+        let kind = entry.header().entry_type();
+        let wanted = kind.is_file() || (preserve && kind.is_symlink());
+        if !wanted {
+            continue;
+        }
+        let remote = entry.path().context("Invalid archive")?.into_owned();
+        let mut path = dir.to_path_buf();
+        for component in remote.components() {
+            if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+                bail!(
+                    "Refusing to save {} through the symlink {}",
+                    remote.display(),
+                    path.display()
+                );
+            }
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir | Component::RootDir => {}
+                _ => bail!(
+                    "Refusing to save {} outside of {}",
+                    remote.display(),
+                    dir.display()
+                ),
+            }
+        }
+        if kind.is_symlink() {
+            if let Some(target) = entry.link_name().context("Invalid archive")? {
+                links.push((path, target.into_owned()));
+            }
+            continue;
+        }
+        let mut contents = Vec::new();
+        entry
+            .read_to_end(&mut contents)
+            .context("Invalid archive")?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let target = free_path(&path, |existing| {
+            Ok(!existing.file_type().is_symlink() && std::fs::read(&path)? == contents)
+        })?;
+        if target != path {
+            unpacked.renamed.push((path, target.clone()));
+        }
+        std::fs::write(&target, &contents)
+            .with_context(|| format!("Failed to write {}", target.display()))?;
+        if preserve {
+            preserve_attrs(&target, entry.header())?;
+        }
+        unpacked.files += 1;
+        unpacked.bytes += contents.len() as u64;
+    }
+    for (path, link) in links {
+        let target = free_path(&path, |existing| {
+            Ok(existing.file_type().is_symlink() && std::fs::read_link(&path)? == link)
+        })?;
+        if target != path {
+            unpacked.renamed.push((path, target.clone()));
+        }
+        if std::fs::symlink_metadata(&target).is_err() {
+            std::os::unix::fs::symlink(&link, &target)
+                .with_context(|| format!("Failed to create {}", target.display()))?;
+        }
+        unpacked.links += 1;
+    }
+    Ok(unpacked)
+}
+
+/// `path`, or the first of `path.~1~`, `path.~2~`... that is free or
+/// already has what is saved, as `same` tells from its metadata
+fn free_path(
+    path: &Path,
+    same: impl Fn(&std::fs::Metadata) -> io::Result<bool>,
+) -> Result<PathBuf> {
+    let mut candidate = path.to_path_buf();
+    for n in 1.. {
+        match std::fs::symlink_metadata(&candidate) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(candidate),
+            Ok(existing) if same(&existing).unwrap_or(false) => return Ok(candidate),
+            _ => {}
+        }
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".~{}~", n));
+        candidate = PathBuf::from(name);
+    }
+    unreachable!()
+}
+
+/// Print how the transfer went on every host, one line each, or more when
+/// the description has several. `done` finishes a successful transfer and
+/// describes it. Returns whether every host succeeded.
+pub fn print_results(results: &[HostResult], done: impl Fn(&HostResult) -> Result<String>) -> bool {
+    let width = results.iter().map(|r| r.host.len()).max().unwrap_or(0);
+    let mut ok = true;
+    for result in results {
+        let outcome = if result.success() {
+            done(result).map_err(|e| format!("{:#}", e))
+        } else {
+            Err(failure_reason(result))
+        };
+        match outcome {
+            Ok(message) => {
+                for line in message.lines() {
+                    println!("{:width$} | {}", result.host, line);
+                }
+            }
+            Err(message) => {
+                ok = false;
+                println!("{:width$} | error: {}", result.host, message);
+            }
+        }
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_ranges_cover_the_file() {
+        const MIB: u64 = CHUNK_ALIGN;
+        assert_eq!(chunk_ranges(0, 1), vec![]);
+        assert_eq!(chunk_ranges(0, 4), vec![]);
+        assert_eq!(chunk_ranges(1, 1), vec![(0, 1)]);
+        assert_eq!(chunk_ranges(1, 4), vec![(0, 1)]);
+        assert_eq!(chunk_ranges(MIB, 1), vec![(0, MIB)]);
+        assert_eq!(
+            chunk_ranges(4 * MIB, 4),
+            vec![(0, MIB), (MIB, MIB), (2 * MIB, MIB), (3 * MIB, MIB)]
+        );
+        assert_eq!(chunk_ranges(MIB + 1, 1), vec![(0, MIB + 1)]);
+        assert_eq!(chunk_ranges(MIB + 1, 2), vec![(0, MIB), (MIB, 1)]);
+        assert_eq!(
+            chunk_ranges(4 * MIB + 1, 4),
+            vec![(0, 2 * MIB), (2 * MIB, 2 * MIB), (4 * MIB, 1)]
+        );
+    }
+}
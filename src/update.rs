@@ -0,0 +1,146 @@
+//! `multissh self-update`: replace the running binary with the one of the
+//! latest GitHub release.
+//!
+//! Releases carry one bare binary per platform, named
+//! `multissh-rs-<arch>-<os>` (e.g. `multissh-rs-x86_64-linux`), and a
+//! `SHA256SUMS` file listing them. The binary is only installed when its
+//! checksum matches. Downloads go through `curl`, which hosts without a
+//! package manager still tend to have, like they have `ssh`.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/lcrownover/multissh-rs/releases/latest";
+
+/// Name of the file listing the checksums of the release binaries
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// Version of the release, without the `v` of the tag
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    fn asset(&self, name: &str) -> Result<&Asset> {
+        self.assets
+            .iter()
+            .find(|a| a.name == name)
+            .with_context(|| format!("Release {} has no {}", self.tag_name, name))
+    }
+}
+
+/// The latest release on GitHub
+pub fn latest_release() -> Result<Release> {
+    let body = download(LATEST_RELEASE_URL)?;
+    serde_json::from_slice(&body).context("Unexpected answer from the GitHub releases API")
+}
+
+/// Name of the release binary for this platform
+pub fn asset_name() -> String {
+    format!(
+        "multissh-rs-{}-{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    )
+}
+
+/// Whether `version` is newer than `current`, both like `1.2.3`
+pub fn is_newer(version: &str, current: &str) -> bool {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .split(['.', '-', '+'])
+            .map_while(|part| part.parse().ok())
+            .collect()
+    };
+    parse(version) > parse(current)
+}
+
+/// Download the binary of `release` for this platform and check it against
+/// the release checksums
+pub fn download_binary(release: &Release) -> Result<Vec<u8>> {
+    let name = asset_name();
+    let binary = release.asset(&name)?;
+    let checksums = download(&release.asset(CHECKSUMS_ASSET)?.browser_download_url)?;
+    let expected = String::from_utf8_lossy(&checksums)
+        .lines()
+        .find_map(|line| {
+            // `sha256sum` output: checksum, then the name, `*` marking binary mode
+            let (sum, file) = line.split_once(char::is_whitespace)?;
+            (file.trim().trim_start_matches('*') == name).then(|| sum.to_lowercase())
+        })
+        .with_context(|| {
+            format!(
+                "{} of {} has no {}",
+                CHECKSUMS_ASSET, release.tag_name, name
+            )
+        })?;
+    let data = download(&binary.browser_download_url)?;
+    let actual = hex::encode(Sha256::digest(&data));
+    if actual != expected {
+        bail!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            name,
+            expected,
+            actual
+        );
+    }
+    Ok(data)
+}
+
+/// Replace the binary at `exe` with `data`, keeping its permissions. The new
+/// file is written next to it and renamed over it, so a failed update
+/// leaves the old binary in place.
+pub fn install(exe: &Path, data: &[u8]) -> Result<()> {
+    let file_name = exe.file_name().context("Invalid path of the binary")?;
+    let tmp = exe.with_file_name(format!(".{}.new", file_name.to_string_lossy()));
+    let permissions = std::fs::metadata(exe)
+        .with_context(|| format!("Failed to read {}", exe.display()))?
+        .permissions();
+    std::fs::write(&tmp, data).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::set_permissions(&tmp, permissions)
+        .and_then(|_| std::fs::rename(&tmp, exe))
+        .with_context(|| format!("Failed to replace {}", exe.display()))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })
+}
+
+fn download(url: &str) -> Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args([
+            "-fsSL",
+            "--proto",
+            "=https",
+            "-H",
+            "User-Agent: multissh-rs",
+        ])
+        .arg("--")
+        .arg(url)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run curl")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,244 @@
+//! Decryption of inventories encrypted with age or ansible-vault.
+//!
+//! Encrypted inventories are detected from their header and decrypted in
+//! memory, the plaintext is never written to disk. The key is read from a
+//! key file when one is given, otherwise the passphrase is asked for once
+//! and reused for every encrypted inventory.
+//!
+//! - age: binary or ASCII-armored files encrypted to a passphrase
+//!   (`age -p`), or to a recipient whose identity is in the key file
+//!   (`age -r`, decrypted with the `AGE-SECRET-KEY-...` file from
+//!   `age-keygen`)
+//! - ansible-vault: `$ANSIBLE_VAULT;1.1;AES256` and `1.2` files, the key
+//!   file holds the vault password on its first line
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use age::secrecy::SecretString;
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+const AGE_ARMOR_MAGIC: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+const ANSIBLE_VAULT_MAGIC: &[u8] = b"$ANSIBLE_VAULT;";
+
+/// PBKDF2 iterations used by ansible-vault
+const ANSIBLE_VAULT_ITERATIONS: u32 = 10000;
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// Encryption format of an inventory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    Age,
+    AnsibleVault,
+}
+
+impl Format {
+    pub fn detect(data: &[u8]) -> Format {
+        let data = data.trim_ascii_start();
+        if data.starts_with(AGE_MAGIC) || data.starts_with(AGE_ARMOR_MAGIC) {
+            Format::Age
+        } else if data.starts_with(ANSIBLE_VAULT_MAGIC) {
+            Format::AnsibleVault
+        } else {
+            Format::Plain
+        }
+    }
+}
+
+/// Where the key of encrypted inventories comes from
+#[derive(Debug, Default)]
+pub struct Keys {
+    /// age identity file, or file holding the ansible-vault password
+    pub key_file: Option<PathBuf>,
+    /// Passphrase asked for on the terminal, kept for the next inventory
+    passphrase: RefCell<Option<SecretString>>,
+}
+
+impl Keys {
+    pub fn new(key_file: Option<PathBuf>) -> Self {
+        Self {
+            key_file,
+            ..Default::default()
+        }
+    }
+
+    /// Contents of `data` read from `path`, decrypted if it is encrypted
+    pub fn decrypt(&self, path: &Path, data: Vec<u8>) -> Result<Vec<u8>> {
+        let plaintext = match Format::detect(&data) {
+            Format::Plain => return Ok(data),
+            Format::Age => self.decrypt_age(&data),
+            Format::AnsibleVault => self.decrypt_ansible_vault(&data),
+        };
+        plaintext.with_context(|| format!("Failed to decrypt inventory {}", path.display()))
+    }
+
+    fn decrypt_age(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let decryptor = age::Decryptor::new_buffered(age::armor::ArmoredReader::new(data))?;
+        let mut reader = if decryptor.is_scrypt() {
+            let identity = age::scrypt::Identity::new(self.passphrase()?);
+            decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))?
+        } else {
+            let Some(key_file) = &self.key_file else {
+                bail!("encrypted to an age recipient, pass its identity file with --inventory-key");
+            };
+            let context = || format!("Failed to read age identity file {}", key_file.display());
+            let identities = age::IdentityFile::from_file(key_file.display().to_string())
+                .with_context(context)?
+                .into_identities()
+                .with_context(context)?;
+            decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))?
+        };
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    fn decrypt_ansible_vault(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let text = std::str::from_utf8(data).context("invalid ansible-vault file")?;
+        let mut lines = text.trim().lines();
+        let header = lines.next().unwrap_or_default();
+        // $ANSIBLE_VAULT;1.1;AES256 or $ANSIBLE_VAULT;1.2;AES256;<vault id>
+        let fields: Vec<&str> = header.trim().split(';').collect();
+        if fields.len() < 3 || !matches!(fields[1], "1.1" | "1.2") {
+            bail!("unsupported ansible-vault header: {}", header);
+        }
+        if fields[2] != "AES256" {
+            bail!("unsupported ansible-vault cipher: {}", fields[2]);
+        }
+
+        let body: String = lines.map(str::trim).collect();
+        let body = hex::decode(body).context("invalid ansible-vault data")?;
+        let body = String::from_utf8(body).context("invalid ansible-vault data")?;
+        let parts: Vec<Vec<u8>> = body
+            .lines()
+            .map(hex::decode)
+            .collect::<Result<_, _>>()
+            .context("invalid ansible-vault data")?;
+        let [salt, expected_hmac, ciphertext] = parts.as_slice() else {
+            bail!("invalid ansible-vault data");
+        };
+
+        let mut derived = [0u8; 80];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            self.ansible_vault_password()?.as_bytes(),
+            salt,
+            ANSIBLE_VAULT_ITERATIONS,
+            &mut derived,
+        );
+        let (cipher_key, rest) = derived.split_at(32);
+        let (hmac_key, iv) = rest.split_at(32);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(hmac_key)?;
+        mac.update(ciphertext);
+        if mac.verify_slice(expected_hmac).is_err() {
+            bail!("wrong vault password");
+        }
+
+        let mut plaintext = ciphertext.clone();
+        Aes256Ctr::new(cipher_key.into(), iv.into()).apply_keystream(&mut plaintext);
+        // PKCS#7 padding
+        let padding = plaintext.last().copied().unwrap_or(0) as usize;
+        if padding == 0 || padding > 16 || padding > plaintext.len() {
+            bail!("invalid ansible-vault padding");
+        }
+        plaintext.truncate(plaintext.len() - padding);
+        Ok(plaintext)
+    }
+
+    /// Vault password from the first line of the key file, or the passphrase
+    fn ansible_vault_password(&self) -> Result<String> {
+        use age::secrecy::ExposeSecret;
+        match &self.key_file {
+            Some(key_file) => {
+                let text = std::fs::read_to_string(key_file).with_context(|| {
+                    format!("Failed to read vault password file {}", key_file.display())
+                })?;
+                Ok(text.lines().next().unwrap_or_default().trim().to_string())
+            }
+            None => Ok(self.passphrase()?.expose_secret().to_string()),
+        }
+    }
+
+    /// Ask for the passphrase the first time it is needed
+    fn passphrase(&self) -> Result<SecretString> {
+        let mut passphrase = self.passphrase.borrow_mut();
+        if let Some(passphrase) = passphrase.as_ref() {
+            return Ok(passphrase.clone());
+        }
+        let entered = rpassword::prompt_password("Inventory passphrase: ")?;
+        Ok(passphrase.insert(SecretString::from(entered)).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// [`PLAINTEXT`] encrypted with the password "correct horse" the way
+    /// `ansible-vault encrypt` does (its `VaultAES256`, 80 column lines)
+    const VAULT: &str = "$ANSIBLE_VAULT;1.1;AES256
+31336136343432633863336264333163383266383433326465666462653132396632623436326365
+6135383331306234323737396263623036616462323665300a326439373036623964356135383839
+33653363343438383334393331383437303163323961393132346139306664636664323339656136
+6165343236643331380a633864303230393435383537393264356634663234383766656464323461
+36396339396532633466343032633730666531366439346438613863653861316635323363393764
+3862643738323432383465633562306165343662396237666132
+";
+
+    const PLAINTEXT: &str = "all:\n  hosts:\n    web1:\n    web2:\n";
+
+    fn keys(password: &str) -> Keys {
+        let keys = Keys::new(None);
+        *keys.passphrase.borrow_mut() = Some(SecretString::from(password.to_string()));
+        keys
+    }
+
+    fn decrypt(keys: &Keys, vault: &str) -> Result<Vec<u8>> {
+        keys.decrypt(Path::new("inventory.yml"), vault.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn decrypts_ansible_vault() {
+        assert_eq!(Format::detect(VAULT.as_bytes()), Format::AnsibleVault);
+        let plaintext = decrypt(&keys("correct horse"), VAULT).unwrap();
+        assert_eq!(String::from_utf8(plaintext).unwrap(), PLAINTEXT);
+    }
+
+    #[test]
+    fn rejects_wrong_password() {
+        let e = decrypt(&keys("wrong horse"), VAULT).unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("wrong vault password"),
+            "{:#}",
+            e
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_hmac() {
+        let (header, body) = VAULT.split_once('\n').unwrap();
+        let body: String = body.lines().collect();
+        let body = hex::decode(body).unwrap();
+        let mut parts: Vec<String> = String::from_utf8(body)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        let flipped = if parts[1].starts_with('0') { "1" } else { "0" };
+        parts[1].replace_range(..1, flipped);
+        let tampered = format!("{}\n{}\n", header, hex::encode(parts.join("\n")));
+
+        let e = decrypt(&keys("correct horse"), &tampered).unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("wrong vault password"),
+            "{:#}",
+            e
+        );
+    }
+}
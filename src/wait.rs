@@ -0,0 +1,143 @@
+//! Waiting for targets to come back, after `multissh reboot --wait` or with
+//! `multissh wait` once new hosts are provisioned.
+//!
+//! Every host is polled on its own thread with a cheap probe command over
+//! ssh until the probe says it's back or the timeout expires. Rebooted
+//! hosts are told apart from hosts that haven't gone down yet by their
+//! kernel boot id, which changes on every boot.
+
+use crate::ssh::SshOptions;
+use crate::targets::Target;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Remote command printing the boot id of the host, nothing where the
+/// kernel has none
+pub const BOOT_ID_COMMAND: &str = "cat /proc/sys/kernel/random/boot_id 2>/dev/null; true";
+
+/// Remote command rebooting the host, through `sudo -n` unless connected as
+/// root. It prints the boot id first, and the reboot itself happens in the
+/// background a moment later so the command still exits cleanly.
+pub const REBOOT_COMMAND: &str = "S=$([ \"$(id -u)\" = 0 ] || echo sudo -n) && $S true && \
+     { cat /proc/sys/kernel/random/boot_id 2>/dev/null; \
+     nohup sh -c \"sleep 2; $S reboot\" > /dev/null 2>&1 & }";
+
+#[derive(Clone, Copy, Debug)]
+pub struct WaitOptions {
+    /// How long to wait for each host, from when waiting starts
+    pub timeout: Duration,
+    /// Delay between two probes of a host
+    pub interval: Duration,
+}
+
+/// How waiting for a host went
+#[derive(Clone, Debug)]
+pub struct Waited {
+    pub host: String,
+    /// Time until the host was back, or the last reason it wasn't
+    pub back: Result<Duration, String>,
+}
+
+/// Poll every target with `probe` until `is_back` says it's back. `is_back`
+/// gets the host and the probe's output, `None` when the host couldn't be
+/// reached or the probe failed. Times are counted from `since`. `report` is
+/// called as soon as each host is done waiting. Results are returned in
+/// target order.
+pub fn wait_until<B, R>(
+    targets: &[Target],
+    ssh: &SshOptions,
+    probe: &str,
+    options: &WaitOptions,
+    since: Instant,
+    is_back: B,
+    report: R,
+) -> Vec<Waited>
+where
+    B: Fn(&str, Option<&str>) -> bool + Sync,
+    R: Fn(&Waited) + Sync,
+{
+    let (is_back, report) = (&is_back, &report);
+    thread::scope(|scope| {
+        let handles: Vec<_> = targets
+            .iter()
+            .map(|target| {
+                scope.spawn(move || {
+                    let waited = match ssh.with_vars(&target.vars) {
+                        Ok(ssh) => wait_host(&target.host, &ssh, probe, options, since, is_back),
+                        Err(e) => Waited {
+                            host: target.host.clone(),
+                            back: Err(format!("{:#}", e)),
+                        },
+                    };
+                    report(&waited);
+                    waited
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
+
+fn wait_host(
+    host: &str,
+    ssh: &SshOptions,
+    probe: &str,
+    options: &WaitOptions,
+    since: Instant,
+    is_back: &(dyn Fn(&str, Option<&str>) -> bool + Sync),
+) -> Waited {
+    let mut reason = "not down yet".to_string();
+    loop {
+        let started = Instant::now();
+        let output = match ssh
+            .command(host, probe)
+            .and_then(|mut cmd| Ok(cmd.output()?))
+        {
+            Ok(output) if output.status.success() => {
+                Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                reason = match stderr.lines().rfind(|l| !l.trim().is_empty()) {
+                    Some(line) => line.trim().to_string(),
+                    None => format!("probe exited with {:?}", output.status.code()),
+                };
+                None
+            }
+            Err(e) => {
+                reason = format!("{:#}", e);
+                None
+            }
+        };
+        if is_back(host, output.as_deref()) {
+            return Waited {
+                host: host.to_string(),
+                back: Ok(since.elapsed()),
+            };
+        }
+        if since.elapsed() >= options.timeout {
+            return Waited {
+                host: host.to_string(),
+                back: Err(reason),
+            };
+        }
+        // Probes that hang until ConnectTimeout already waited long enough
+        thread::sleep(options.interval.saturating_sub(started.elapsed()));
+    }
+}
+
+/// Duration as e.g. `1m 12s`
+pub fn human_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds / 60 {
+        0 => format!("{}s", seconds),
+        minutes => format!("{}m {}s", minutes, seconds % 60),
+    }
+}
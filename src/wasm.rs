@@ -0,0 +1,115 @@
+//! Sandboxed WebAssembly plugins (feature `wasm`), a portable alternative
+//! to the programs of [`crate::plugin`] that can't touch the files, network
+//! or processes of the machine: modules get no imports at all, a fixed
+//! amount of memory and of fuel per call.
+//!
+//! Modules export their `memory`, `alloc(len: i32) -> i32` returning room
+//! for `len` bytes, and the plugin functions, called with the pointer and
+//! length of a JSON request and returning the pointer of the JSON answer in
+//! the upper 32 bits and its length in the lower ones:
+//!
+//! - `inventory(ptr: i32, len: i32) -> i64`, for `-i wasm:FILE[:ARG]`, with
+//!   the request and answer of [`crate::plugin`]
+//! - `transform(ptr: i32, len: i32) -> i64`, for `--filter-script
+//!   FILE.wasm`, with the host `{"host", "stdout", "stderr", "exit_code",
+//!   "error", "duration", "success"}` and answering what to change, any of
+//!   `{"stdout", "stderr", "note", "hide"}`, or nothing to leave it as is
+//!
+//! Modules can be given as `.wasm` binaries or `.wat` text.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::Path;
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Memory a module can grow to
+const MAX_MEMORY: usize = 256 << 20;
+
+/// Instructions (roughly) a call can run, a few seconds' worth
+const FUEL: u64 = 10_000_000_000;
+
+/// A compiled plugin module
+pub struct WasmPlugin {
+    engine: Engine,
+    module: Module,
+    name: String,
+}
+
+impl std::fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WasmPlugin {
+    /// Read and compile the module at `path`
+    pub fn load(path: &Path) -> Result<WasmPlugin> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::from_file(&engine, path)
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("Failed to load WASM plugin {}", path.display()))?;
+        if let Some(import) = module.imports().next() {
+            bail!(
+                "WASM plugin {} imports {}::{}, plugins can't import anything",
+                path.display(),
+                import.module(),
+                import.name()
+            );
+        }
+        Ok(WasmPlugin {
+            engine,
+            module,
+            name: path.display().to_string(),
+        })
+    }
+
+    /// Whether the module exports the plugin function `export`
+    pub fn exports(&self, export: &str) -> bool {
+        self.module.get_export(export).is_some()
+    }
+
+    /// Call the plugin function `export` with `input`, in an instance of
+    /// its own, and return its answer
+    pub fn call(&self, export: &str, input: &[u8]) -> Result<Vec<u8>> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build();
+        let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL)?;
+        let context = || format!("WASM plugin {} failed in {}", self.name, export);
+        let instance = Instance::new(&mut store, &self.module, &[]);
+        let instance = instance
+            .map_err(anyhow::Error::from)
+            .with_context(context)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("WASM plugin {} exports no memory", self.name))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(anyhow::Error::from)
+            .with_context(context)?;
+        let function = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, export)
+            .map_err(anyhow::Error::from)
+            .with_context(context)?;
+
+        let len = i32::try_from(input.len()).context("Request too large for a WASM plugin")?;
+        let ptr = alloc.call(&mut store, len);
+        let ptr = ptr.map_err(anyhow::Error::from).with_context(context)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input)
+            .with_context(context)?;
+        let answer = function
+            .call(&mut store, (ptr, len))
+            .map_err(anyhow::Error::from)
+            .with_context(context)?;
+        let (ptr, len) = ((answer >> 32) as u32 as usize, answer as u32 as usize);
+        let data = memory.data(&store);
+        match data.get(ptr..ptr.saturating_add(len)) {
+            Some(answer) => Ok(answer.to_vec()),
+            None => bail!("WASM plugin {} answered out of its memory", self.name),
+        }
+    }
+}